@@ -1,10 +1,12 @@
 use {
     bytemuck::{Pod, Zeroable},
     cgmath::Vector3,
+    serde::{Deserialize, Serialize},
     std::ops,
 };
 
-#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable, Serialize, Deserialize)]
+#[serde(from = "[f32; 3]", into = "[f32; 3]")]
 #[repr(C)]
 pub struct Vec3([f32; 3]);
 
@@ -182,6 +184,15 @@ impl_binary_op!(Sub : sub => (lhs: Vec3, rhs: Vec3) -> Vec3 {
     ])
 });
 
+// Component-wise, like multiplying two vec3s in WGSL
+impl_binary_op!(Mul : mul => (lhs: Vec3, rhs: Vec3) -> Vec3 {
+    Vec3([
+        lhs.x() * rhs.x(),
+        lhs.y() * rhs.y(),
+        lhs.z() * rhs.z(),
+    ])
+});
+
 impl_binary_op!(Mul : mul => (lhs: Vec3, rhs: f32) -> Vec3 {
     Vec3([
         lhs.x() * rhs,
@@ -218,6 +229,12 @@ impl ops::SubAssign for Vec3 {
     }
 }
 
+impl ops::MulAssign for Vec3 {
+    fn mul_assign(&mut self, rhs: Self) {
+        *self = *self * rhs;
+    }
+}
+
 impl ops::MulAssign<f32> for Vec3 {
     fn mul_assign(&mut self, rhs: f32) {
         *self = *self * rhs;
@@ -237,12 +254,32 @@ impl ops::Neg for Vec3 {
     }
 }
 
-impl Into<Vector3<f32>> for Vec3 {
-    fn into(self) -> Vector3<f32> {
+// Look up a component by axis, 0 = x, 1 = y, 2 = z
+impl ops::Index<usize> for Vec3 {
+    type Output = f32;
+    fn index(&self, axis: usize) -> &f32 {
+        &self.0[axis]
+    }
+}
+
+impl From<[f32; 3]> for Vec3 {
+    fn from(v: [f32; 3]) -> Self {
+        Vec3(v)
+    }
+}
+
+impl From<Vec3> for [f32; 3] {
+    fn from(v: Vec3) -> Self {
+        v.0
+    }
+}
+
+impl From<Vec3> for Vector3<f32> {
+    fn from(val: Vec3) -> Self {
         Vector3::<f32> {
-            x: self.x(),
-            y: self.y(),
-            z: self.z(),
+            x: val.x(),
+            y: val.y(),
+            z: val.z(),
         }
     }
 }
@@ -0,0 +1,477 @@
+use std::{ops::Range, sync::mpsc};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    bvh::{ObjectRef, AABB, BVH},
+    primitives::Scene,
+    texture::{Texture, TextureLayers},
+};
+
+// Storage buffers can't be empty and WGSL needs room for at least one element of every array
+const MIN_BUFFER_SIZE: u64 = 256;
+
+/// GPU storage buffer that is reallocated whenever the data written to it no longer fits
+pub struct GrowableBuffer {
+    label: &'static str,
+    buffer: wgpu::Buffer,
+}
+
+impl GrowableBuffer {
+    pub fn new(device: &wgpu::Device, label: &'static str) -> Self {
+        Self {
+            label,
+            buffer: Self::create(device, label, MIN_BUFFER_SIZE),
+        }
+    }
+
+    fn create(device: &wgpu::Device, label: &'static str, size: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size,
+            // Copies out of the buffer are only used to check what compute shaders wrote to it
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Upload `data` to the start of the buffer, growing it first if needed.
+    ///
+    /// Returns true if the buffer was replaced, in which case any bind groups using it have to be
+    /// recreated. Fails if the data is bigger than the device allows a storage buffer to be.
+    pub fn write(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &[u8],
+    ) -> Result<bool> {
+        self.write_parts(device, queue, &[data])
+    }
+
+    /// Upload several pieces of data one after the other, like `write` but without having to
+    /// join them first
+    pub fn write_parts(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        parts: &[&[u8]],
+    ) -> Result<bool> {
+        let size = parts.iter().map(|p| p.len() as u64).sum::<u64>();
+        let reallocated = self.reserve(device, size)?;
+        let mut offset = 0;
+        for part in parts.iter().filter(|p| !p.is_empty()) {
+            queue.write_buffer(&self.buffer, offset, part);
+            offset += part.len() as u64;
+        }
+        Ok(reallocated)
+    }
+
+    /// Make sure the buffer holds at least `size` bytes, for data that is written to it by the
+    /// GPU or with `write_at`. Returns true if the buffer was replaced like `write` does, which
+    /// also loses everything that was in it
+    pub fn reserve(&mut self, device: &wgpu::Device, size: u64) -> Result<bool> {
+        let reallocated = size > self.buffer.size();
+        if reallocated {
+            let max_size = device.limits().max_storage_buffer_binding_size as u64;
+            if size > max_size {
+                bail!(
+                    "The {} need {size} bytes but the GPU only allows {max_size} bytes in a \
+                    storage buffer",
+                    self.label.to_lowercase()
+                );
+            }
+            // Grow geometrically so adding objects one at a time doesn't reallocate every frame
+            let new_size = size.next_power_of_two().clamp(MIN_BUFFER_SIZE, max_size);
+            log::info!(
+                "Growing the {} buffer from {} to {new_size} bytes",
+                self.label,
+                self.buffer.size()
+            );
+            self.buffer = Self::create(device, self.label, new_size);
+        }
+        Ok(reallocated)
+    }
+
+    /// Upload `data` starting `offset` bytes into the buffer, which has to be big enough already
+    pub fn write_at(&self, queue: &wgpu::Queue, offset: u64, data: &[u8]) {
+        if !data.is_empty() {
+            queue.write_buffer(&self.buffer, offset, data);
+        }
+    }
+}
+
+/// Everything the shader needs to know about the scene
+pub struct SceneBuffers {
+    pub materials: GrowableBuffer,
+    pub bvh: GrowableBuffer,
+    pub objects: GrowableBuffer,
+    pub spheres: GrowableBuffer,
+    pub quads: GrowableBuffer,
+    pub triangles: GrowableBuffer,
+    pub instances: GrowableBuffer,
+    pub textures: SceneTextures,
+}
+
+impl SceneBuffers {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            materials: GrowableBuffer::new(device, "Materials"),
+            bvh: GrowableBuffer::new(device, "BVH"),
+            objects: GrowableBuffer::new(device, "BVH Objects"),
+            spheres: GrowableBuffer::new(device, "Spheres"),
+            quads: GrowableBuffer::new(device, "Quads"),
+            triangles: GrowableBuffer::new(device, "Triangles"),
+            instances: GrowableBuffer::new(device, "Instances"),
+            textures: SceneTextures::new(device),
+        }
+    }
+
+    /// Upload both the top level BVH and the ones of the meshes. Returns true if any buffer had to
+    /// be reallocated, which means the bind groups have to be recreated
+    pub fn upload_bvh(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+        bvh: &BVH,
+    ) -> Result<bool> {
+        let mut reallocated = false;
+        // The BVHs of the meshes go after the top level one
+        let mesh_bvh = scene.get_mesh_bvh();
+        reallocated |= self.bvh.write_parts(
+            device,
+            queue,
+            &[
+                bytemuck::cast_slice(&bvh.nodes),
+                bytemuck::cast_slice(&mesh_bvh.nodes),
+            ],
+        )?;
+        reallocated |= self.objects.write_parts(
+            device,
+            queue,
+            &[
+                bytemuck::cast_slice(&bvh.objects),
+                bytemuck::cast_slice(&mesh_bvh.objects),
+            ],
+        )?;
+        Ok(reallocated)
+    }
+
+    /// Make room for a top level BVH with `node_count` nodes and `object_count` objects at the
+    /// start of the BVH buffers and upload the BVHs of the meshes after it, for when the top
+    /// level one is built on the GPU. Returns true if a buffer had to be reallocated, like
+    /// `upload_bvh`
+    pub fn upload_mesh_bvhs(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+        node_count: usize,
+        object_count: usize,
+    ) -> Result<bool> {
+        let mesh_bvh = scene.get_mesh_bvh();
+        let node_bytes = (node_count * std::mem::size_of::<AABB>()) as u64;
+        let object_bytes = (object_count * std::mem::size_of::<ObjectRef>()) as u64;
+        let mesh_nodes: &[u8] = bytemuck::cast_slice(&mesh_bvh.nodes);
+        let mesh_objects: &[u8] = bytemuck::cast_slice(&mesh_bvh.objects);
+        let mut reallocated = false;
+        reallocated |= self
+            .bvh
+            .reserve(device, node_bytes + mesh_nodes.len() as u64)?;
+        reallocated |= self
+            .objects
+            .reserve(device, object_bytes + mesh_objects.len() as u64)?;
+        self.bvh.write_at(queue, node_bytes, mesh_nodes);
+        self.objects.write_at(queue, object_bytes, mesh_objects);
+        Ok(reallocated)
+    }
+
+    /// Upload some of the nodes of the top level BVH after it was refitted. The tree has to have
+    /// been uploaded in full since it was last built
+    pub fn upload_bvh_nodes(&self, queue: &wgpu::Queue, bvh: &BVH, nodes: &[Range<usize>]) {
+        const NODE_SIZE: usize = std::mem::size_of::<AABB>();
+        for range in nodes {
+            queue.write_buffer(
+                self.bvh.buffer(),
+                (range.start * NODE_SIZE) as u64,
+                bytemuck::cast_slice(&bvh.nodes[range.clone()]),
+            );
+        }
+    }
+
+    /// Upload everything but the BVHs. Returns true if a buffer had to be reallocated, like
+    /// `upload_bvh`
+    pub fn upload_objects(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+    ) -> Result<bool> {
+        let mut reallocated = false;
+        reallocated |= self.materials.write(
+            device,
+            queue,
+            bytemuck::cast_slice(scene.get_material_arr()),
+        )?;
+        reallocated |=
+            self.spheres
+                .write(device, queue, bytemuck::cast_slice(scene.get_sphere_arr()))?;
+        reallocated |=
+            self.quads
+                .write(device, queue, bytemuck::cast_slice(scene.get_quad_arr()))?;
+        reallocated |= self.triangles.write(
+            device,
+            queue,
+            bytemuck::cast_slice(scene.get_triangle_arr()),
+        )?;
+        reallocated |= self.instances.write(
+            device,
+            queue,
+            bytemuck::cast_slice(scene.get_instance_arr()),
+        )?;
+        reallocated |= self.textures.upload(device, queue, scene.get_textures())?;
+        Ok(reallocated)
+    }
+}
+
+// The GL backend guesses how a texture will be viewed from its layers and would take a texture
+// with just one as a plain 2D texture, so arrays always get at least two
+const MIN_TEXTURE_LAYERS: u32 = 2;
+
+/// The textures of the scene as the layers of a texture array, along with the sampler the
+/// shaders read them with. There is no mipmapping, the jitter of the samples in each pixel
+/// averages out textures seen from afar instead
+pub struct SceneTextures {
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    sampler: wgpu::Sampler,
+    uploaded: Vec<u64>, // Ids of the textures in the layers
+}
+
+impl SceneTextures {
+    pub fn new(device: &wgpu::Device) -> Self {
+        // Texture arrays can't be empty so this stands in until the scene has textures
+        let texture = Self::create(device, 1, 1, MIN_TEXTURE_LAYERS);
+        Self {
+            view: Self::create_view(&texture),
+            texture,
+            sampler: device.create_sampler(&wgpu::SamplerDescriptor {
+                label: Some("Texture Sampler"),
+                address_mode_u: wgpu::AddressMode::Repeat,
+                address_mode_v: wgpu::AddressMode::Repeat,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            }),
+            uploaded: Vec::new(),
+        }
+    }
+
+    fn create(device: &wgpu::Device, width: u32, height: u32, layers: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Textures"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            // Colours stay sRGB encoded so roughness can be read as it is, see `Texture`
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        })
+    }
+
+    // A texture with a single layer would be viewed as a plain 2D texture by default
+    fn create_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+        texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        })
+    }
+
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    pub fn sampler(&self) -> &wgpu::Sampler {
+        &self.sampler
+    }
+
+    /// Upload the textures if they aren't the ones uploaded last time. Returns true if the
+    /// texture array was replaced, like `GrowableBuffer::write`
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        textures: &[Texture],
+    ) -> Result<bool> {
+        if textures
+            .iter()
+            .map(Texture::id)
+            .eq(self.uploaded.iter().copied())
+        {
+            return Ok(false);
+        }
+        let max_layers = device.limits().max_texture_array_layers as usize;
+        if textures.len() > max_layers {
+            bail!(
+                "The scene has {} textures but the GPU only allows {max_layers}",
+                textures.len()
+            );
+        }
+
+        let layers = TextureLayers::new(textures);
+        let (width, height) = (layers.width, layers.height);
+        self.texture = Self::create(
+            device,
+            width,
+            height,
+            (textures.len() as u32).max(MIN_TEXTURE_LAYERS),
+        );
+        self.view = Self::create_view(&self.texture);
+        for (i, layer) in layers.layers.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &self.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: i as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                layer.as_raw(),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * width),
+                    rows_per_image: Some(height),
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        log::info!("Uploaded {} textures at {width}x{height}", textures.len());
+        self.uploaded = textures.iter().map(Texture::id).collect();
+        Ok(true)
+    }
+}
+
+/// How often the shader had to give up on part of the BVH, which shows up as holes in the image
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TraversalStats {
+    pub stack_overflows: u32, // Nodes skipped because the traversal stack was full
+    pub iteration_limit_hits: u32, // Rays that stopped before visiting every node they hit
+}
+
+impl TraversalStats {
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn warn_if_incomplete(&self) {
+        if !self.is_clean() {
+            log::warn!(
+                "BVH traversal gave up early ({} stack overflows, {} rays hit the iteration \
+                limit), parts of the scene may be missing",
+                self.stack_overflows,
+                self.iteration_limit_hits
+            );
+        }
+    }
+}
+
+/// Counters the shader increments when BVH traversal hits one of its limits, along with a buffer
+/// to read them back with
+pub struct TraversalCounters {
+    buffer: wgpu::Buffer,
+    readback: wgpu::Buffer,
+    pending: Option<mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>>,
+}
+
+impl TraversalCounters {
+    const SIZE: u64 = std::mem::size_of::<TraversalStats>() as u64;
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Traversal Counters"),
+                size: Self::SIZE,
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            readback: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Traversal Counters Readback"),
+                size: Self::SIZE,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            pending: None,
+        }
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn clear(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.clear_buffer(&self.buffer, 0, None);
+    }
+
+    /// Copy the counters to the readback buffer, unless an earlier copy is still being read
+    pub fn copy_for_reading(&self, encoder: &mut wgpu::CommandEncoder) -> bool {
+        if self.pending.is_some() {
+            return false;
+        }
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &self.readback, 0, Self::SIZE);
+        true
+    }
+
+    /// Start mapping the readback buffer, the commands from `copy_for_reading` have to have been
+    /// submitted first
+    pub fn start_reading(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        self.readback
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+        self.pending = Some(receiver);
+    }
+
+    /// The counters from the last `start_reading` if the GPU has finished with them
+    pub fn try_read(&mut self, device: &wgpu::Device) -> Option<TraversalStats> {
+        let receiver = self.pending.as_ref()?;
+        // Mapping callbacks only run when the device is polled
+        device.poll(wgpu::Maintain::Poll);
+        let result = match receiver.try_recv() {
+            Ok(result) => result,
+            Err(mpsc::TryRecvError::Empty) => return None,
+            Err(mpsc::TryRecvError::Disconnected) => Err(wgpu::BufferAsyncError),
+        };
+        self.pending = None;
+        if let Err(e) = result {
+            log::error!("Failed to read the traversal counters: {e}");
+            return None;
+        }
+        let stats = *bytemuck::from_bytes(&self.readback.slice(..).get_mapped_range());
+        self.readback.unmap();
+        Some(stats)
+    }
+}
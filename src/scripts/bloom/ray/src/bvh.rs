@@ -1,23 +1,96 @@
-use std::u32;
+use std::ops::Range;
 
-use bytemuck::Zeroable;
+use crate::{
+    algebra::Vec3,
+    primitives::{Extrema, ObjectType, Scene, Triangle},
+};
 
-use crate::{algebra::Vec3, primitives::Scene, MAX_OBJECT_COUNT};
+mod lbvh;
+mod sah;
+mod stats;
+mod validate;
+
+pub(crate) use lbvh::{lbvh_depth_bound, lbvh_inputs};
+pub use stats::BvhStats;
 
 const AABB_PADDING_SIZE: f32 = 0.0001;
+pub const DEFAULT_MAX_LEAF_SIZE: u32 = 4;
+pub const DEFAULT_REBUILD_THRESHOLD: f32 = 1.5;
+
+// Relative cost of visiting a node and of intersecting an object, used to estimate how fast a
+// tree is to trace
+const TRAVERSAL_COST: f32 = 1.;
+const INTERSECTION_COST: f32 = 1.;
+
+// Bounds on the size of the shader's traversal stack. Rounding the size up means the pipeline
+// doesn't have to be rebuilt every time the tree gets a level deeper, and every entry is held by
+// every ray in flight so it can't grow without limit
+const TRAVERSAL_STACK_GRANULARITY: u32 = 16;
+pub(crate) const MAX_TRAVERSAL_STACK_SIZE: u32 = 256;
+
+/// How the BVH decides where to split the objects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BvhBuilder {
+    /// Sort along the longest axis and split at the middle object
+    Median,
+    /// Binned surface area heuristic, slower to build but much faster to trace when objects are
+    /// spread unevenly (e.g. a detailed mesh next to a few big spheres)
+    #[default]
+    Sah,
+    /// Sort the objects along a Morton curve and split wherever the codes first differ, with one
+    /// object per leaf. Every step runs in parallel, so the viewer builds these with compute
+    /// shaders instead of on the CPU. The fastest to build but the slowest to trace
+    Lbvh,
+}
+
+/// How the shader walks the BVH
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BvhTraversal {
+    /// Keep the nodes still to be visited on a stack, visiting the nearer child first so further
+    /// ones can be skipped once something closer is hit
+    #[default]
+    Stack,
+    /// Follow each node's miss link instead of keeping a stack. Nodes are always visited in the
+    /// same order, but there is no per-ray array to spill to slow memory
+    Stackless,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BvhSettings {
+    pub builder: BvhBuilder,
+    pub max_leaf_size: u32, // Leaves with more objects than this are always split
+    pub traversal: BvhTraversal,
+    // Refitting the tree after objects move is much faster than building a new one, but the
+    // tree gets slower to trace the further they go. It is rebuilt once its SAH cost has grown
+    // by this factor since it was built, 1 or less always rebuilds
+    pub rebuild_threshold: f32,
+}
+
+impl Default for BvhSettings {
+    fn default() -> Self {
+        Self {
+            builder: BvhBuilder::default(),
+            max_leaf_size: DEFAULT_MAX_LEAF_SIZE,
+            traversal: BvhTraversal::default(),
+            rebuild_threshold: DEFAULT_REBUILD_THRESHOLD,
+        }
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct AABB {
     // Axis Aligned Bounding Box
-    min: Vec3,
-    left_child_index: u32,
-    max: Vec3,
-    right_child_index: u32,
-    object_type: u32, // 0 - Sphere, 1 - Quad
-    object_index: u32,
-    is_populated: u32,
-    _pad: [u32; 1],
+    pub(crate) min: Vec3,
+    pub(crate) left_child_index: u32,
+    pub(crate) max: Vec3,
+    pub(crate) right_child_index: u32,
+    pub(crate) first_object: u32, // Index of a leaf's first entry in the BVH's object list
+    pub(crate) object_count: u32, // 0 for internal nodes
+    pub(crate) is_populated: u32, // 0 only for the root of an empty tree, see `AABB::empty`
+    // Next node to visit after this one is missed or its subtree is finished, u32::MAX when there
+    // is nothing left. Hitting an internal node moves on to its left child
+    pub(crate) miss_index: u32,
 }
 
 impl AABB {
@@ -25,29 +98,41 @@ impl AABB {
         Self {
             min,
             max,
-            object_type: u32::MAX,
-            object_index: u32::MAX,
+            first_object: 0,
+            object_count: 0,
             left_child_index: 0,
             right_child_index: 0,
             is_populated: 1,
-            _pad: [0; 1],
+            miss_index: u32::MAX,
+        }
+    }
+    // The lone node of a tree without any objects. Ray traversal checks the root for this before
+    // anything else
+    pub(crate) fn empty() -> Self {
+        Self {
+            is_populated: 0,
+            ..Self::new((Vec3::all(0.), Vec3::all(0.)))
         }
     }
     fn grow(&mut self, (min, max): (Vec3, Vec3)) {
         self.min = self.min.min_extrema(&min);
         self.max = self.max.max_extrema(&max);
     }
-    fn sort_longest_axis(&self, scene: &mut Scene, start: usize, end: usize) {
-        // Get longest axis
+    fn surface_area(&self) -> f32 {
+        let size = self.max - self.min;
+        2. * (size.x() * size.y() + size.y() * size.z() + size.z() * size.x())
+    }
+    pub fn is_leaf(&self) -> bool {
+        self.object_count > 0
+    }
+    fn longest_axis(&self) -> usize {
         let size = self.max - self.min;
-
-        // Sort the relevant part scene by the longest axis so that we can split it by selecting the middle object
         if size.x() > size.y() && size.x() > size.z() {
-            scene.sort_x(start, end);
+            0
         } else if size.y() > size.z() {
-            scene.sort_y(start, end);
+            1
         } else {
-            scene.sort_z(start, end);
+            2
         }
     }
     fn pad(&mut self, padding_size: f32) {
@@ -68,58 +153,534 @@ impl AABB {
     }
 }
 
+/// Entry in the BVH's object list, leaves refer to a contiguous run of these
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ObjectRef {
+    pub(crate) object_type: u32, // 0 - Sphere, 1 - Quad, 2 - Triangle
+    pub(crate) object_index: u32,
+}
+
+impl ObjectRef {
+    fn new(object_type: ObjectType, index: usize) -> Self {
+        Self {
+            object_type: object_type as u32,
+            object_index: index as u32,
+        }
+    }
+
+    fn bounds(&self, scene: &Scene) -> (Vec3, Vec3) {
+        let object_type =
+            ObjectType::try_from(self.object_type).expect("BVH objects have a valid type");
+        padded(scene.get_object_extrema(object_type, self.object_index as usize))
+    }
+}
+
+// Flat objects still need some thickness for rays to hit their boxes. The objects are padded
+// rather than the nodes so every node encloses the padded boxes of its children
+fn padded(bounds: (Vec3, Vec3)) -> (Vec3, Vec3) {
+    let mut aabb = AABB::new(bounds);
+    aabb.pad(AABB_PADDING_SIZE);
+    (aabb.min, aabb.max)
+}
+
+// An object as the builders see it, with its bounds already padded
+struct Primitive {
+    bounds: (Vec3, Vec3),
+    centroid: Vec3,
+    object: ObjectRef,
+}
+
+impl Primitive {
+    // Everything at the top level of the scene, instances included
+    fn all_in_scene(scene: &Scene) -> Vec<Self> {
+        (0..scene.len())
+            .map(|i| Self {
+                bounds: padded(scene.get_extrema_of(i)),
+                centroid: scene.get_center_of(i),
+                object: ObjectRef::new(scene.get_type_of(i), scene.get_index_of(i)),
+            })
+            .collect()
+    }
+
+    // The triangles of a mesh, the first one being at `first_triangle` in the scene's triangles
+    fn all_in_mesh(triangles: &[Triangle], first_triangle: usize) -> Vec<Self> {
+        triangles
+            .iter()
+            .enumerate()
+            .map(|(i, t)| Self {
+                bounds: padded(t.get_extrema()),
+                centroid: t.get_center(),
+                object: ObjectRef::new(ObjectType::Triangle, first_triangle + i),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct BVH {
-    // Bounding Volume Hierarchy
-    nodes: [AABB; 2 * MAX_OBJECT_COUNT - 1],
+    // Bounding Volume Hierarchy, stored depth first with the root at index 0
+    pub(crate) nodes: Vec<AABB>,
+    pub(crate) objects: Vec<ObjectRef>,
+    built_cost: f32, // SAH cost when the tree was built, to tell how much refits have hurt it
 }
 
 impl BVH {
-    fn populate(&mut self, scene: &mut Scene, node_index: &mut usize, start: usize, end: usize) {
-        // There is only one object left to sort so we will make this node a leaf
-        if end - start == 1 {
-            self.nodes[*node_index].object_type = scene.get_type_of(start) as u32;
-            self.nodes[*node_index].object_index = scene.get_index_of(start) as u32;
-            self.nodes[*node_index].pad(AABB_PADDING_SIZE);
-            *node_index += 1;
+    fn with_capacity(object_count: usize) -> Self {
+        // A binary tree with one object per leaf has 2n - 1 nodes, bigger leaves need fewer
+        Self {
+            nodes: Vec::with_capacity(2 * object_count),
+            objects: Vec::with_capacity(object_count),
+            built_cost: 0.,
+        }
+    }
+
+    // Add a node and return its index, its children have to be added after it
+    fn push_node(&mut self, node: AABB) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    // Thread the miss links through the tree so it can be walked without a stack. A hit moves on
+    // to the node's left child, a miss to the next node that isn't below it
+    fn link_miss_indices(&mut self) {
+        if self.is_empty() {
             return;
         }
+        let mut stack = vec![(0, u32::MAX)];
+        while let Some((index, miss_index)) = stack.pop() {
+            let node = &mut self.nodes[index as usize];
+            node.miss_index = miss_index;
+            if !node.is_leaf() {
+                stack.push((node.left_child_index, node.right_child_index));
+                stack.push((node.right_child_index, miss_index));
+            }
+        }
+    }
+
+    fn make_leaf(&mut self, node_index: usize, objects: impl Iterator<Item = ObjectRef>) {
+        let first_object = self.objects.len();
+        self.objects.extend(objects);
+        let node = &mut self.nodes[node_index];
+        node.first_object = first_object as u32;
+        node.object_count = (self.objects.len() - first_object) as u32;
+    }
 
-        for i in start..end {
-            self.nodes[*node_index].grow(scene.get_extrema_of(i));
+    // Sort the objects along the longest axis of their bounds and split them at the middle one
+    fn populate(&mut self, primitives: &mut [Primitive], max_leaf_size: usize) {
+        let mut node = AABB::new(primitives[0].bounds);
+        for p in &primitives[1..] {
+            node.grow(p.bounds);
         }
+        let node_index = self.push_node(node);
 
-        self.nodes[*node_index].sort_longest_axis(scene, start, end);
+        // There are few enough objects left so we will make this node a leaf
+        if primitives.len() <= max_leaf_size {
+            self.make_leaf(node_index, primitives.iter().map(|p| p.object));
+            return;
+        }
 
-        let halfway_point = (end - start) / 2 + start;
-        let parent_index = *node_index;
-        *node_index += 1;
+        let axis = node.longest_axis();
+        primitives.sort_by(|p1, p2| {
+            p1.centroid[axis]
+                .partial_cmp(&p2.centroid[axis])
+                .expect("Object centres can't be NaN")
+        });
+        let (left, right) = primitives.split_at_mut(primitives.len() / 2);
 
         // Deal with the left child
-        self.nodes[parent_index].left_child_index = (*node_index) as u32;
-
-        self.nodes[*node_index] = AABB::new(scene.get_extrema_of(start));
-        self.populate(scene, node_index, start, halfway_point);
+        self.nodes[node_index].left_child_index = self.nodes.len() as u32;
+        self.populate(left, max_leaf_size);
 
         // Deal with the right child
-        self.nodes[parent_index].right_child_index = (*node_index) as u32;
-        self.nodes[*node_index] = AABB::new(scene.get_extrema_of(halfway_point));
-        self.populate(scene, node_index, halfway_point, end);
+        self.nodes[node_index].right_child_index = self.nodes.len() as u32;
+        self.populate(right, max_leaf_size);
     }
 
-    fn new(scene: &mut Scene) -> Self {
-        let mut nodes = [AABB::zeroed(); 2 * MAX_OBJECT_COUNT - 1];
+    fn new_median(mut primitives: Vec<Primitive>, max_leaf_size: usize) -> Self {
+        let mut bvh = Self::with_capacity(primitives.len());
+        bvh.populate(&mut primitives, max_leaf_size);
+        bvh
+    }
 
-        nodes[0] = AABB::new(scene.get_extrema_of(0));
+    fn build(primitives: Vec<Primitive>, settings: &BvhSettings) -> Self {
+        if primitives.is_empty() {
+            return Self {
+                nodes: vec![AABB::empty()],
+                ..Self::default()
+            };
+        }
+        // Every tree is checked as it is built while debugging, they are only as big as the
+        // scene so this doesn't take long compared to building them
+        let expected = cfg!(debug_assertions).then(|| {
+            primitives
+                .iter()
+                .map(|p| (p.object, p.bounds))
+                .collect::<Vec<_>>()
+        });
 
-        let mut bvh = Self { nodes };
-        let mut index = 0;
-        bvh.populate(scene, &mut index, 0, scene.len());
+        let max_leaf_size = settings.max_leaf_size.max(1) as usize;
+        let mut bvh = match settings.builder {
+            BvhBuilder::Median => Self::new_median(primitives, max_leaf_size),
+            BvhBuilder::Sah => sah::build(primitives, max_leaf_size),
+            BvhBuilder::Lbvh => lbvh::build(primitives),
+        };
+        bvh.link_miss_indices();
+        bvh.built_cost = bvh.sah_cost();
+        if let Some(expected) = expected {
+            if let Err(e) = bvh.validate_objects(&expected) {
+                panic!(
+                    "The {:?} builder made an invalid BVH: {e:#}",
+                    settings.builder
+                );
+            }
+        }
         bvh
     }
+
+    /// True if the tree has no objects in it, in which case it is a single unpopulated node
+    pub fn is_empty(&self) -> bool {
+        self.nodes.first().is_none_or(|root| root.is_populated == 0)
+    }
+
+    /// Recompute the bounds of every node from the objects under it, e.g. after some of them
+    /// have moved. The shape of the tree stays the same, so it only works if no objects were
+    /// added or removed. Returns the ranges of nodes whose bounds changed
+    pub fn refit(&mut self, scene: &Scene) -> Vec<Range<usize>> {
+        let mut changed: Vec<Range<usize>> = Vec::new();
+        // Walking the nodes depth first and then backwards reaches every node after everything
+        // below it. The median and SAH builders store them in that order already, but linear
+        // BVHs don't
+        for index in self.depth_first_order().into_iter().rev() {
+            let node = self.nodes[index];
+            let bounds = if node.is_leaf() {
+                let first = node.first_object as usize;
+                let objects = &self.objects[first..first + node.object_count as usize];
+                let mut bounds = AABB::new(objects[0].bounds(scene));
+                for object in &objects[1..] {
+                    bounds.grow(object.bounds(scene));
+                }
+                bounds
+            } else {
+                let left = self.nodes[node.left_child_index as usize];
+                let right = self.nodes[node.right_child_index as usize];
+                let mut bounds = AABB::new((left.min, left.max));
+                bounds.grow((right.min, right.max));
+                bounds
+            };
+
+            let refitted = AABB {
+                min: bounds.min,
+                max: bounds.max,
+                ..node
+            };
+            if bytemuck::bytes_of(&refitted) == bytemuck::bytes_of(&node) {
+                continue;
+            }
+            self.nodes[index] = refitted;
+            changed.push(index..index + 1);
+        }
+
+        // Merge neighbouring nodes so they can be uploaded together
+        changed.sort_unstable_by_key(|range| range.start);
+        changed.dedup_by(|next, range| {
+            let adjacent = next.start == range.end;
+            if adjacent {
+                range.end = next.end;
+            }
+            adjacent
+        });
+        changed
+    }
+
+    fn depth_first_order(&self) -> Vec<usize> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            order.push(index);
+            let node = &self.nodes[index];
+            if !node.is_leaf() {
+                stack.push(node.right_child_index as usize);
+                stack.push(node.left_child_index as usize);
+            }
+        }
+        order
+    }
+
+    /// How many times as costly to trace the tree has become through refits, 1 when it has just
+    /// been built
+    pub fn refit_degradation(&self) -> f32 {
+        match self.built_cost {
+            0. => 1.,
+            built_cost => self.sah_cost() / built_cost,
+        }
+    }
+
+    /// Add the nodes and objects of another tree after this one's, returning the index its root
+    /// ends up at. Links between the added nodes are kept, so the result holds several trees
+    pub(crate) fn append(&mut self, other: &BVH) -> u32 {
+        let node_offset = self.nodes.len() as u32;
+        let object_offset = self.objects.len() as u32;
+        self.nodes.extend(other.nodes.iter().map(|node| {
+            let mut node = *node;
+            if node.is_leaf() {
+                node.first_object += object_offset;
+            } else {
+                node.left_child_index += node_offset;
+                node.right_child_index += node_offset;
+            }
+            if node.miss_index != u32::MAX {
+                node.miss_index += node_offset;
+            }
+            node
+        }));
+        self.objects.extend_from_slice(&other.objects);
+        node_offset
+    }
+
+    /// Number of levels in the tree, a lone root node is 1 level deep
+    pub fn depth(&self) -> u32 {
+        if self.is_empty() {
+            return 0;
+        }
+        let mut depth = 0;
+        let mut stack = vec![(0, 1)];
+        while let Some((index, level)) = stack.pop() {
+            let node: &AABB = &self.nodes[index as usize];
+            depth = depth.max(level);
+            if !node.is_leaf() {
+                stack.push((node.left_child_index, level + 1));
+                stack.push((node.right_child_index, level + 1));
+            }
+        }
+        depth
+    }
+
+    /// Number of entries the shader's traversal stack needs so it never drops part of the tree.
+    ///
+    /// A ray pops one node and pushes at most both of its children, so the stack holds at most
+    /// one pending node per level on the way down plus the two children at the bottom, which is
+    /// never more than the depth of the tree. A leaf pushes the root of every mesh instance it
+    /// holds, and the deepest mesh BVH can then be walked from any of them.
+    pub(crate) fn traversal_stack_size(&self, scene: &Scene) -> u32 {
+        let instances_per_leaf = self
+            .nodes
+            .iter()
+            .filter(|node| node.is_leaf())
+            .map(|node| {
+                let first = node.first_object as usize;
+                self.objects[first..first + node.object_count as usize]
+                    .iter()
+                    .filter(|o| o.object_type == ObjectType::Instance as u32)
+                    .count() as u32
+            })
+            .max()
+            .unwrap_or(0);
+        traversal_stack_size(self.depth(), instances_per_leaf, scene)
+    }
+
+    /// Expected cost of tracing a random ray through the tree according to the surface area
+    /// heuristic, lower is better. Only meaningful when comparing trees for the same scene.
+    pub fn sah_cost(&self) -> f32 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let root = self.nodes[0];
+        // The chance of a ray hitting a node is proportional to its surface area
+        let root_area = root.surface_area();
+        self.nodes
+            .iter()
+            .map(|node| {
+                let cost = match node.is_leaf() {
+                    true => INTERSECTION_COST * node.object_count as f32,
+                    false => TRAVERSAL_COST,
+                };
+                cost * node.surface_area() / root_area
+            })
+            .sum()
+    }
+}
+
+/// Size of the traversal stack for a tree `depth` levels deep that has up to `instances_per_leaf`
+/// mesh instances in a leaf, see `BVH::traversal_stack_size`
+pub(crate) fn traversal_stack_size(depth: u32, instances_per_leaf: u32, scene: &Scene) -> u32 {
+    let mesh_depth = match instances_per_leaf {
+        0 => 0,
+        _ => instances_per_leaf + scene.max_mesh_depth(),
+    };
+    (depth + mesh_depth)
+        .max(1)
+        .next_multiple_of(TRAVERSAL_STACK_GRANULARITY)
+        .min(MAX_TRAVERSAL_STACK_SIZE)
+}
+
+/// Build the top level BVH over the objects and mesh instances in the scene. The meshes have
+/// their own BVHs, built when they are added, so moving an instance only needs this rebuilt
+pub fn create_bvh(scene: &Scene, settings: &BvhSettings) -> BVH {
+    let bvh = BVH::build(Primitive::all_in_scene(scene), settings);
+    let stats = bvh.stats();
+    log::info!("Built a {:?} BVH, {stats}", settings.builder);
+    let depth = stats.depth;
+    if depth > MAX_TRAVERSAL_STACK_SIZE {
+        log::warn!(
+            "The BVH is {depth} levels deep but the GPU can only follow {MAX_TRAVERSAL_STACK_SIZE} \
+            levels, parts of the scene may be missing"
+        );
+    }
+    bvh
+}
+
+/// What `update_bvh` did to bring the tree up to date
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum BvhUpdate {
+    Rebuilt,
+    Refitted(Vec<Range<usize>>), // Nodes whose bounds changed
+}
+
+impl BvhUpdate {
+    // Combine with an update that came after this one, before either was uploaded
+    pub(crate) fn then(self, later: BvhUpdate) -> BvhUpdate {
+        match (self, later) {
+            (BvhUpdate::Refitted(mut nodes), BvhUpdate::Refitted(later_nodes)) => {
+                nodes.extend(later_nodes);
+                BvhUpdate::Refitted(nodes)
+            }
+            _ => BvhUpdate::Rebuilt,
+        }
+    }
+}
+
+/// Bring the top level BVH up to date after objects in the scene have moved. The tree is only
+/// refitted unless objects were added or removed, or refits have made it too slow to trace
+pub(crate) fn update_bvh(bvh: &mut BVH, scene: &Scene, settings: &BvhSettings) -> BvhUpdate {
+    if !bvh.is_empty() && bvh.objects.len() == scene.len() {
+        let changed = bvh.refit(scene);
+        let degradation = bvh.refit_degradation();
+        // Refits can also make the tree cheaper, which mustn't stop a threshold of 1 rebuilding
+        if settings.rebuild_threshold > 1. && degradation < settings.rebuild_threshold {
+            return BvhUpdate::Refitted(changed);
+        }
+        log::info!("Refits have made the BVH {degradation:.2} times as costly, rebuilding it");
+    }
+    *bvh = create_bvh(scene, settings);
+    BvhUpdate::Rebuilt
+}
+
+/// Build the BVH for the triangles of a mesh, they have to be stored in the scene starting at
+/// `first_triangle`
+pub(crate) fn create_mesh_bvh(triangles: &[Triangle], first_triangle: usize) -> BVH {
+    BVH::build(
+        Primitive::all_in_mesh(triangles, first_triangle),
+        &BvhSettings::default(),
+    )
 }
 
-pub fn create_bvh(scene: &mut Scene) -> [AABB; 2 * MAX_OBJECT_COUNT - 1] {
-    BVH::new(scene).nodes
+#[cfg(test)]
+mod tests {
+    use super::{create_bvh, update_bvh, BvhSettings, BvhUpdate, BVH};
+    use crate::{
+        algebra::Vec3,
+        primitives::{Scene, Sphere},
+    };
+
+    const LAST: usize = 7;
+
+    // A row of spheres, the last of which decides where every node around it ends
+    fn row_of_spheres() -> Scene {
+        let mut scene = Scene::new();
+        for x in 0..=LAST {
+            scene.add_sphere(Sphere::new(Vec3::new(x as f32, 0., 0.), 0.5, 0));
+        }
+        scene
+    }
+
+    fn move_last_sphere(scene: &mut Scene, dx: f32) {
+        scene.get_sphere_arr_mut()[LAST].center += Vec3::new(dx, 0., 0.);
+    }
+
+    fn with_threshold(rebuild_threshold: f32) -> BvhSettings {
+        BvhSettings {
+            rebuild_threshold,
+            ..Default::default()
+        }
+    }
+
+    // The leaf holding an object and every node above it, in order
+    fn leaf_and_ancestors(bvh: &BVH, object: usize) -> Vec<usize> {
+        let mut nodes: Vec<usize> = (0..bvh.nodes.len())
+            .filter(|&i| {
+                let node = bvh.nodes[i];
+                let first = node.first_object as usize;
+                node.is_leaf()
+                    && bvh.objects[first..first + node.object_count as usize]
+                        .iter()
+                        .any(|o| o.object_index as usize == object)
+            })
+            .collect();
+        assert_eq!(nodes.len(), 1);
+        while let Some(parent) = bvh.nodes.iter().position(|node| {
+            !node.is_leaf()
+                && [node.left_child_index, node.right_child_index].contains(&(nodes[0] as u32))
+        }) {
+            nodes.insert(0, parent);
+        }
+        nodes.sort_unstable();
+        nodes
+    }
+
+    #[test]
+    fn small_moves_refit_the_nodes_above() {
+        let mut scene = row_of_spheres();
+        let settings = BvhSettings::default();
+        let mut bvh = create_bvh(&scene, &settings);
+        let expected = leaf_and_ancestors(&bvh, LAST);
+        move_last_sphere(&mut scene, 0.1);
+
+        let BvhUpdate::Refitted(changed) = update_bvh(&mut bvh, &scene, &settings) else {
+            panic!("A small move shouldn't rebuild the tree");
+        };
+        let changed: Vec<usize> = changed.into_iter().flatten().collect();
+        assert_eq!(changed, expected);
+        bvh.validate(&scene).unwrap();
+    }
+
+    // Including when the move makes the tree cheaper to trace than it was when it was built
+    #[test]
+    fn threshold_of_one_or_less_always_rebuilds() {
+        for (dx, threshold) in [(0.1, 1.), (-0.5, 1.), (0.1, 0.5), (0., 1.)] {
+            let mut scene = row_of_spheres();
+            let settings = with_threshold(threshold);
+            let mut bvh = create_bvh(&scene, &settings);
+            move_last_sphere(&mut scene, dx);
+            assert_eq!(
+                update_bvh(&mut bvh, &scene, &settings),
+                BvhUpdate::Rebuilt,
+                "Moving by {dx} with a threshold of {threshold}"
+            );
+        }
+    }
+
+    #[test]
+    fn adding_objects_rebuilds() {
+        let mut scene = row_of_spheres();
+        let settings = BvhSettings::default();
+        let mut bvh = create_bvh(&scene, &settings);
+        scene.add_sphere(Sphere::new(Vec3::new(0., 2., 0.), 0.5, 0));
+        assert_eq!(update_bvh(&mut bvh, &scene, &settings), BvhUpdate::Rebuilt);
+        assert_eq!(bvh.objects.len(), LAST + 2);
+    }
+
+    #[test]
+    fn rebuilds_win_when_combining_updates() {
+        let refitted = || BvhUpdate::Refitted(vec![0..2, 3..4]);
+        assert_eq!(refitted().then(BvhUpdate::Rebuilt), BvhUpdate::Rebuilt);
+        assert_eq!(BvhUpdate::Rebuilt.then(refitted()), BvhUpdate::Rebuilt);
+        assert_eq!(
+            refitted().then(BvhUpdate::Refitted(vec![5..6, 8..9])),
+            BvhUpdate::Refitted(vec![0..2, 3..4, 5..6, 8..9])
+        );
+    }
 }
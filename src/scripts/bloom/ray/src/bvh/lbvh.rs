@@ -0,0 +1,205 @@
+use super::{Primitive, AABB, BVH};
+use crate::{algebra::Vec3, primitives::Scene};
+
+// Bits per axis in the Morton codes, three of them have to fit in a u32. Has to stay in sync with
+// morton_code in lbvh.wgsl
+const MORTON_BITS: u32 = 10;
+
+/// Maps the centres of the objects' bounds onto the unit cube the Morton curve fills
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MortonFrame {
+    pub(crate) min: Vec3,
+    pub(crate) scale: Vec3, // 1 / size of the centres' bounds, or 0 along axes where they are flat
+}
+
+impl MortonFrame {
+    fn new(bounds: impl Iterator<Item = (Vec3, Vec3)>) -> Self {
+        let (min, max) = bounds.map(centre).fold(
+            (Vec3::all(f32::MAX), Vec3::all(f32::MIN)),
+            |(min, max), c| (min.min_extrema(&c), max.max_extrema(&c)),
+        );
+        let size = max - min;
+        let inverse = |x: f32| if x > 0. { 1. / x } else { 0. };
+        Self {
+            min,
+            scale: Vec3::new(inverse(size.x()), inverse(size.y()), inverse(size.z())),
+        }
+    }
+
+    // Interleave the bits of the quantised position, x being the most significant
+    fn code(&self, bounds: (Vec3, Vec3)) -> u32 {
+        let p = centre(bounds) - self.min;
+        let cells = (1 << MORTON_BITS) as f32;
+        let quantise = |x: f32, scale: f32| (x * scale * cells).clamp(0., cells - 1.) as u32;
+        (expand_bits(quantise(p.x(), self.scale.x())) << 2)
+            | (expand_bits(quantise(p.y(), self.scale.y())) << 1)
+            | expand_bits(quantise(p.z(), self.scale.z()))
+    }
+}
+
+// Computed the same way as in lbvh.wgsl so both builders sort the objects identically
+fn centre((min, max): (Vec3, Vec3)) -> Vec3 {
+    (min + max) * 0.5
+}
+
+// Spread the lowest 10 bits out so there are two zero bits between each of them
+fn expand_bits(v: u32) -> u32 {
+    let mut x = v & 0x3ff;
+    x = (x | (x << 16)) & 0x030000ff;
+    x = (x | (x << 8)) & 0x0300f00f;
+    x = (x | (x << 4)) & 0x030c30c3;
+    x = (x | (x << 2)) & 0x09249249;
+    x
+}
+
+/// An object as the GPU builder reads it, the same as `Primitive` in lbvh.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct LbvhPrimitive {
+    min: Vec3,
+    object_type: u32,
+    max: Vec3,
+    object_index: u32,
+}
+
+/// Everything at the top level of the scene as the GPU builder needs it, along with the frame
+/// their Morton codes are computed in
+pub(crate) fn lbvh_inputs(scene: &Scene) -> (Vec<LbvhPrimitive>, MortonFrame) {
+    let primitives: Vec<_> = Primitive::all_in_scene(scene)
+        .iter()
+        .map(|p| LbvhPrimitive {
+            min: p.bounds.0,
+            object_type: p.object.object_type,
+            max: p.bounds.1,
+            object_index: p.object.object_index,
+        })
+        .collect();
+    let frame = MortonFrame::new(primitives.iter().map(|p| (p.min, p.max)));
+    (primitives, frame)
+}
+
+/// Most levels a linear BVH over `object_count` objects can have. Every level down the tree the
+/// keys below a node share at least one more bit, and the keys are the Morton code followed by
+/// as many bits of the object's position in the sorted list as there are objects
+pub(crate) fn lbvh_depth_bound(object_count: usize) -> u32 {
+    let index_bits = usize::BITS - object_count.saturating_sub(1).leading_zeros();
+    3 * MORTON_BITS + index_bits + 1
+}
+
+// Linear BVH as in Karras, "Maximizing Parallelism in the Construction of BVHs, Octrees, and k-d
+// Trees" (2012). The internal nodes come first with the root at 0, followed by one leaf for each
+// object in Morton order. lbvh.wgsl builds exactly the same tree on the GPU
+pub(super) fn build(primitives: Vec<Primitive>) -> BVH {
+    let n = primitives.len();
+    let frame = MortonFrame::new(primitives.iter().map(|p| p.bounds));
+
+    // Objects with the same code stay in their original order, the same as the GPU's radix sort
+    let mut keys: Vec<(u32, usize)> = primitives
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (frame.code(p.bounds), i))
+        .collect();
+    keys.sort_unstable();
+    let codes: Vec<u32> = keys.iter().map(|&(code, _)| code).collect();
+
+    let mut bvh = BVH::with_capacity(n);
+    let node_count = (2 * n).saturating_sub(1);
+    let empty = AABB::new((Vec3::all(f32::MAX), Vec3::all(f32::MIN)));
+    bvh.nodes.resize(node_count, empty);
+    let mut parents = vec![u32::MAX; node_count];
+
+    for i in 0..n.saturating_sub(1) {
+        let (left, right) = find_children(&codes, i);
+        bvh.nodes[i].left_child_index = left;
+        bvh.nodes[i].right_child_index = right;
+        parents[left as usize] = i as u32;
+        parents[right as usize] = i as u32;
+    }
+
+    for (k, &(_, i)) in keys.iter().enumerate() {
+        let mut leaf = AABB::new(primitives[i].bounds);
+        leaf.first_object = k as u32;
+        leaf.object_count = 1;
+        bvh.objects.push(primitives[i].object);
+
+        // Grow every node above the leaf to fit it
+        let leaf_index = n - 1 + k;
+        let mut parent = parents[leaf_index];
+        while parent != u32::MAX {
+            bvh.nodes[parent as usize].grow((leaf.min, leaf.max));
+            parent = parents[parent as usize];
+        }
+        bvh.nodes[leaf_index] = leaf;
+    }
+    bvh
+}
+
+// Length of the prefix the keys at i and j have in common, or -1 if j is outside the list. Keys
+// with the same code are told apart by their position in the list
+fn common_prefix(codes: &[u32], i: i64, j: i64) -> i32 {
+    if j < 0 || j >= codes.len() as i64 {
+        return -1;
+    }
+    let (a, b) = (codes[i as usize], codes[j as usize]);
+    if a == b {
+        return 32 + (i as u32 ^ j as u32).leading_zeros() as i32;
+    }
+    (a ^ b).leading_zeros() as i32
+}
+
+// Indices of the children of internal node i. The node covers a range of keys that starts or ends
+// at key i, and is split where the keys in the range first differ
+fn find_children(codes: &[u32], i: usize) -> (u32, u32) {
+    let n = codes.len() as i64;
+    let i = i as i64;
+    let delta = |j: i64| common_prefix(codes, i, j);
+
+    // The range extends towards the neighbour that has more in common with key i
+    let d = if delta(i + 1) > delta(i - 1) { 1 } else { -1 };
+
+    // Find the other end of the range, everything in it has more in common with key i than the
+    // key on the other side does
+    let delta_min = delta(i - d);
+    let mut max_length = 2;
+    while delta(i + max_length * d) > delta_min {
+        max_length *= 2;
+    }
+    let mut length = 0;
+    let mut step = max_length / 2;
+    while step >= 1 {
+        if delta(i + (length + step) * d) > delta_min {
+            length += step;
+        }
+        step /= 2;
+    }
+    let j = i + length * d;
+
+    // Find where the keys in the range stop sharing the prefix they all have in common
+    let delta_node = delta(j);
+    let mut split = 0;
+    let mut step = length;
+    loop {
+        step = (step + 1) / 2;
+        if delta(i + (split + step) * d) > delta_node {
+            split += step;
+        }
+        if step <= 1 {
+            break;
+        }
+    }
+    let gamma = i + split * d + d.min(0);
+
+    // Ranges of a single key are leaves
+    let leaf = |k: i64| (n - 1 + k) as u32;
+    let left = if i.min(j) == gamma {
+        leaf(gamma)
+    } else {
+        gamma as u32
+    };
+    let right = if i.max(j) == gamma + 1 {
+        leaf(gamma + 1)
+    } else {
+        gamma as u32 + 1
+    };
+    (left, right)
+}
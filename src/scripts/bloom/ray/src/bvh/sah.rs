@@ -0,0 +1,141 @@
+use super::{Primitive, AABB, BVH, INTERSECTION_COST, TRAVERSAL_COST};
+use crate::algebra::Vec3;
+
+// Candidate split planes per axis are placed between bins of object centroids. More bins find
+// slightly better splits but take longer to evaluate
+const BIN_COUNT: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: Option<AABB>,
+    count: usize,
+}
+
+impl Bin {
+    const EMPTY: Self = Self {
+        bounds: None,
+        count: 0,
+    };
+
+    fn add(&mut self, bounds: (Vec3, Vec3), count: usize) {
+        match &mut self.bounds {
+            Some(b) => b.grow(bounds),
+            None => self.bounds = Some(AABB::new(bounds)),
+        }
+        self.count += count;
+    }
+
+    fn area_times_count(&self) -> f32 {
+        self.bounds.map_or(0., |b| b.surface_area()) * self.count as f32
+    }
+}
+
+struct Split {
+    axis: usize,
+    bin: usize, // Objects in this bin and the ones before it go to the left child
+    cost: f32,
+}
+
+pub(super) fn build(mut primitives: Vec<Primitive>, max_leaf_size: usize) -> BVH {
+    let mut bvh = BVH::with_capacity(primitives.len());
+    build_node(&mut bvh, &mut primitives, max_leaf_size);
+    bvh
+}
+
+fn build_node(bvh: &mut BVH, primitives: &mut [Primitive], max_leaf_size: usize) {
+    let mut node = AABB::new(primitives[0].bounds);
+    let mut centroids = AABB::new((primitives[0].centroid, primitives[0].centroid));
+    for p in &primitives[1..] {
+        node.grow(p.bounds);
+        centroids.grow((p.centroid, p.centroid));
+    }
+    let node_index = bvh.push_node(node);
+    let node = bvh.nodes[node_index];
+
+    // Splitting only pays off if tracing the children is expected to be cheaper than
+    // intersecting every object in the node
+    let leaf_cost = INTERSECTION_COST * primitives.len() as f32;
+    let split = find_split(primitives, &node, &centroids);
+    let split_point = match split {
+        Some(split) if split.cost < leaf_cost || primitives.len() > max_leaf_size => {
+            partition(primitives, |p| {
+                bin_of(p, &centroids, split.axis) <= split.bin
+            })
+        }
+        // All the centroids are in the same place so no plane can separate them, just cut the
+        // list in half to keep the leaves small
+        None if primitives.len() > max_leaf_size => primitives.len() / 2,
+        _ => {
+            bvh.make_leaf(node_index, primitives.iter().map(|p| p.object));
+            return;
+        }
+    };
+
+    let (left, right) = primitives.split_at_mut(split_point);
+    bvh.nodes[node_index].left_child_index = bvh.nodes.len() as u32;
+    build_node(bvh, left, max_leaf_size);
+    bvh.nodes[node_index].right_child_index = bvh.nodes.len() as u32;
+    build_node(bvh, right, max_leaf_size);
+}
+
+// Find the cheapest split plane between bins on any axis, if there is one that leaves objects on
+// both sides
+fn find_split(primitives: &[Primitive], node: &AABB, centroids: &AABB) -> Option<Split> {
+    let mut best: Option<Split> = None;
+    let node_area = node.surface_area();
+
+    for axis in 0..3 {
+        if centroids.max[axis] - centroids.min[axis] <= 0. {
+            continue;
+        }
+
+        let mut bins = [Bin::EMPTY; BIN_COUNT];
+        for p in primitives {
+            bins[bin_of(p, centroids, axis)].add(p.bounds, 1);
+        }
+
+        // Sweep from the right to get the cost of everything after each plane, then from the left
+        let mut right_costs = [0.; BIN_COUNT];
+        let mut right = Bin::EMPTY;
+        for i in (1..BIN_COUNT).rev() {
+            if let Some(b) = bins[i].bounds {
+                right.add((b.min, b.max), bins[i].count);
+            }
+            right_costs[i - 1] = right.area_times_count();
+        }
+
+        let mut left = Bin::EMPTY;
+        for (i, bin) in bins[..BIN_COUNT - 1].iter().enumerate() {
+            if let Some(b) = bin.bounds {
+                left.add((b.min, b.max), bin.count);
+            }
+            if left.count == 0 || left.count == primitives.len() {
+                continue;
+            }
+            let cost = TRAVERSAL_COST
+                + INTERSECTION_COST * (left.area_times_count() + right_costs[i]) / node_area;
+            if best.as_ref().is_none_or(|b| cost < b.cost) {
+                best = Some(Split { axis, bin: i, cost });
+            }
+        }
+    }
+    best
+}
+
+fn bin_of(p: &Primitive, centroids: &AABB, axis: usize) -> usize {
+    let extent = centroids.max[axis] - centroids.min[axis];
+    let bin = ((p.centroid[axis] - centroids.min[axis]) / extent * BIN_COUNT as f32) as usize;
+    bin.min(BIN_COUNT - 1)
+}
+
+// Move the primitives matching `is_left` to the front, returning how many there are
+fn partition(primitives: &mut [Primitive], is_left: impl Fn(&Primitive) -> bool) -> usize {
+    let mut split_point = 0;
+    for i in 0..primitives.len() {
+        if is_left(&primitives[i]) {
+            primitives.swap(i, split_point);
+            split_point += 1;
+        }
+    }
+    split_point
+}
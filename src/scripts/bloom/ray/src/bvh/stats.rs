@@ -0,0 +1,83 @@
+use std::fmt;
+
+use super::{AABB, BVH};
+
+/// Summary of the shape of a BVH, to compare builders or spot a tree that has gone bad
+#[derive(Debug, Clone, PartialEq)]
+pub struct BvhStats {
+    pub node_count: usize,
+    pub object_count: usize,
+    pub depth: u32,
+    pub leaf_sizes: Vec<usize>, // Number of leaves holding each number of objects, by index
+    pub sah_cost: f32,
+    // Surface area where the children of a node overlap, summed over the tree relative to the
+    // root's. Rays through these parts have to visit both children, so lower is better
+    pub overlap: f32,
+}
+
+impl BvhStats {
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_sizes.iter().sum()
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes for {} objects, {} levels deep, SAH cost {:.2}, overlap {:.2}, leaf sizes",
+            self.node_count, self.object_count, self.depth, self.sah_cost, self.overlap
+        )?;
+        // As objects in the leaf: number of leaves
+        let sizes = self.leaf_sizes.iter().enumerate().filter(|(_, &n)| n > 0);
+        for (i, (size, count)) in sizes.enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{separator}{size}: {count}")?;
+        }
+        if self.leaf_count() == 0 {
+            write!(f, " none")?;
+        }
+        Ok(())
+    }
+}
+
+impl BVH {
+    pub fn stats(&self) -> BvhStats {
+        let mut leaf_sizes = Vec::new();
+        let mut overlap = 0.;
+        if !self.is_empty() {
+            let root_area = self.nodes[0].surface_area();
+            for index in self.depth_first_order() {
+                let node = &self.nodes[index];
+                if node.is_leaf() {
+                    let size = node.object_count as usize;
+                    if leaf_sizes.len() <= size {
+                        leaf_sizes.resize(size + 1, 0);
+                    }
+                    leaf_sizes[size] += 1;
+                } else {
+                    let left = &self.nodes[node.left_child_index as usize];
+                    let right = &self.nodes[node.right_child_index as usize];
+                    overlap += intersection_area(left, right) / root_area;
+                }
+            }
+        }
+        BvhStats {
+            node_count: self.nodes.len(),
+            object_count: self.objects.len(),
+            depth: self.depth(),
+            leaf_sizes,
+            sah_cost: self.sah_cost(),
+            overlap,
+        }
+    }
+}
+
+fn intersection_area(a: &AABB, b: &AABB) -> f32 {
+    let min = a.min.max_extrema(&b.min);
+    let max = a.max.min_extrema(&b.max);
+    if (0..3).any(|axis| min[axis] > max[axis]) {
+        return 0.;
+    }
+    AABB::new((min, max)).surface_area()
+}
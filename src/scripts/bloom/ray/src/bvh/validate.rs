@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure, Result};
+
+use super::{ObjectRef, Primitive, AABB, BVH};
+use crate::{algebra::Vec3, primitives::Scene};
+
+impl BVH {
+    /// Check that the tree is sound for the objects at the top level of the scene: every node's
+    /// bounds enclose everything below it, every object is reached exactly once, the links
+    /// between nodes stay inside the tree and only the root of an empty tree is left
+    /// unpopulated. Returns the first problem found.
+    pub fn validate(&self, scene: &Scene) -> Result<()> {
+        let expected: Vec<_> = Primitive::all_in_scene(scene)
+            .iter()
+            .map(|p| (p.object, p.bounds))
+            .collect();
+        self.validate_objects(&expected)
+    }
+
+    // Check the tree against the objects it should hold and their bounds
+    pub(super) fn validate_objects(&self, expected: &[(ObjectRef, (Vec3, Vec3))]) -> Result<()> {
+        let node_count = self.nodes.len();
+        ensure!(
+            node_count > 0,
+            "The tree has no nodes, not even an empty root"
+        );
+        if self.is_empty() {
+            ensure!(
+                expected.is_empty(),
+                "The root isn't populated but there are {} objects",
+                expected.len()
+            );
+            ensure!(
+                node_count == 1 && self.objects.is_empty(),
+                "An empty tree has {node_count} nodes and {} objects instead of a lone root",
+                self.objects.len()
+            );
+            return Ok(());
+        }
+
+        let positions: HashMap<ObjectRef, usize> = expected
+            .iter()
+            .enumerate()
+            .map(|(i, (object, _))| (*object, i))
+            .collect();
+        let mut times_reached = vec![0; expected.len()];
+        let mut visited = vec![false; node_count];
+
+        // Walk the tree the same way the stackless traversal does, so the miss link every node
+        // should have is known when it is reached
+        let mut stack = vec![(0, u32::MAX)];
+        while let Some((index, miss_index)) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            ensure!(
+                !visited[index as usize],
+                "Node {index} is reached more than once"
+            );
+            visited[index as usize] = true;
+            ensure!(
+                node.is_populated == 1,
+                "Node {index} is marked as unpopulated ({}) but isn't the root of an empty tree",
+                node.is_populated
+            );
+            ensure!(
+                node.miss_index == miss_index,
+                "Node {index} links to {} when it is missed instead of {miss_index}",
+                node.miss_index
+            );
+
+            if node.is_leaf() {
+                let first = node.first_object as usize;
+                let Some(objects) = self.objects.get(first..first + node.object_count as usize)
+                else {
+                    bail!(
+                        "Leaf {index} refers to objects {first}..{} but there are only {}",
+                        first + node.object_count as usize,
+                        self.objects.len()
+                    );
+                };
+                for object in objects {
+                    ensure!(
+                        object.object_index != u32::MAX,
+                        "Leaf {index} holds a placeholder object ({object:?})"
+                    );
+                    let Some(&position) = positions.get(object) else {
+                        bail!("Leaf {index} holds {object:?}, which isn't in the scene");
+                    };
+                    times_reached[position] += 1;
+                    ensure!(
+                        encloses(node, expected[position].1),
+                        "Leaf {index} doesn't enclose {object:?}"
+                    );
+                }
+            } else {
+                for (side, child) in [
+                    ("left", node.left_child_index),
+                    ("right", node.right_child_index),
+                ] {
+                    let Some(child_node) = self.nodes.get(child as usize) else {
+                        bail!("Node {index} has a {side} child {child} outside the tree");
+                    };
+                    ensure!(
+                        encloses(node, (child_node.min, child_node.max)),
+                        "Node {index} doesn't enclose its {side} child {child}"
+                    );
+                }
+                stack.push((node.left_child_index, node.right_child_index));
+                stack.push((node.right_child_index, miss_index));
+            }
+        }
+
+        if let Some(index) = visited.iter().position(|&v| !v) {
+            bail!("Node {index} can't be reached from the root");
+        }
+        if let Some((position, &count)) = times_reached.iter().enumerate().find(|(_, &n)| n != 1) {
+            bail!(
+                "{:?} is held by {count} leaves instead of 1",
+                expected[position].0
+            );
+        }
+        ensure!(
+            self.objects.len() == expected.len(),
+            "The tree lists {} objects but there are {} in the scene",
+            self.objects.len(),
+            expected.len()
+        );
+        Ok(())
+    }
+}
+
+fn encloses(node: &AABB, (min, max): (Vec3, Vec3)) -> bool {
+    (0..3).all(|axis| node.min[axis] <= min[axis] && max[axis] <= node.max[axis])
+}
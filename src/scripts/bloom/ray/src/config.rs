@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    algebra::Vec3, bvh::BvhSettings, camera::Camera, gpu_tracer::TraceMode,
+    DEFAULT_MAX_PATH_LENGTH, DOF_SCALE, FOCAL_DISTANCE, MAX_PASSES, VFOV_DEG,
+};
+
+/// Settings for a render that can be chosen at runtime instead of being compiled in
+#[derive(Debug, Clone)]
+pub struct RenderConfig {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,          // Number of frames before we accept the result
+    pub passes_per_frame: u32, // Samples the viewer traces before showing each frame
+    pub max_path_length: u32,
+    pub seed: u32,
+    pub scene: Option<PathBuf>,
+    pub meshes: Vec<PathBuf>, // OBJ and glTF files (URLs on the web) added on top of the scene
+    pub camera: CameraOverrides,
+    pub bvh: BvhSettings,
+    pub trace_mode: TraceMode,
+    pub timings: bool, // Measure and log how long each stage of tracing takes, see `StageTimer`
+    // Fraction of the output (or window) resolution the GPU traces paths at, the image is scaled
+    // up to fill it. Lower is faster but blurrier, e.g. 0.5 traces a quarter as many paths
+    pub render_scale: f32,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            width: 1280,
+            height: 720,
+            samples: MAX_PASSES,
+            passes_per_frame: 1,
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            seed: 0,
+            scene: None,
+            meshes: Vec::new(),
+            camera: CameraOverrides::default(),
+            bvh: BvhSettings::default(),
+            trace_mode: TraceMode::default(),
+            timings: false,
+            render_scale: 1.,
+        }
+    }
+}
+
+/// Resolution to trace at for an image shown at `width` x `height`, see
+/// `RenderConfig::render_scale`
+pub(crate) fn trace_size(width: u32, height: u32, render_scale: f32) -> (u32, u32) {
+    let scale = |size: u32| ((size as f32 * render_scale).round() as u32).max(1);
+    (scale(width), scale(height))
+}
+
+/// Camera parameters that replace the ones provided by the scene when set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CameraOverrides {
+    pub position: Option<Vec3>,
+    pub look_at: Option<Vec3>,
+    pub vfov_deg: Option<f32>,
+    pub aperture: Option<f32>,
+    pub focal_distance: Option<f32>,
+}
+
+/// Everything needed to place a camera with `Camera::look_at`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraSettings {
+    pub position: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub focal_distance: f32,
+    pub vfov_deg: f32,
+    pub aperture: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            position: Vec3::new(3., 2., 3.),
+            look_at: Vec3::new(0., 1., 0.),
+            up: Vec3::new(0., 1., 0.),
+            focal_distance: FOCAL_DISTANCE,
+            vfov_deg: VFOV_DEG,
+            aperture: DOF_SCALE,
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn with_overrides(self, overrides: &CameraOverrides) -> Self {
+        Self {
+            position: overrides.position.unwrap_or(self.position),
+            look_at: overrides.look_at.unwrap_or(self.look_at),
+            up: self.up,
+            focal_distance: overrides.focal_distance.unwrap_or(self.focal_distance),
+            vfov_deg: overrides.vfov_deg.unwrap_or(self.vfov_deg),
+            aperture: overrides.aperture.unwrap_or(self.aperture),
+        }
+    }
+
+    pub fn to_camera(self) -> Camera {
+        Camera::look_at(
+            self.position,
+            self.look_at,
+            self.up,
+            self.focal_distance,
+            self.vfov_deg,
+            self.aperture,
+        )
+    }
+}
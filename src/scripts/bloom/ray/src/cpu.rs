@@ -0,0 +1,1399 @@
+use std::{
+    f32::consts::{PI, TAU},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use cgmath::{Matrix, Matrix4, Vector3};
+
+use crate::{
+    algebra::Vec3,
+    bvh::{create_bvh, BvhTraversal, ObjectRef, AABB, BVH},
+    camera::CameraUniforms,
+    helpers,
+    material::Material,
+    pattern::{
+        PATTERN_CHECKER_UV, PATTERN_CHECKER_WORLD, PATTERN_GRADIENT, PATTERN_MARBLE, PATTERN_NOISE,
+    },
+    primitives::{Instance, ObjectType, Quad, Scene, Sky, Sphere, Triangle},
+    texture::{TextureLayers, NO_TEXTURE},
+    RenderConfig,
+};
+
+// These have to stay in sync with the constants at the top of trace.wgsl
+const EPSILON: f32 = 1e-2;
+const TRIANGLE_EPSILON: f32 = 1e-12;
+const QUAD_SELECT_WIDTH: f32 = 0.01;
+
+/// Path trace the scene described by `config` on the CPU and save it as a PNG.
+///
+/// This is much slower than `render_to_png` but needs no graphics adapter at all, and the result
+/// matches the GPU up to floating point differences.
+pub fn render_to_png_cpu(path: &Path, config: &RenderConfig) -> Result<()> {
+    let radiance = render_cpu(config)?;
+    image::save_buffer(
+        path,
+        &radiance.to_rgba8(),
+        config.width,
+        config.height,
+        image::ColorType::Rgba8,
+    )
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Path trace the scene described by `config` on the CPU, without any tone mapping
+pub fn render_cpu(config: &RenderConfig) -> Result<Radiance> {
+    let (scene, camera_settings) = helpers::create_scene(config)?;
+    let camera = camera_settings.to_camera();
+    let bvh = create_bvh(&scene, &config.bvh);
+    Ok(render(&scene, &bvh, camera.uniforms(), config))
+}
+
+/// Average linear radiance of every pixel, row by row from the top left
+#[derive(Debug, Clone)]
+pub struct Radiance {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3>,
+}
+
+impl Radiance {
+    /// Convert to the same 8 bit sRGB values the GPU writes to its output texture
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|p| {
+                // The shader applies a gamma of 2.2 and the sRGB texture then encodes the result
+                // again, so do both here too
+                let [r, g, b] = [p.x(), p.y(), p.z()].map(|c| encode_srgb(c.powf(1. / 2.2)));
+                [r, g, b, u8::MAX]
+            })
+            .collect()
+    }
+}
+
+fn encode_srgb(c: f32) -> u8 {
+    let c = c.clamp(0., 1.);
+    let encoded = if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    };
+    (encoded * 255.).round() as u8
+}
+
+/// Trace `config.samples` paths through every pixel using all the CPU cores.
+///
+/// This mirrors `cs_main` in megakernel.wgsl step for step, down to the order random numbers are
+/// drawn in, so with the same seed every path follows the same bounces as on the GPU.
+pub(crate) fn render(
+    scene: &Scene,
+    bvh: &BVH,
+    camera: &CameraUniforms,
+    config: &RenderConfig,
+) -> Radiance {
+    let tracer = Tracer {
+        scene,
+        bvh,
+        traversal: config.bvh.traversal,
+        camera,
+        sky: &scene.sky,
+        textures: TextureLayers::new(scene.get_textures()),
+        width: config.width,
+        height: config.height,
+        seed: config.seed,
+        max_path_length: config.max_path_length,
+    };
+
+    let mut pixels = vec![Vec3::zero(); (config.width * config.height) as usize];
+    // Threads take one row at a time so the ones with cheap rows (e.g. sky) don't sit idle
+    let rows = Mutex::new(pixels.chunks_mut(config.width as usize).enumerate());
+    let thread_count = std::thread::available_parallelism().map_or(1, |n| n.get());
+    std::thread::scope(|s| {
+        for _ in 0..thread_count {
+            s.spawn(|| loop {
+                let Some((y, row)) = rows.lock().unwrap().next() else {
+                    break;
+                };
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    // The GPU accumulates one sample per frame, starting from frame 1
+                    let mut sum = Vec3::zero();
+                    for frame_num in 1..=config.samples {
+                        sum += tracer.sample(x as u32, y as u32, frame_num);
+                    }
+                    *pixel = sum / config.samples.max(1) as f32;
+                }
+            });
+        }
+    });
+
+    Radiance {
+        width: config.width,
+        height: config.height,
+        pixels,
+    }
+}
+
+// ----------------------- RNG Tools -----------------------
+struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    fn new(x: u32, y: u32, width: u32, frame_num: u32, user_seed: u32) -> Self {
+        let seed = (x.wrapping_add(y.wrapping_mul(width)))
+            ^ jenkins_hash(frame_num ^ jenkins_hash(user_seed));
+        Self {
+            state: jenkins_hash(seed),
+        }
+    }
+
+    fn xorshift32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    fn rand_f32(&mut self) -> f32 {
+        f32::from_bits(0x3f800000 | (self.xorshift32() >> 9)) - 1.
+    }
+
+    fn random_unit_vector(&mut self) -> Vec3 {
+        let x = self.rand_f32() * 2. - 1.;
+        let y = self.rand_f32() * 2. - 1.;
+        let z = self.rand_f32() * 2. - 1.;
+        Vec3::new(x, y, z).normalized()
+    }
+
+    fn random_in_unit_disk(&mut self) -> Vec3 {
+        let r = self.rand_f32() * 2. - 1.;
+        let theta = self.rand_f32() * TAU;
+        Vec3::new(r * theta.cos(), r * theta.sin(), 0.)
+    }
+}
+
+fn jenkins_hash(i: u32) -> u32 {
+    let mut x = i;
+    x = x.wrapping_add(x << 10);
+    x ^= x >> 6;
+    x = x.wrapping_add(x << 3);
+    x ^= x >> 11;
+    x = x.wrapping_add(x << 15);
+    x
+}
+
+// ----------------------- WGSL built-ins -----------------------
+fn reflect(i: Vec3, n: Vec3) -> Vec3 {
+    i - 2. * n.dot(&i) * n
+}
+
+fn refract(i: Vec3, n: Vec3, eta: f32) -> Vec3 {
+    let n_dot_i = n.dot(&i);
+    let k = 1. - eta * eta * (1. - n_dot_i * n_dot_i);
+    if k < 0. {
+        return Vec3::zero();
+    }
+    eta * i - (eta * n_dot_i + k.sqrt()) * n
+}
+
+fn face_forward(n: Vec3, i: Vec3, n_ref: Vec3) -> Vec3 {
+    if n_ref.dot(&i) < 0. {
+        n
+    } else {
+        -n
+    }
+}
+
+fn mix(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+    a * (1. - t) + b * t
+}
+
+// ----------------------- Patterns -----------------------
+// Octaves of noise summed up for turbulence, as in Ray Tracing: The Next Week
+const TURBULENCE_DEPTH: u32 = 7;
+
+// The albedo of a material at a point in the world with the texture coordinates `uv`, before
+// any albedo texture is applied
+fn pattern_albedo(material: &Material, point: Vec3, [u, v]: [f32; 2]) -> Vec3 {
+    let scale = material.pattern_scale;
+    let t = match material.pattern {
+        PATTERN_CHECKER_WORLD => {
+            // Squares are centred on the lattice so a floor at y = 0 isn't split between two
+            let cell = |c: f32| (c * scale + 0.5).floor() as i32;
+            ((cell(point.x()) + cell(point.y()) + cell(point.z())) & 1) as f32
+        }
+        PATTERN_CHECKER_UV => {
+            let cell = |c: f32| (c * scale).floor() as i32;
+            ((cell(u) + cell(v)) & 1) as f32
+        }
+        PATTERN_GRADIENT => u.clamp(0., 1.),
+        PATTERN_NOISE => turbulence(point * scale).min(1.),
+        PATTERN_MARBLE => 0.5 * (1. + (scale * point.z() + 10. * turbulence(point)).sin()),
+        _ => 0.,
+    };
+    mix(material.albedo, material.pattern_colour, t)
+}
+
+// Hash a lattice point with the same hash the random numbers are seeded with
+fn hash_lattice(x: i32, y: i32, z: i32) -> u32 {
+    jenkins_hash(x as u32 ^ jenkins_hash(y as u32 ^ jenkins_hash(z as u32)))
+}
+
+// Dot product of the offset from a lattice point with one of the twelve gradients along the
+// edges of a cube, picked by the hash. From Ken Perlin's "Improving Noise"
+fn gradient_dot(hash: u32, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+// Perlin noise, between about -1 and 1 and zero on the integer lattice
+fn perlin_noise(p: Vec3) -> f32 {
+    let (x0, y0, z0) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (fx, fy, fz) = (p.x() - x0, p.y() - y0, p.z() - z0);
+    let (ix, iy, iz) = (x0 as i32, y0 as i32, z0 as i32);
+    let fade = |t: f32| t * t * t * (t * (t * 6. - 15.) + 10.);
+    let (sx, sy, sz) = (fade(fx), fade(fy), fade(fz));
+    let lerp = |a: f32, b: f32, t: f32| a * (1. - t) + b * t;
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let hash = hash_lattice(
+            ix.wrapping_add(dx),
+            iy.wrapping_add(dy),
+            iz.wrapping_add(dz),
+        );
+        gradient_dot(hash, fx - dx as f32, fy - dy as f32, fz - dz as f32)
+    };
+    let edge = |dy: i32, dz: i32| lerp(corner(0, dy, dz), corner(1, dy, dz), sx);
+    let face = |dz: i32| lerp(edge(0, dz), edge(1, dz), sy);
+    lerp(face(0), face(1), sz)
+}
+
+// Noise summed over several octaves, each twice the frequency and half the weight of the last
+fn turbulence(p: Vec3) -> f32 {
+    let mut sum = 0.;
+    let mut p = p;
+    let mut weight = 1.;
+    for _ in 0..TURBULENCE_DEPTH {
+        sum += weight * perlin_noise(p).abs();
+        weight *= 0.5;
+        p *= 2.;
+    }
+    sum
+}
+
+// ----------------------- Path tracing -----------------------
+#[derive(Clone, Copy)]
+struct Ray {
+    origin: Vec3,
+    direction: Vec3,
+}
+
+impl Ray {
+    fn at(&self, t: f32) -> Vec3 {
+        self.origin + t * self.direction
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Intersection {
+    normal: Vec3,
+    t: f32,
+    material: Material,
+    uv: [f32; 2],
+}
+
+impl Intersection {
+    fn none() -> Self {
+        Self {
+            normal: Vec3::zero(),
+            t: -1.,
+            material: Material::default(),
+            uv: [0.; 2],
+        }
+    }
+
+    fn is_hit(&self) -> bool {
+        self.t > 0.
+    }
+}
+
+struct Scatter {
+    attenuation: Vec3,
+    ray: Ray,
+    medium: Vec3, // Absorption of what the ray goes through next
+}
+
+// Red outline drawn around selected objects
+fn selection_material() -> Material {
+    let red = Vec3::new(1., 0., 0.);
+    Material::new(red, 0., 1., 1. / 1.5, 1., 1., red)
+}
+
+struct Tracer<'a> {
+    scene: &'a Scene,
+    bvh: &'a BVH,
+    traversal: BvhTraversal,
+    camera: &'a CameraUniforms,
+    sky: &'a Sky,
+    textures: TextureLayers<'a>,
+    width: u32,
+    height: u32,
+    seed: u32,
+    max_path_length: u32,
+}
+
+impl Tracer<'_> {
+    fn sample(&self, x: u32, y: u32, frame_num: u32) -> Vec3 {
+        let rng = &mut Rng::new(x, y, self.width, frame_num, self.seed);
+        let camera = self.camera;
+
+        let aspect_ratio = self.width as f32 / self.height as f32;
+
+        // Samples are relative to the center of the pixel
+        let offset = rng.random_in_unit_disk();
+        let u = (x as f32 + 0.5 + offset.x()) / (self.width - 1) as f32;
+        let v = (y as f32 + 0.5 + offset.y()) / (self.height - 1) as f32;
+        let viewport_scale_factor = 2. * camera.focal_distance * (camera.vfov_rad / 2.).tan();
+        // Flip y so it points up like in camera space
+        let u = (2. * u - 1.) * aspect_ratio * viewport_scale_factor;
+        let v = -(2. * v - 1.) * viewport_scale_factor;
+
+        let rotate = |p: Vec3| camera.u * p.x() + camera.v * p.y() + camera.w * p.z();
+        let dof_offset = rotate(rng.random_in_unit_disk()) * camera.dof_scale;
+        let direction = rotate(Vec3::new(u, v, camera.focal_distance)) - dof_offset;
+        let mut ray = Ray {
+            origin: camera.origin + dof_offset,
+            direction: direction.normalized(),
+        };
+        let mut throughput = Vec3::all(1.);
+        let mut radiance_sample = Vec3::zero();
+        let mut medium = Vec3::zero();
+
+        for _ in 0..self.max_path_length {
+            let hit = self.intersect_scene(&ray);
+            if !hit.is_hit() {
+                radiance_sample += throughput * self.sky_colour(&ray);
+                break;
+            }
+            throughput *= transmittance(medium, &ray, &hit);
+            let hit = Intersection {
+                material: self.surface_material(&ray, &hit),
+                ..hit
+            };
+            radiance_sample +=
+                throughput * hit.material.emitted_colour * hit.material.emission_strength;
+
+            let scattered = scatter(rng, &ray, &hit, medium);
+            throughput *= scattered.attenuation;
+            ray = scattered.ray;
+            medium = scattered.medium;
+        }
+        radiance_sample
+    }
+
+    // The material of the hit with its pattern and textures applied at the point that was hit
+    fn surface_material(&self, ray: &Ray, hit: &Intersection) -> Material {
+        let mut material = hit.material;
+        material.albedo = pattern_albedo(&material, ray.at(hit.t), hit.uv);
+        let sample = |texture| {
+            let [r, g, b, _] = self.textures.sample(texture, hit.uv);
+            Vec3::new(r, g, b)
+        };
+        if material.albedo_texture != NO_TEXTURE {
+            material.albedo *= srgb_to_linear(sample(material.albedo_texture));
+        }
+        if material.roughness_texture != NO_TEXTURE {
+            let roughness = sample(material.roughness_texture).y();
+            material.roughness *= roughness;
+        }
+        if material.metalness_texture != NO_TEXTURE {
+            let metalness = sample(material.metalness_texture).z();
+            material.metalness *= metalness;
+        }
+        if material.emission_texture != NO_TEXTURE {
+            material.emitted_colour *= srgb_to_linear(sample(material.emission_texture));
+        }
+        material
+    }
+
+    fn sky_colour(&self, ray: &Ray) -> Vec3 {
+        let t = 0.5 * (ray.direction.normalized().y() + 1.);
+        (1. - t) * self.sky.horizon + t * self.sky.zenith
+    }
+
+    fn intersect_scene(&self, ray: &Ray) -> Intersection {
+        match self.traversal {
+            BvhTraversal::Stack => self.intersect_scene_with_stack(ray),
+            BvhTraversal::Stackless => self.intersect_scene_stackless(ray),
+        }
+    }
+
+    fn intersect_primitive(&self, ray: &Ray, object: &ObjectRef) -> Intersection {
+        let i = object.object_index as usize;
+        if object.object_type == ObjectType::Sphere as u32 {
+            self.intersect_sphere(ray, &self.scene.get_sphere_arr()[i])
+        } else if object.object_type == ObjectType::Quad as u32 {
+            self.intersect_quad(ray, &self.scene.get_quad_arr()[i])
+        } else if object.object_type == ObjectType::Triangle as u32 {
+            self.intersect_tri(ray, &self.scene.get_triangle_arr()[i])
+        } else {
+            Intersection::none()
+        }
+    }
+
+    // Test a sphere, quad or triangle, keeping the hit if it is the closest one so far. Hits on a
+    // mesh instance are moved back into world space
+    fn intersect_leaf_object(
+        &self,
+        ray: &Ray,
+        object: &ObjectRef,
+        instance: Option<&Instance>,
+        closest_hit: &mut Intersection,
+    ) {
+        let mut hit = self.intersect_primitive(ray, object);
+        if hit.t <= 0. || hit.t >= closest_hit.t {
+            return;
+        }
+        if let Some(instance) = instance {
+            // Normals are transformed by the inverse transpose of the object to world matrix
+            let normal_matrix = instance.world_to_object().transpose();
+            hit.normal = transform(&normal_matrix, hit.normal, 0.).normalized();
+            if let Some(material) = instance.material() {
+                hit.material = self.material(material);
+            }
+        }
+        *closest_hit = hit;
+    }
+
+    fn intersect_scene_with_stack(&self, world_ray: &Ray) -> Intersection {
+        let mut closest_hit = Intersection::none();
+        closest_hit.t = f32::MAX;
+
+        let tlas = &self.bvh;
+        if tlas.nodes.first().is_none_or(|root| root.is_populated == 0) {
+            return Intersection::none();
+        }
+        let instances = self.scene.get_instance_arr();
+        let mesh_bvh = self.scene.get_mesh_bvh();
+
+        // Nodes waiting to be visited along with where the ray enters them and the instance they
+        // belong to, whose nodes are in the mesh BVHs rather than the top level one
+        let mut node_stack: Vec<(u32, f32, Option<usize>)> = Vec::new();
+        let root_t = intersect_aabb(world_ray, &tlas.nodes[0]);
+        if root_t < f32::MAX {
+            node_stack.push((0, root_t, None));
+        }
+
+        // Every node is visited at most once per instance, so this only stops a broken BVH from
+        // looping forever
+        let max_iterations = (tlas.nodes.len() + mesh_bvh.nodes.len()) * instances.len().max(1);
+        let mut current_instance = None;
+        let mut ray = *world_ray;
+        for _ in 0..max_iterations {
+            let Some((index, t, instance_index)) = node_stack.pop() else {
+                break;
+            };
+            if t >= closest_hit.t {
+                continue;
+            }
+
+            if instance_index != current_instance {
+                current_instance = instance_index;
+                ray = match instance_index {
+                    Some(i) => transform_ray(world_ray, &instances[i]),
+                    None => *world_ray,
+                };
+            }
+            let instance = instance_index.map(|i| &instances[i]);
+            let bvh = if instance.is_some() { mesh_bvh } else { tlas };
+
+            let node = &bvh.nodes[index as usize];
+            if node.is_leaf() {
+                let first = node.first_object as usize;
+                for object in &bvh.objects[first..first + node.object_count as usize] {
+                    if object.object_type != ObjectType::Instance as u32 {
+                        self.intersect_leaf_object(&ray, object, instance, &mut closest_hit);
+                        continue;
+                    }
+                    // Carry on down the instance's mesh BVH
+                    let i = object.object_index as usize;
+                    let mesh_ray = transform_ray(world_ray, &instances[i]);
+                    let root = instances[i].root_node;
+                    let t = intersect_aabb(&mesh_ray, &mesh_bvh.nodes[root as usize]);
+                    if t < closest_hit.t {
+                        node_stack.push((root, t, Some(i)));
+                    }
+                }
+                continue;
+            }
+
+            // Visit the nearer child first, same as the shader
+            let mut near = (
+                node.left_child_index,
+                intersect_aabb(&ray, &bvh.nodes[node.left_child_index as usize]),
+                instance_index,
+            );
+            let mut far = (
+                node.right_child_index,
+                intersect_aabb(&ray, &bvh.nodes[node.right_child_index as usize]),
+                instance_index,
+            );
+            if far.1 < near.1 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            for child in [far, near] {
+                if child.1 < closest_hit.t {
+                    node_stack.push(child);
+                }
+            }
+        }
+
+        if closest_hit.t < f32::MAX {
+            return closest_hit;
+        }
+        Intersection::none()
+    }
+
+    fn intersect_scene_stackless(&self, ray: &Ray) -> Intersection {
+        let mut closest_hit = Intersection::none();
+        closest_hit.t = f32::MAX;
+
+        let bvh = &self.bvh;
+        if bvh.nodes.first().is_none_or(|root| root.is_populated == 0) {
+            return Intersection::none();
+        }
+
+        let mut node_index = 0;
+        for _ in 0..bvh.nodes.len() {
+            if node_index == u32::MAX {
+                break;
+            }
+            let node = &bvh.nodes[node_index as usize];
+            if intersect_aabb(ray, node) >= closest_hit.t {
+                node_index = node.miss_index;
+            } else if node.is_leaf() {
+                let first = node.first_object as usize;
+                for object in &bvh.objects[first..first + node.object_count as usize] {
+                    if object.object_type == ObjectType::Instance as u32 {
+                        let instance = &self.scene.get_instance_arr()[object.object_index as usize];
+                        self.intersect_instance_stackless(ray, instance, &mut closest_hit);
+                    } else {
+                        self.intersect_leaf_object(ray, object, None, &mut closest_hit);
+                    }
+                }
+                node_index = node.miss_index;
+            } else {
+                node_index = node.left_child_index;
+            }
+        }
+
+        if closest_hit.t < f32::MAX {
+            return closest_hit;
+        }
+        Intersection::none()
+    }
+
+    // Walk the BVH of an instance's mesh the same way as the top level one
+    fn intersect_instance_stackless(
+        &self,
+        world_ray: &Ray,
+        instance: &Instance,
+        closest_hit: &mut Intersection,
+    ) {
+        let ray = transform_ray(world_ray, instance);
+        let bvh = self.scene.get_mesh_bvh();
+
+        let mut node_index = instance.root_node;
+        for _ in 0..bvh.nodes.len() {
+            if node_index == u32::MAX {
+                break;
+            }
+            let node = &bvh.nodes[node_index as usize];
+            if intersect_aabb(&ray, node) >= closest_hit.t {
+                node_index = node.miss_index;
+            } else if node.is_leaf() {
+                let first = node.first_object as usize;
+                for object in &bvh.objects[first..first + node.object_count as usize] {
+                    self.intersect_leaf_object(&ray, object, Some(instance), closest_hit);
+                }
+                node_index = node.miss_index;
+            } else {
+                node_index = node.left_child_index;
+            }
+        }
+    }
+
+    fn material(&self, index: u32) -> Material {
+        self.scene.get_material_arr()[index as usize]
+    }
+
+    fn intersect_sphere(&self, ray: &Ray, sphere: &Sphere) -> Intersection {
+        let v = ray.origin - sphere.center;
+        let a = ray.direction.dot(&ray.direction);
+        let b = v.dot(&ray.direction);
+        let c = v.dot(&v) - sphere.radius * sphere.radius;
+
+        let d = b * b - a * c;
+        if d < 0. {
+            return Intersection::none();
+        }
+
+        let sqrt_d = d.sqrt();
+        let recip_a = 1. / a;
+        let mb = -b;
+        let t1 = (mb - sqrt_d) * recip_a;
+        let t2 = (mb + sqrt_d) * recip_a;
+        let t = if t1 > EPSILON { t1 } else { t2 };
+        if t <= EPSILON {
+            return Intersection::none();
+        }
+
+        let p = ray.at(t);
+        let normal = (p - sphere.center) / sphere.radius;
+        let uv = sphere_uv(normal);
+
+        if sphere.is_selected > 0 && d <= 0.05 * sphere.radius {
+            return Intersection {
+                normal,
+                t,
+                material: selection_material(),
+                uv,
+            };
+        }
+
+        Intersection {
+            normal,
+            t,
+            material: self.material(sphere.material),
+            uv,
+        }
+    }
+
+    fn intersect_quad(&self, ray: &Ray, quad: &Quad) -> Intersection {
+        let denom = quad.normal.dot(&ray.direction);
+        if denom.abs() < 1e-8 {
+            return Intersection::none();
+        }
+
+        let t = (quad.d - quad.normal.dot(&ray.origin)) / denom;
+        if t < EPSILON {
+            return Intersection::none();
+        }
+
+        let planar_to_hit = ray.at(t) - quad.q;
+        let alpha = quad.w.dot(&planar_to_hit.cross(&quad.v));
+        let beta = quad.w.dot(&quad.u.cross(&planar_to_hit));
+        if !((0. ..=1.).contains(&alpha) && (0. ..=1.).contains(&beta)) {
+            return Intersection::none();
+        }
+
+        // Images are upright when u points right and v up, same as the shader
+        let uv = [alpha, 1. - beta];
+        let on_edge = |x: f32| !(QUAD_SELECT_WIDTH..=1. - QUAD_SELECT_WIDTH).contains(&x);
+        if quad.is_selected > 0 && (on_edge(alpha) || on_edge(beta)) {
+            return Intersection {
+                normal: quad.normal,
+                t,
+                material: selection_material(),
+                uv,
+            };
+        }
+
+        Intersection {
+            normal: quad.normal,
+            t,
+            material: self.material(quad.material),
+            uv,
+        }
+    }
+
+    fn intersect_tri(&self, ray: &Ray, triangle: &Triangle) -> Intersection {
+        // Möller–Trumbore
+        let e1 = triangle.b - triangle.a;
+        let e2 = triangle.c - triangle.a;
+
+        let ray_cross_e2 = ray.direction.cross(&e2);
+        let det = e1.dot(&ray_cross_e2);
+        if det.abs() < TRIANGLE_EPSILON {
+            return Intersection::none();
+        }
+
+        let inv_det = 1. / det;
+        let s = ray.origin - triangle.a;
+        let u = inv_det * s.dot(&ray_cross_e2);
+        if !(0. ..=1.).contains(&u) {
+            return Intersection::none();
+        }
+
+        let s_cross_e1 = s.cross(&e1);
+        let v = inv_det * ray.direction.dot(&s_cross_e1);
+        if v < 0. || u + v > 1. {
+            return Intersection::none();
+        }
+
+        let t = inv_det * e2.dot(&s_cross_e1);
+        if t > EPSILON {
+            let w = 1. - u - v;
+            let uv = std::array::from_fn(|i| {
+                w * triangle.uv_a[i] + u * triangle.uv_b[i] + v * triangle.uv_c[i]
+            });
+            return Intersection {
+                normal: e1.cross(&e2).normalized(),
+                t,
+                material: self.material(triangle.material),
+                uv,
+            };
+        }
+        Intersection::none()
+    }
+}
+
+// Longitude and latitude of a point on a sphere, same as sphere_uv in trace.wgsl
+fn sphere_uv(normal: Vec3) -> [f32; 2] {
+    let longitude = (-normal.z()).atan2(normal.x()) / TAU + 0.5;
+    let latitude = normal.y().clamp(-1., 1.).acos() / (TAU / 2.);
+    [longitude, latitude]
+}
+
+fn srgb_to_linear(c: Vec3) -> Vec3 {
+    let decode = |c: f32| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    Vec3::new(decode(c.x()), decode(c.y()), decode(c.z()))
+}
+
+// Move a ray into an instance's mesh space. The direction is left unnormalised so distances along
+// it are the same in both spaces
+fn transform_ray(ray: &Ray, instance: &Instance) -> Ray {
+    let world_to_object = instance.world_to_object();
+    Ray {
+        origin: transform(&world_to_object, ray.origin, 1.),
+        direction: transform(&world_to_object, ray.direction, 0.),
+    }
+}
+
+// Multiply by a matrix as a point when w is 1 or as a direction when it is 0
+fn transform(matrix: &Matrix4<f32>, v: Vec3, w: f32) -> Vec3 {
+    let v = matrix * Vector3::from(v).extend(w);
+    Vec3::new(v.x, v.y, v.z)
+}
+
+// Distance along the ray to where it enters the box, or f32::MAX if it misses
+fn intersect_aabb(ray: &Ray, node: &AABB) -> f32 {
+    let slab = |bound: Vec3| {
+        let d = bound - ray.origin;
+        Vec3::new(
+            d.x() / ray.direction.x(),
+            d.y() / ray.direction.y(),
+            d.z() / ray.direction.z(),
+        )
+    };
+    let t_aabb_min = slab(node.min);
+    let t_aabb_max = slab(node.max);
+
+    // If the ray is coming in the opposite direction, the min and max values stored by the aabb
+    // will be reversed from our perspective
+    let t_min = t_aabb_min.min_extrema(&t_aabb_max);
+    let t_max = t_aabb_min.max_extrema(&t_aabb_max);
+
+    let max_t_min = t_min.x().max(t_min.y()).max(t_min.z());
+    let min_t_max = t_max.x().min(t_max.y()).min(t_max.z());
+
+    if min_t_max < 0. || max_t_min >= min_t_max {
+        return f32::MAX;
+    }
+    max_t_min.max(0.)
+}
+
+fn is_reflective_schlick(rng: &mut Rng, cosine: f32, ior: f32) -> bool {
+    let r0 = (1. - ior) / (1. + ior);
+    let r0 = r0 * r0;
+    (r0 + (1. - r0) * (1. - cosine).powf(5.)) > rng.rand_f32()
+}
+
+// ----------------------- Microfacets -----------------------
+// Same as in trace.wgsl: directions are in a frame with the normal along z
+
+fn ggx_alpha(roughness: f32) -> f32 {
+    roughness * roughness
+}
+
+// Columns of the frame around a unit normal, see tangent_frame in trace.wgsl
+struct TangentFrame([Vec3; 3]);
+
+impl TangentFrame {
+    fn new(n: Vec3) -> Self {
+        let s = if n.z() >= 0. { 1. } else { -1. };
+        let a = -1. / (s + n.z());
+        let b = n.x() * n.y() * a;
+        Self([
+            Vec3::new(1. + s * n.x() * n.x() * a, s * b, -s * n.x()),
+            Vec3::new(b, s + n.y() * n.y() * a, -n.y()),
+            n,
+        ])
+    }
+
+    fn to_local(&self, v: Vec3) -> Vec3 {
+        let [t, b, n] = &self.0;
+        Vec3::new(v.dot(t), v.dot(b), v.dot(n))
+    }
+
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        let [t, b, n] = self.0;
+        t * v.x() + b * v.y() + n * v.z()
+    }
+}
+
+fn ggx_distribution(m: Vec3, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = m.z() * m.z() * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+fn smith_lambda(cos_theta: f32, alpha: f32) -> f32 {
+    let cos2 = cos_theta * cos_theta;
+    let tan2 = (1. - cos2).max(0.) / cos2.max(1e-12);
+    0.5 * ((1. + alpha * alpha * tan2).sqrt() - 1.)
+}
+
+fn smith_g1(cos_theta: f32, alpha: f32) -> f32 {
+    1. / (1. + smith_lambda(cos_theta, alpha))
+}
+
+fn smith_g2(wo: Vec3, wi: Vec3, alpha: f32) -> f32 {
+    1. / (1. + smith_lambda(wo.z(), alpha) + smith_lambda(wi.z(), alpha))
+}
+
+fn ggx_weight(wo: Vec3, wi: Vec3, alpha: f32) -> f32 {
+    let lambda_o = smith_lambda(wo.z(), alpha);
+    (1. + lambda_o) / (1. + lambda_o + smith_lambda(wi.z(), alpha))
+}
+
+fn sample_ggx_normal(rng: &mut Rng, wo: Vec3, alpha: f32) -> Vec3 {
+    if alpha == 0. {
+        return Vec3::new(0., 0., 1.);
+    }
+    let v = Vec3::new(alpha * wo.x(), alpha * wo.y(), wo.z().max(0.)).normalized();
+    let length2 = v.x() * v.x() + v.y() * v.y();
+    let t1 = if length2 > 0. {
+        Vec3::new(-v.y(), v.x(), 0.) / length2.sqrt()
+    } else {
+        Vec3::new(1., 0., 0.)
+    };
+    let t2 = v.cross(&t1);
+    let r = rng.rand_f32().sqrt();
+    let phi = TAU * rng.rand_f32();
+    let p1 = r * phi.cos();
+    let blend = 0.5 * (1. + v.z());
+    let p2 = (1. - blend) * (1. - p1 * p1).sqrt() + blend * r * phi.sin();
+    let n = p1 * t1 + p2 * t2 + (1. - p1 * p1 - p2 * p2).max(0.).sqrt() * v;
+    Vec3::new(alpha * n.x(), alpha * n.y(), n.z().max(0.)).normalized()
+}
+
+fn schlick_fresnel(f0: Vec3, cos_theta: f32) -> Vec3 {
+    f0 + (Vec3::all(1.) - f0) * (1. - cos_theta.clamp(0., 1.)).powf(5.)
+}
+
+fn schlick_weight(cos_theta: f32) -> f32 {
+    (1. - cos_theta.clamp(0., 1.)).powf(5.)
+}
+
+fn luminance(c: Vec3) -> f32 {
+    c.dot(&Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+// ----------------------- Principled BSDF -----------------------
+// Same as in trace.wgsl: opaque surfaces are a diffuse base with sheen under a specular layer
+// under a clear coat, and one of them is picked to sample each bounce
+
+const MIN_LAYER_ALPHA: f32 = 1e-3;
+const CLEARCOAT_F0: f32 = 0.04;
+
+struct Lobes {
+    metalness: f32,
+    dielectric_f0: f32,
+    diffuse: f32,
+    base: f32,
+    alpha: f32,
+    clearcoat_alpha: f32,
+    probabilities: [f32; 3],
+}
+
+impl Lobes {
+    fn new(wo: Vec3, material: &Material) -> Self {
+        let opaque = 1. - (1. - material.metalness) * material.transmission;
+        let metalness = if opaque > 0. {
+            material.metalness / opaque
+        } else {
+            0.
+        };
+        let r0 = (material.ior - 1.) / (material.ior + 1.);
+        let dielectric_f0 = r0 * r0;
+        let dielectric = material.specular * schlick_fresnel(Vec3::all(dielectric_f0), wo.z()).x();
+        let diffuse = (1. - metalness) * (1. - dielectric);
+        let coat = material.clearcoat * schlick_fresnel(Vec3::all(CLEARCOAT_F0), wo.z()).x();
+        let base = 1. - coat;
+        let specular = mix(
+            Vec3::all(dielectric),
+            schlick_fresnel(material.albedo, wo.z()),
+            metalness,
+        );
+        let weights = [
+            base * diffuse * (luminance(material.albedo) + luminance(material.sheen_colour)),
+            base * luminance(specular),
+            coat,
+        ];
+        let total = weights.iter().sum::<f32>().max(1e-12);
+        Self {
+            metalness,
+            dielectric_f0,
+            diffuse,
+            base,
+            alpha: ggx_alpha(material.roughness).max(MIN_LAYER_ALPHA),
+            clearcoat_alpha: ggx_alpha(material.clearcoat_roughness).max(MIN_LAYER_ALPHA),
+            probabilities: weights.map(|w| w / total),
+        }
+    }
+
+    // The BSDF times the cosine of `wi`, and the chance of sampling `wi`
+    fn evaluate(&self, wo: Vec3, wi: Vec3, material: &Material) -> (Vec3, f32) {
+        let h = (wo + wi).normalized();
+        let cos_d = wi.dot(&h);
+        let wo_z = wo.z().max(1e-6);
+
+        let diffuse = self.diffuse
+            * (material.albedo / PI + material.sheen_colour * schlick_weight(cos_d))
+            * wi.z();
+        let d = ggx_distribution(h, self.alpha);
+        let fresnel = mix(
+            Vec3::all(
+                material.specular * schlick_fresnel(Vec3::all(self.dielectric_f0), cos_d).x(),
+            ),
+            schlick_fresnel(material.albedo, cos_d),
+            self.metalness,
+        );
+        let specular = fresnel * d * smith_g2(wo, wi, self.alpha) / (4. * wo_z);
+        let d_coat = ggx_distribution(h, self.clearcoat_alpha);
+        let coat = material.clearcoat
+            * schlick_fresnel(Vec3::all(CLEARCOAT_F0), cos_d).x()
+            * d_coat
+            * smith_g2(wo, wi, self.clearcoat_alpha)
+            / (4. * wo_z);
+
+        let [p_diffuse, p_specular, p_coat] = self.probabilities;
+        let pdf = p_diffuse * wi.z() / PI
+            + p_specular * smith_g1(wo.z(), self.alpha) * d / (4. * wo_z)
+            + p_coat * smith_g1(wo.z(), self.clearcoat_alpha) * d_coat / (4. * wo_z);
+        (self.base * (diffuse + specular) + Vec3::all(coat), pdf)
+    }
+}
+
+fn opaque_scatter(rng: &mut Rng, input_ray: &Ray, hit: &Intersection, medium: Vec3) -> Scatter {
+    // Opaque surfaces are two sided, so reflect off whichever side was hit (mesh winding varies)
+    let normal = face_forward(hit.normal, input_ray.direction, hit.normal);
+    let frame = TangentFrame::new(normal);
+    let wo = frame.to_local(-input_ray.direction.normalized());
+    let lobes = Lobes::new(wo, &hit.material);
+
+    let [p_diffuse, p_specular, _] = lobes.probabilities;
+    let pick = rng.rand_f32();
+    let wi = if pick < p_diffuse {
+        let reflected = normal + rng.random_unit_vector();
+        if reflected.dot(&reflected) > 1e-12 {
+            frame.to_local(reflected.normalized())
+        } else {
+            Vec3::new(0., 0., 1.)
+        }
+    } else if pick < p_diffuse + p_specular {
+        reflect(-wo, sample_ggx_normal(rng, wo, lobes.alpha))
+    } else {
+        reflect(-wo, sample_ggx_normal(rng, wo, lobes.clearcoat_alpha))
+    };
+
+    let attenuation = match wi.z() > 0. {
+        true => match lobes.evaluate(wo, wi, &hit.material) {
+            (value, pdf) if pdf > 0. => value / pdf,
+            _ => Vec3::zero(),
+        },
+        false => Vec3::zero(),
+    };
+    Scatter {
+        attenuation,
+        ray: Ray {
+            origin: input_ray.at(hit.t) + normal * EPSILON,
+            direction: frame.to_world(wi),
+        },
+        medium,
+    }
+}
+
+fn dielectric_scatter(rng: &mut Rng, input_ray: &Ray, hit: &Intersection, medium: Vec3) -> Scatter {
+    // Figure out which side of the surface we are hitting
+    let normal = face_forward(hit.normal, input_ray.direction, hit.normal);
+    let entering = input_ray.direction.dot(&hit.normal) < 0.;
+    let eta = if entering {
+        1. / hit.material.ior
+    } else {
+        hit.material.ior
+    };
+
+    let frame = TangentFrame::new(normal);
+    let wo = frame.to_local(-input_ray.direction.normalized());
+    let alpha = ggx_alpha(hit.material.roughness);
+    let m = sample_ggx_normal(rng, wo, alpha);
+    let refracted = refract(-wo, m, eta);
+    let cos_theta = wo.dot(&m).min(1.);
+
+    let cannot_refract = refracted.x() == 0. && refracted.y() == 0. && refracted.z() == 0.;
+    let reflects = cannot_refract || is_reflective_schlick(rng, cos_theta, hit.material.ior);
+    let (wi, origin, medium) = if reflects {
+        (
+            reflect(-wo, m),
+            input_ray.at(hit.t) + normal * EPSILON,
+            medium,
+        )
+    } else if entering {
+        (refracted, input_ray.at(hit.t), hit.material.absorption)
+    } else {
+        (refracted, input_ray.at(hit.t), Vec3::zero())
+    };
+
+    // Fresnel already picked between reflection and refraction, so only the masking is left. As
+    // with metals, light a microfacet sends out the wrong side of the surface is lost
+    let valid = if reflects { wi.z() > 0. } else { wi.z() < 0. };
+    let attenuation = if valid {
+        Vec3::all(ggx_weight(wo, wi, alpha))
+    } else {
+        Vec3::zero()
+    };
+    Scatter {
+        attenuation,
+        ray: Ray {
+            origin,
+            direction: frame.to_world(wi),
+        },
+        medium,
+    }
+}
+
+fn scatter(rng: &mut Rng, input_ray: &Ray, hit: &Intersection, ray_medium: Vec3) -> Scatter {
+    let medium = medium_at_hit(ray_medium, input_ray, hit);
+    // Probability of going into the glass, which metals never do
+    if (1. - hit.material.metalness) * hit.material.transmission > rng.rand_f32() {
+        return dielectric_scatter(rng, input_ray, hit, medium);
+    }
+    opaque_scatter(rng, input_ray, hit, medium)
+}
+
+// Only rays that leave through the back of a surface were inside it, see medium_at_hit in
+// trace.wgsl
+fn medium_at_hit(medium: Vec3, ray: &Ray, hit: &Intersection) -> Vec3 {
+    if ray.direction.dot(&hit.normal) > 0. {
+        medium
+    } else {
+        Vec3::zero()
+    }
+}
+
+// What is left of the light after going through `medium` from the ray's origin to the hit, see
+// transmittance in trace.wgsl
+fn transmittance(medium: Vec3, ray: &Ray, hit: &Intersection) -> Vec3 {
+    let medium = medium_at_hit(medium, ray, hit);
+    let distance = hit.t * ray.direction.length();
+    Vec3::new(
+        (-medium.x() * distance).exp(),
+        (-medium.y() * distance).exp(),
+        (-medium.z() * distance).exp(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::{Pattern, PatternSpace};
+
+    fn patterned(pattern: Pattern) -> Material {
+        Material::new_basic(Vec3::all(1.), 0.).with_pattern(pattern)
+    }
+
+    #[test]
+    fn checkers_alternate() {
+        let world = patterned(Pattern::Checker {
+            space: PatternSpace::World,
+            scale: 2.,
+            colour: Vec3::zero(),
+        });
+        let albedo = |p: Vec3| pattern_albedo(&world, p, [0., 0.]).x();
+        // Squares of half a unit centred on the lattice, so the floor is one colour around 0
+        assert_eq!(albedo(Vec3::new(0.1, 0., 0.1)), 1.);
+        assert_eq!(albedo(Vec3::new(0.1, -1e-3, -0.2)), 1.);
+        assert_eq!(albedo(Vec3::new(0.3, 0., 0.1)), 0.);
+        assert_eq!(albedo(Vec3::new(-0.3, 0., 0.1)), 0.);
+        assert_eq!(albedo(Vec3::new(0.6, 0., 0.6)), 1.);
+
+        let uv = patterned(Pattern::Checker {
+            space: PatternSpace::Uv,
+            scale: 4.,
+            colour: Vec3::zero(),
+        });
+        let albedo = |p: [f32; 2]| pattern_albedo(&uv, Vec3::zero(), p).x();
+        assert_eq!(albedo([0.1, 0.1]), 1.);
+        assert_eq!(albedo([0.3, 0.1]), 0.);
+        assert_eq!(albedo([0.3, 0.3]), 1.);
+        assert_eq!(albedo([0.9, 0.6]), 0.);
+    }
+
+    #[test]
+    fn noise_is_smooth_and_bounded() {
+        let mut previous = perlin_noise(Vec3::new(0., 0.3, 0.7));
+        for i in 1..2000 {
+            let p = Vec3::new(i as f32 * 0.01, 0.3 + i as f32 * 0.003, 0.7);
+            let noise = perlin_noise(p);
+            assert!((-1.1..=1.1).contains(&noise), "noise({p:?}) = {noise}");
+            assert!(
+                (noise - previous).abs() < 0.1,
+                "Noise jumped from {previous} to {noise} at {p:?}"
+            );
+            previous = noise;
+        }
+        for p in [
+            Vec3::zero(),
+            Vec3::new(3., -2., 7.),
+            Vec3::new(-5., 1., -1.),
+        ] {
+            assert_eq!(
+                perlin_noise(p),
+                0.,
+                "Noise isn't zero on the lattice at {p:?}"
+            );
+        }
+        // Not the same everywhere
+        let values: Vec<f32> = (0..10)
+            .map(|i| perlin_noise(Vec3::new(i as f32 + 0.5, 0.5, 0.5)))
+            .collect();
+        assert!(values.iter().any(|&v| v != values[0]));
+    }
+
+    #[test]
+    fn tangent_frames_are_orthonormal() {
+        for n in [
+            Vec3::new(0., 0., 1.),
+            Vec3::new(0., 0., -1.),
+            Vec3::new(0.3, -0.8, 0.1).normalized(),
+            Vec3::new(-1., 0., 0.),
+        ] {
+            let frame = TangentFrame::new(n);
+            for (i, a) in frame.0.iter().enumerate() {
+                assert!(
+                    (a.length() - 1.).abs() < 1e-5,
+                    "{n:?}: {a:?} isn't unit length"
+                );
+                for b in &frame.0[i + 1..] {
+                    assert!(
+                        a.dot(b).abs() < 1e-5,
+                        "{n:?}: {a:?} and {b:?} aren't orthogonal"
+                    );
+                }
+            }
+            let v = Vec3::new(0.2, 0.5, -0.4);
+            assert!((frame.to_world(frame.to_local(v)) - v).length() < 1e-5);
+            assert!((frame.to_local(n) - Vec3::new(0., 0., 1.)).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn ggx_samples_visible_normals() {
+        let rng = &mut Rng::new(1, 2, 3, 4, 5);
+        for alpha in [0.01, 0.2, 0.6, 1.] {
+            for wo in [
+                Vec3::new(0., 0., 1.),
+                Vec3::new(0.7, 0., 0.7).normalized(),
+                Vec3::new(-0.3, 0.95, 0.05).normalized(),
+            ] {
+                for _ in 0..200 {
+                    let m = sample_ggx_normal(rng, wo, alpha);
+                    assert!((m.length() - 1.).abs() < 1e-4, "{m:?} isn't unit length");
+                    assert!(
+                        m.z() >= 0. && wo.dot(&m) >= -1e-6,
+                        "{m:?} isn't visible from {wo:?}"
+                    );
+                    let wi = reflect(-wo, m);
+                    let weight = ggx_weight(wo, wi, alpha);
+                    assert!((0. ..=1.).contains(&weight), "Weight {weight} out of range");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_microfacets_are_mirrors() {
+        let rng = &mut Rng::new(1, 2, 3, 4, 5);
+        let wo = Vec3::new(0.6, 0., 0.8);
+        let normal = Vec3::new(0., 0., 1.);
+        assert_eq!((sample_ggx_normal(rng, wo, 0.) - normal).length(), 0.);
+        assert_eq!(ggx_weight(wo, reflect(-wo, normal), 0.), 1.);
+    }
+
+    #[test]
+    fn glass_absorbs_only_inside() {
+        let absorption = Vec3::new(0.1, 0.5, 2.);
+        let glass = Material::new_clear(Vec3::all(1.)).with_absorption(absorption);
+        let hit = Intersection {
+            normal: Vec3::new(0., 0., 1.),
+            t: 1.,
+            material: glass,
+            uv: [0.; 2],
+        };
+        let rng = &mut Rng::new(1, 2, 3, 4, 5);
+        let outside = Vec3::new(0.3, 0.2, 0.1);
+        for _ in 0..100 {
+            // Going in takes on the glass's absorption, bouncing off keeps the air's
+            let entering = Ray {
+                origin: Vec3::new(0.6, 0., 0.8),
+                direction: Vec3::new(-0.6, 0., -0.8),
+            };
+            let scattered = dielectric_scatter(rng, &entering, &hit, outside);
+            let expected = match scattered.ray.direction.z() < 0. {
+                true => absorption,
+                false => outside,
+            };
+            assert_eq!((scattered.medium - expected).length(), 0.);
+
+            // Going out always ends up in the air, unless the ray stays inside
+            let leaving = Ray {
+                origin: Vec3::new(0., 0., -1.),
+                direction: Vec3::new(0., 0., 1.),
+            };
+            let scattered = dielectric_scatter(rng, &leaving, &hit, absorption);
+            let expected = match scattered.ray.direction.z() > 0. {
+                true => Vec3::zero(),
+                false => absorption,
+            };
+            assert_eq!((scattered.medium - expected).length(), 0.);
+        }
+
+        // Twice the distance through the glass lets through the square of the light
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0., 0., 1.),
+        };
+        let through = |t| transmittance(absorption, &ray, &Intersection { t, ..hit });
+        let (once, twice) = (through(0.5), through(1.));
+        assert!((once * once - twice).length() < 1e-6);
+        assert!((through(1.).z() - (-2f32).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn open_surfaces_dont_absorb_behind_them() {
+        let absorption = Vec3::new(0.1, 0.5, 2.);
+        let pane = Intersection {
+            normal: Vec3::new(0., 0., 1.),
+            t: 1.,
+            material: Material::new_clear(Vec3::all(1.))
+                .with_absorption(absorption)
+                .with_roughness(0.),
+            uv: [0.; 2],
+        };
+        let rng = &mut Rng::new(1, 2, 3, 4, 5);
+        let ray = Ray {
+            origin: Vec3::new(0., 0., 1.),
+            direction: Vec3::new(0., 0., -1.),
+        };
+        // Find a ray that went through the lone pane, which thinks it is inside glass now
+        let through = std::iter::repeat_with(|| dielectric_scatter(rng, &ray, &pane, Vec3::zero()))
+            .find(|scattered| scattered.ray.direction.z() < 0.)
+            .unwrap();
+        assert_eq!((through.medium - absorption).length(), 0.);
+
+        // But it reaches the front of the floor behind it, so it was in the air all along
+        let floor = Intersection {
+            normal: Vec3::new(0., 0., 1.),
+            t: 3.,
+            material: Material::default(),
+            uv: [0.; 2],
+        };
+        let light = transmittance(through.medium, &through.ray, &floor);
+        assert_eq!((light - Vec3::all(1.)).length(), 0.);
+        let bounced = scatter(rng, &through.ray, &floor, through.medium);
+        assert_eq!(bounced.medium.length(), 0.);
+    }
+
+    // The mean weight of the sampled bounces has to be how much light the BSDF reflects in total,
+    // which is only the case if the chance of sampling each direction is worked out correctly
+    #[test]
+    fn opaque_sampling_matches_the_bsdf() {
+        let rough_plastic = Material::new_basic(Vec3::new(0.8, 0.3, 0.2), 0.)
+            .with_roughness(0.4)
+            .with_specular(1.);
+        let gold = Material::new_basic(Vec3::new(1., 0.8, 0.3), 0.)
+            .with_roughness(0.5)
+            .with_metalness(1.);
+        let velvet = Material::new_basic(Vec3::new(0.2, 0.2, 0.6), 0.)
+            .with_roughness(0.6)
+            .with_metalness(0.3)
+            .with_specular(0.5)
+            .with_clearcoat(0.8, 0.3)
+            .with_sheen(Vec3::new(0.5, 0.4, 0.3));
+        let wo = Vec3::new(0.6, 0., 0.8);
+
+        let rng = &mut Rng::new(1, 2, 3, 4, 5);
+        for material in [rough_plastic, gold, velvet] {
+            let hit = Intersection {
+                normal: Vec3::new(0., 0., 1.),
+                t: 1.,
+                material,
+                uv: [0.; 2],
+            };
+            let ray = Ray {
+                origin: wo,
+                direction: -wo,
+            };
+            let samples = 200_000;
+            let mut sampled = Vec3::zero();
+            for _ in 0..samples {
+                sampled += opaque_scatter(rng, &ray, &hit, Vec3::zero()).attenuation;
+            }
+            let sampled = sampled / samples as f32;
+
+            // Midpoint rule over the hemisphere
+            let lobes = Lobes::new(wo, &material);
+            let (steps_theta, steps_phi) = (400, 800);
+            let (d_theta, d_phi) = (TAU / 4. / steps_theta as f32, TAU / steps_phi as f32);
+            let mut integrated = Vec3::zero();
+            for i in 0..steps_theta {
+                let theta = (i as f32 + 0.5) * d_theta;
+                for j in 0..steps_phi {
+                    let phi = (j as f32 + 0.5) * d_phi;
+                    let wi = Vec3::new(
+                        theta.sin() * phi.cos(),
+                        theta.sin() * phi.sin(),
+                        theta.cos(),
+                    );
+                    let (value, _) = lobes.evaluate(wo, wi, &material);
+                    integrated += value * (theta.sin() * d_theta * d_phi);
+                }
+            }
+
+            let error = (sampled - integrated).length() / integrated.length();
+            assert!(
+                error < 0.02,
+                "Sampled {sampled:?} but the BSDF reflects {integrated:?}"
+            );
+        }
+    }
+}
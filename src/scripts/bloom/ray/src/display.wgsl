@@ -0,0 +1,43 @@
+// ----------------------- Vertex shader -----------------------
+// Cover the viewport with a quad
+
+alias TriangleVertices = array<vec2f, 6>;
+var<private> vertices: TriangleVertices = TriangleVertices(
+    vec2f(-1.0,  1.0),
+    vec2f(-1.0, -1.0),
+    vec2f( 1.0,  1.0),
+    vec2f( 1.0,  1.0),
+    vec2f(-1.0, -1.0),
+    vec2f( 1.0, -1.0),
+);
+
+struct VertexOutput {
+    @builtin(position) position: vec4f,
+    @location(0) uv: vec2f, // 0,0 at the top left of the image, 1,1 at the bottom right
+}
+
+@vertex
+fn vs_main(
+     @builtin(vertex_index) index: u32,
+) -> VertexOutput {
+    let vertex = vertices[index];
+    return VertexOutput(vec4f(vertex, 0.0, 1.0), vertex * vec2f(0.5, -0.5) + 0.5);
+}
+
+// ----------------------- Fragment shader -----------------------
+// Show the average of the samples traced so far, stretched over the render target
+
+// Sum of the samples in rgb and how many there are in a
+@group(0) @binding(0) var radiance_samples: texture_2d<f32>;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // The image can be traced at a different resolution than it is shown at
+    let size = textureDimensions(radiance_samples);
+    let pixel = min(vec2u(in.uv * vec2f(size)), size - 1u);
+    let sum = textureLoad(radiance_samples, pixel, 0);
+
+    // Apply gamma correction to go from linear colour space to sRGB (gamma = 2.2)
+    let colour = sum.rgb / max(sum.a, 1.);
+    return vec4(pow(colour, vec3(1. / 2.2)), 1.);
+}
@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use cgmath::{InnerSpace, Matrix4, Vector4};
+use gltf::{
+    buffer::Source, camera::Projection, image, material::AlphaMode, mesh::Mode, texture, Document,
+    Gltf,
+};
+
+#[cfg(target_arch = "wasm32")]
+use crate::mesh::fetch;
+use crate::{
+    algebra::Vec3,
+    config::CameraSettings,
+    material::Material,
+    mesh::file_stem,
+    primitives::{Scene, Triangle},
+    texture::{Texture, NO_TEXTURE},
+};
+
+// Extensions that change how materials are converted, everything else is ignored with a warning
+const SUPPORTED_EXTENSIONS: [&str; 7] = [
+    "KHR_materials_clearcoat",
+    "KHR_materials_emissive_strength",
+    "KHR_materials_ior",
+    "KHR_materials_sheen",
+    "KHR_materials_specular",
+    "KHR_materials_transmission",
+    "KHR_materials_volume",
+];
+
+impl Scene {
+    /// Add the meshes of a glTF or GLB file to the scene, returning the first camera it contains.
+    ///
+    /// The node hierarchy is flattened so every triangle is stored in world space. The BVH has to
+    /// be rebuilt afterwards for the new triangles to be visible. Images that can't be loaded are
+    /// left out with a warning.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_gltf(&mut self, path: &std::path::Path) -> Result<Option<CameraSettings>> {
+        let file = std::fs::read(path)
+            .with_context(|| format!("Failed to read glTF file {}", path.display()))?;
+        let gltf = Gltf::from_slice(&file)
+            .with_context(|| format!("Failed to parse glTF file {}", path.display()))?;
+
+        // External buffers are relative to the file that references them
+        let directory = path.parent().unwrap_or(std::path::Path::new(""));
+        let mut buffers = Vec::new();
+        for buffer in gltf.buffers() {
+            buffers.push(match buffer.source() {
+                Source::Bin => glb_blob(&gltf)?,
+                Source::Uri(uri) => match decode_data_uri(uri) {
+                    Some(data) => data?,
+                    None => std::fs::read(directory.join(uri)).with_context(|| {
+                        format!("Failed to read buffer {uri} of {}", path.display())
+                    })?,
+                },
+            });
+        }
+
+        let mut images = Vec::new();
+        for image in gltf.images() {
+            let texture = match embedded_image(&image, &buffers, &path.to_string_lossy()) {
+                Some(texture) => texture,
+                None => {
+                    let image_path = directory.join(image_uri(&image));
+                    Texture::load(&image_path.to_string_lossy(), &image_path)
+                }
+            };
+            images.push(texture.map_err(|e| log::warn!("{e:#}")).ok());
+        }
+
+        self.add_gltf(&gltf, &buffers, images, &path.to_string_lossy())
+            .with_context(|| format!("Failed to add {} to the scene", path.display()))
+    }
+
+    /// Add the meshes of a glTF or GLB file to the scene, returning the first camera it contains.
+    ///
+    /// External buffers are fetched relative to the file's URL. The BVH has to be rebuilt
+    /// afterwards for the new triangles to be visible.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load_gltf(&mut self, url: &str) -> Result<Option<CameraSettings>> {
+        let file = fetch(url).await?;
+        let gltf =
+            Gltf::from_slice(&file).with_context(|| format!("Failed to parse glTF file {url}"))?;
+
+        let base_url = match url.rfind('/') {
+            Some(i) => &url[..=i],
+            None => "",
+        };
+        let mut buffers = Vec::new();
+        for buffer in gltf.buffers() {
+            buffers.push(match buffer.source() {
+                Source::Bin => glb_blob(&gltf)?,
+                Source::Uri(uri) => match decode_data_uri(uri) {
+                    Some(data) => data?,
+                    None => fetch(&format!("{base_url}{uri}")).await?,
+                },
+            });
+        }
+
+        let mut images = Vec::new();
+        for image in gltf.images() {
+            let texture = match embedded_image(&image, &buffers, url) {
+                Some(texture) => texture,
+                None => {
+                    let image_url = format!("{base_url}{}", image_uri(&image));
+                    fetch(&image_url).await.and_then(|bytes| {
+                        Texture::from_bytes(&image_url, &bytes, Some(image_url.clone()))
+                    })
+                }
+            };
+            images.push(texture.map_err(|e| log::warn!("{e:#}")).ok());
+        }
+
+        self.add_gltf(&gltf, &buffers, images, url)
+            .with_context(|| format!("Failed to add {url} to the scene"))
+    }
+
+    // `images` has a texture for each of the file's images that could be loaded, `source` is the
+    // path or URL of the file
+    fn add_gltf(
+        &mut self,
+        document: &Document,
+        buffers: &[Vec<u8>],
+        images: Vec<Option<Texture>>,
+        source: &str,
+    ) -> Result<Option<CameraSettings>> {
+        for extension in document.extensions_used() {
+            if !SUPPORTED_EXTENSIONS.contains(&extension) {
+                log::warn!("glTF extension {extension} isn't supported and will be ignored");
+            }
+        }
+
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+            .ok_or_else(|| anyhow!("The file doesn't contain any scenes"))?;
+
+        let mut flattened = Flattened::default();
+        for node in scene.nodes() {
+            flattened.visit(&node, Matrix4::from_scale(1.), buffers)?;
+        }
+
+        self.reserve_triangles(flattened.triangles.len());
+
+        // glTF materials (and the images they use) are only added once something uses them, and
+        // only shared between the primitives of this file
+        let file_name = file_stem(source);
+        let mut material_indices = HashMap::new();
+        let mut texture_indices = HashMap::new();
+        for (mut triangle, gltf_material) in flattened.triangles {
+            triangle.material = match gltf_material {
+                Some(index) => *material_indices.entry(index).or_insert_with(|| {
+                    let m = document
+                        .materials()
+                        .nth(index)
+                        .expect("Primitives can only refer to materials that exist");
+                    let name = m
+                        .name()
+                        .map(str::to_string)
+                        .unwrap_or_else(|| format!("gltf_material_{index}"));
+                    let texture = |info: Option<texture::Info>| {
+                        let Some(info) = info else {
+                            return NO_TEXTURE;
+                        };
+                        if info.tex_coord() != 0 {
+                            log::warn!(
+                                "Material {name} uses texture coordinates other than the first, \
+                                which aren't supported"
+                            );
+                        }
+                        let image = info.texture().source().index();
+                        *texture_indices.entry(image).or_insert_with(|| {
+                            match images.get(image).cloned().flatten() {
+                                Some(texture) => self.find_or_add_texture(texture),
+                                None => NO_TEXTURE,
+                            }
+                        })
+                    };
+                    let material = convert_material(&m, texture);
+                    self.add_imported_material(&name, file_name, material)
+                }),
+                // glTF's default material is a plain white surface, much like ours
+                None => 0,
+            };
+            self.add_triangle(triangle);
+        }
+
+        Ok(flattened.camera)
+    }
+}
+
+#[derive(Default)]
+struct Flattened {
+    triangles: Vec<(Triangle, Option<usize>)>, // World space triangle and its glTF material
+    camera: Option<CameraSettings>,
+}
+
+impl Flattened {
+    fn visit(
+        &mut self,
+        node: &gltf::Node,
+        parent: Matrix4<f32>,
+        buffers: &[Vec<u8>],
+    ) -> Result<()> {
+        let transform = parent * Matrix4::from(node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            let mesh_name = mesh.name().unwrap_or("unnamed");
+            if node.skin().is_some() || mesh.weights().is_some() {
+                log::warn!("Mesh {mesh_name} is skinned or morphed, only its rest pose is used");
+            }
+            for primitive in mesh.primitives() {
+                let reader = primitive.reader(|b| buffers.get(b.index()).map(Vec::as_slice));
+                let Some(positions) = reader.read_positions() else {
+                    log::warn!("Skipping a primitive of mesh {mesh_name} without positions");
+                    continue;
+                };
+                let positions: Vec<Vec3> = positions
+                    .map(|[x, y, z]| {
+                        let p = transform * Vector4::new(x, y, z, 1.);
+                        Vec3::new(p.x, p.y, p.z)
+                    })
+                    .collect();
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                let uvs: Option<Vec<[f32; 2]>> = reader
+                    .read_tex_coords(0)
+                    .map(|uvs| uvs.into_f32().collect());
+                let faces: Vec<[u32; 3]> = match primitive.mode() {
+                    Mode::Triangles => indices
+                        .chunks_exact(3)
+                        .map(|f| [f[0], f[1], f[2]])
+                        .collect(),
+                    // Every other triangle in a strip is flipped to keep the winding consistent
+                    Mode::TriangleStrip => indices
+                        .windows(3)
+                        .enumerate()
+                        .map(|(i, f)| match i % 2 {
+                            0 => [f[0], f[1], f[2]],
+                            _ => [f[1], f[0], f[2]],
+                        })
+                        .collect(),
+                    Mode::TriangleFan => indices
+                        .get(1..)
+                        .unwrap_or_default()
+                        .windows(2)
+                        .map(|f| [indices[0], f[0], f[1]])
+                        .collect(),
+                    mode => {
+                        log::warn!(
+                            "Skipping a primitive of mesh {mesh_name} drawn as {mode:?}, only \
+                            triangles are supported"
+                        );
+                        continue;
+                    }
+                };
+
+                let vertex = |i: u32| {
+                    positions.get(i as usize).copied().ok_or_else(|| {
+                        anyhow!("Mesh {mesh_name} refers to vertex {i} which doesn't exist")
+                    })
+                };
+                // glTF's texture coordinates already go down from the top of the image like ours
+                let uv = |i: u32| {
+                    let uvs = uvs.as_ref()?;
+                    Some(uvs.get(i as usize).copied().ok_or_else(|| {
+                        anyhow!("Mesh {mesh_name} has no texture coordinates for vertex {i}")
+                    }))
+                };
+                for [a, b, c] in faces {
+                    let mut triangle = Triangle::new(vertex(a)?, vertex(b)?, vertex(c)?, 0);
+                    if let (Some(uv_a), Some(uv_b), Some(uv_c)) = (uv(a), uv(b), uv(c)) {
+                        triangle = triangle.with_uvs([uv_a?, uv_b?, uv_c?]);
+                    }
+                    self.triangles
+                        .push((triangle, primitive.material().index()));
+                }
+            }
+        }
+
+        if let Some(camera) = node.camera() {
+            match camera.projection() {
+                Projection::Perspective(p) if self.camera.is_none() => {
+                    self.camera = Some(camera_settings(&transform, p.yfov()));
+                }
+                Projection::Perspective(_) => {
+                    log::info!("Only the first camera in a glTF file is used");
+                }
+                Projection::Orthographic(_) => {
+                    log::warn!("Orthographic cameras aren't supported and will be ignored");
+                }
+            }
+        }
+
+        for child in node.children() {
+            self.visit(&child, transform, buffers)?;
+        }
+        Ok(())
+    }
+}
+
+// glTF cameras look down their local -Z axis. Our camera orbits around +Y so any roll is dropped
+fn camera_settings(transform: &Matrix4<f32>, yfov_rad: f32) -> CameraSettings {
+    let to_vec3 = |v: Vector4<f32>| Vec3::new(v.x, v.y, v.z);
+    let defaults = CameraSettings::default();
+    let position = to_vec3(transform * Vector4::new(0., 0., 0., 1.));
+    let forward = to_vec3((transform * Vector4::new(0., 0., -1., 0.)).normalize());
+    CameraSettings {
+        position,
+        // The camera orbits around the point it's looking at, so put it where things are in focus
+        look_at: position + forward * defaults.focal_distance,
+        vfov_deg: yfov_rad.to_degrees(),
+        ..defaults
+    }
+}
+
+// Map the PBR metallic-roughness model and its extensions onto our own. `texture` gives the index
+// of the scene texture for an image the material uses. Textures are always sampled with linear
+// filtering and repeated, whatever their samplers say
+fn convert_material(
+    m: &gltf::Material,
+    mut texture: impl FnMut(Option<texture::Info>) -> u32,
+) -> Material {
+    let pbr = m.pbr_metallic_roughness();
+    let [r, g, b, coverage] = pbr.base_color_factor();
+    // Both transmission and blended transparency let light through the surface
+    let transmission = m.transmission().map_or(0., |t| t.transmission_factor());
+    let coverage = match m.alpha_mode() {
+        AlphaMode::Blend => coverage,
+        _ => 1.,
+    };
+    let transmission = (1. - (1. - transmission) * coverage).clamp(0., 1.);
+    // The specular colour can only tint the layer as a whole, so its brightest channel is used
+    let specular = m.specular().map_or(1., |s| {
+        let [r, g, b] = s.specular_color_factor();
+        s.specular_factor() * r.max(g).max(b)
+    });
+    // Volumes absorb so much of the light going through them that the attenuation colour is left
+    // after the attenuation distance. Thin walled materials (zero thickness) don't absorb at all
+    let absorption = match m.volume() {
+        Some(volume) if volume.thickness_factor() > 0. => {
+            let distance = volume.attenuation_distance();
+            let [r, g, b] = volume
+                .attenuation_color()
+                .map(|c| -c.max(1e-6).ln() / distance);
+            Vec3::new(r, g, b)
+        }
+        _ => Vec3::zero(),
+    };
+
+    let [er, eg, eb] = m.emissive_factor();
+    let emissive = Vec3::new(er, eg, eb) * m.emissive_strength().unwrap_or(1.);
+    let emission_strength = emissive.x().max(emissive.y()).max(emissive.z());
+    let emitted_colour = if emission_strength > 0. {
+        emissive / emission_strength
+    } else {
+        Vec3::new(1., 1., 1.)
+    };
+
+    // The clearcoat and sheen extensions aren't parsed by the gltf crate, so read their factors
+    // from the JSON. Their textures aren't used
+    let factor = |extension: &str, field: &str| -> Option<f32> {
+        Some(m.extension_value(extension)?.get(field)?.as_f64()? as f32)
+    };
+    let colour = |extension: &str, field: &str| -> Option<Vec3> {
+        let values = m.extension_value(extension)?.get(field)?.as_array()?;
+        let channel = |i: usize| Some(values.get(i)?.as_f64()? as f32);
+        Some(Vec3::new(channel(0)?, channel(1)?, channel(2)?))
+    };
+    let clearcoat = factor("KHR_materials_clearcoat", "clearcoatFactor").unwrap_or(0.);
+    let clearcoat_roughness =
+        factor("KHR_materials_clearcoat", "clearcoatRoughnessFactor").unwrap_or(0.);
+    let sheen_colour = colour("KHR_materials_sheen", "sheenColorFactor").unwrap_or(Vec3::zero());
+
+    // The metallic-roughness map has the roughness in green and the metalness in blue
+    Material::new_basic(Vec3::new(r, g, b), 0.)
+        .with_roughness(pbr.roughness_factor())
+        .with_metalness(pbr.metallic_factor())
+        .with_specular(specular)
+        .with_transmission(transmission, m.ior().unwrap_or(1.5))
+        .with_absorption(absorption)
+        .with_clearcoat(clearcoat, clearcoat_roughness)
+        .with_sheen(sheen_colour)
+        .with_emission(emitted_colour, emission_strength)
+        .with_albedo_texture(texture(pbr.base_color_texture()))
+        .with_roughness_texture(texture(pbr.metallic_roughness_texture()))
+        .with_metalness_texture(texture(pbr.metallic_roughness_texture()))
+        .with_emission_texture(texture(m.emissive_texture()))
+}
+
+// Decode an image stored in a buffer or in the file itself, or None if it is a file of its own.
+// It is named after the file it is in, `source`, so it is only shared with the same image of the
+// same file
+fn embedded_image(
+    image: &image::Image,
+    buffers: &[Vec<u8>],
+    source: &str,
+) -> Option<Result<Texture>> {
+    let name = format!("{source}#image{}", image.index());
+    let bytes = match image.source() {
+        image::Source::View { view, .. } => buffers
+            .get(view.buffer().index())
+            .and_then(|buffer| buffer.get(view.offset()..view.offset() + view.length()))
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("Image {name} is outside of its buffer")),
+        image::Source::Uri { uri, .. } => decode_data_uri(uri)?,
+    };
+    Some(bytes.and_then(|bytes| Texture::from_bytes(&name, &bytes, None)))
+}
+
+// Where an image that isn't embedded is, relative to the file
+fn image_uri<'a>(image: &image::Image<'a>) -> &'a str {
+    match image.source() {
+        image::Source::Uri { uri, .. } => uri,
+        image::Source::View { .. } => unreachable!("Images in buffers are embedded"),
+    }
+}
+
+fn glb_blob(gltf: &Gltf) -> Result<Vec<u8>> {
+    gltf.blob
+        .clone()
+        .context("The file refers to a GLB binary chunk but doesn't have one")
+}
+
+// Buffers can be embedded in the file as base64, e.g. "data:application/octet-stream;base64,..."
+fn decode_data_uri(uri: &str) -> Option<Result<Vec<u8>>> {
+    let data = uri.strip_prefix("data:")?;
+    Some(match data.split_once(";base64,") {
+        Some((_, encoded)) => base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .context("Failed to decode an embedded buffer"),
+        None => Err(anyhow!("Only base64 encoded data URIs are supported")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use crate::{algebra::Vec3, material::Material, primitives::Scene};
+
+    // Two boxes with a material each, the top one scaled and turned, and a camera tilted down
+    #[test]
+    fn imports_boxes() {
+        let mut scene = Scene::new();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/boxes.gltf");
+        let camera = scene.load_gltf(&path).unwrap().unwrap();
+
+        assert_eq!(scene.get_triangle_arr().len(), 24);
+        assert_eq!(scene.material_count(), 3);
+        let copper = scene.find_material("brushed_copper").unwrap();
+        let lamp = scene.find_material("lamp").unwrap();
+        let materials = scene.get_material_arr();
+        assert_eq!(materials[copper as usize].metalness, 1.);
+        assert_eq!(materials[copper as usize].roughness, 0.3);
+        assert_eq!(materials[lamp as usize].emission_strength, 3.);
+        assert_eq!(materials[lamp as usize].clearcoat, 1.);
+        let triangles = scene.get_triangle_arr();
+        assert!(triangles[..12].iter().all(|t| t.material == copper));
+        assert!(triangles[12..].iter().all(|t| t.material == lamp));
+
+        // The camera is 2 up and 5 back, looking 20 degrees below the horizon
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-5;
+        assert!(close(camera.position, Vec3::new(0., 2., 5.)));
+        let forward = (camera.look_at - camera.position) * (1. / camera.focal_distance);
+        let tilt = 20f32.to_radians();
+        assert!(close(forward, Vec3::new(0., -tilt.sin(), -tilt.cos())));
+        assert!((camera.vfov_deg - 0.7f32.to_degrees()).abs() < 1e-4);
+    }
+
+    // Materials with the same name in the scene or in another file mustn't be used in place of
+    // the file's own ones
+    #[test]
+    fn materials_are_not_shared_between_files() {
+        let mut scene = Scene::new();
+        let lamp = scene.add_named_material("lamp", Material::default());
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/boxes.gltf");
+        scene.load_gltf(&path).unwrap();
+        scene.load_gltf(&path).unwrap();
+
+        assert_eq!(scene.material_count(), 6);
+        let names: Vec<_> = (0..6).map(|i| scene.get_material_name(i)).collect();
+        assert_eq!(
+            names,
+            [
+                "default",
+                "lamp",
+                "brushed_copper",
+                "boxes_lamp",
+                "boxes_brushed_copper",
+                "boxes_lamp_2"
+            ]
+        );
+        let triangles = scene.get_triangle_arr();
+        assert!(triangles.iter().all(|t| t.material != lamp));
+        assert!(triangles[24..].iter().all(|t| t.material >= 4));
+    }
+
+    // Images inside two files mustn't be mistaken for each other just because they are the first
+    // image of each
+    #[test]
+    fn embedded_images_are_not_shared_between_files() {
+        let boxes = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/boxes.gltf");
+        let boxes: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(boxes).unwrap()).unwrap();
+        let dir = std::env::temp_dir().join(format!("ray_gltf_images_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut scene = Scene::new();
+        let colours = [[255, 0, 0, 255], [0, 0, 255, 255]];
+        for (i, colour) in colours.into_iter().enumerate() {
+            let mut png = std::io::Cursor::new(Vec::new());
+            image::RgbaImage::from_pixel(1, 1, image::Rgba(colour))
+                .write_to(&mut png, image::ImageFormat::Png)
+                .unwrap();
+            let mut gltf = boxes.clone();
+            gltf["images"] = serde_json::json!([{
+                "uri": format!("data:image/png;base64,{}", STANDARD.encode(png.into_inner()))
+            }]);
+            gltf["textures"] = serde_json::json!([{ "source": 0 }]);
+            gltf["materials"][0]["pbrMetallicRoughness"]["baseColorTexture"] =
+                serde_json::json!({ "index": 0 });
+            let path = dir.join(format!("box_{i}.gltf"));
+            std::fs::write(&path, gltf.to_string()).unwrap();
+            scene.load_gltf(&path).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let textures = scene.get_textures();
+        assert_eq!(textures.len(), 2);
+        for (texture, colour) in textures.iter().zip(colours) {
+            assert_eq!(texture.image().get_pixel(0, 0).0, colour);
+        }
+        let copper = |name| scene.get_material_arr()[scene.find_material(name).unwrap() as usize];
+        assert_eq!(copper("brushed_copper").albedo_texture, 0);
+        assert_eq!(copper("box_1_brushed_copper").albedo_texture, 1);
+    }
+}
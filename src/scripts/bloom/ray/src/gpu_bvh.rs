@@ -0,0 +1,477 @@
+use std::{iter, num::NonZeroU64};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    algebra::Vec3,
+    buffers::{GrowableBuffer, SceneBuffers},
+    bvh::{
+        create_bvh, lbvh_depth_bound, lbvh_inputs, traversal_stack_size, update_bvh, BvhBuilder,
+        BvhSettings, BvhUpdate, AABB, BVH,
+    },
+    primitives::Scene,
+};
+
+// Have to stay in sync with lbvh.wgsl
+const WORKGROUP_SIZE: usize = 256;
+const RADIX_BITS: u32 = 4;
+const RADIX: usize = 1 << RADIX_BITS;
+const RADIX_PASSES: u32 = u32::BITS / RADIX_BITS;
+
+// Every radix sort pass needs its own parameters, so they are all written to one buffer up front
+// and picked with a dynamic offset. Offsets have to be multiples of the device's alignment,
+// which is 256 bytes by default
+const PARAMS_STRIDE: u64 = 256;
+
+/// Same as `Params` in lbvh.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LbvhParams {
+    primitive_count: u32,
+    group_count: u32,
+    shift: u32,
+    _padding0: u32,
+    centroid_min: Vec3,
+    _padding1: u32,
+    centroid_scale: Vec3,
+    _padding2: u32,
+}
+
+// One leaf for every object with a node above each pair, or just the root of an empty tree
+fn node_count(object_count: usize) -> usize {
+    (2 * object_count).saturating_sub(1).max(1)
+}
+
+/// The top level BVH as the renderer keeps it. Linear BVHs are built by compute shaders straight
+/// into the scene buffers, so only their size is known on the CPU
+pub(crate) enum TopLevelBvh {
+    Cpu(BVH),
+    Gpu { object_count: usize },
+}
+
+impl TopLevelBvh {
+    pub(crate) fn new(scene: &Scene, settings: &BvhSettings) -> Self {
+        match settings.builder {
+            BvhBuilder::Lbvh => {
+                let object_count = scene.len();
+                log::info!(
+                    "Building a linear BVH with {} nodes for {object_count} objects on the GPU",
+                    node_count(object_count)
+                );
+                Self::Gpu { object_count }
+            }
+            _ => Self::Cpu(create_bvh(scene, settings)),
+        }
+    }
+
+    pub(crate) fn node_count(&self) -> usize {
+        match self {
+            Self::Cpu(bvh) => bvh.nodes.len(),
+            Self::Gpu { object_count } => node_count(*object_count),
+        }
+    }
+
+    pub(crate) fn object_count(&self) -> usize {
+        match self {
+            Self::Cpu(bvh) => bvh.objects.len(),
+            Self::Gpu { object_count } => *object_count,
+        }
+    }
+
+    /// See `BVH::traversal_stack_size`. The depth of a tree built on the GPU isn't known, so the
+    /// deepest it could be is assumed
+    pub(crate) fn traversal_stack_size(&self, scene: &Scene) -> u32 {
+        match self {
+            Self::Cpu(bvh) => bvh.traversal_stack_size(scene),
+            Self::Gpu { object_count } => {
+                // Every leaf holds a single object
+                let instances_per_leaf = u32::from(!scene.get_instance_arr().is_empty());
+                traversal_stack_size(lbvh_depth_bound(*object_count), instances_per_leaf, scene)
+            }
+        }
+    }
+
+    /// See `update_bvh`. Trees built on the GPU are always rebuilt, which is about as fast as a
+    /// refit would be
+    pub(crate) fn update(&mut self, scene: &Scene, settings: &BvhSettings) -> BvhUpdate {
+        match self {
+            Self::Cpu(bvh) => update_bvh(bvh, scene, settings),
+            Self::Gpu { .. } => {
+                *self = Self::new(scene, settings);
+                BvhUpdate::Rebuilt
+            }
+        }
+    }
+
+    /// Upload the whole tree along with the BVHs of the meshes, building it on the GPU first if
+    /// it is a linear BVH. The builder is only created the first time it is needed. Returns true
+    /// if a buffer had to be reallocated, like `SceneBuffers::upload_bvh`
+    pub(crate) fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+        scene_buffers: &mut SceneBuffers,
+        builder: &mut Option<GpuBvhBuilder>,
+    ) -> Result<bool> {
+        match self {
+            Self::Cpu(bvh) => scene_buffers.upload_bvh(device, queue, scene, bvh),
+            Self::Gpu { .. } => builder
+                .get_or_insert_with(|| GpuBvhBuilder::new(device))
+                .build(device, queue, scene, scene_buffers),
+        }
+    }
+}
+
+/// Builds linear BVHs with the compute shaders in lbvh.wgsl, see `BvhBuilder::Lbvh`
+pub(crate) struct GpuBvhBuilder {
+    bind_group_layout: wgpu::BindGroupLayout,
+    compute_morton_codes: wgpu::ComputePipeline,
+    count_digits: wgpu::ComputePipeline,
+    scan_digit_offsets: wgpu::ComputePipeline,
+    scatter_keys: wgpu::ComputePipeline,
+    init_leaves: wgpu::ComputePipeline,
+    build_hierarchy: wgpu::ComputePipeline,
+    propagate_bounds: wgpu::ComputePipeline,
+    finish_nodes: wgpu::ComputePipeline,
+
+    params: wgpu::Buffer,
+    // Scratch space, kept between builds so it only has to grow when the scene does
+    primitives: GrowableBuffer,
+    keys: GrowableBuffer,
+    sorted_keys: GrowableBuffer,
+    digit_offsets: GrowableBuffer,
+    parents: GrowableBuffer,
+    node_bounds: GrowableBuffer,
+}
+
+impl GpuBvhBuilder {
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let storage = |binding: u32, read_only: bool| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("LBVH Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: NonZeroU64::new(std::mem::size_of::<LbvhParams>() as u64),
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+                storage(3, false),
+                storage(4, false),
+                storage(5, false),
+                storage(6, false),
+                storage(7, false),
+                storage(8, false),
+            ],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("lbvh.wgsl"));
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("LBVH Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                cache: None,
+            })
+        };
+
+        Self {
+            compute_morton_codes: pipeline("compute_morton_codes"),
+            count_digits: pipeline("count_digits"),
+            scan_digit_offsets: pipeline("scan_digit_offsets"),
+            scatter_keys: pipeline("scatter_keys"),
+            init_leaves: pipeline("init_leaves"),
+            build_hierarchy: pipeline("build_hierarchy"),
+            propagate_bounds: pipeline("propagate_bounds"),
+            finish_nodes: pipeline("finish_nodes"),
+            bind_group_layout,
+
+            params: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("LBVH Params"),
+                size: RADIX_PASSES as u64 * PARAMS_STRIDE,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            primitives: GrowableBuffer::new(device, "LBVH Primitives"),
+            keys: GrowableBuffer::new(device, "LBVH Keys"),
+            sorted_keys: GrowableBuffer::new(device, "LBVH Sorted Keys"),
+            digit_offsets: GrowableBuffer::new(device, "LBVH Digit Offsets"),
+            parents: GrowableBuffer::new(device, "LBVH Parents"),
+            node_bounds: GrowableBuffer::new(device, "LBVH Node Bounds"),
+        }
+    }
+
+    /// Build a linear BVH over the top level of the scene into the start of the scene's BVH
+    /// buffers, and upload the BVHs of the meshes after it. The work is submitted before this
+    /// returns. Returns true if a scene buffer had to be reallocated
+    pub(crate) fn build(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+        scene_buffers: &mut SceneBuffers,
+    ) -> Result<bool> {
+        let (primitives, frame) = lbvh_inputs(scene);
+        let n = primitives.len();
+        let node_count = node_count(n);
+        let reallocated = scene_buffers.upload_mesh_bvhs(device, queue, scene, node_count, n)?;
+        if n == 0 {
+            scene_buffers
+                .bvh
+                .write_at(queue, 0, bytemuck::bytes_of(&AABB::empty()));
+            return Ok(reallocated);
+        }
+
+        let group_count = n.div_ceil(WORKGROUP_SIZE);
+        let node_group_count = node_count.div_ceil(WORKGROUP_SIZE);
+        let max_group_count = device.limits().max_compute_workgroups_per_dimension as usize;
+        if node_group_count > max_group_count {
+            bail!(
+                "A linear BVH over {n} objects needs {node_group_count} workgroups but the GPU \
+                only allows {max_group_count}"
+            );
+        }
+
+        const KEY_SIZE: usize = 2 * std::mem::size_of::<u32>();
+        const NODE_BOUNDS_SIZE: usize = 6 * std::mem::size_of::<u32>();
+        self.primitives
+            .write(device, queue, bytemuck::cast_slice(&primitives))?;
+        self.keys.reserve(device, (n * KEY_SIZE) as u64)?;
+        self.sorted_keys.reserve(device, (n * KEY_SIZE) as u64)?;
+        self.digit_offsets.reserve(
+            device,
+            (RADIX * group_count * std::mem::size_of::<u32>()) as u64,
+        )?;
+        self.parents
+            .reserve(device, (node_count * std::mem::size_of::<u32>()) as u64)?;
+        self.node_bounds
+            .reserve(device, ((n - 1) * NODE_BOUNDS_SIZE) as u64)?;
+
+        // The parameters for radix sort pass i are at offset i, the other steps use the first
+        let mut params = vec![0; (RADIX_PASSES as u64 * PARAMS_STRIDE) as usize];
+        for pass in 0..RADIX_PASSES {
+            let pass_params = LbvhParams {
+                primitive_count: n as u32,
+                group_count: group_count as u32,
+                shift: pass * RADIX_BITS,
+                _padding0: 0,
+                centroid_min: frame.min,
+                _padding1: 0,
+                centroid_scale: frame.scale,
+                _padding2: 0,
+            };
+            let offset = (pass as u64 * PARAMS_STRIDE) as usize;
+            let bytes = bytemuck::bytes_of(&pass_params);
+            params[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        queue.write_buffer(&self.params, 0, &params);
+
+        // The sort moves the keys back and forth between the two key buffers. There is an even
+        // number of passes so they end up where they started
+        let bind_groups = [
+            self.create_bind_group(device, scene_buffers, &self.keys, &self.sorted_keys),
+            self.create_bind_group(device, scene_buffers, &self.sorted_keys, &self.keys),
+        ];
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("LBVH Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("LBVH Pass"),
+                timestamp_writes: None,
+            });
+            let mut dispatch = |pipeline: &wgpu::ComputePipeline,
+                                bind_group: &wgpu::BindGroup,
+                                params_index: u32,
+                                workgroups: usize| {
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, bind_group, &[params_index * PARAMS_STRIDE as u32]);
+                pass.dispatch_workgroups(workgroups as u32, 1, 1);
+            };
+
+            dispatch(&self.compute_morton_codes, &bind_groups[0], 0, group_count);
+            for i in 0..RADIX_PASSES {
+                let bind_group = &bind_groups[i as usize % 2];
+                dispatch(&self.count_digits, bind_group, i, group_count);
+                dispatch(&self.scan_digit_offsets, bind_group, i, 1);
+                dispatch(&self.scatter_keys, bind_group, i, group_count);
+            }
+            dispatch(&self.init_leaves, &bind_groups[0], 0, group_count);
+            dispatch(&self.build_hierarchy, &bind_groups[0], 0, group_count);
+            dispatch(&self.propagate_bounds, &bind_groups[0], 0, group_count);
+            dispatch(&self.finish_nodes, &bind_groups[0], 0, node_group_count);
+        }
+        queue.submit(iter::once(encoder.finish()));
+        Ok(reallocated)
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        scene_buffers: &SceneBuffers,
+        keys: &GrowableBuffer,
+        sorted_keys: &GrowableBuffer,
+    ) -> wgpu::BindGroup {
+        fn storage(binding: u32, buffer: &GrowableBuffer) -> wgpu::BindGroupEntry<'_> {
+            wgpu::BindGroupEntry {
+                binding,
+                resource: buffer.buffer().as_entire_binding(),
+            }
+        }
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("LBVH Bind Group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &self.params,
+                        offset: 0,
+                        size: NonZeroU64::new(std::mem::size_of::<LbvhParams>() as u64),
+                    }),
+                },
+                storage(1, &self.primitives),
+                storage(2, keys),
+                storage(3, sorted_keys),
+                storage(4, &self.digit_offsets),
+                storage(5, &scene_buffers.bvh),
+                storage(6, &scene_buffers.objects),
+                storage(7, &self.parents),
+                storage(8, &self.node_bounds),
+            ],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{bvh::ObjectRef, headless, helpers, RenderConfig};
+
+    fn read_buffer<T: bytemuck::Pod>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        buffer: &wgpu::Buffer,
+        count: usize,
+    ) -> Vec<T> {
+        let size = (count * std::mem::size_of::<T>()) as u64;
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &readback, 0, size);
+        queue.submit(iter::once(encoder.finish()));
+        readback
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, |r| r.unwrap());
+        device.poll(wgpu::Maintain::Wait);
+        let data = bytemuck::cast_slice(&readback.slice(..).get_mapped_range()).to_vec();
+        data
+    }
+
+    // The GPU has to build exactly the same tree as the CPU, bit for bit
+    fn check_matches_cpu(config: RenderConfig) {
+        let Ok((device, queue)) = pollster::block_on(headless::create_device()) else {
+            eprintln!("No graphics adapter, skipping");
+            return;
+        };
+        let (scene, _) = helpers::create_scene(&config).unwrap();
+        let settings = BvhSettings {
+            builder: BvhBuilder::Lbvh,
+            ..Default::default()
+        };
+        let expected = create_bvh(&scene, &settings);
+
+        let mut scene_buffers = SceneBuffers::new(&device);
+        GpuBvhBuilder::new(&device)
+            .build(&device, &queue, &scene, &mut scene_buffers)
+            .unwrap();
+        let nodes: Vec<AABB> = read_buffer(
+            &device,
+            &queue,
+            scene_buffers.bvh.buffer(),
+            expected.nodes.len(),
+        );
+        let objects: Vec<ObjectRef> = read_buffer(
+            &device,
+            &queue,
+            scene_buffers.objects.buffer(),
+            expected.objects.len(),
+        );
+
+        let node_fields = |n: &AABB| {
+            (
+                [
+                    n.min.x(),
+                    n.min.y(),
+                    n.min.z(),
+                    n.max.x(),
+                    n.max.y(),
+                    n.max.z(),
+                ],
+                [
+                    n.left_child_index,
+                    n.right_child_index,
+                    n.first_object,
+                    n.object_count,
+                    n.is_populated,
+                    n.miss_index,
+                ],
+            )
+        };
+        for (i, (gpu, cpu)) in nodes.iter().zip(&expected.nodes).enumerate() {
+            assert_eq!(node_fields(gpu), node_fields(cpu), "Node {i} differs");
+        }
+        for (i, (gpu, cpu)) in objects.iter().zip(&expected.objects).enumerate() {
+            assert_eq!(
+                (gpu.object_type, gpu.object_index),
+                (cpu.object_type, cpu.object_index),
+                "Object {i} differs"
+            );
+        }
+    }
+
+    #[test]
+    fn default_scene_matches_cpu() {
+        check_matches_cpu(RenderConfig::default());
+    }
+
+    #[test]
+    fn mesh_matches_cpu() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        check_matches_cpu(RenderConfig {
+            scene: Some(manifest_dir.join("scenes/instances.ron")),
+            meshes: vec![manifest_dir.join("scenes/pyramid.obj")],
+            ..Default::default()
+        });
+    }
+}
@@ -0,0 +1,562 @@
+use crate::{
+    buffers::{SceneBuffers, TraversalCounters},
+    bvh::BvhTraversal,
+    timings::StageTimer,
+    wavefront::Wavefront,
+    Uniforms,
+};
+
+// Must match WORKGROUP_SIZE in megakernel.wgsl
+const WORKGROUP_SIZE: u32 = 8;
+
+/// How the GPU traces paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceMode {
+    /// Follow each path from the camera to its end in one kernel, with an invocation per pixel
+    #[default]
+    Megakernel,
+    /// Trace every path a bounce at a time with a kernel for each step (generate camera rays,
+    /// extend rays to their hits, shade the hits, connect finished paths to the image), keeping
+    /// the paths still going in a compacted queue in between. Invocations diverge less when
+    /// neighbouring paths hit different materials or end early, at the cost of storing the state
+    /// of every path between kernels
+    Wavefront,
+}
+
+/// Traces paths with compute shaders, adding one sample per pixel to an accumulation texture
+/// every pass, and draws the average of the samples so far onto a render target.
+///
+/// Tracing doesn't need a surface, so the viewer and headless renders share it, and the image
+/// can be traced at a different resolution than it is shown at. The textures are sized for the
+/// traced image, so they have to be recreated with `resize` when it changes.
+pub(crate) struct GpuTracer {
+    width: u32,
+    height: u32,
+    // Each pass reads the samples so far from one texture and writes them with its own added
+    // to the other
+    radiance_samples: [wgpu::Texture; 2],
+    kernels: Kernels,
+
+    display_pipeline: wgpu::RenderPipeline,
+    display_bind_group_layout: wgpu::BindGroupLayout,
+    display_bind_groups: [wgpu::BindGroup; 2],
+}
+
+enum Kernels {
+    Megakernel(Megakernel),
+    Wavefront(Box<Wavefront>),
+}
+
+impl GpuTracer {
+    /// `format` is the format of the render targets passed to `display`
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        (width, height): (u32, u32),
+        mode: TraceMode,
+        uniforms_buffer: &wgpu::Buffer,
+        scene_buffers: &SceneBuffers,
+        traversal_counters: &TraversalCounters,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) -> Self {
+        let radiance_samples = create_sample_textures(device, width, height);
+        let bindings = TraceBindings::new(
+            &radiance_samples,
+            uniforms_buffer,
+            scene_buffers,
+            traversal_counters,
+        );
+        let kernels = match mode {
+            TraceMode::Megakernel => Kernels::Megakernel(Megakernel::new(
+                device,
+                &bindings,
+                bvh_traversal,
+                bvh_stack_size,
+            )),
+            TraceMode::Wavefront => Kernels::Wavefront(Box::new(Wavefront::new(
+                device,
+                width * height,
+                &bindings,
+                bvh_traversal,
+                bvh_stack_size,
+            ))),
+        };
+
+        let display_bind_group_layout = create_display_bind_group_layout(device);
+        let display_pipeline = create_display_pipeline(device, &display_bind_group_layout, format);
+        let display_bind_groups =
+            create_display_bind_groups(device, &display_bind_group_layout, &radiance_samples);
+
+        Self {
+            width,
+            height,
+            radiance_samples,
+            kernels,
+            display_pipeline,
+            display_bind_group_layout,
+            display_bind_groups,
+        }
+    }
+
+    /// Size of the traced image
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Trace the image at a new resolution. The samples so far are lost, so the next pass has to
+    /// start the image again
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        (width, height): (u32, u32),
+        uniforms_buffer: &wgpu::Buffer,
+        scene_buffers: &SceneBuffers,
+        traversal_counters: &TraversalCounters,
+    ) {
+        if (width, height) == self.size() {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        self.radiance_samples = create_sample_textures(device, width, height);
+        self.display_bind_groups = create_display_bind_groups(
+            device,
+            &self.display_bind_group_layout,
+            &self.radiance_samples,
+        );
+        let bindings = TraceBindings::new(
+            &self.radiance_samples,
+            uniforms_buffer,
+            scene_buffers,
+            traversal_counters,
+        );
+        match &mut self.kernels {
+            Kernels::Megakernel(megakernel) => megakernel.recreate_bind_groups(device, &bindings),
+            Kernels::Wavefront(wavefront) => wavefront.resize(device, width * height, &bindings),
+        }
+    }
+
+    /// Point the tracer at the scene buffers again after they have been reallocated
+    pub fn recreate_bind_groups(
+        &mut self,
+        device: &wgpu::Device,
+        uniforms_buffer: &wgpu::Buffer,
+        scene_buffers: &SceneBuffers,
+        traversal_counters: &TraversalCounters,
+    ) {
+        let bindings = TraceBindings::new(
+            &self.radiance_samples,
+            uniforms_buffer,
+            scene_buffers,
+            traversal_counters,
+        );
+        match &mut self.kernels {
+            Kernels::Megakernel(megakernel) => megakernel.recreate_bind_groups(device, &bindings),
+            Kernels::Wavefront(wavefront) => wavefront.recreate_bind_groups(device, &bindings),
+        }
+    }
+
+    /// Switch the BVH traversal or grow its stack, which are compiled into the shaders
+    pub fn recreate_trace_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) {
+        match &mut self.kernels {
+            Kernels::Megakernel(megakernel) => {
+                megakernel.recreate_pipeline(device, bvh_traversal, bvh_stack_size)
+            }
+            Kernels::Wavefront(wavefront) => {
+                wavefront.recreate_pipelines(device, bvh_traversal, bvh_stack_size)
+            }
+        }
+    }
+
+    /// Add a sample to every pixel, using the uniforms already uploaded for this pass. The first
+    /// pass (`frame_num` 1) ignores the samples already in the textures
+    pub fn trace(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        timer: &mut StageTimer,
+        uniforms: &Uniforms,
+    ) {
+        match &self.kernels {
+            Kernels::Megakernel(megakernel) => timer.stage(encoder, "trace", |encoder| {
+                megakernel.trace(encoder, (self.width, self.height), uniforms.frame_num)
+            }),
+            Kernels::Wavefront(wavefront) => wavefront.trace(encoder, timer, uniforms),
+        }
+    }
+
+    /// Draw the image traced up to pass `frame_num`, scaled to fill `view`
+    pub fn display(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        frame_num: u32,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Display Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        // The texture the last pass wrote to
+        render_pass.set_bind_group(
+            0,
+            &self.display_bind_groups[((frame_num + 1) % 2) as usize],
+            &[],
+        );
+        render_pass.set_pipeline(&self.display_pipeline);
+
+        // Provide vertices to cover the target
+        render_pass.draw(0..6, 0..1);
+    }
+}
+
+/// The resources trace.wgsl binds, which the megakernel uses all of and each wavefront kernel some
+pub(crate) struct TraceBindings<'a> {
+    views: [wgpu::TextureView; 2],
+    uniforms_buffer: &'a wgpu::Buffer,
+    scene_buffers: &'a SceneBuffers,
+    traversal_counters: &'a TraversalCounters,
+}
+
+impl<'a> TraceBindings<'a> {
+    fn new(
+        radiance_samples: &[wgpu::Texture; 2],
+        uniforms_buffer: &'a wgpu::Buffer,
+        scene_buffers: &'a SceneBuffers,
+        traversal_counters: &'a TraversalCounters,
+    ) -> Self {
+        Self {
+            views: radiance_samples
+                .each_ref()
+                .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default())),
+            uniforms_buffer,
+            scene_buffers,
+            traversal_counters,
+        }
+    }
+
+    /// Layout of a binding in trace.wgsl
+    pub fn layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+        let ty = match binding {
+            0 => wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            1 => wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format: wgpu::TextureFormat::Rgba32Float,
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            2 => wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            // Only the traversal counters are written to
+            3..=10 => wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage {
+                    read_only: binding != 9,
+                },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            11 => wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            12 => wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            _ => unreachable!("trace.wgsl has no binding {binding}"),
+        };
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty,
+            count: None,
+        }
+    }
+
+    /// The resource for a binding in trace.wgsl. Every other pass reads the samples so far from
+    /// the second texture and writes to the first instead, `swap_textures` is set for those
+    pub fn entry(&self, binding: u32, swap_textures: bool) -> wgpu::BindGroupEntry<'_> {
+        let resource = match binding {
+            0 | 1 => wgpu::BindingResource::TextureView(
+                &self.views[(binding as usize + swap_textures as usize) % 2],
+            ),
+            2 => self.uniforms_buffer.as_entire_binding(),
+            3 => self.scene_buffers.materials.buffer().as_entire_binding(),
+            4 => self.scene_buffers.bvh.buffer().as_entire_binding(),
+            5 => self.scene_buffers.spheres.buffer().as_entire_binding(),
+            6 => self.scene_buffers.quads.buffer().as_entire_binding(),
+            7 => self.scene_buffers.triangles.buffer().as_entire_binding(),
+            8 => self.scene_buffers.objects.buffer().as_entire_binding(),
+            9 => self.traversal_counters.buffer().as_entire_binding(),
+            10 => self.scene_buffers.instances.buffer().as_entire_binding(),
+            11 => wgpu::BindingResource::TextureView(self.scene_buffers.textures.view()),
+            12 => wgpu::BindingResource::Sampler(self.scene_buffers.textures.sampler()),
+            _ => unreachable!("trace.wgsl has no binding {binding}"),
+        };
+        wgpu::BindGroupEntry { binding, resource }
+    }
+}
+
+/// Build a shader from trace.wgsl and the kernels that use it.
+///
+/// WGSL arrays need a constant size, so the traversal stack is sized by prepending a constant.
+/// The traversal is chosen the same way so the one that isn't used is compiled out
+pub(crate) fn create_trace_shader(
+    device: &wgpu::Device,
+    label: &str,
+    kernels: &str,
+    bvh_traversal: BvhTraversal,
+    bvh_stack_size: u32,
+) -> wgpu::ShaderModule {
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(label),
+        source: wgpu::ShaderSource::Wgsl(
+            format!(
+                "const BVH_STACKLESS: bool = {};\nconst BVH_STACK_SIZE: u32 = {bvh_stack_size}u;\n{}{kernels}",
+                bvh_traversal == BvhTraversal::Stackless,
+                include_str!("trace.wgsl"),
+            )
+            .into(),
+        ),
+    })
+}
+
+// Traces each path from start to finish in megakernel.wgsl
+struct Megakernel {
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+    bind_groups: [wgpu::BindGroup; 2],
+}
+
+impl Megakernel {
+    fn new(
+        device: &wgpu::Device,
+        bindings: &TraceBindings,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) -> Self {
+        let entries: Vec<_> = (0..=12).map(TraceBindings::layout_entry).collect();
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Megakernel Bind Group Layout"),
+            entries: &entries,
+        });
+        let pipeline =
+            Self::create_pipeline(device, &bind_group_layout, bvh_traversal, bvh_stack_size);
+        let bind_groups = Self::create_bind_groups(device, &bind_group_layout, bindings);
+        Self {
+            bind_group_layout,
+            pipeline,
+            bind_groups,
+        }
+    }
+
+    fn recreate_bind_groups(&mut self, device: &wgpu::Device, bindings: &TraceBindings) {
+        self.bind_groups = Self::create_bind_groups(device, &self.bind_group_layout, bindings);
+    }
+
+    fn recreate_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) {
+        self.pipeline = Self::create_pipeline(
+            device,
+            &self.bind_group_layout,
+            bvh_traversal,
+            bvh_stack_size,
+        );
+    }
+
+    fn trace(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        (width, height): (u32, u32),
+        frame_num: u32,
+    ) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Megakernel Pass"),
+            timestamp_writes: None,
+        });
+        // Swap the textures around for storing the previous pass
+        compute_pass.set_bind_group(0, &self.bind_groups[(frame_num % 2) as usize], &[]);
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.dispatch_workgroups(
+            width.div_ceil(WORKGROUP_SIZE),
+            height.div_ceil(WORKGROUP_SIZE),
+            1,
+        );
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) -> wgpu::ComputePipeline {
+        let shader = create_trace_shader(
+            device,
+            "Megakernel Shader",
+            include_str!("megakernel.wgsl"),
+            bvh_traversal,
+            bvh_stack_size,
+        );
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Megakernel Pipeline Layout"),
+            bind_group_layouts: &[layout],
+            push_constant_ranges: &[],
+        });
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Megakernel Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: Some("cs_main"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            cache: None,
+        })
+    }
+
+    // Even passes read from the first texture and write to the second, odd ones the other way
+    // around
+    fn create_bind_groups(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        bindings: &TraceBindings,
+    ) -> [wgpu::BindGroup; 2] {
+        [false, true].map(|swap_textures| {
+            let entries: Vec<_> = (0..=12)
+                .map(|binding| bindings.entry(binding, swap_textures))
+                .collect();
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Megakernel Bind Group"),
+                layout,
+                entries: &entries,
+            })
+        })
+    }
+}
+
+fn create_sample_textures(device: &wgpu::Device, width: u32, height: u32) -> [wgpu::Texture; 2] {
+    let desc = wgpu::TextureDescriptor {
+        label: Some("Radiance Samples"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+        view_formats: &[],
+    };
+    // Create two textures with the same parameters.
+    [device.create_texture(&desc), device.create_texture(&desc)]
+}
+
+fn create_display_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        }],
+        label: Some("Display Bind Group Layout"),
+    })
+}
+
+// One bind group for each of the accumulation textures
+fn create_display_bind_groups(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    textures: &[wgpu::Texture; 2],
+) -> [wgpu::BindGroup; 2] {
+    textures.each_ref().map(|texture| {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Display Bind Group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            }],
+        })
+    })
+}
+
+fn create_display_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    let shader = device.create_shader_module(wgpu::include_wgsl!("display.wgsl"));
+
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Display Pipeline Layout"),
+        bind_group_layouts: &[layout],
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Display Pipeline"),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: Some("vs_main"),
+            buffers: &[],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: Some("fs_main"),
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill, // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+            unclipped_depth: false,                // Requires Features::DEPTH_CLIP_CONTROL
+            conservative: false,                   // Requires Features::CONSERVATIVE_RASTERIZATION
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: None,
+    })
+}
@@ -0,0 +1,134 @@
+use std::{path::Path, time::Instant};
+
+use crate::{helpers, RenderConfig, RenderTarget, Renderer};
+use anyhow::{Context, Result};
+
+/// Render the default scene into an offscreen texture and save it as a PNG.
+///
+/// No window or surface is needed, so this works on build machines without a display. If no
+/// hardware adapter is available wgpu's software fallback (e.g. llvmpipe or lavapipe) is used.
+pub async fn render_to_png(path: &Path, config: &RenderConfig) -> Result<()> {
+    let (device, queue) = create_device().await?;
+    let pixels = render(&device, &queue, config)?;
+    image::save_buffer(
+        path,
+        &pixels,
+        config.width,
+        config.height,
+        image::ColorType::Rgba8,
+    )
+    .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Create a device without a surface for `Renderer`, using a hardware adapter if there is one
+/// and falling back on a software one
+pub async fn create_device() -> Result<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::all()),
+        ..Default::default()
+    });
+
+    let adapter = match instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::util::power_preference_from_env().unwrap_or_default(),
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        })
+        .await
+    {
+        Some(adapter) => adapter,
+        None => instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: true,
+            })
+            .await
+            .context("Failed to find a graphics adapter (hardware or software)")?,
+    };
+    log::info!("Rendering with {:?}", adapter.get_info());
+
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits::default(),
+                memory_hints: Default::default(),
+            },
+            None,
+        )
+        .await
+        .context("Failed to create a device")
+}
+
+// Render the image described by `config`, returning its pixels as sRGB Rgba8
+pub(crate) fn render(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    config: &RenderConfig,
+) -> Result<Vec<u8>> {
+    let (scene, camera) = helpers::create_scene(config)?;
+    let mut renderer = Renderer::new(device, queue, RenderTarget::None, config, scene, &camera)?;
+
+    // Every pass adds one sample per pixel to the accumulation textures, the average is only
+    // drawn into the output texture once they are all done
+    let start = Instant::now();
+    renderer
+        .render_samples(config.samples)
+        .context("Failed to draw the render")?;
+    let pixels = renderer.read_back()?;
+    log::info!(
+        "Traced {} samples per pixel with the {:?} tracer and {:?} BVH traversal in {:.2?}",
+        config.samples,
+        config.trace_mode,
+        config.bvh.traversal,
+        start.elapsed()
+    );
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_device, render};
+    use crate::RenderConfig;
+
+    // Tracing at half the resolution should give the same image as a render half the size,
+    // with every pixel shown as a 2x2 block
+    #[test]
+    fn render_scale_upscales_smaller_render() {
+        let Ok((device, queue)) = pollster::block_on(create_device()) else {
+            eprintln!("No graphics adapter, skipping");
+            return;
+        };
+        let small_config = RenderConfig {
+            width: 31,
+            height: 23,
+            samples: 4,
+            ..Default::default()
+        };
+        let scaled_config = RenderConfig {
+            width: 62,
+            height: 46,
+            render_scale: 0.5,
+            ..small_config.clone()
+        };
+        let small = render(&device, &queue, &small_config).unwrap();
+        let scaled = render(&device, &queue, &scaled_config).unwrap();
+
+        let pixel = |pixels: &[u8], width: u32, x: u32, y: u32| {
+            let start = 4 * (y * width + x) as usize;
+            pixels[start..start + 4].to_vec()
+        };
+        for y in 0..scaled_config.height {
+            for x in 0..scaled_config.width {
+                assert_eq!(
+                    pixel(&scaled, scaled_config.width, x, y),
+                    pixel(&small, small_config.width, x / 2, y / 2),
+                    "Pixel {x},{y} doesn't match the smaller render"
+                );
+            }
+        }
+    }
+}
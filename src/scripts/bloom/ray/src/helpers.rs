@@ -1,172 +1,127 @@
 #[cfg(not(target_arch = "wasm32"))]
 use rand::rngs::ThreadRng;
 
-pub fn create_sample_textures(
-    device: &wgpu::Device,
-    width: u32,
-    height: u32,
-) -> [wgpu::Texture; 2] {
-    let desc = wgpu::TextureDescriptor {
-        label: Some("Radiance Samples"),
-        size: wgpu::Extent3d {
-            width,
-            height,
-            depth_or_array_layers: 1,
-        },
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: wgpu::TextureFormat::Rgba32Float,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
-        view_formats: &[],
+use anyhow::Result;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::mesh::MeshFormat;
+use crate::{
+    algebra::Vec3,
+    config::{CameraSettings, RenderConfig},
+    material::Material,
+    pattern::{Pattern, PatternSpace},
+    primitives::{Quad, Scene, Sphere, Triangle},
+    scene_file::load_scene,
+};
+
+/// Build the scene the config asks for (or the demo scene), along with its camera after any
+/// overrides from the config have been applied
+///
+/// The scene can also be a glTF file on its own. If the scene doesn't set a camera the first one
+/// found in the meshes is used. On the web the meshes have to be fetched, so they are left for
+/// the caller to load.
+pub fn create_scene(config: &RenderConfig) -> Result<(Scene, CameraSettings)> {
+    #[allow(unused_mut)]
+    let (mut scene, mut camera) = match &config.scene {
+        #[cfg(not(target_arch = "wasm32"))]
+        Some(path) if MeshFormat::from_path(path) == Some(MeshFormat::Gltf) => {
+            let mut scene = Scene::new();
+            let camera = scene.load_gltf(path)?;
+            if scene.is_empty() {
+                anyhow::bail!("{} doesn't contain any triangles", path.display());
+            }
+            (scene, camera)
+        }
+        Some(path) => load_scene(path)?,
+        None => (create_default_scene(), None),
     };
-    // Create two textures with the same parameters.
-    [device.create_texture(&desc), device.create_texture(&desc)]
+    #[cfg(not(target_arch = "wasm32"))]
+    for path in &config.meshes {
+        let mesh_camera = scene.load_mesh(path)?;
+        camera = camera.or(mesh_camera);
+    }
+    let camera = camera.unwrap_or_default().with_overrides(&config.camera);
+    Ok((scene, camera))
 }
 
-pub fn create_display_bind_groups(
-    device: &wgpu::Device,
-    layout: &wgpu::BindGroupLayout,
-    textures: &[wgpu::Texture; 2],
-    uniforms_buffer: &wgpu::Buffer,
-    material_buffer: &wgpu::Buffer,
-    bvh_buffer: &wgpu::Buffer,
-    sphere_buffer: &wgpu::Buffer,
-    quad_buffer: &wgpu::Buffer,
-    triangle_buffer: &wgpu::Buffer,
-) -> [wgpu::BindGroup; 2] {
-    let views = [
-        textures[0].create_view(&wgpu::TextureViewDescriptor::default()),
-        textures[1].create_view(&wgpu::TextureViewDescriptor::default()),
-    ];
-    [
-        // Bind group with view[0] assigned to binding 1 and view[1] assigned to binding 2.
-        device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&views[0]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&views[1]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: uniforms_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: material_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: bvh_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: sphere_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 6,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: quad_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 7,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: triangle_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-            ],
-        }),
-        // Bind group with view[1] assigned to binding 1 and view[0] assigned to binding 2.
-        device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&views[1]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&views[0]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: uniforms_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: material_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: bvh_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: sphere_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 6,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: quad_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 7,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: triangle_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-            ],
+pub fn create_default_scene() -> Scene {
+    let mut scene = Scene::new();
+    // A checkered floor makes reflections and depth of field easier to judge
+    scene.add_material(
+        Material::new_basic(Vec3::new(0.5, 0.5, 0.5), 0.).with_pattern(Pattern::Checker {
+            space: PatternSpace::World,
+            scale: 1.,
+            colour: Vec3::new(0.15, 0.15, 0.15),
         }),
-    ]
+    );
+    scene.add_sphere(Sphere::new(Vec3::new(0., -1000., -1.), 1000., 1));
+    // for a in -11..11 {
+    //     for b in -11..11 {
+    //         scene.add_sphere(Sphere::new(
+    //             Vec3::new(
+    //                 (a as f64 + 0.9 * get_random(&mut self.rng)) as f32,
+    //                 0.2,
+    //                 (b as f64 + 0.9 * get_random(&mut self.rng)) as f32,
+    //             ),
+    //             0.2,
+    //             Material::new(
+    //                 Vec3::new(get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32).normalized(),
+    //                 get_random(&mut self.rng) as f32,
+    //                 get_random(&mut self.rng) as f32,
+    //                 1. / 1.5,
+    //                 get_random(&mut self.rng) as f32,
+    //                 get_random(&mut self.rng) as f32,
+    //                 Vec3::new(get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32),
+    //             ),
+    //         )));
+    //     }
+    // }
+    scene.add_sphere(Sphere::new(Vec3::new(2., 1., -2.), 1.0, 0));
+    let mut current_material_index = scene.add_material(Material::new_clear(Vec3::new(1., 1., 1.)));
+    scene.add_sphere(Sphere::new(
+        Vec3::new(-2., 1., 0.),
+        1.0,
+        current_material_index,
+    ));
+    current_material_index = scene.add_material(Material::new(
+        Vec3::new(0.9, 0.0, 0.3),
+        0.,
+        1.,
+        0.67,
+        1.,
+        0.1,
+        Vec3::new(0.9, 0.0, 0.3),
+    ));
+    scene.add_sphere(Sphere::new(
+        Vec3::new(0., 3., 0.),
+        0.5,
+        current_material_index,
+    ));
+    current_material_index = scene.add_material(Material::new(
+        Vec3::new(1.0, 1.0, 1.0),
+        0.,
+        1.,
+        0.67,
+        1.,
+        1.,
+        Vec3::new(1.0, 1.0, 1.0),
+    ));
+    scene.add_sphere(Sphere::new(
+        Vec3::new(0., 3., -1.5),
+        0.5,
+        current_material_index,
+    ));
+    current_material_index =
+        scene.add_material(Material::new_emissive(Vec3::new(1.0, 0.8, 0.7), 1.0));
+    scene.add_quad(Quad::new(
+        Vec3::new(-3.0, 4.0, -3.0),
+        Vec3::new(6.0, 0.0, 0.0),
+        Vec3::new(0., 0.0, 6.0),
+        current_material_index,
+    ));
+    scene.add_quad(Quad::default());
+    scene.add_triangle(Triangle::default());
+    scene
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -0,0 +1,341 @@
+// Builds a linear BVH (Karras 2012) straight into the buffers trace.wgsl reads. The objects
+// are sorted along a Morton curve, then every internal node finds its own children from the
+// sorted keys, so each step runs one thread per object or node. gpu_bvh.rs dispatches the entry
+// points in the order they appear here, and bvh/lbvh.rs builds the same tree on the CPU
+
+struct Params {
+    primitive_count: u32,
+    group_count: u32, // Workgroups in each radix sort pass
+    shift: u32, // Lowest bit of the digit the current radix sort pass sorts by
+    _pad0: u32,
+    centroid_min: vec3f,
+    _pad1: u32,
+    centroid_scale: vec3f, // Maps the centres of the objects' bounds into the unit cube
+    _pad2: u32,
+}
+
+struct Primitive {
+    min: vec3f,
+    object_type: u32,
+    max: vec3f,
+    object_index: u32,
+}
+
+// Same layout as in trace.wgsl
+struct AABB {
+    min: vec3f,
+    left_child_index: u32,
+    max: vec3f,
+    right_child_index: u32,
+    first_object: u32,
+    object_count: u32,
+    is_populated: u32,
+    miss_index: u32,
+}
+
+struct ObjectRef {
+    object_type: u32,
+    object_index: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> primitives: array<Primitive>;
+// Morton code and index of each primitive, sorted by code once the radix sort is done
+@group(0) @binding(2) var<storage, read_write> keys: array<vec2u>;
+@group(0) @binding(3) var<storage, read_write> sorted_keys: array<vec2u>;
+// Where each workgroup's keys with each digit go, digit by digit
+@group(0) @binding(4) var<storage, read_write> digit_offsets: array<u32>;
+@group(0) @binding(5) var<storage, read_write> bvh: array<AABB>;
+@group(0) @binding(6) var<storage, read_write> objects: array<ObjectRef>;
+@group(0) @binding(7) var<storage, read_write> parents: array<u32>;
+// Bounds of the internal nodes while they are grown to fit their leaves, 3 minimums then 3
+// maximums per node
+@group(0) @binding(8) var<storage, read_write> node_bounds: array<atomic<u32>>;
+
+const U32_MAX: u32 = 4294967295u;
+const SIGN_BIT: u32 = 0x80000000u;
+const WORKGROUP_SIZE: u32 = 256u;
+const RADIX: u32 = 16u; // 4 bits are sorted at a time
+const MORTON_CELLS: f32 = 1024.; // 10 bits per axis
+
+var<workgroup> digit_counts: array<atomic<u32>, RADIX>;
+var<workgroup> chunk_sums: array<u32, WORKGROUP_SIZE>;
+var<workgroup> block_digits: array<u32, WORKGROUP_SIZE>;
+
+// Spread the lowest 10 bits out so there are two zero bits between each of them
+fn expand_bits(v: u32) -> u32 {
+    var x = v & 0x3ffu;
+    x = (x | (x << 16u)) & 0x030000ffu;
+    x = (x | (x << 8u)) & 0x0300f00fu;
+    x = (x | (x << 4u)) & 0x030c30c3u;
+    x = (x | (x << 2u)) & 0x09249249u;
+    return x;
+}
+
+fn morton_code(primitive: Primitive) -> u32 {
+    let centroid = (primitive.min + primitive.max) * 0.5;
+    let p = centroid - params.centroid_min;
+    let cell = vec3u(clamp(p * params.centroid_scale * MORTON_CELLS, vec3f(0.), vec3f(MORTON_CELLS - 1.)));
+    return (expand_bits(cell.x) << 2u) | (expand_bits(cell.y) << 1u) | expand_bits(cell.z);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn compute_morton_codes(@builtin(global_invocation_id) id: vec3u) {
+    if id.x >= params.primitive_count {
+        return;
+    }
+    keys[id.x] = vec2u(morton_code(primitives[id.x]), id.x);
+}
+
+// ----------------------- Radix sort -----------------------
+fn digit_of(key: vec2u) -> u32 {
+    return (key.x >> params.shift) & (RADIX - 1u);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn count_digits(
+    @builtin(global_invocation_id) id: vec3u,
+    @builtin(local_invocation_index) local: u32,
+    @builtin(workgroup_id) group: vec3u,
+) {
+    if id.x < params.primitive_count {
+        atomicAdd(&digit_counts[digit_of(keys[id.x])], 1u);
+    }
+    workgroupBarrier();
+    if local < RADIX {
+        digit_offsets[local * params.group_count + group.x] = atomicLoad(&digit_counts[local]);
+    }
+}
+
+// Turn the counts into where each workgroup's keys go. Runs as a single workgroup, each thread
+// adding up one chunk of the counts
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn scan_digit_offsets(@builtin(local_invocation_index) local: u32) {
+    let count = RADIX * params.group_count;
+    let chunk_size = (count + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+    let start = min(local * chunk_size, count);
+    let end = min(start + chunk_size, count);
+
+    var sum = 0u;
+    for (var i = start; i < end; i++) {
+        sum += digit_offsets[i];
+    }
+    chunk_sums[local] = sum;
+    workgroupBarrier();
+
+    // Inclusive scan of the chunk sums
+    for (var offset = 1u; offset < WORKGROUP_SIZE; offset *= 2u) {
+        var value = chunk_sums[local];
+        if local >= offset {
+            value += chunk_sums[local - offset];
+        }
+        workgroupBarrier();
+        chunk_sums[local] = value;
+        workgroupBarrier();
+    }
+
+    var total = 0u;
+    if local > 0u {
+        total = chunk_sums[local - 1u];
+    }
+    for (var i = start; i < end; i++) {
+        let c = digit_offsets[i];
+        digit_offsets[i] = total;
+        total += c;
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn scatter_keys(
+    @builtin(global_invocation_id) id: vec3u,
+    @builtin(local_invocation_index) local: u32,
+    @builtin(workgroup_id) group: vec3u,
+) {
+    let in_range = id.x < params.primitive_count;
+    var key = vec2u(0u);
+    var digit = RADIX; // Threads past the end don't match any key
+    if in_range {
+        key = keys[id.x];
+        digit = digit_of(key);
+    }
+    block_digits[local] = digit;
+    workgroupBarrier();
+    if !in_range {
+        return;
+    }
+
+    // Keys with the same digit keep their order, which makes the sort stable
+    var rank = 0u;
+    for (var i = 0u; i < local; i++) {
+        if block_digits[i] == digit {
+            rank++;
+        }
+    }
+    sorted_keys[digit_offsets[digit * params.group_count + group.x] + rank] = key;
+}
+
+// ----------------------- Hierarchy -----------------------
+// Map floats to unsigned integers in the same order, so atomicMin and atomicMax work on them
+fn to_ordered(f: f32) -> u32 {
+    let bits = bitcast<u32>(f);
+    return select(bits | SIGN_BIT, ~bits, (bits & SIGN_BIT) != 0u);
+}
+
+fn from_ordered(u: u32) -> f32 {
+    return bitcast<f32>(select(~u, u & ~SIGN_BIT, (u & SIGN_BIT) != 0u));
+}
+
+// The leaves go after the internal nodes, one for each key
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn init_leaves(@builtin(global_invocation_id) id: vec3u) {
+    let n = params.primitive_count;
+    let k = id.x;
+    if k >= n {
+        return;
+    }
+    let primitive = primitives[keys[k].y];
+
+    // The bounds of the objects are padded already
+    var leaf: AABB;
+    leaf.min = primitive.min;
+    leaf.max = primitive.max;
+    leaf.first_object = k;
+    leaf.object_count = 1u;
+    leaf.is_populated = 1u;
+    bvh[n - 1u + k] = leaf;
+    objects[k] = ObjectRef(primitive.object_type, primitive.object_index);
+
+    // There is one internal node fewer than leaves, start it off empty
+    if k + 1u < n {
+        for (var axis = 0u; axis < 3u; axis++) {
+            atomicStore(&node_bounds[6u * k + axis], U32_MAX);
+            atomicStore(&node_bounds[6u * k + 3u + axis], 0u);
+        }
+    }
+    if k == 0u {
+        parents[0] = U32_MAX;
+    }
+}
+
+// Length of the prefix the keys at i and j have in common, or -1 if j is outside the list. Keys
+// with the same code are told apart by their position in the list
+fn common_prefix(i: i32, j: i32) -> i32 {
+    if j < 0 || j >= i32(params.primitive_count) {
+        return -1;
+    }
+    let a = keys[i].x;
+    let b = keys[j].x;
+    if a == b {
+        return 32 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+    }
+    return i32(countLeadingZeros(a ^ b));
+}
+
+// Each internal node covers a range of keys that starts or ends at its own index, and is split
+// where the keys in the range first differ
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn build_hierarchy(@builtin(global_invocation_id) id: vec3u) {
+    let n = i32(params.primitive_count);
+    let i = i32(id.x);
+    if i >= n - 1 {
+        return;
+    }
+
+    // The range extends towards the neighbour that has more in common with key i
+    let d = select(-1, 1, common_prefix(i, i + 1) > common_prefix(i, i - 1));
+
+    // Find the other end of the range, everything in it has more in common with key i than the
+    // key on the other side does
+    let delta_min = common_prefix(i, i - d);
+    var max_length = 2;
+    while common_prefix(i, i + max_length * d) > delta_min {
+        max_length *= 2;
+    }
+    var length = 0;
+    for (var step = max_length / 2; step >= 1; step /= 2) {
+        if common_prefix(i, i + (length + step) * d) > delta_min {
+            length += step;
+        }
+    }
+    let j = i + length * d;
+
+    // Find where the keys in the range stop sharing the prefix they all have in common
+    let delta_node = common_prefix(i, j);
+    var split = 0;
+    var step = length;
+    loop {
+        step = (step + 1) / 2;
+        if common_prefix(i, i + (split + step) * d) > delta_node {
+            split += step;
+        }
+        if step <= 1 {
+            break;
+        }
+    }
+    let gamma = i + split * d + min(d, 0);
+
+    // Ranges of a single key are leaves
+    let left = select(u32(gamma), u32(n - 1 + gamma), min(i, j) == gamma);
+    let right = select(u32(gamma + 1), u32(n + gamma), max(i, j) == gamma + 1);
+    bvh[i].left_child_index = left;
+    bvh[i].right_child_index = right;
+    parents[left] = u32(i);
+    parents[right] = u32(i);
+}
+
+// Grow every node above each leaf to fit it
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn propagate_bounds(@builtin(global_invocation_id) id: vec3u) {
+    let n = params.primitive_count;
+    if id.x >= n {
+        return;
+    }
+    let leaf = bvh[n - 1u + id.x];
+    var node = parents[n - 1u + id.x];
+    // Every step goes up a level, the limit only stops a broken tree from looping forever
+    for (var i = 0u; i < n && node != U32_MAX; i++) {
+        for (var axis = 0u; axis < 3u; axis++) {
+            atomicMin(&node_bounds[6u * node + axis], to_ordered(leaf.min[axis]));
+            atomicMax(&node_bounds[6u * node + 3u + axis], to_ordered(leaf.max[axis]));
+        }
+        node = parents[node];
+    }
+}
+
+// Store the bounds of the internal nodes and link up every node's miss index
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn finish_nodes(@builtin(global_invocation_id) id: vec3u) {
+    let n = params.primitive_count;
+    let index = id.x;
+    if index >= 2u * n - 1u {
+        return;
+    }
+    if index < n - 1u {
+        var bounds: array<f32, 6>;
+        for (var i = 0u; i < 6u; i++) {
+            bounds[i] = from_ordered(atomicLoad(&node_bounds[6u * index + i]));
+        }
+        bvh[index].min = vec3f(bounds[0], bounds[1], bounds[2]);
+        bvh[index].max = vec3f(bounds[3], bounds[4], bounds[5]);
+        bvh[index].first_object = 0u;
+        bvh[index].object_count = 0u;
+        bvh[index].is_populated = 1u;
+    }
+
+    // A miss moves on to the right sibling of the nearest node (this one included) that is a
+    // left child, or ends the walk if there is none
+    var node = index;
+    var miss_index = U32_MAX;
+    for (var i = 0u; i < n; i++) {
+        let parent = parents[node];
+        if parent == U32_MAX {
+            break;
+        }
+        if bvh[parent].left_child_index == node {
+            miss_index = bvh[parent].right_child_index;
+            break;
+        }
+        node = parent;
+    }
+    bvh[index].miss_index = miss_index;
+}
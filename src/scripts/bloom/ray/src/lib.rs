@@ -1,21 +1,62 @@
+#![allow(
+    clippy::upper_case_acronyms,
+    clippy::too_many_arguments,
+    clippy::collapsible_match
+)]
+
 mod algebra;
+mod buffers;
 mod bvh;
 mod camera;
+mod config;
+#[cfg(not(target_arch = "wasm32"))]
+mod cpu;
+mod gltf_import;
+mod gpu_bvh;
+mod gpu_tracer;
+#[cfg(not(target_arch = "wasm32"))]
+mod headless;
 mod helpers;
 mod material;
+mod mesh;
+mod obj;
+mod pattern;
 mod primitives;
+mod renderer;
+mod scene_file;
 mod select;
+mod texture;
+mod timings;
+mod wavefront;
 
 use core::f32;
-use std::iter;
 
-use algebra::Vec3;
-use bvh::{create_bvh, AABB, BVH};
-use camera::{Camera, CameraUniforms};
+pub use algebra::Vec3;
+pub use bvh::{
+    create_bvh, BvhBuilder, BvhSettings, BvhStats, BvhTraversal, BVH, DEFAULT_MAX_LEAF_SIZE,
+    DEFAULT_REBUILD_THRESHOLD,
+};
+use camera::CameraUniforms;
+pub use config::{CameraOverrides, CameraSettings, RenderConfig};
+use gpu_bvh::TopLevelBvh;
+pub use gpu_tracer::TraceMode;
+pub use helpers::create_scene;
 use helpers::get_random;
 use material::Material;
-use primitives::{Quad, Scene, Sphere, Triangle};
+pub use pattern::{Pattern, PatternSpace};
+pub use primitives::Scene;
+pub use primitives::Transform;
+use primitives::{Sky, Sphere};
+pub use renderer::{RenderTarget, Renderer, READ_BACK_FORMAT};
+pub use scene_file::{load_scene, save_scene, SceneFile, SceneFormat, SCENE_FILE_VERSION};
 use select::{add_selection, clear_all_selections, get_selected_object, remove_selection};
+pub use texture::Texture;
+pub use timings::StageTimings;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use cpu::{render_cpu, render_to_png_cpu, Radiance};
+#[cfg(not(target_arch = "wasm32"))]
+pub use headless::{create_device, render_to_png};
 
 use bytemuck::Zeroable;
 use wgpu::Limits;
@@ -48,12 +89,8 @@ const DOF_SCALE: f32 = 0.05;
 
 #[cfg(target_arch = "wasm32")]
 const FPS_HISTORY_LENGTH: usize = 60;
-pub const MAX_MATERIAL_COUNT: usize = 10;
-pub const MAX_SPHERE_COUNT: usize = 100;
-pub const MAX_QUAD_COUNT: usize = 100;
-pub const MAX_TRIANGLE_COUNT: usize = 100;
-pub const MAX_OBJECT_COUNT: usize = MAX_SPHERE_COUNT + MAX_QUAD_COUNT + MAX_TRIANGLE_COUNT;
 pub const MAX_PASSES: u32 = 100; // Number of frames before we accept the result
+pub const DEFAULT_MAX_PATH_LENGTH: u32 = 8;
 
 // We need this for Rust to store our data correctly for the shaders
 #[repr(C)]
@@ -64,17 +101,28 @@ struct Uniforms {
     frame_num: u32,
     width: u32,
     height: u32,
-    _padding: u32,
+    seed: u32,
+    max_path_length: u32,
+    // The mesh BVHs are stored after the top level one, their indices are relative to these
+    mesh_node_offset: u32,
+    mesh_object_offset: u32,
+    _padding: [u32; 1],
+    sky: Sky,
 }
 
 impl Uniforms {
-    fn new() -> Self {
+    fn new(config: &RenderConfig) -> Self {
         Self {
             camera: CameraUniforms::zeroed(),
             frame_num: 0,
             width: 0,
             height: 0,
-            _padding: 0,
+            seed: config.seed,
+            max_path_length: config.max_path_length,
+            mesh_node_offset: 0,
+            mesh_object_offset: 0,
+            _padding: [0; 1],
+            sky: Sky::default(),
         }
     }
     fn tick(&mut self) {
@@ -87,36 +135,26 @@ impl Uniforms {
     fn reset_samples(&mut self) {
         self.frame_num = 0;
     }
+    fn set_bvh(&mut self, bvh: &TopLevelBvh) {
+        self.mesh_node_offset = bvh.node_count() as u32;
+        self.mesh_object_offset = bvh.object_count() as u32;
+    }
 }
 
+// The interactive viewer, a client of `Renderer` that presents to a window and moves the camera
+// and objects around with the mouse and keyboard
 struct State<'a> {
     limits: Limits,
-    surface: wgpu::Surface<'a>,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
-    config: wgpu::SurfaceConfiguration,
+    renderer: Renderer<'a>,
     size: winit::dpi::PhysicalSize<u32>,
-
-    render_pipeline: wgpu::RenderPipeline,
-
-    uniforms: Uniforms,
-    uniforms_buffer: wgpu::Buffer,
-    display_bind_groups: [wgpu::BindGroup; 2],
-
-    scene: Scene,
-    material_buffer: wgpu::Buffer,
-    sphere_buffer: wgpu::Buffer,
-    quad_buffer: wgpu::Buffer,
-    triangle_buffer: wgpu::Buffer,
-
-    bvh: [AABB; 2 * MAX_OBJECT_COUNT - 1],
-    bvh_buffer: wgpu::Buffer,
+    passes_per_frame: u32,
+    needs_display: bool, // The finished image has to be shown again, e.g. after a resize
 
     // The window must be declared after the surface so
     // it gets dropped after it as the surface contains
     // unsafe references to the window's resources.
     window: &'a Window,
-    camera: Camera,
+    dof_scale: f32,
     mouse_position: PhysicalPosition<f64>,
     mouse_pressed_position: [PhysicalPosition<f64>; 3],
     mouse_button_pressed: [bool; 3],
@@ -142,375 +180,24 @@ struct State<'a> {
 }
 
 impl<'a> State<'a> {
-    async fn new(
+    fn new(
         window: &'a Window,
         limits: Limits,
+        renderer: Renderer<'a>,
+        render_config: &RenderConfig,
+        dof_scale: f32,
         #[cfg(target_arch = "wasm32")] canvas: web_sys::HtmlCanvasElement,
         #[cfg(target_arch = "wasm32")] cover_canvas: Option<web_sys::HtmlElement>,
     ) -> State<'a> {
-        let size = window.inner_size();
-
-        // The instance is a handle to our GPU
-        // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            #[cfg(not(target_arch = "wasm32"))]
-            backends: wgpu::Backends::PRIMARY,
-            #[cfg(target_arch = "wasm32")]
-            backends: wgpu::Backends::BROWSER_WEBGPU,
-            ..Default::default()
-        });
-
-        let surface = instance.create_surface(window).unwrap();
-
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            })
-            .await
-            .unwrap();
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    required_features: wgpu::Features::empty(),
-                    required_limits: limits.clone(),
-                    memory_hints: Default::default(),
-                },
-                // Some(&std::path::Path::new("trace")), // Trace path
-                None,
-            )
-            .await
-            .unwrap();
-
-        let surface_caps = surface.get_capabilities(&adapter);
-        // Shader code in this tutorial assumes an Srgb surface texture. Using a different
-        // one will result all the colors comming out darker. If you want to support non
-        // Srgb surfaces, you'll need to account for that when drawing to the frame.
-        let surface_format = surface_caps
-            .formats
-            .iter()
-            .copied()
-            .find(|f| f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
-        let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface_format,
-            width: size.width,
-            height: size.height,
-            present_mode: surface_caps.present_modes[0],
-            alpha_mode: surface_caps.alpha_modes[0],
-            desired_maximum_frame_latency: 2,
-            view_formats: vec![],
-        };
-
-        let camera = Camera::look_at(
-            Vec3::new(3., 2., 3.),
-            Vec3::new(0., 1., 0.),
-            Vec3::new(0., 1., 0.),
-            FOCAL_DISTANCE,
-            VFOV_DEG,
-            DOF_SCALE,
-        );
-
-        let uniforms = Uniforms::new();
-        let uniforms_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Uniforms"),
-            size: std::mem::size_of::<Uniforms>() as u64,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-        let material_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Materials"),
-            size: (std::mem::size_of::<Material>() * MAX_MATERIAL_COUNT) as u64,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-
-        let mut scene = Scene::new();
-        scene.add_material(Material::new_basic(Vec3::new(0.5, 0.5, 0.5), 0.));
-        scene.add_sphere(Sphere::new(Vec3::new(0., -1000., -1.), 1000., 1));
-        // for a in -11..11 {
-        //     for b in -11..11 {
-        //         scene.add_sphere(Sphere::new(
-        //             Vec3::new(
-        //                 (a as f64 + 0.9 * get_random(&mut self.rng)) as f32,
-        //                 0.2,
-        //                 (b as f64 + 0.9 * get_random(&mut self.rng)) as f32,
-        //             ),
-        //             0.2,
-        //             Material::new(
-        //                 Vec3::new(get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32).normalized(),
-        //                 get_random(&mut self.rng) as f32,
-        //                 get_random(&mut self.rng) as f32,
-        //                 1. / 1.5,
-        //                 get_random(&mut self.rng) as f32,
-        //                 get_random(&mut self.rng) as f32,
-        //                 Vec3::new(get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32, get_random(&mut self.rng) as f32),
-        //             ),
-        //         )));
-        //     }
-        // }
-        scene.add_sphere(Sphere::new(Vec3::new(2., 1., -2.), 1.0, 0));
-        let mut current_material_index =
-            scene.add_material(Material::new_clear(Vec3::new(1., 1., 1.)));
-        scene.add_sphere(Sphere::new(
-            Vec3::new(-2., 1., 0.),
-            1.0,
-            current_material_index,
-        ));
-        current_material_index = scene.add_material(Material::new(
-            Vec3::new(0.9, 0.0, 0.3),
-            0.,
-            1.,
-            0.67,
-            1.,
-            0.1,
-            Vec3::new(0.9, 0.0, 0.3),
-        ));
-        scene.add_sphere(Sphere::new(
-            Vec3::new(0., 3., 0.),
-            0.5,
-            current_material_index,
-        ));
-        current_material_index = scene.add_material(Material::new(
-            Vec3::new(1.0, 1.0, 1.0),
-            0.,
-            1.,
-            0.67,
-            1.,
-            1.,
-            Vec3::new(1.0, 1.0, 1.0),
-        ));
-        scene.add_sphere(Sphere::new(
-            Vec3::new(0., 3., -1.5),
-            0.5,
-            current_material_index,
-        ));
-        current_material_index =
-            scene.add_material(Material::new_emissive(Vec3::new(1.0, 0.8, 0.7), 1.0));
-        scene.add_quad(Quad::new(
-            Vec3::new(-3.0, 4.0, -3.0),
-            Vec3::new(6.0, 0.0, 0.0),
-            Vec3::new(0., 0.0, 6.0),
-            current_material_index,
-        ));
-        scene.add_quad(Quad::default());
-        scene.add_triangle(Triangle::default());
-        let sphere_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Spheres"),
-            size: (std::mem::size_of::<Sphere>() * MAX_SPHERE_COUNT) as u64,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-        let quad_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Quads"),
-            size: (std::mem::size_of::<Quad>() * MAX_QUAD_COUNT) as u64,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-        let triangle_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Triangles"),
-            size: (std::mem::size_of::<Triangle>() * MAX_TRIANGLE_COUNT) as u64,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-
-        let bvh_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("BVH"),
-            size: std::mem::size_of::<BVH>() as u64,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-
-        let radiance_samples = helpers::create_sample_textures(&device, 1280, 720);
-
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::StorageTexture {
-                        access: wgpu::StorageTextureAccess::WriteOnly,
-                        format: wgpu::TextureFormat::Rgba32Float,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 4,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 5,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 6,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 7,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
-            label: Some("bind_group_layout"),
-        });
-
-        let display_bind_groups = helpers::create_display_bind_groups(
-            &device,
-            &bind_group_layout,
-            &radiance_samples,
-            &uniforms_buffer,
-            &material_buffer,
-            &bvh_buffer,
-            &sphere_buffer,
-            &quad_buffer,
-            &triangle_buffer,
-        );
-
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Shader"),
-            source: wgpu::ShaderSource::Wgsl(
-                format!(
-                    "{}{}",
-                    include_str!("vertex.wgsl"),
-                    include_str!("fragment.wgsl")
-                )
-                .into(),
-            ),
-        });
-
-        let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
-
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: Some("vs_main"),
-                buffers: &[],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: Some("fs_main"),
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill, // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                unclipped_depth: false,                // Requires Features::DEPTH_CLIP_CONTROL
-                conservative: false, // Requires Features::CONSERVATIVE_RASTERIZATION
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-            cache: None,
-        });
-
-        let bvh = create_bvh(&mut scene);
-
-        // log::warn!("{:#?}", bvh);
-
         Self {
             limits,
-
-            surface,
-            device,
-            queue,
-            config,
-            size,
-            render_pipeline,
-
-            uniforms,
-            display_bind_groups,
-            uniforms_buffer,
-
-            scene,
-            material_buffer,
-            sphere_buffer,
-            quad_buffer,
-            triangle_buffer,
-
-            bvh,
-            bvh_buffer,
+            renderer,
+            size: window.inner_size(),
+            passes_per_frame: render_config.passes_per_frame,
+            needs_display: false,
 
             window,
-            camera,
+            dof_scale,
             mouse_position: PhysicalPosition { x: 0., y: 0. },
             mouse_pressed_position: [PhysicalPosition { x: 0., y: 0. }; 3],
             mouse_button_pressed: [false; 3],
@@ -536,22 +223,51 @@ impl<'a> State<'a> {
         }
     }
 
-    fn rebuild_scene(&mut self) {
-        self.bvh = create_bvh(&mut self.scene);
+    // Move the selected spheres along with the mouse, across the screen
+    fn drag_selection(&mut self, dx: f32, dy: f32) {
+        let camera = *self.renderer.camera().uniforms();
+        let offset = -(dx * camera.u + dy * camera.v);
+        let mut moved = false;
+        for sphere in self.renderer.scene_mut().get_sphere_arr_mut() {
+            if sphere.is_selected > 0 {
+                sphere.center += offset;
+                moved = true;
+            }
+        }
+        if moved {
+            self.renderer.refit_scene();
+            self.renderer.reset_samples();
+        }
+    }
+
+    // Where the mouse is in the traced image, which can have a different resolution to the window
+    fn traced_mouse_position(&self) -> PhysicalPosition<f64> {
+        let uniforms = self.renderer.uniforms();
+        PhysicalPosition {
+            x: self.mouse_position.x * uniforms.width as f64 / self.size.width as f64,
+            y: self.mouse_position.y * uniforms.height as f64 / self.size.height as f64,
+        }
+    }
+
+    fn toggle_bvh_traversal(&mut self) {
+        let traversal = match self.renderer.bvh_traversal() {
+            BvhTraversal::Stack => BvhTraversal::Stackless,
+            BvhTraversal::Stackless => BvhTraversal::Stack,
+        };
+        self.renderer.set_bvh_traversal(traversal);
+        log::info!("Switched to {traversal:?} BVH traversal");
     }
 
     fn window(&self) -> &Window {
-        &self.window
+        self.window
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
-            self.uniforms.update(new_size.width, new_size.height);
-            self.uniforms.reset_samples();
+            // Traces at the new resolution, starting the image again
+            self.renderer.resize(new_size.width, new_size.height);
+            self.needs_display = true;
         }
     }
 
@@ -580,7 +296,7 @@ impl<'a> State<'a> {
             WindowEvent::Touch(touch) => {
                 self.mouse_position = touch.location;
                 // We are only going to support a single finger, all others will be ignored
-                if self.touch_finger_id == None && touch.phase == TouchPhase::Started {
+                if self.touch_finger_id.is_none() && touch.phase == TouchPhase::Started {
                     self.touch_finger_id = Some(touch.id);
                     let event = DeviceEvent::Button {
                         button: 0, // Set all touchscreen inputs as left mouse clicks
@@ -609,25 +325,30 @@ impl<'a> State<'a> {
                 ..
             } => match (physical_key, state) {
                 (PhysicalKey::Code(KeyCode::KeyA), ElementState::Pressed) => {
+                    let scene = self.renderer.scene_mut();
                     for _ in 0..10 {
-                        self.scene.add_sphere(Sphere::new(
+                        scene.add_sphere(Sphere::new(
                             Vec3::new(
-                                (10. * get_random(&mut self.rng) - 5.0) as f32,
-                                (5. * get_random(&mut self.rng)) as f32,
-                                (10. * get_random(&mut self.rng) - 5.0) as f32,
+                                10. * get_random(&mut self.rng) - 5.0,
+                                5. * get_random(&mut self.rng),
+                                10. * get_random(&mut self.rng) - 5.0,
                             ),
                             0.2,
-                            self.scene.get_random_material(&mut self.rng),
+                            scene.get_random_material(&mut self.rng),
                         ));
                     }
-                    self.rebuild_scene();
-                    self.uniforms.reset_samples();
+                    self.renderer.rebuild_scene();
+                    self.renderer.reset_samples();
+                    true
+                }
+                (PhysicalKey::Code(KeyCode::KeyT), ElementState::Pressed) => {
+                    self.toggle_bvh_traversal();
                     true
                 }
                 // (PhysicalKey::Code(KeyCode::KeyR), ElementState::Pressed) => {
                 //     self.scene.pop();
                 //     self.rebuild_scene();
-                //     self.uniforms.reset_samples();
+                //     self.renderer.reset_samples();
                 //     true
                 // }
                 (PhysicalKey::Code(KeyCode::ControlLeft), _) => {
@@ -647,8 +368,8 @@ impl<'a> State<'a> {
                     MouseScrollDelta::PixelDelta(delta) => 0.001 * delta.y as f32,
                     MouseScrollDelta::LineDelta(_, y) => y * 0.1,
                 };
-                self.camera.zoom(delta);
-                self.uniforms.reset_samples();
+                self.renderer.camera_mut().zoom(delta);
+                self.renderer.reset_samples();
             }
             DeviceEvent::Button { button, state } => {
                 // 0 - Left
@@ -665,6 +386,7 @@ impl<'a> State<'a> {
                         // Allow for the mouse to move a little bit between being pressed and released
                         if (pos.x - last_pos.x).abs() < 5. && (pos.y - last_pos.y).abs() < 5. {
                             if *button == 0 && !self.ctrl_pressed {
+                                #[cfg(target_arch = "wasm32")]
                                 if let Some(canvas) = &mut self.cover_canvas {
                                     canvas.set_hidden(!canvas.hidden());
                                 }
@@ -672,15 +394,17 @@ impl<'a> State<'a> {
                                 // Check if there are any object we can select
                                 // TODO: Select other primitives
                                 let (hit_object, dist_to_object) = get_selected_object(
-                                    &self.mouse_position,
-                                    &self.uniforms,
-                                    self.scene.get_sphere_arr(),
+                                    &self.traced_mouse_position(),
+                                    self.renderer.uniforms(),
+                                    self.renderer.scene().get_sphere_arr(),
                                 );
 
                                 if hit_object == usize::MAX {
-                                    clear_all_selections(self.scene.get_sphere_arr_mut());
+                                    clear_all_selections(
+                                        self.renderer.scene_mut().get_sphere_arr_mut(),
+                                    );
                                     if *button == 0 {
-                                        self.camera.uniforms.dof_scale = 0.;
+                                        self.renderer.camera_mut().uniforms.dof_scale = 0.;
                                     }
                                 } else {
                                     match *button {
@@ -688,32 +412,35 @@ impl<'a> State<'a> {
                                             if self.ctrl_pressed {
                                                 add_selection(
                                                     hit_object,
-                                                    self.scene.get_sphere_arr_mut(),
+                                                    self.renderer.scene_mut().get_sphere_arr_mut(),
                                                 );
                                             } else {
-                                                self.camera.uniforms.focal_distance =
-                                                    dist_to_object;
-                                                self.camera.uniforms.dof_scale = DOF_SCALE;
+                                                self.renderer
+                                                    .camera_mut()
+                                                    .uniforms
+                                                    .focal_distance = dist_to_object;
+                                                self.renderer.camera_mut().uniforms.dof_scale =
+                                                    self.dof_scale;
                                             }
                                         }
                                         2 => {
                                             if self.ctrl_pressed {
                                                 remove_selection(
                                                     hit_object,
-                                                    self.scene.get_sphere_arr_mut(),
+                                                    self.renderer.scene_mut().get_sphere_arr_mut(),
                                                 );
                                             }
                                         }
                                         _ => {
                                             if self.ctrl_pressed {
                                                 clear_all_selections(
-                                                    self.scene.get_sphere_arr_mut(),
+                                                    self.renderer.scene_mut().get_sphere_arr_mut(),
                                                 );
                                             }
                                         }
                                     }
                                 }
-                                self.uniforms.reset_samples();
+                                self.renderer.reset_samples();
                             }
                         }
                     }
@@ -722,15 +449,17 @@ impl<'a> State<'a> {
             DeviceEvent::MouseMotion { delta: (dx, dy) } => {
                 let dx = *dx as f32 * -0.01;
                 let dy = *dy as f32 * 0.01;
-                if self.mouse_button_pressed[0] {
-                    self.camera.orbit(dx, dy);
-                    self.uniforms.reset_samples();
+                if self.mouse_button_pressed[0] && self.ctrl_pressed {
+                    self.drag_selection(dx, dy);
+                } else if self.mouse_button_pressed[0] {
+                    self.renderer.camera_mut().orbit(dx, dy);
+                    self.renderer.reset_samples();
                 } else if self.mouse_button_pressed[1] {
-                    self.camera.pan(dx, dy);
-                    self.uniforms.reset_samples();
+                    self.renderer.camera_mut().pan(dx, dy);
+                    self.renderer.reset_samples();
                 } else if self.mouse_button_pressed[2] {
-                    self.camera.zoom(-dy);
-                    self.uniforms.reset_samples();
+                    self.renderer.camera_mut().zoom(-dy);
+                    self.renderer.reset_samples();
                 }
             }
             _ => (),
@@ -740,7 +469,8 @@ impl<'a> State<'a> {
     fn update(&mut self) {}
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        if self.uniforms.frame_num > MAX_PASSES {
+        self.renderer.check_traversal_counters();
+        if self.renderer.samples_left() == 0 && !self.needs_display {
             return Ok(());
         }
         #[cfg(target_arch = "wasm32")]
@@ -757,94 +487,90 @@ impl<'a> State<'a> {
             self.frame_rate_pos %= self.frame_rate_history.len();
         }
 
-        // Update Uniforms
-        self.uniforms.camera = *self.camera.uniforms();
-        self.uniforms.tick();
-        self.queue.write_buffer(
-            &self.uniforms_buffer,
-            0,
-            bytemuck::cast_slice(&[self.uniforms]),
-        );
-
-        // Update scene
-        self.queue.write_buffer(
-            &self.material_buffer,
-            0,
-            bytemuck::cast_slice(self.scene.get_material_arr()),
-        );
-        self.queue.write_buffer(
-            &self.sphere_buffer,
-            0,
-            bytemuck::cast_slice(self.scene.get_sphere_arr()),
-        );
-        self.queue.write_buffer(
-            &self.quad_buffer,
-            0,
-            bytemuck::cast_slice(self.scene.get_quad_arr()),
-        );
-        self.queue.write_buffer(
-            &self.triangle_buffer,
-            0,
-            bytemuck::cast_slice(self.scene.get_triangle_arr()),
-        );
-
-        self.queue
-            .write_buffer(&self.bvh_buffer, 0, bytemuck::cast_slice(&self.bvh));
-
-        // Prepare pipeline
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
+        // Trace several passes before presenting if asked to, without going past the last one
+        let passes = self.passes_per_frame.min(self.renderer.samples_left());
+        self.renderer.render_samples(passes)?;
+        self.needs_display = false;
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.,
-                            g: 0.,
-                            b: 0.,
-                            a: 1.0,
-                        }),
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                occlusion_query_set: None,
-                timestamp_writes: None,
-            });
-
-            // Swap the textures around for storing the previous frame
-            render_pass.set_bind_group(
-                0,
-                &self.display_bind_groups[(self.uniforms.frame_num % 2) as usize],
-                &[],
-            );
-            render_pass.set_pipeline(&self.render_pipeline);
+        Ok(())
+    }
+}
 
-            // Provide vertices to cover the screen
-            render_pass.draw(0..6, 0..1);
-        }
+// Find a GPU that can present to the window, and the surface to present to
+async fn connect_to_gpu<'w>(
+    window: &'w Window,
+    limits: &Limits,
+) -> (wgpu::Device, wgpu::Queue, RenderTarget<'w>) {
+    let size = window.inner_size();
+
+    // The instance is a handle to our GPU
+    // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        #[cfg(not(target_arch = "wasm32"))]
+        backends: wgpu::Backends::PRIMARY,
+        #[cfg(target_arch = "wasm32")]
+        backends: wgpu::Backends::BROWSER_WEBGPU,
+        ..Default::default()
+    });
 
-        self.queue.submit(iter::once(encoder.finish()));
-        output.present();
+    let surface = instance.create_surface(window).unwrap();
 
-        Ok(())
-    }
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: Some(&surface),
+            force_fallback_adapter: false,
+        })
+        .await
+        .unwrap();
+
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: wgpu::Features::empty(),
+                required_limits: limits.clone(),
+                memory_hints: Default::default(),
+            },
+            // Some(&std::path::Path::new("trace")), // Trace path
+            None,
+        )
+        .await
+        .unwrap();
+
+    let surface_caps = surface.get_capabilities(&adapter);
+    // Shader code in this tutorial assumes an Srgb surface texture. Using a different
+    // one will result all the colors comming out darker. If you want to support non
+    // Srgb surfaces, you'll need to account for that when drawing to the frame.
+    let surface_format = surface_caps
+        .formats
+        .iter()
+        .copied()
+        .find(|f| f.is_srgb())
+        .unwrap_or(surface_caps.formats[0]);
+    let config = wgpu::SurfaceConfiguration {
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        format: surface_format,
+        width: size.width,
+        height: size.height,
+        present_mode: surface_caps.present_modes[0],
+        alpha_mode: surface_caps.alpha_modes[0],
+        desired_maximum_frame_latency: 2,
+        view_formats: vec![],
+    };
+    (device, queue, RenderTarget::Surface { surface, config })
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 pub async fn run(canvas_id: &str) {
+    if let Err(e) = run_with_config(canvas_id, RenderConfig::default()).await {
+        log::error!("{e:#}");
+    }
+}
+
+/// Open the interactive viewer using the given settings instead of the defaults
+#[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
+pub async fn run_with_config(canvas_id: &str, config: RenderConfig) -> anyhow::Result<()> {
     cfg_if::cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
             std::panic::set_hook(Box::new(console_error_panic_hook::hook));
@@ -855,15 +581,31 @@ pub async fn run(canvas_id: &str) {
         }
     }
 
+    #[allow(unused_mut)]
+    let (mut scene, mut camera_settings) = helpers::create_scene(&config)?;
+    #[cfg(target_arch = "wasm32")]
+    for url in &config.meshes {
+        if let Some(camera) = scene.load_mesh(&url.to_string_lossy()).await? {
+            camera_settings = camera.with_overrides(&config.camera);
+        }
+    }
+
     let event_loop = EventLoop::new().unwrap();
     // let mut window_builder = WindowBuilder::new();
     let mut window_attributes = Window::default_attributes();
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        window_attributes = window_attributes
+            .with_inner_size(winit::dpi::PhysicalSize::new(config.width, config.height));
+    }
 
     // WebGL doesn't support all of wgpu's features, so if
     // we're building for that (or other old APIs) we'll have to disable some.
     let limits = wgpu::Limits::default();
 
+    #[cfg(target_arch = "wasm32")]
     let canvas: web_sys::HtmlCanvasElement;
+    #[cfg(target_arch = "wasm32")]
     let mut cover_canvas: Option<web_sys::HtmlElement> = None;
 
     #[cfg(target_arch = "wasm32")]
@@ -892,13 +634,28 @@ pub async fn run(canvas_id: &str) {
         }
     }
 
+    #[allow(deprecated)]
     let window = event_loop.create_window(window_attributes).unwrap();
 
-    // State::new uses async code, so we're going to wait for it to finish
-    let mut state = State::new(&window, limits, canvas, cover_canvas).await;
+    // Connecting to the GPU uses async code, so we're going to wait for it to finish
+    let (device, queue, target) = connect_to_gpu(&window, &limits).await;
+    let renderer = Renderer::new(&device, &queue, target, &config, scene, &camera_settings)?;
+    #[cfg(target_arch = "wasm32")]
+    let mut state = State::new(
+        &window,
+        limits,
+        renderer,
+        &config,
+        camera_settings.aperture,
+        canvas,
+        cover_canvas,
+    );
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut state = State::new(&window, limits, renderer, &config, camera_settings.aperture);
     let mut surface_configured = false;
 
     // TODO: replace run with run_app
+    #[allow(deprecated)]
     event_loop
         .run(move |event, control_flow| {
             match event {
@@ -965,6 +722,6 @@ pub async fn run(canvas_id: &str) {
                 Event::DeviceEvent { event, .. } => {state.mouse_input(&event)}
                 _ => {}
             }
-        })
-        .unwrap();
+        })?;
+    Ok(())
 }
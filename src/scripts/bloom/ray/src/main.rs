@@ -1,5 +1,226 @@
-use ray_rs::run;
+use std::path::PathBuf;
 
-fn main() {
-    pollster::block_on(run(""));
+use anyhow::{anyhow, bail, Result};
+use clap::Parser;
+use ray_rs::{
+    create_scene, render_to_png, render_to_png_cpu, run_with_config, save_scene, BvhBuilder,
+    BvhSettings, BvhTraversal, CameraOverrides, RenderConfig, TraceMode, Vec3,
+    DEFAULT_MAX_LEAF_SIZE, DEFAULT_MAX_PATH_LENGTH, DEFAULT_REBUILD_THRESHOLD, MAX_PASSES,
+};
+
+/// Path trace a scene, either in an interactive window or straight to a PNG
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Render offscreen and write the result to --output instead of opening a window
+    #[arg(long)]
+    headless: bool,
+
+    /// Trace the headless render on the CPU instead of the GPU, e.g. on machines without one
+    #[arg(long, requires = "headless")]
+    cpu: bool,
+
+    /// Width of the render (and the window) in pixels
+    #[arg(long, default_value_t = 1280)]
+    width: u32,
+
+    /// Height of the render (and the window) in pixels
+    #[arg(long, default_value_t = 720)]
+    height: u32,
+
+    /// Samples per pixel to accumulate before the render is considered finished
+    #[arg(short, long, default_value_t = MAX_PASSES, value_parser = clap::value_parser!(u32).range(1..))]
+    samples: u32,
+
+    /// Samples per pixel the viewer traces before showing each frame, higher converges faster
+    /// at the cost of responsiveness
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    passes_per_frame: u32,
+
+    /// Maximum number of bounces for each path
+    #[arg(long, default_value_t = DEFAULT_MAX_PATH_LENGTH)]
+    max_path_length: u32,
+
+    /// Where to save the image in headless mode
+    #[arg(short, long, default_value = "render.png")]
+    output: PathBuf,
+
+    /// Scene file (.ron, .json, .gltf or .glb) to render instead of the built in demo scene
+    #[arg(long)]
+    scene: Option<PathBuf>,
+
+    /// OBJ or glTF file to add to the scene, can be given more than once
+    #[arg(long = "mesh", value_name = "PATH")]
+    meshes: Vec<PathBuf>,
+
+    /// Save the scene and camera being rendered to a .ron or .json file and exit
+    #[arg(long)]
+    save_scene: Option<PathBuf>,
+
+    /// Camera position as x,y,z
+    #[arg(long, value_parser = parse_vec3, allow_hyphen_values = true)]
+    position: Option<Vec3>,
+
+    /// Point the camera looks at as x,y,z
+    #[arg(long, value_parser = parse_vec3, allow_hyphen_values = true)]
+    look_at: Option<Vec3>,
+
+    /// Vertical field of view in degrees
+    #[arg(long)]
+    vfov: Option<f32>,
+
+    /// Size of the lens, 0 disables depth of field
+    #[arg(long)]
+    aperture: Option<f32>,
+
+    /// Distance from the camera to the plane in focus
+    #[arg(long)]
+    focal_distance: Option<f32>,
+
+    /// Seed for the random number generator, renders with the same seed are identical
+    #[arg(long, default_value_t = 0)]
+    seed: u32,
+
+    /// How to build the BVH: "sah" (surface area heuristic), "median" (split at the middle
+    /// object along the longest axis) or "lbvh" (sort along a Morton curve, built on the GPU
+    /// unless rendering with --cpu)
+    #[arg(long, value_parser = parse_bvh_builder, default_value = "sah")]
+    bvh: BvhBuilder,
+
+    /// Most objects a BVH leaf can hold before it has to be split
+    #[arg(long, default_value_t = DEFAULT_MAX_LEAF_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+    max_leaf_size: u32,
+
+    /// How rays walk the BVH: "stack" (nearest child first, using a stack per ray) or
+    /// "stackless" (follow links stored in the nodes). Press T in the viewer to switch
+    #[arg(long, value_parser = parse_bvh_traversal, default_value = "stack")]
+    traversal: BvhTraversal,
+
+    /// How many times as costly to trace the BVH can get from being refitted as objects are
+    /// dragged around in the viewer before it is rebuilt, 1 always rebuilds
+    #[arg(long, default_value_t = DEFAULT_REBUILD_THRESHOLD)]
+    rebuild_threshold: f32,
+
+    /// How the GPU traces paths: "megakernel" (each path start to finish in one shader) or
+    /// "wavefront" (a bounce of every path at a time, with separate kernels to generate, extend,
+    /// shade and connect paths)
+    #[arg(long, value_parser = parse_trace_mode, default_value = "megakernel")]
+    tracer: TraceMode,
+
+    /// Measure how long each stage of tracing takes on the GPU and log it once the render is done
+    /// (shown with RUST_LOG=info). Waits for every stage to finish, so rendering is slower
+    #[arg(long)]
+    timings: bool,
+
+    /// Trace at this fraction of the resolution and scale the image up to fill the window or
+    /// output, e.g. 0.5 for a quarter as many paths on slow machines
+    #[arg(long, default_value_t = 1., value_parser = parse_render_scale)]
+    render_scale: f32,
+}
+
+fn parse_vec3(s: &str) -> Result<Vec3> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("{e} in '{s}'"))?;
+    match values[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(anyhow!(
+            "Expected three comma separated values (x,y,z), got '{s}'"
+        )),
+    }
+}
+
+fn parse_bvh_builder(s: &str) -> Result<BvhBuilder> {
+    match s {
+        "sah" => Ok(BvhBuilder::Sah),
+        "median" => Ok(BvhBuilder::Median),
+        "lbvh" => Ok(BvhBuilder::Lbvh),
+        _ => Err(anyhow!(
+            "Expected \"sah\", \"median\" or \"lbvh\", got '{s}'"
+        )),
+    }
+}
+
+fn parse_bvh_traversal(s: &str) -> Result<BvhTraversal> {
+    match s {
+        "stack" => Ok(BvhTraversal::Stack),
+        "stackless" => Ok(BvhTraversal::Stackless),
+        _ => Err(anyhow!("Expected \"stack\" or \"stackless\", got '{s}'")),
+    }
+}
+
+fn parse_trace_mode(s: &str) -> Result<TraceMode> {
+    match s {
+        "megakernel" => Ok(TraceMode::Megakernel),
+        "wavefront" => Ok(TraceMode::Wavefront),
+        _ => Err(anyhow!(
+            "Expected \"megakernel\" or \"wavefront\", got '{s}'"
+        )),
+    }
+}
+
+fn parse_render_scale(s: &str) -> Result<f32> {
+    let scale: f32 = s.parse().map_err(|e| anyhow!("{e} in '{s}'"))?;
+    if !(scale > 0. && scale <= 1.) {
+        bail!("Expected a scale above 0 and at most 1, got '{s}'");
+    }
+    Ok(scale)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if args.width == 0 || args.height == 0 {
+        bail!("Resolution must be at least 1x1");
+    }
+
+    let config = RenderConfig {
+        width: args.width,
+        height: args.height,
+        samples: args.samples,
+        passes_per_frame: args.passes_per_frame,
+        max_path_length: args.max_path_length,
+        seed: args.seed,
+        scene: args.scene,
+        meshes: args.meshes,
+        camera: CameraOverrides {
+            position: args.position,
+            look_at: args.look_at,
+            vfov_deg: args.vfov,
+            aperture: args.aperture,
+            focal_distance: args.focal_distance,
+        },
+        bvh: BvhSettings {
+            builder: args.bvh,
+            max_leaf_size: args.max_leaf_size,
+            traversal: args.traversal,
+            rebuild_threshold: args.rebuild_threshold,
+        },
+        trace_mode: args.tracer,
+        timings: args.timings,
+        render_scale: args.render_scale,
+    };
+
+    if let Some(path) = &args.save_scene {
+        env_logger::init();
+        let (scene, camera) = create_scene(&config)?;
+        save_scene(path, &scene, Some(camera))?;
+        println!("Saved scene to {}", path.display());
+        return Ok(());
+    }
+
+    if args.headless {
+        env_logger::init();
+        if args.cpu {
+            render_to_png_cpu(&args.output, &config)?;
+        } else {
+            pollster::block_on(render_to_png(&args.output, &config))?;
+        }
+        log::info!("Saved render to {}", args.output.display());
+    } else {
+        pollster::block_on(run_with_config("", config))?;
+    }
+    Ok(())
 }
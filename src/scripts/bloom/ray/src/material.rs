@@ -1,19 +1,47 @@
-use crate::algebra::Vec3;
+use crate::{
+    algebra::Vec3,
+    pattern::{Pattern, PATTERN_SOLID},
+    texture::NO_TEXTURE,
+};
 
+/// A principled (Disney style) material: a base colour that is diffuse, metallic or seen through
+/// glass, a specular layer on top of the diffuse part, a clear coat over everything and sheen at
+/// grazing angles. The parameters mostly go from 0 to 1 like those of glTF and Blender
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Material {
-    albedo: Vec3,
-    alpha: f32,             // 0.0 = Transparent (Dielectric), 1.0 = Opaque
-    refraction_index: f32,  // Relative from air into material (glass is ~1.0/1.5)
-    smoothness: f32,        // 0.0 = Matte (Lambertian), 1.0 = Mirror (Specular)
-    emissivity: f32,        // 0.0 = No emission, 1.0 = every ray will add light to the scene
-    emission_strength: f32, // > 0
-    emitted_colour: Vec3,
-    _pad: [u32; 1],
+    pub(crate) albedo: Vec3,      // Base colour
+    pub(crate) transmission: f32, // 0.0 = Opaque, 1.0 = Glass (unless metallic)
+    pub(crate) ior: f32,          // Index of refraction, of the glass and the specular layer
+    pub(crate) roughness: f32,    // 0.0 = Mirror, 1.0 = Matte
+    pub(crate) metalness: f32,    // 0.0 = Dielectric, 1.0 = Metal tinted by the base colour
+    pub(crate) specular: f32,     // Scales the reflectance the IOR gives the specular layer
+    pub(crate) emitted_colour: Vec3,
+    pub(crate) emission_strength: f32, // 0.0 = No emission
+    pub(crate) sheen_colour: Vec3,     // Added to the diffuse part at grazing angles, like velvet
+    pub(crate) clearcoat: f32,         // Strength of a colourless glossy layer over everything
+    // What the albedo is blended with across the surface, see `Pattern`
+    pub(crate) pattern_colour: Vec3,
+    pub(crate) pattern_scale: f32,
+    // How much of each colour light loses per unit of distance it travels inside the material,
+    // which is how glass gets its tint (Beer-Lambert law). Only closed objects whose surfaces
+    // face outwards have an inside for it to work in, see `medium_at_hit` in trace.wgsl
+    pub(crate) absorption: Vec3,
+    pub(crate) clearcoat_roughness: f32,
+    // Indices of the scene's textures the albedo, roughness (green), metalness (blue) and emitted
+    // colour are multiplied by, NO_TEXTURE for none
+    pub(crate) albedo_texture: u32,
+    pub(crate) roughness_texture: u32,
+    pub(crate) metalness_texture: u32,
+    pub(crate) emission_texture: u32,
+    pub(crate) pattern: u32,
+    _pad: [u32; 3],
 }
 
 impl Material {
+    /// A material described the way they were before the principled model: `alpha` is how opaque
+    /// it is, `refraction_index` is relative from air into the material and `emissivity` is how
+    /// much of the emission strength is used. See `with_smoothness` for `smoothness`
     pub fn new(
         albedo: Vec3,
         smoothness: f32,
@@ -25,36 +53,151 @@ impl Material {
     ) -> Self {
         Self {
             albedo,
-            smoothness,
-            alpha,
-            refraction_index,
-            emissivity,
-            emission_strength,
+            transmission: 1. - alpha,
+            ior: 1. / refraction_index,
+            emission_strength: emissivity * emission_strength,
             emitted_colour,
-            _pad: [0; 1],
+            ..Default::default()
         }
+        .with_smoothness(smoothness)
     }
     #[allow(unused)]
     pub fn new_basic(albedo: Vec3, smoothness: f32) -> Self {
-        let mut material = Material::default();
-        material.albedo = albedo;
-        material.smoothness = smoothness;
-        material
+        Material {
+            albedo,
+            ..Default::default()
+        }
+        .with_smoothness(smoothness)
     }
     #[allow(unused)]
     pub fn new_clear(albedo: Vec3) -> Self {
-        let mut material = Material::default();
-        material.albedo = albedo;
-        material.alpha = 0.;
-        material
+        Material {
+            albedo,
+            transmission: 1.,
+            ..Default::default()
+        }
     }
     #[allow(unused)]
     pub fn new_emissive(emitted_colour: Vec3, emission_strength: f32) -> Self {
-        let mut material = Material::default();
-        material.emissivity = 1.;
-        material.emission_strength = emission_strength;
-        material.emitted_colour = emitted_colour;
-        material
+        Material {
+            emission_strength,
+            emitted_colour,
+            ..Default::default()
+        }
+    }
+
+    /// Smoothness used to blend each bounce between a diffuse and a mirror direction. It now says
+    /// how metallic the surface is, and how much less rough than matte that metal is. Glass
+    /// ignored it and stays smooth, and only the opaque part of a translucent material was
+    /// metallic, so that keeps letting the same light through
+    pub fn with_smoothness(self, smoothness: f32) -> Self {
+        let metalness = smoothness * (1. - self.transmission);
+        Self {
+            metalness,
+            transmission: if metalness < 1. {
+                self.transmission / (1. - metalness)
+            } else {
+                0.
+            },
+            roughness: if self.transmission > 0. {
+                0.
+            } else {
+                1. - smoothness
+            },
+            ..self
+        }
+    }
+    pub fn with_roughness(self, roughness: f32) -> Self {
+        Self { roughness, ..self }
+    }
+    pub fn with_metalness(self, metalness: f32) -> Self {
+        Self { metalness, ..self }
+    }
+    pub fn with_transmission(self, transmission: f32, ior: f32) -> Self {
+        Self {
+            transmission,
+            ior,
+            ..self
+        }
+    }
+    pub fn with_specular(self, specular: f32) -> Self {
+        Self { specular, ..self }
+    }
+    pub fn with_absorption(self, absorption: Vec3) -> Self {
+        Self { absorption, ..self }
+    }
+    pub fn with_clearcoat(self, clearcoat: f32, clearcoat_roughness: f32) -> Self {
+        Self {
+            clearcoat,
+            clearcoat_roughness,
+            ..self
+        }
+    }
+    pub fn with_emission(self, emitted_colour: Vec3, emission_strength: f32) -> Self {
+        Self {
+            emitted_colour,
+            emission_strength,
+            ..self
+        }
+    }
+    pub fn with_sheen(self, sheen_colour: Vec3) -> Self {
+        Self {
+            sheen_colour,
+            ..self
+        }
+    }
+
+    pub fn with_albedo_texture(self, texture: u32) -> Self {
+        Self {
+            albedo_texture: texture,
+            ..self
+        }
+    }
+    pub fn with_roughness_texture(self, texture: u32) -> Self {
+        Self {
+            roughness_texture: texture,
+            ..self
+        }
+    }
+    pub fn with_metalness_texture(self, texture: u32) -> Self {
+        Self {
+            metalness_texture: texture,
+            ..self
+        }
+    }
+    pub fn with_emission_texture(self, texture: u32) -> Self {
+        Self {
+            emission_texture: texture,
+            ..self
+        }
+    }
+
+    pub fn with_pattern(self, pattern: Pattern) -> Self {
+        let (pattern, pattern_scale, pattern_colour) = pattern.to_gpu();
+        Self {
+            pattern,
+            pattern_scale,
+            pattern_colour,
+            ..self
+        }
+    }
+    pub fn pattern(&self) -> Pattern {
+        Pattern::from_gpu(self.pattern, self.pattern_scale, self.pattern_colour)
+    }
+
+    // Point the material at other textures, e.g. when it moves to another scene
+    pub(crate) fn map_textures(self, map: impl Fn(u32) -> u32) -> Self {
+        let map = |texture| match texture {
+            NO_TEXTURE => NO_TEXTURE,
+            texture => map(texture),
+        };
+        Self {
+            albedo_texture: map(self.albedo_texture),
+            roughness_texture: map(self.roughness_texture),
+            metalness_texture: map(self.metalness_texture),
+            emission_texture: map(self.emission_texture),
+            ..self
+        }
     }
 
     // pub fn new_random() -> Self {
@@ -83,13 +226,26 @@ impl Default for Material {
     fn default() -> Self {
         Self {
             albedo: Vec3::new(1.0, 1.0, 1.0),
-            smoothness: 0.0,
-            alpha: 1.0,
-            refraction_index: 1. / 1.5,
-            emissivity: 0.0,
-            emission_strength: 0.0,
+            transmission: 0.0,
+            ior: 1.5,
+            roughness: 0.0,
+            metalness: 0.0,
+            // No specular layer, so materials that don't ask for one are plain Lambertian
+            specular: 0.0,
             emitted_colour: Vec3::new(1., 1., 1.),
-            _pad: [0; 1],
+            emission_strength: 0.0,
+            sheen_colour: Vec3::zero(),
+            clearcoat: 0.0,
+            pattern_colour: Vec3::zero(),
+            pattern_scale: 1.,
+            absorption: Vec3::zero(),
+            clearcoat_roughness: 0.0,
+            albedo_texture: NO_TEXTURE,
+            roughness_texture: NO_TEXTURE,
+            metalness_texture: NO_TEXTURE,
+            emission_texture: NO_TEXTURE,
+            pattern: PATTERN_SOLID,
+            _pad: [0; 3],
         }
     }
 }
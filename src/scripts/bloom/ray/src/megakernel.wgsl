@@ -0,0 +1,56 @@
+// ----------------------- Megakernel -----------------------
+// Follow each path from the camera to its end in one invocation
+
+// Pixels traced by each workgroup along x and y, has to match WORKGROUP_SIZE in gpu_tracer.rs
+const WORKGROUP_SIZE: u32 = 8u;
+
+// Trace one path through every pixel and add it to the running sum of samples. The alpha channel
+// counts the samples so the display pass can average them
+@compute @workgroup_size(WORKGROUP_SIZE, WORKGROUP_SIZE)
+fn cs_main(@builtin(global_invocation_id) id: vec3u) {
+    if id.x >= uniforms.width || id.y >= uniforms.height {
+        return;
+    }
+    // Sample positions are relative to the centre of the pixel, like fragment positions
+    let pos = vec2f(id.xy) + 0.5;
+
+    // Seed the Random Number Generator
+    init_rng(id.xy, uniforms.width, uniforms.frame_num, uniforms.seed);
+
+    var ray = camera_ray(pos);
+    var throughput = vec3f(1.);
+    var radiance_sample = vec3(0.);
+    // The camera is always out in the air
+    var medium = vec3(0.);
+
+    // Propagate the ray into the spheres and get the final colours
+    var path_length = 0u;
+    while path_length < uniforms.max_path_length {
+        let hit = intersect_scene(ray);
+        if !is_intersection(hit) {
+            // If not intersection was found, return the colour of the sky and terminate the path
+            radiance_sample += throughput * sky_colour(ray);
+            break;
+        }
+        throughput *= transmittance(medium, ray, hit);
+        let material = surface_material(ray, hit);
+        radiance_sample += throughput * material.emission_colour * material.emission_strength;
+
+        let scattered = scatter(ray, hit, material, medium);
+        throughput *= scattered.attenuation;
+        ray = scattered.ray;
+        medium = scattered.medium;
+        path_length += 1u;
+    }
+
+    // Fetch the old sum of samples
+    var old_sum: vec4f;
+    if uniforms.frame_num > 1 {
+        old_sum = textureLoad(radiance_samples_old, id.xy, 0);
+    } else {
+        old_sum = vec4(0.);
+    }
+
+    // Compute and store the new sum
+    textureStore(radiance_samples_new, id.xy, old_sum + vec4(radiance_sample, 1.));
+}
@@ -0,0 +1,122 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    config::CameraSettings,
+    primitives::{Scene, Triangle},
+};
+
+/// File formats that can be added to a scene as triangles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshFormat {
+    Obj,
+    Gltf,
+}
+
+impl MeshFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "obj" => Some(Self::Obj),
+            "gltf" | "glb" => Some(Self::Gltf),
+            _ => None,
+        }
+    }
+}
+
+impl Scene {
+    /// Add the triangles of an OBJ or glTF file to the scene, returning the first camera it
+    /// contains (only glTF files have cameras)
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_mesh(&mut self, path: &Path) -> Result<Option<CameraSettings>> {
+        match MeshFormat::from_path(path) {
+            Some(MeshFormat::Obj) => self.load_obj(path).map(|_| None),
+            Some(MeshFormat::Gltf) => self.load_gltf(path),
+            None => bail!(
+                "Can't tell the format of {}, meshes must end in .obj, .gltf or .glb",
+                path.display()
+            ),
+        }
+    }
+
+    /// Add the triangles of an OBJ or glTF file to the scene, returning the first camera it
+    /// contains (only glTF files have cameras)
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load_mesh(&mut self, url: &str) -> Result<Option<CameraSettings>> {
+        match MeshFormat::from_path(Path::new(url)) {
+            Some(MeshFormat::Obj) => self.load_obj(url).await.map(|_| None),
+            Some(MeshFormat::Gltf) => self.load_gltf(url).await,
+            None => bail!("Can't tell the format of {url}, meshes must end in .obj, .gltf or .glb"),
+        }
+    }
+
+    /// Load an OBJ or glTF file as a mesh that can be placed any number of times with
+    /// `add_instance`, returning its index
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_instanced_mesh(&mut self, name: &str, path: &Path) -> Result<u32> {
+        let mut mesh = Scene::new();
+        mesh.load_mesh(path)?;
+        self.add_mesh_from(name, &mesh, path.to_string_lossy().into_owned())
+    }
+
+    /// Load an OBJ or glTF file as a mesh that can be placed any number of times with
+    /// `add_instance`, returning its index
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load_instanced_mesh(&mut self, name: &str, url: &str) -> Result<u32> {
+        let mut mesh = Scene::new();
+        mesh.load_mesh(url).await?;
+        self.add_mesh_from(name, &mesh, url.to_string())
+    }
+
+    // Move the triangles of a scene that only holds one mesh into this one, bringing their
+    // materials and textures along
+    fn add_mesh_from(&mut self, name: &str, mesh: &Scene, source: String) -> Result<u32> {
+        let textures: Vec<u32> = mesh
+            .get_textures()
+            .iter()
+            .map(|texture| self.find_or_add_texture(texture.clone()))
+            .collect();
+        // Every scene starts with the same default material, the mesh's others are its own
+        let materials: Vec<u32> = (0..mesh.material_count() as u32)
+            .map(|i| {
+                if i == 0 {
+                    return 0;
+                }
+                let material = mesh.get_material_arr()[i as usize]
+                    .map_textures(|texture| textures[texture as usize]);
+                self.add_imported_material(mesh.get_material_name(i), name, material)
+            })
+            .collect();
+        let triangles = mesh
+            .get_triangle_arr()
+            .iter()
+            .map(|t| Triangle::new(t.a, t.b, t.c, materials[t.material as usize]).with_uvs(t.uvs()))
+            .collect();
+        self.add_mesh(name, triangles, Some(source))
+    }
+}
+
+// What to tell apart the materials of a file by when their names clash with ones already in the
+// scene, the file name without its directory or extension
+pub(crate) fn file_stem(source: &str) -> &str {
+    Path::new(source)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(source)
+}
+
+/// Download a file that lives next to the page
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn fetch(url: &str) -> Result<Vec<u8>> {
+    use anyhow::Context;
+
+    let bytes = reqwest::get(url)
+        .await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("Failed to fetch {url}"))?
+        .bytes()
+        .await
+        .with_context(|| format!("Failed to read {url}"))?;
+    Ok(bytes.to_vec())
+}
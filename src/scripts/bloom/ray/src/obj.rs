@@ -0,0 +1,280 @@
+use anyhow::Result;
+
+#[cfg(target_arch = "wasm32")]
+use crate::mesh::fetch;
+use crate::{
+    algebra::Vec3,
+    material::Material,
+    mesh::file_stem,
+    primitives::{Scene, Triangle},
+    texture::{Texture, NO_TEXTURE},
+};
+
+// Triangulate every face and share one index between positions, normals and texture coordinates
+const LOAD_OPTIONS: tobj::LoadOptions = tobj::GPU_LOAD_OPTIONS;
+
+impl Scene {
+    /// Add every face of a Wavefront OBJ file (and the materials from its MTL files, along with
+    /// the images they use) to the scene.
+    ///
+    /// The BVH has to be rebuilt afterwards for the new triangles to be visible.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_obj(&mut self, path: &std::path::Path) -> Result<()> {
+        use anyhow::Context;
+
+        let (models, materials) = tobj::load_obj(path, &LOAD_OPTIONS)
+            .with_context(|| format!("Failed to load OBJ file {}", path.display()))?;
+        let materials = materials.unwrap_or_else(|e| {
+            log::warn!("Failed to load the materials of {}: {e}", path.display());
+            Vec::new()
+        });
+
+        // Images are relative to the OBJ file, like its MTL files
+        let directory = path.parent().unwrap_or(std::path::Path::new(""));
+        let mut textures = Vec::new();
+        for image in texture_paths(&materials) {
+            let image_path = directory.join(image);
+            match Texture::load(&image_path.to_string_lossy(), &image_path) {
+                Ok(texture) => textures.push((image.to_string(), texture)),
+                Err(e) => log::warn!("{e:#}"),
+            }
+        }
+
+        self.add_obj(&models, &materials, textures, &path.to_string_lossy())
+            .with_context(|| format!("Failed to add {} to the scene", path.display()))
+    }
+
+    /// Add every face of a Wavefront OBJ file (and the materials from its MTL files) to the scene.
+    ///
+    /// MTL files are fetched relative to the OBJ's URL. The BVH has to be rebuilt afterwards for
+    /// the new triangles to be visible.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn load_obj(&mut self, url: &str) -> Result<()> {
+        use anyhow::Context;
+
+        let obj = fetch(url).await?;
+        let base_url = match url.rfind('/') {
+            Some(i) => &url[..=i],
+            None => "",
+        };
+        let (models, materials) =
+            tobj::load_obj_buf_async(&mut obj.as_slice(), &LOAD_OPTIONS, |mtl_path| async move {
+                let mtl = fetch(&format!("{base_url}{mtl_path}")).await.map_err(|e| {
+                    log::warn!("{e:#}");
+                    tobj::LoadError::OpenFileFailed
+                })?;
+                tobj::load_mtl_buf(&mut mtl.as_slice())
+            })
+            .await
+            .with_context(|| format!("Failed to load OBJ file {url}"))?;
+        let materials = materials.unwrap_or_else(|e| {
+            log::warn!("Failed to load the materials of {url}: {e}");
+            Vec::new()
+        });
+
+        let mut textures = Vec::new();
+        for image in texture_paths(&materials) {
+            let image_url = format!("{base_url}{image}");
+            let texture = fetch(&image_url)
+                .await
+                .and_then(|bytes| Texture::from_bytes(&image_url, &bytes, Some(image_url.clone())));
+            match texture {
+                Ok(texture) => textures.push((image.to_string(), texture)),
+                Err(e) => log::warn!("{e:#}"),
+            }
+        }
+
+        self.add_obj(&models, &materials, textures, url)
+            .with_context(|| format!("Failed to add {url} to the scene"))
+    }
+
+    // `textures` are the images the materials use that could be loaded, by their path in the MTL
+    // file. `source` is the path or URL of the OBJ file
+    fn add_obj(
+        &mut self,
+        models: &[tobj::Model],
+        materials: &[tobj::Material],
+        textures: Vec<(String, Texture)>,
+        source: &str,
+    ) -> Result<()> {
+        self.reserve_triangles(models.iter().map(|m| m.mesh.indices.len() / 3).sum());
+
+        let textures: Vec<(String, u32)> = textures
+            .into_iter()
+            .map(|(image, texture)| (image, self.find_or_add_texture(texture)))
+            .collect();
+        let texture_index = |image: Option<&str>| {
+            textures
+                .iter()
+                .find(|(path, _)| Some(path.as_str()) == image)
+                .map_or(NO_TEXTURE, |(_, index)| *index)
+        };
+        let material_indices: Vec<u32> = materials
+            .iter()
+            .map(|m| {
+                let [albedo, roughness, metalness, emission] = texture_maps(m).map(texture_index);
+                let material = convert_material(m)
+                    .with_albedo_texture(albedo)
+                    .with_roughness_texture(roughness)
+                    .with_metalness_texture(metalness)
+                    .with_emission_texture(emission);
+                self.add_imported_material(&m.name, file_stem(source), material)
+            })
+            .collect();
+
+        for model in models {
+            let mesh = &model.mesh;
+            let material = mesh
+                .material_id
+                .and_then(|id| material_indices.get(id).copied())
+                .unwrap_or(0);
+            let vertex = |i: u32| {
+                let i = 3 * i as usize;
+                Vec3::new(
+                    mesh.positions[i],
+                    mesh.positions[i + 1],
+                    mesh.positions[i + 2],
+                )
+            };
+            // OBJ texture coordinates go up from the bottom of the image, ours go down from the top
+            let uv = |i: u32| {
+                let i = 2 * i as usize;
+                [mesh.texcoords[i], 1. - mesh.texcoords[i + 1]]
+            };
+            for face in mesh.indices.chunks_exact(3) {
+                let mut triangle =
+                    Triangle::new(vertex(face[0]), vertex(face[1]), vertex(face[2]), material);
+                if !mesh.texcoords.is_empty() {
+                    triangle = triangle.with_uvs([uv(face[0]), uv(face[1]), uv(face[2])]);
+                }
+                self.add_triangle(triangle);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Map an MTL material onto our own. Materials with the PBR extension's roughness or metalness
+// map onto it directly, older Phong style ones as well as we can
+fn convert_material(m: &tobj::Material) -> Material {
+    // tobj doesn't know about Ke or the PBR extension so they end up with the unknown parameters
+    let param = |key: &str| {
+        m.unknown_param
+            .get(key)
+            .and_then(|v| v.trim().parse::<f32>().ok())
+    };
+    // d = 1 is fully opaque, anything less becomes glass
+    let transmission = 1. - m.dissolve.clamp(0., 1.);
+    // Ni is the index of refraction of the material
+    let ior = if m.optical_density > 0. {
+        m.optical_density
+    } else {
+        Material::default().ior
+    };
+    let emissive = m
+        .unknown_param
+        .get("Ke")
+        .and_then(|ke| parse_colour(ke))
+        .unwrap_or([0.; 3]);
+    let emission_strength = max_component(emissive);
+    let emitted_colour = if emission_strength > 0. {
+        Vec3::from(emissive) / emission_strength
+    } else {
+        Vec3::new(1., 1., 1.)
+    };
+    let material = Material::new_basic(m.diffuse.into(), 0.)
+        .with_transmission(transmission, ior)
+        .with_emission(emitted_colour, emission_strength)
+        .with_clearcoat(param("Pc").unwrap_or(0.), param("Pcr").unwrap_or(0.))
+        .with_sheen(Vec3::all(param("Ps").unwrap_or(0.)));
+
+    match (param("Pr"), param("Pm")) {
+        (None, None) => {
+            // Ks says how strong the highlights are and Ns (0 to 1000) how tight they are, a
+            // mirror needs both
+            let shininess = (m.shininess / 1000.).clamp(0., 1.).sqrt();
+            material.with_smoothness(max_component(m.specular) * shininess)
+        }
+        (roughness, metalness) => material
+            .with_roughness(roughness.unwrap_or(1.))
+            .with_metalness(metalness.unwrap_or(0.))
+            .with_specular(1.),
+    }
+}
+
+// Images a material takes its albedo, roughness, metalness and emitted colour from. map_Pr and
+// map_Pm are from the PBR extension to MTL and tobj only knows about map_Kd, so the others end up
+// as unknown parameters
+fn texture_maps(m: &tobj::Material) -> [Option<&str>; 4] {
+    // Options like "-bm 0.5" come before the file name
+    fn file_name(value: &str) -> Option<&str> {
+        value.split_whitespace().last()
+    }
+    [
+        file_name(&m.diffuse_texture),
+        m.unknown_param.get("map_Pr").and_then(|v| file_name(v)),
+        m.unknown_param.get("map_Pm").and_then(|v| file_name(v)),
+        m.unknown_param.get("map_Ke").and_then(|v| file_name(v)),
+    ]
+}
+
+// Every image the materials use, once each
+fn texture_paths(materials: &[tobj::Material]) -> Vec<&str> {
+    let mut paths: Vec<&str> = Vec::new();
+    for path in materials.iter().flat_map(texture_maps).flatten() {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+fn max_component(c: [f32; 3]) -> f32 {
+    c[0].max(c[1]).max(c[2])
+}
+
+fn parse_colour(s: &str) -> Option<[f32; 3]> {
+    let mut values = s.split_whitespace().map(|v| v.parse::<f32>());
+    let r = values.next()?.ok()?;
+    // A single value is used for all three channels
+    let g = values.next().map_or(Ok(r), |v| v).ok()?;
+    let b = values.next().map_or(Ok(r), |v| v).ok()?;
+    Some([r, g, b])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{material::Material, primitives::Scene};
+
+    // Materials of another file or the scene mustn't take the place of ones with the same name
+    #[test]
+    fn materials_are_not_shared_between_files() {
+        let mut scene = Scene::new();
+        let gold = scene.add_named_material("gold", Material::default());
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/pyramid.obj");
+        scene.load_obj(&path).unwrap();
+        scene.load_obj(&path).unwrap();
+
+        let names: Vec<_> = (0..scene.material_count() as u32)
+            .map(|i| scene.get_material_name(i))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "default",
+                "gold",
+                "pyramid_gold",
+                "glow",
+                "pyramid_gold_2",
+                "pyramid_glow"
+            ]
+        );
+        let triangles = scene.get_triangle_arr();
+        assert!(triangles.iter().all(|t| t.material != gold));
+        assert!(triangles[triangles.len() / 2..]
+            .iter()
+            .all(|t| t.material >= 4));
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+use crate::algebra::Vec3;
+
+// How `Material::pattern` is stored for the shaders, see `pattern_albedo` in trace.wgsl and
+// cpu.rs
+pub(crate) const PATTERN_SOLID: u32 = 0;
+pub(crate) const PATTERN_CHECKER_WORLD: u32 = 1;
+pub(crate) const PATTERN_CHECKER_UV: u32 = 2;
+pub(crate) const PATTERN_GRADIENT: u32 = 3;
+pub(crate) const PATTERN_NOISE: u32 = 4;
+pub(crate) const PATTERN_MARBLE: u32 = 5;
+
+/// Where the albedo of a material comes from. Patterns blend between the material's albedo and
+/// a second colour, and any albedo texture is applied on top of them
+///
+/// `scale` is how often a pattern repeats, per unit of distance in the world or across the
+/// surface in texture coordinates
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Pattern {
+    /// The albedo on its own
+    #[default]
+    Solid,
+    /// Squares (or cubes in world space) alternating between the albedo and `colour`
+    Checker {
+        space: PatternSpace,
+        scale: f32,
+        colour: Vec3,
+    },
+    /// From the albedo where u is 0 to `colour` where it is 1
+    Gradient { colour: Vec3 },
+    /// Perlin turbulence through the world, `colour` where it is strongest
+    Noise { scale: f32, colour: Vec3 },
+    /// Veins of `colour` along z, bent by turbulence
+    Marble { scale: f32, colour: Vec3 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatternSpace {
+    World,
+    Uv, // The texture coordinates of the surface
+}
+
+impl Pattern {
+    pub fn is_solid(&self) -> bool {
+        matches!(self, Self::Solid)
+    }
+
+    // The pattern's fields of a material
+    pub(crate) fn to_gpu(self) -> (u32, f32, Vec3) {
+        match self {
+            Self::Solid => (PATTERN_SOLID, 1., Vec3::zero()),
+            Self::Checker {
+                space: PatternSpace::World,
+                scale,
+                colour,
+            } => (PATTERN_CHECKER_WORLD, scale, colour),
+            Self::Checker {
+                space: PatternSpace::Uv,
+                scale,
+                colour,
+            } => (PATTERN_CHECKER_UV, scale, colour),
+            Self::Gradient { colour } => (PATTERN_GRADIENT, 1., colour),
+            Self::Noise { scale, colour } => (PATTERN_NOISE, scale, colour),
+            Self::Marble { scale, colour } => (PATTERN_MARBLE, scale, colour),
+        }
+    }
+
+    pub(crate) fn from_gpu(pattern: u32, scale: f32, colour: Vec3) -> Self {
+        match pattern {
+            PATTERN_CHECKER_WORLD => Self::Checker {
+                space: PatternSpace::World,
+                scale,
+                colour,
+            },
+            PATTERN_CHECKER_UV => Self::Checker {
+                space: PatternSpace::Uv,
+                scale,
+                colour,
+            },
+            PATTERN_GRADIENT => Self::Gradient { colour },
+            PATTERN_NOISE => Self::Noise { scale, colour },
+            PATTERN_MARBLE => Self::Marble { scale, colour },
+            _ => Self::Solid,
+        }
+    }
+}
@@ -1,18 +1,37 @@
-use bytemuck::Zeroable;
+use anyhow::{bail, Result};
+use cgmath::{Deg, Matrix4, SquareMatrix, Vector4};
+use serde::{Deserialize, Serialize};
 
 #[cfg(not(target_arch = "wasm32"))]
 use rand::rngs::ThreadRng;
 
 use crate::{
-    algebra::Vec3, Material, MAX_MATERIAL_COUNT, MAX_QUAD_COUNT, MAX_SPHERE_COUNT,
-    MAX_TRIANGLE_COUNT,
+    algebra::Vec3,
+    bvh::{create_mesh_bvh, BVH},
+    texture::Texture,
+    Material,
 };
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ObjectType {
     Sphere = 0,
     Quad = 1,
     Triangle = 2,
+    Instance = 3,
+}
+
+impl TryFrom<u32> for ObjectType {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Sphere),
+            1 => Ok(Self::Quad),
+            2 => Ok(Self::Triangle),
+            3 => Ok(Self::Instance),
+            _ => Err(value),
+        }
+    }
 }
 
 pub trait Extrema {
@@ -30,7 +49,7 @@ pub struct Object {
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
-    material: u32,
+    pub(crate) material: u32,
     pub is_selected: u32,
     _pad: [u32; 2],
 }
@@ -73,16 +92,16 @@ impl Extrema for Sphere {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Quad {
-    q: Vec3,
-    is_selected: u32,
-    u: Vec3,
+    pub(crate) q: Vec3,
+    pub(crate) is_selected: u32,
+    pub(crate) u: Vec3,
     _pad0: u32,
-    v: Vec3,
+    pub(crate) v: Vec3,
     _pad1: u32,
-    normal: Vec3,
-    d: f32,
-    w: Vec3,
-    material: u32,
+    pub(crate) normal: Vec3,
+    pub(crate) d: f32,
+    pub(crate) w: Vec3,
+    pub(crate) material: u32,
 }
 
 impl Quad {
@@ -149,16 +168,26 @@ impl Extrema for Quad {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Triangle {
-    a: Vec3,
-    is_selected: u32,
-    b: Vec3,
-    material: u32,
-    c: Vec3,
+    pub(crate) a: Vec3,
+    pub(crate) is_selected: u32,
+    pub(crate) b: Vec3,
+    pub(crate) material: u32,
+    pub(crate) c: Vec3,
     _pad0: u32,
+    // Texture coordinates of a, b and c
+    pub(crate) uv_a: [f32; 2],
+    pub(crate) uv_b: [f32; 2],
+    pub(crate) uv_c: [f32; 2],
+    _pad1: [u32; 2],
 }
 
+/// Texture coordinates of triangles that weren't given any, the barycentric coordinates of the
+/// hit on b and c
+pub(crate) const DEFAULT_TRIANGLE_UVS: [[f32; 2]; 3] = [[0., 0.], [1., 0.], [0., 1.]];
+
 impl Triangle {
     pub fn new(a: Vec3, b: Vec3, c: Vec3, material: u32) -> Self {
+        let [uv_a, uv_b, uv_c] = DEFAULT_TRIANGLE_UVS;
         Self {
             a,
             b,
@@ -167,8 +196,25 @@ impl Triangle {
             material,
             // normal: (b - a).cross(&(c - a)).normalized(),
             _pad0: 0,
+            uv_a,
+            uv_b,
+            uv_c,
+            _pad1: [0; 2],
+        }
+    }
+
+    pub fn with_uvs(self, [uv_a, uv_b, uv_c]: [[f32; 2]; 3]) -> Self {
+        Self {
+            uv_a,
+            uv_b,
+            uv_c,
+            ..self
         }
     }
+
+    pub fn uvs(&self) -> [[f32; 2]; 3] {
+        [self.uv_a, self.uv_b, self.uv_c]
+    }
 }
 
 impl Default for Triangle {
@@ -195,178 +241,454 @@ impl Extrema for Triangle {
     }
 }
 
+/// Where a mesh instance is placed. The mesh is scaled first, then rotated around the x, y and z
+/// axes in that order, then moved
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation_deg: Vec3,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::zero(),
+            rotation_deg: Vec3::zero(),
+            scale: Vec3::all(1.),
+        }
+    }
+}
+
+impl Transform {
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self {
+            translation,
+            ..Default::default()
+        }
+    }
+
+    /// Object to world space matrix
+    pub fn matrix(&self) -> Matrix4<f32> {
+        let r = self.rotation_deg;
+        Matrix4::from_translation(self.translation.into())
+            * Matrix4::from_angle_z(Deg(r.z()))
+            * Matrix4::from_angle_y(Deg(r.y()))
+            * Matrix4::from_angle_x(Deg(r.x()))
+            * Matrix4::from_nonuniform_scale(self.scale.x(), self.scale.y(), self.scale.z())
+    }
+}
+
+/// Triangles that are only drawn where instances place them, with a BVH of their own that is
+/// built once when the mesh is added
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub name: String,
+    pub source: Option<String>, // File (or URL) the mesh was loaded from, used when saving scenes
+    triangle_count: usize,
+    root_node: u32, // Index of the root of the mesh's BVH in the scene's mesh BVH nodes
+    depth: u32,
+    bounds: (Vec3, Vec3),
+}
+
+impl Mesh {
+    pub fn triangle_count(&self) -> usize {
+        self.triangle_count
+    }
+}
+
+/// A copy of a mesh placed in the scene, it shares the mesh's triangles and BVH with every other
+/// instance of it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Instance {
+    object_to_world: [[f32; 4]; 4],
+    world_to_object: [[f32; 4]; 4], // Rays are moved into the mesh's space to be traced
+    mesh: u32,
+    pub(crate) root_node: u32, // Root of the mesh's BVH, relative to the first mesh BVH node
+    pub(crate) material: u32,  // Replaces the materials of the mesh, u32::MAX keeps them
+    pub(crate) is_selected: u32,
+}
+
+impl Instance {
+    fn new(mesh_index: u32, mesh: &Mesh, transform: &Transform, material: Option<u32>) -> Self {
+        let object_to_world = transform.matrix();
+        Self {
+            object_to_world: object_to_world.into(),
+            // Scaling by 0 would flatten the mesh so there is no going back, such instances
+            // can't be hit anyway
+            world_to_object: object_to_world
+                .invert()
+                .unwrap_or(Matrix4::from_scale(0.))
+                .into(),
+            mesh: mesh_index,
+            root_node: mesh.root_node,
+            material: material.unwrap_or(u32::MAX),
+            is_selected: 0,
+        }
+    }
+
+    pub fn mesh(&self) -> u32 {
+        self.mesh
+    }
+
+    pub fn material(&self) -> Option<u32> {
+        (self.material != u32::MAX).then_some(self.material)
+    }
+
+    pub(crate) fn object_to_world(&self) -> Matrix4<f32> {
+        self.object_to_world.into()
+    }
+
+    pub(crate) fn world_to_object(&self) -> Matrix4<f32> {
+        self.world_to_object.into()
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sky {
+    pub zenith: Vec3, // Colour when looking straight up
+    #[serde(skip)]
+    _pad0: u32,
+    pub horizon: Vec3, // Colour at the horizon and below
+    #[serde(skip)]
+    _pad1: u32,
+}
+
+impl Sky {
+    pub fn new(zenith: Vec3, horizon: Vec3) -> Self {
+        Self {
+            zenith,
+            horizon,
+            _pad0: 0,
+            _pad1: 0,
+        }
+    }
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        // Vertical linear gradient from white to light blue
+        Self::new(Vec3::new(0.3, 0.5, 1.), Vec3::new(1., 1., 1.))
+    }
+}
+
+pub const DEFAULT_MATERIAL_NAME: &str = "default";
+
 pub struct Scene {
     pub scene_vec: Vec<Object>,
-    mat_arr: [Material; MAX_MATERIAL_COUNT],
-    sphere_arr: [Sphere; MAX_SPHERE_COUNT],
-    quad_arr: [Quad; MAX_QUAD_COUNT],
-    triangle_arr: [Triangle; MAX_TRIANGLE_COUNT],
-    last_material_index: usize,
-    last_sphere_index: usize,
-    last_quad_index: usize,
-    last_triangle_index: usize,
+    pub sky: Sky,
+    mat_arr: Vec<Material>,
+    mat_names: Vec<String>,
+    textures: Vec<Texture>,
+    sphere_arr: Vec<Sphere>,
+    quad_arr: Vec<Quad>,
+    triangle_arr: Vec<Triangle>, // Loose triangles as well as the triangles of every mesh
+    meshes: Vec<Mesh>,
+    mesh_bvh: BVH, // The BVHs of all the meshes one after the other
+    instance_arr: Vec<Instance>,
+    instance_transforms: Vec<Transform>,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scene {
     pub fn new() -> Self {
         Self {
             scene_vec: Vec::new(),
-            mat_arr: [Material::default(); MAX_MATERIAL_COUNT],
-            sphere_arr: [Sphere::zeroed(); MAX_SPHERE_COUNT],
-            quad_arr: [Quad::zeroed(); MAX_QUAD_COUNT],
-            triangle_arr: [Triangle::zeroed(); MAX_TRIANGLE_COUNT],
-            last_material_index: 0,
-            last_sphere_index: 0,
-            last_quad_index: 0,
-            last_triangle_index: 0,
+            sky: Sky::default(),
+            mat_arr: vec![Material::default()], // 0 is always available as the default
+            mat_names: vec![DEFAULT_MATERIAL_NAME.to_string()],
+            textures: Vec::new(),
+            sphere_arr: Vec::new(),
+            quad_arr: Vec::new(),
+            triangle_arr: Vec::new(),
+            meshes: Vec::new(),
+            mesh_bvh: BVH::default(),
+            instance_arr: Vec::new(),
+            instance_transforms: Vec::new(),
         }
     }
+    /// Add a material with a generated name, skipping any names already taken so it can't be
+    /// confused with a named material
     pub fn add_material(&mut self, mat: Material) -> u32 {
-        self.last_material_index += 1; // Makes sure 0 is always available as the default
-        if self.last_material_index >= MAX_MATERIAL_COUNT {
-            log::warn!("Trying to add too many materials");
-            return 0;
-        }
-        self.mat_arr[self.last_material_index] = mat;
-        self.last_material_index as u32
+        let name = (self.mat_arr.len()..)
+            .map(|n| format!("material_{n}"))
+            .find(|name| self.find_material(name).is_none())
+            .unwrap();
+        self.add_named_material(&name, mat)
+    }
+    pub fn add_named_material(&mut self, name: &str, mat: Material) -> u32 {
+        self.mat_arr.push(mat);
+        self.mat_names.push(name.to_string());
+        (self.mat_arr.len() - 1) as u32
+    }
+    /// Add a material loaded from a file. Importers share materials between the parts of one
+    /// file themselves, but never with materials already in the scene, which may only have the
+    /// same name by chance. When the name is taken `source` (the file's name) is put in front of
+    /// it, and a number after that if needed
+    pub(crate) fn add_imported_material(&mut self, name: &str, source: &str, mat: Material) -> u32 {
+        let prefixed = format!("{source}_{name}");
+        let name = [name.to_string(), prefixed.clone()]
+            .into_iter()
+            .chain((2..).map(|n| format!("{prefixed}_{n}")))
+            .find(|name| self.find_material(name).is_none())
+            .unwrap();
+        self.add_named_material(&name, mat)
+    }
+    pub fn find_material(&self, name: &str) -> Option<u32> {
+        self.mat_names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| i as u32)
+    }
+    pub fn get_material_name(&self, index: u32) -> &str {
+        &self.mat_names[index as usize]
+    }
+    pub fn material_count(&self) -> usize {
+        self.mat_names.len()
+    }
+    /// Add an image for materials to use, returning the index to refer to it with
+    pub fn add_texture(&mut self, texture: Texture) -> u32 {
+        self.textures.push(texture);
+        (self.textures.len() - 1) as u32
+    }
+    /// Reuse the texture with the same name if there is one. Images are named after the file
+    /// they were loaded from, so this only shares an image that is loaded more than once
+    pub(crate) fn find_or_add_texture(&mut self, texture: Texture) -> u32 {
+        self.find_texture(&texture.name)
+            .unwrap_or_else(|| self.add_texture(texture))
+    }
+    pub fn find_texture(&self, name: &str) -> Option<u32> {
+        self.textures
+            .iter()
+            .position(|t| t.name == name)
+            .map(|i| i as u32)
+    }
+    pub fn get_textures(&self) -> &[Texture] {
+        &self.textures
+    }
+    pub fn sphere_count(&self) -> usize {
+        self.sphere_arr.len()
+    }
+    pub fn quad_count(&self) -> usize {
+        self.quad_arr.len()
+    }
+    pub fn triangle_count(&self) -> usize {
+        self.triangle_arr.len()
     }
     pub fn add_sphere(&mut self, sphere: Sphere) {
-        if self.last_sphere_index >= MAX_SPHERE_COUNT {
-            log::warn!("Trying to add too many spheres");
-            return;
-        }
-        self.sphere_arr[self.last_sphere_index] = sphere;
         self.scene_vec.push(Object {
             object_type: ObjectType::Sphere,
-            index: self.last_sphere_index,
+            index: self.sphere_arr.len(),
         });
-        self.last_sphere_index += 1;
+        self.sphere_arr.push(sphere);
     }
     pub fn add_quad(&mut self, quad: Quad) {
-        if self.last_quad_index >= MAX_QUAD_COUNT {
-            log::warn!("Trying to add too many quads");
-            return;
-        }
-        self.quad_arr[self.last_quad_index] = quad;
         self.scene_vec.push(Object {
             object_type: ObjectType::Quad,
-            index: self.last_quad_index,
+            index: self.quad_arr.len(),
         });
-        self.last_quad_index += 1;
+        self.quad_arr.push(quad);
     }
     pub fn add_triangle(&mut self, triangle: Triangle) {
-        if self.last_quad_index >= MAX_QUAD_COUNT {
-            log::warn!("Trying to add too many triangles");
-            return;
-        }
-        self.triangle_arr[self.last_triangle_index] = triangle;
         self.scene_vec.push(Object {
             object_type: ObjectType::Triangle,
-            index: self.last_triangle_index,
+            index: self.triangle_arr.len(),
         });
-        self.last_triangle_index += 1;
+        self.triangle_arr.push(triangle);
+    }
+    /// Add triangles that are only drawn where instances of the mesh are placed, returning the
+    /// index to create the instances with. The triangles are in the mesh's own coordinates
+    pub fn add_mesh(
+        &mut self,
+        name: &str,
+        triangles: Vec<Triangle>,
+        source: Option<String>,
+    ) -> Result<u32> {
+        if triangles.is_empty() {
+            bail!("Mesh {name} doesn't have any triangles");
+        }
+        let first_triangle = self.triangle_arr.len();
+        let bvh = create_mesh_bvh(&triangles, first_triangle);
+        let root = bvh.nodes[0];
+        log::info!(
+            "Built the BVH for mesh {name} with {} nodes for {} triangles",
+            bvh.nodes.len(),
+            triangles.len()
+        );
+
+        self.meshes.push(Mesh {
+            name: name.to_string(),
+            source,
+            triangle_count: triangles.len(),
+            root_node: self.mesh_bvh.append(&bvh),
+            depth: bvh.depth(),
+            bounds: (root.min, root.max),
+        });
+        self.triangle_arr.extend(triangles);
+        Ok((self.meshes.len() - 1) as u32)
+    }
+    pub fn find_mesh(&self, name: &str) -> Option<u32> {
+        self.meshes
+            .iter()
+            .position(|m| m.name == name)
+            .map(|i| i as u32)
+    }
+    pub fn get_meshes(&self) -> &[Mesh] {
+        &self.meshes
+    }
+    pub(crate) fn get_mesh_bvh(&self) -> &BVH {
+        &self.mesh_bvh
+    }
+    pub(crate) fn max_mesh_depth(&self) -> u32 {
+        self.meshes.iter().map(|m| m.depth).max().unwrap_or(0)
+    }
+    /// Place a copy of a mesh in the scene, optionally drawing all of it with one material
+    pub fn add_instance(&mut self, mesh: u32, transform: Transform, material: Option<u32>) {
+        self.scene_vec.push(Object {
+            object_type: ObjectType::Instance,
+            index: self.instance_arr.len(),
+        });
+        let instance = Instance::new(mesh, &self.meshes[mesh as usize], &transform, material);
+        self.instance_arr.push(instance);
+        self.instance_transforms.push(transform);
+    }
+    /// Move an instance, the top level BVH has to be refitted afterwards but the mesh's doesn't
+    pub fn set_instance_transform(&mut self, index: usize, transform: Transform) {
+        let instance = &self.instance_arr[index];
+        let (mesh, material) = (instance.mesh, instance.material());
+        self.instance_arr[index] =
+            Instance::new(mesh, &self.meshes[mesh as usize], &transform, material);
+        self.instance_transforms[index] = transform;
+    }
+    pub fn get_instance_arr(&self) -> &[Instance] {
+        &self.instance_arr
+    }
+    pub fn get_instance_transform(&self, index: usize) -> &Transform {
+        &self.instance_transforms[index]
+    }
+    /// Make space for a batch of triangles up front, e.g. before loading a mesh
+    pub fn reserve_triangles(&mut self, additional: usize) {
+        self.triangle_arr.reserve(additional);
+        self.scene_vec.reserve(additional);
     }
 
     pub fn len(&self) -> usize {
         self.scene_vec.len()
     }
 
-    pub fn get_material_arr(&self) -> &[Material; MAX_MATERIAL_COUNT] {
+    pub fn is_empty(&self) -> bool {
+        self.scene_vec.is_empty()
+    }
+
+    pub fn get_material_arr(&self) -> &[Material] {
         &self.mat_arr
     }
-    pub fn get_sphere_arr(&self) -> &[Sphere; MAX_SPHERE_COUNT] {
+    pub fn get_sphere_arr(&self) -> &[Sphere] {
         &self.sphere_arr
     }
-    pub fn get_sphere_arr_mut(&mut self) -> &mut [Sphere; MAX_SPHERE_COUNT] {
+    pub fn get_sphere_arr_mut(&mut self) -> &mut [Sphere] {
         &mut self.sphere_arr
     }
-    pub fn get_quad_arr(&self) -> &[Quad; MAX_QUAD_COUNT] {
+    pub fn get_quad_arr(&self) -> &[Quad] {
         &self.quad_arr
     }
-    pub fn get_triangle_arr(&self) -> &[Triangle; MAX_TRIANGLE_COUNT] {
+    pub fn get_triangle_arr(&self) -> &[Triangle] {
         &self.triangle_arr
     }
 
     pub fn get_extrema_of(&self, index: usize) -> (Vec3, Vec3) {
         let o = &self.scene_vec[index];
-        match o.object_type {
-            ObjectType::Quad => self.quad_arr[o.index].get_extrema(),
-            ObjectType::Sphere => self.sphere_arr[o.index].get_extrema(),
-            ObjectType::Triangle => self.triangle_arr[o.index].get_extrema(),
-        }
+        self.get_object_extrema(o.object_type, o.index)
     }
 
-    pub fn get_type_of(&self, index: usize) -> ObjectType {
-        self.scene_vec[index].object_type
+    // Like `get_extrema_of` but with the index into the array of that type of object
+    pub(crate) fn get_object_extrema(&self, object_type: ObjectType, index: usize) -> (Vec3, Vec3) {
+        match object_type {
+            ObjectType::Quad => self.quad_arr[index].get_extrema(),
+            ObjectType::Sphere => self.sphere_arr[index].get_extrema(),
+            ObjectType::Triangle => self.triangle_arr[index].get_extrema(),
+            ObjectType::Instance => self.instance_extrema(&self.instance_arr[index]),
+        }
     }
 
-    pub fn get_index_of(&self, index: usize) -> usize {
-        self.scene_vec[index].index
+    pub fn get_center_of(&self, index: usize) -> Vec3 {
+        let o = &self.scene_vec[index];
+        match o.object_type {
+            ObjectType::Quad => self.quad_arr[o.index].get_center(),
+            ObjectType::Sphere => self.sphere_arr[o.index].get_center(),
+            ObjectType::Triangle => self.triangle_arr[o.index].get_center(),
+            ObjectType::Instance => {
+                let (min, max) = self.instance_extrema(&self.instance_arr[o.index]);
+                (min + max) / 2.
+            }
+        }
     }
 
-    pub fn sort_x(&mut self, start: usize, end: usize) {
-        self.scene_vec[start..end].sort_by(|o1, o2| {
-            (match o1.object_type {
-                ObjectType::Quad => self.quad_arr[o1.index].get_center().x(),
-                ObjectType::Sphere => self.sphere_arr[o1.index].get_center().x(),
-                ObjectType::Triangle => self.triangle_arr[o1.index].get_center().x(),
-            })
-            .partial_cmp(
-                &(match o2.object_type {
-                    ObjectType::Quad => self.quad_arr[o2.index].get_center().x(),
-                    ObjectType::Sphere => self.sphere_arr[o2.index].get_center().x(),
-                    ObjectType::Triangle => self.triangle_arr[o2.index].get_center().x(),
-                }),
-            )
-            .expect("Sort x")
+    // World space box around the corners of the mesh's box
+    fn instance_extrema(&self, instance: &Instance) -> (Vec3, Vec3) {
+        let (min, max) = self.meshes[instance.mesh as usize].bounds;
+        let object_to_world = instance.object_to_world();
+        let corners = (0..8).map(|i| {
+            let pick = |bit: usize, axis: usize| match i & bit {
+                0 => min[axis],
+                _ => max[axis],
+            };
+            let p = object_to_world * Vector4::new(pick(1, 0), pick(2, 1), pick(4, 2), 1.);
+            Vec3::new(p.x, p.y, p.z)
         });
+        corners.fold(
+            (Vec3::all(f32::MAX), Vec3::all(f32::MIN)),
+            |(min, max), p| (min.min_extrema(&p), max.max_extrema(&p)),
+        )
     }
 
-    pub fn sort_y(&mut self, start: usize, end: usize) {
-        self.scene_vec[start..end].sort_by(|o1, o2| {
-            (match o1.object_type {
-                ObjectType::Quad => self.quad_arr[o1.index].get_center().y(),
-                ObjectType::Sphere => self.sphere_arr[o1.index].get_center().y(),
-                ObjectType::Triangle => self.triangle_arr[o1.index].get_center().y(),
-            })
-            .partial_cmp(
-                &(match o2.object_type {
-                    ObjectType::Quad => self.quad_arr[o2.index].get_center().y(),
-                    ObjectType::Sphere => self.sphere_arr[o2.index].get_center().y(),
-                    ObjectType::Triangle => self.triangle_arr[o2.index].get_center().y(),
-                }),
-            )
-            .expect("Sort y")
-        });
+    pub fn get_type_of(&self, index: usize) -> ObjectType {
+        self.scene_vec[index].object_type
     }
 
-    pub fn sort_z(&mut self, start: usize, end: usize) {
-        self.scene_vec[start..end].sort_by(|o1, o2| {
-            (match o1.object_type {
-                ObjectType::Quad => self.quad_arr[o1.index].get_center().z(),
-                ObjectType::Sphere => self.sphere_arr[o1.index].get_center().z(),
-                ObjectType::Triangle => self.triangle_arr[o1.index].get_center().z(),
-            })
-            .partial_cmp(
-                &(match o2.object_type {
-                    ObjectType::Quad => self.quad_arr[o2.index].get_center().z(),
-                    ObjectType::Sphere => self.sphere_arr[o2.index].get_center().z(),
-                    ObjectType::Triangle => self.triangle_arr[o2.index].get_center().z(),
-                }),
-            )
-            .expect("Sort z")
-        });
+    pub fn get_index_of(&self, index: usize) -> usize {
+        self.scene_vec[index].index
     }
 
     #[cfg(target_arch = "wasm32")]
     pub fn get_random_material(&self, _rng: &mut u32) -> u32 {
         use web_sys::js_sys::Math::random;
-        (random() as f32 * (self.last_material_index + 1) as f32) as u32
+        (random() as f32 * self.mat_arr.len() as f32) as u32
     }
 
     #[cfg(not(target_arch = "wasm32"))]
     pub fn get_random_material(&self, rng: &mut ThreadRng) -> u32 {
         use rand::Rng;
-        (rng.gen::<f32>() * (self.last_material_index + 1) as f32) as u32
+        (rng.gen::<f32>() * self.mat_arr.len() as f32) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scene;
+    use crate::material::Material;
+
+    // A material added without a name mustn't take the name of one added with it, or loading
+    // a model that uses that name would pick the wrong material
+    #[test]
+    fn generated_material_names_are_unique() {
+        let mut scene = Scene::new();
+        let named = scene.add_named_material("material_1", Material::default());
+        let unnamed = scene.add_material(Material::default());
+        assert_ne!(scene.get_material_name(unnamed), "material_1");
+        assert_eq!(scene.find_material("material_1"), Some(named));
+        assert_eq!(
+            scene.find_material(scene.get_material_name(unnamed)),
+            Some(unnamed)
+        );
     }
 }
@@ -0,0 +1,578 @@
+use std::{iter, sync::mpsc};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    buffers::{SceneBuffers, TraversalCounters},
+    bvh::{BvhSettings, BvhTraversal, BvhUpdate},
+    camera::Camera,
+    config::trace_size,
+    gpu_bvh::{GpuBvhBuilder, TopLevelBvh},
+    gpu_tracer::GpuTracer,
+    timings::{StageTimer, StageTimings},
+    CameraSettings, RenderConfig, Scene, Uniforms,
+};
+
+/// Format of the texture a `Renderer` draws into when it isn't given a target. It is sRGB like
+/// the surface the viewer picks, so images read back match what the viewer shows
+pub const READ_BACK_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Where a `Renderer` draws the image
+pub enum RenderTarget<'window> {
+    /// Present every frame to a window. The renderer configures the surface with `config`, and
+    /// again whenever it is resized
+    Surface {
+        surface: wgpu::Surface<'window>,
+        config: wgpu::SurfaceConfiguration,
+    },
+    /// Draw into a texture with `RENDER_ATTACHMENT` usage. It can only be read back if it also
+    /// has `COPY_SRC` usage and an 8 bit RGBA format
+    Texture(wgpu::Texture),
+    /// Only draw the image into a texture of the configured size to be read back
+    None,
+}
+
+// `RenderTarget` once a texture has been made for a missing one
+enum Target<'window> {
+    Surface {
+        surface: wgpu::Surface<'window>,
+        config: wgpu::SurfaceConfiguration,
+    },
+    Texture(wgpu::Texture),
+}
+
+/// Path traces a scene with a device and queue that belong to the caller, adding samples to the
+/// image a batch at a time and drawing the average so far onto a target.
+///
+/// The interactive viewer is one client of this, anything else with a `wgpu::Device` can embed
+/// the tracer the same way: create a renderer for a scene, call `render_samples` as often as
+/// suits it and show the target or `read_back` the image. Changing the scene or the camera starts
+/// the image again.
+pub struct Renderer<'a> {
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    target: Target<'a>,
+    render_scale: f32,
+
+    tracer: GpuTracer,
+    bvh_stack_size: u32, // Size of the traversal stack the trace pipeline was built with
+    samples: u32,        // Samples per pixel in a finished image
+    timings: Option<StageTimings>, // For the image being traced, if they are being measured
+
+    uniforms: Uniforms,
+    uniforms_buffer: wgpu::Buffer,
+
+    scene: Scene,
+    scene_buffers: SceneBuffers,
+    traversal_counters: TraversalCounters,
+    bvh: TopLevelBvh,
+    bvh_settings: BvhSettings,
+    bvh_update: Option<BvhUpdate>, // Changes to the BVH that haven't been uploaded yet
+    gpu_bvh_builder: Option<GpuBvhBuilder>, // Only created once a linear BVH has to be built
+    camera: Camera,
+}
+
+impl<'a> Renderer<'a> {
+    /// Set up the tracer for `scene` seen from `camera`. The size of the image comes from the
+    /// target, or from `config` if there isn't one
+    pub fn new(
+        device: &'a wgpu::Device,
+        queue: &'a wgpu::Queue,
+        target: RenderTarget<'a>,
+        config: &RenderConfig,
+        scene: Scene,
+        camera: &CameraSettings,
+    ) -> Result<Self> {
+        let target = match target {
+            RenderTarget::Surface { surface, config } => Target::Surface { surface, config },
+            RenderTarget::Texture(texture) => Target::Texture(texture),
+            RenderTarget::None => {
+                Target::Texture(device.create_texture(&output_texture_descriptor(
+                    (config.width, config.height),
+                    READ_BACK_FORMAT,
+                    wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                )))
+            }
+        };
+        if let Target::Surface { surface, config } = &target {
+            // Windows can start out with no size, they are configured once they get one
+            if config.width > 0 && config.height > 0 {
+                surface.configure(device, config);
+            }
+        }
+
+        // The image is traced at a fraction of the target's resolution if asked to, the display
+        // pass scales it to fit
+        let (width, height) = target.size();
+        let trace_size = trace_size(width, height, config.render_scale);
+        let mut uniforms = Uniforms::new(config);
+        uniforms.update(trace_size.0, trace_size.1);
+        uniforms.sky = scene.sky;
+        let uniforms_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Uniforms"),
+            size: std::mem::size_of::<Uniforms>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bvh = TopLevelBvh::new(&scene, &config.bvh);
+        uniforms.set_bvh(&bvh);
+
+        // The buffers start out big enough for the whole scene so the bind groups only have to
+        // be recreated if objects are added later
+        let mut scene_buffers = SceneBuffers::new(device);
+        let mut gpu_bvh_builder = None;
+        scene_buffers
+            .upload_objects(device, queue, &scene)
+            .and_then(|_| {
+                bvh.upload(
+                    device,
+                    queue,
+                    &scene,
+                    &mut scene_buffers,
+                    &mut gpu_bvh_builder,
+                )
+            })
+            .context("Failed to upload the scene")?;
+        let traversal_counters = TraversalCounters::new(device);
+
+        let bvh_stack_size = bvh.traversal_stack_size(&scene);
+        let tracer = GpuTracer::new(
+            device,
+            target.format(),
+            trace_size,
+            config.trace_mode,
+            &uniforms_buffer,
+            &scene_buffers,
+            &traversal_counters,
+            config.bvh.traversal,
+            bvh_stack_size,
+        );
+
+        Ok(Self {
+            device,
+            queue,
+            target,
+            render_scale: config.render_scale,
+            tracer,
+            bvh_stack_size,
+            samples: config.samples,
+            timings: config.timings.then(StageTimings::default),
+            uniforms,
+            uniforms_buffer,
+            scene,
+            scene_buffers,
+            traversal_counters,
+            bvh,
+            bvh_settings: config.bvh,
+            bvh_update: None,
+            gpu_bvh_builder,
+            camera: camera.to_camera(),
+        })
+    }
+
+    /// Trace a different scene from now on
+    pub fn set_scene(&mut self, scene: Scene) {
+        self.uniforms.sky = scene.sky;
+        self.scene = scene;
+        self.rebuild_scene();
+        self.reset_samples();
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    /// Look at the scene from somewhere else
+    pub fn set_camera(&mut self, camera: &CameraSettings) {
+        self.camera = camera.to_camera();
+        self.reset_samples();
+    }
+
+    pub fn bvh_traversal(&self) -> BvhTraversal {
+        self.bvh_settings.traversal
+    }
+
+    /// Switch how rays walk the BVH, which is compiled into the shaders
+    pub fn set_bvh_traversal(&mut self, traversal: BvhTraversal) {
+        self.bvh_settings.traversal = traversal;
+        self.recreate_trace_pipeline();
+        self.reset_samples();
+    }
+
+    /// Size of the image drawn onto the target
+    pub fn size(&self) -> (u32, u32) {
+        self.target.size()
+    }
+
+    /// Change the size of the image. A surface is reconfigured, which also recovers one that was
+    /// lost, and a texture target is replaced with one of the new size, see `target_texture`. The
+    /// image starts again if it is traced at a new resolution
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        match &mut self.target {
+            Target::Surface { surface, config } => {
+                config.width = width;
+                config.height = height;
+                surface.configure(self.device, config);
+            }
+            Target::Texture(texture) => {
+                if (width, height) != (texture.width(), texture.height()) {
+                    *texture = self.device.create_texture(&output_texture_descriptor(
+                        (width, height),
+                        texture.format(),
+                        texture.usage(),
+                    ));
+                }
+            }
+        }
+
+        let trace_size = trace_size(width, height, self.render_scale);
+        if trace_size != self.tracer.size() {
+            self.tracer.resize(
+                self.device,
+                trace_size,
+                &self.uniforms_buffer,
+                &self.scene_buffers,
+                &self.traversal_counters,
+            );
+            self.uniforms.update(trace_size.0, trace_size.1);
+            self.reset_samples();
+        }
+    }
+
+    /// The texture the image is drawn into, unless it is drawn onto a surface
+    pub fn target_texture(&self) -> Option<&wgpu::Texture> {
+        match &self.target {
+            Target::Texture(texture) => Some(texture),
+            Target::Surface { .. } => None,
+        }
+    }
+
+    /// Samples per pixel traced into the image so far
+    pub fn samples_traced(&self) -> u32 {
+        self.uniforms.frame_num
+    }
+
+    /// Samples per pixel still to trace before the image is finished
+    pub fn samples_left(&self) -> u32 {
+        self.samples.saturating_sub(self.uniforms.frame_num)
+    }
+
+    /// Throw away the samples so far, the next one starts the image again
+    pub fn reset_samples(&mut self) {
+        self.uniforms.reset_samples();
+    }
+
+    /// How long each stage of tracing has taken on the image so far, if `RenderConfig::timings`
+    /// was set
+    pub fn timings(&self) -> Option<&StageTimings> {
+        self.timings.as_ref()
+    }
+
+    /// Add `samples` samples to every pixel and draw the image onto the target. Drawing fails if
+    /// the surface can't be presented to, the samples are traced either way
+    pub fn render_samples(&mut self, samples: u32) -> Result<(), wgpu::SurfaceError> {
+        self.check_traversal_counters();
+
+        // Update scene
+        match self.upload_scene() {
+            Ok(true) => {
+                self.tracer.recreate_bind_groups(
+                    self.device,
+                    &self.uniforms_buffer,
+                    &self.scene_buffers,
+                    &self.traversal_counters,
+                );
+            }
+            Ok(false) => {}
+            // Keep tracing the scene as it was last uploaded
+            Err(e) => log::error!("{e:#}"),
+        }
+
+        // Each pass is submitted on its own as it needs its own uniforms
+        self.uniforms.camera = *self.camera.uniforms();
+        let mut read_counters = false;
+        for _ in 0..samples {
+            self.uniforms.tick();
+            self.queue.write_buffer(
+                &self.uniforms_buffer,
+                0,
+                bytemuck::cast_slice(&[self.uniforms]),
+            );
+
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Trace Encoder"),
+                });
+            // Count traversal problems and time the stages for each image from scratch, and check
+            // on them once it is done
+            if self.uniforms.frame_num == 1 {
+                self.traversal_counters.clear(&mut encoder);
+                if let Some(timings) = &mut self.timings {
+                    *timings = StageTimings::default();
+                }
+            }
+            let mut timer = StageTimer::new(self.device, self.queue, self.timings.as_mut());
+            self.tracer.trace(&mut encoder, &mut timer, &self.uniforms);
+            let finished = self.uniforms.frame_num == self.samples;
+            read_counters |= finished && self.traversal_counters.copy_for_reading(&mut encoder);
+            self.queue.submit(iter::once(encoder.finish()));
+            if let Some(timings) = self.timings.as_ref().filter(|_| finished) {
+                log::info!("Stage timings over {} passes: {timings}", self.samples);
+            }
+        }
+        if read_counters {
+            self.traversal_counters.start_reading();
+        }
+
+        self.display()
+    }
+
+    /// Copy the image last drawn back to the CPU, as 8 bit RGBA rows from the top. Images drawn
+    /// onto a surface can't be read back, and the web doesn't allow waiting for the GPU
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn read_back(&mut self) -> Result<Vec<u8>> {
+        let Target::Texture(texture) = &self.target else {
+            bail!("Images drawn onto a surface can't be read back");
+        };
+        if !matches!(
+            texture.format(),
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb
+        ) {
+            bail!("Can't read back a {:?} texture", texture.format());
+        }
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            bail!("The target texture needs COPY_SRC usage to be read back");
+        }
+        let pixels = read_texture(self.device, self.queue, texture)?;
+
+        // Reading the texture waited for the GPU to finish, so the counters of a finished image
+        // are mapped by now
+        self.check_traversal_counters();
+        Ok(pixels)
+    }
+
+    /// Warn if rays of the finished image gave up walking the BVH, e.g. because its stack
+    /// overflowed. The counters are read back without waiting, so call this now and then until
+    /// they arrive
+    pub fn check_traversal_counters(&mut self) {
+        if let Some(stats) = self.traversal_counters.try_read(self.device) {
+            stats.warn_if_incomplete();
+        }
+    }
+
+    pub(crate) fn uniforms(&self) -> &Uniforms {
+        &self.uniforms
+    }
+
+    pub(crate) fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    // Moving the camera starts the image again
+    pub(crate) fn camera_mut(&mut self) -> &mut Camera {
+        self.reset_samples();
+        &mut self.camera
+    }
+
+    // The BVH has to be rebuilt or refitted after changing the scene through this
+    pub(crate) fn scene_mut(&mut self) -> &mut Scene {
+        &mut self.scene
+    }
+
+    pub(crate) fn rebuild_scene(&mut self) {
+        self.bvh = TopLevelBvh::new(&self.scene, &self.bvh_settings);
+        self.bvh_changed(BvhUpdate::Rebuilt);
+    }
+
+    // Objects have moved without any being added or removed, which usually only needs the
+    // bounds in the BVH updated
+    pub(crate) fn refit_scene(&mut self) {
+        let update = self.bvh.update(&self.scene, &self.bvh_settings);
+        self.bvh_changed(update);
+    }
+
+    fn bvh_changed(&mut self, update: BvhUpdate) {
+        if let BvhUpdate::Rebuilt = update {
+            self.uniforms.set_bvh(&self.bvh);
+
+            // The shader's traversal stack only ever grows so the pipeline isn't rebuilt back and
+            // forth
+            let bvh_stack_size = self.bvh.traversal_stack_size(&self.scene);
+            if bvh_stack_size > self.bvh_stack_size {
+                self.bvh_stack_size = bvh_stack_size;
+                self.recreate_trace_pipeline();
+            }
+        }
+        self.bvh_update = Some(match self.bvh_update.take() {
+            Some(pending) => pending.then(update),
+            None => update,
+        });
+    }
+
+    // Upload everything the shader reads about the scene, only sending the parts of the BVH that
+    // changed. Returns true if the bind groups have to be recreated
+    fn upload_scene(&mut self) -> Result<bool> {
+        let mut reallocated =
+            self.scene_buffers
+                .upload_objects(self.device, self.queue, &self.scene)?;
+        match self.bvh_update.take() {
+            Some(BvhUpdate::Rebuilt) => {
+                let result = self.bvh.upload(
+                    self.device,
+                    self.queue,
+                    &self.scene,
+                    &mut self.scene_buffers,
+                    &mut self.gpu_bvh_builder,
+                );
+                if result.is_err() {
+                    // Only the whole tree can be uploaded after this, so try again next frame
+                    self.bvh_update = Some(BvhUpdate::Rebuilt);
+                }
+                reallocated |= result?;
+            }
+            Some(BvhUpdate::Refitted(nodes)) => {
+                // Only trees built on the CPU are refitted
+                if let TopLevelBvh::Cpu(bvh) = &self.bvh {
+                    self.scene_buffers.upload_bvh_nodes(self.queue, bvh, &nodes);
+                }
+            }
+            None => {}
+        }
+        Ok(reallocated)
+    }
+
+    fn recreate_trace_pipeline(&mut self) {
+        self.tracer.recreate_trace_pipeline(
+            self.device,
+            self.bvh_settings.traversal,
+            self.bvh_stack_size,
+        );
+    }
+
+    // Show the samples so far
+    fn display(&self) -> Result<(), wgpu::SurfaceError> {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render Encoder"),
+            });
+        match &self.target {
+            Target::Surface { surface, .. } => {
+                let output = surface.get_current_texture()?;
+                let view = output
+                    .texture
+                    .create_view(&wgpu::TextureViewDescriptor::default());
+                self.tracer
+                    .display(&mut encoder, &view, self.uniforms.frame_num);
+                self.queue.submit(iter::once(encoder.finish()));
+                output.present();
+            }
+            Target::Texture(texture) => {
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                self.tracer
+                    .display(&mut encoder, &view, self.uniforms.frame_num);
+                self.queue.submit(iter::once(encoder.finish()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Target<'_> {
+    fn size(&self) -> (u32, u32) {
+        match self {
+            Target::Surface { config, .. } => (config.width, config.height),
+            Target::Texture(texture) => (texture.width(), texture.height()),
+        }
+    }
+
+    fn format(&self) -> wgpu::TextureFormat {
+        match self {
+            Target::Surface { config, .. } => config.format,
+            Target::Texture(texture) => texture.format(),
+        }
+    }
+}
+
+fn output_texture_descriptor(
+    (width, height): (u32, u32),
+    format: wgpu::TextureFormat,
+    usage: wgpu::TextureUsages,
+) -> wgpu::TextureDescriptor<'static> {
+    wgpu::TextureDescriptor {
+        label: Some("Render Target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage,
+        view_formats: &[],
+    }
+}
+
+// Copy an Rgba8 texture back to the CPU, removing the padding wgpu requires at the end of each row
+#[cfg(not(target_arch = "wasm32"))]
+fn read_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+) -> Result<Vec<u8>> {
+    let (width, height) = (texture.width(), texture.height());
+    let unpadded_bytes_per_row = width * 4;
+    let padded_bytes_per_row =
+        unpadded_bytes_per_row.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+    let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback"),
+        size: (padded_bytes_per_row * height) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &readback_buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        texture.size(),
+    );
+    queue.submit(iter::once(encoder.finish()));
+
+    let slice = readback_buffer.slice(..);
+    let (sender, receiver) = mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait).panic_on_timeout();
+    receiver
+        .recv()
+        .context("Readback buffer was never mapped")?
+        .context("Failed to map the readback buffer")?;
+
+    let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+    for row in slice
+        .get_mapped_range()
+        .chunks(padded_bytes_per_row as usize)
+    {
+        pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+    }
+    readback_buffer.unmap();
+    Ok(pixels)
+}
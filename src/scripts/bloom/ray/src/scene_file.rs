@@ -0,0 +1,840 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    algebra::Vec3,
+    config::CameraSettings,
+    material::Material,
+    pattern::Pattern,
+    primitives::{
+        ObjectType, Quad, Scene, Sky, Sphere, Transform, Triangle, DEFAULT_MATERIAL_NAME,
+        DEFAULT_TRIANGLE_UVS,
+    },
+    texture::NO_TEXTURE,
+};
+
+/// Bump whenever a change to the format stops older files from loading the same way
+pub const SCENE_FILE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneFormat {
+    Ron,
+    Json,
+}
+
+impl SceneFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ron") => Ok(Self::Ron),
+            Some("json") => Ok(Self::Json),
+            _ => bail!(
+                "Can't tell the format of {}, scene files must end in .ron or .json",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Declarative description of a scene that can be stored as RON or JSON.
+///
+/// Objects refer to materials by name, the material called "default" always exists and is used
+/// when an object doesn't name one. Materials refer to textures by name too.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<CameraSettings>,
+    #[serde(default)]
+    pub sky: Sky,
+    #[serde(default)]
+    pub textures: Vec<TextureDescription>,
+    #[serde(default)]
+    pub materials: Vec<MaterialDescription>,
+    #[serde(default)]
+    pub spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    pub quads: Vec<QuadDescription>,
+    #[serde(default)]
+    pub triangles: Vec<TriangleDescription>,
+    #[serde(default)]
+    pub meshes: Vec<MeshDescription>,
+    #[serde(default)]
+    pub instances: Vec<InstanceDescription>,
+}
+
+/// A material's parameters, see `Material` for what each of them does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaterialDescription {
+    pub name: String,
+    pub albedo: Vec3,
+    pub roughness: f32,
+    pub metalness: f32,
+    pub specular: f32,
+    pub transmission: f32,
+    pub ior: f32,
+    // Fraction of each colour lost per unit of distance light travels through a transmissive
+    // material, which gives glass its colour. Only works on closed objects with their surfaces
+    // facing outwards, like spheres and closed meshes wound counter-clockwise
+    pub absorption: Vec3,
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub sheen_colour: Vec3,
+    pub emission_strength: f32,
+    pub emitted_colour: Vec3,
+    // Older files describe materials the way `Material::new` takes them instead, and give how
+    // smooth they are rather than their roughness and metalness, see `Material::with_smoothness`.
+    // These override the parameters they replaced when given, and saved scenes never use them
+    #[serde(deserialize_with = "deserialize_some", skip_serializing)]
+    pub alpha: Option<f32>,
+    #[serde(deserialize_with = "deserialize_some", skip_serializing)]
+    pub refraction_index: Option<f32>,
+    #[serde(deserialize_with = "deserialize_some", skip_serializing)]
+    pub emissivity: Option<f32>,
+    #[serde(deserialize_with = "deserialize_some", skip_serializing)]
+    pub smoothness: Option<f32>,
+    // Textures the albedo and emitted colour are multiplied by, and the roughness and metalness
+    // are scaled by the green and blue channels of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub albedo_texture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roughness_texture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metalness_texture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emission_texture: Option<String>,
+    // Blended with the albedo across the surface, before the albedo texture
+    #[serde(skip_serializing_if = "Pattern::is_solid")]
+    pub pattern: Pattern,
+}
+
+impl Default for MaterialDescription {
+    fn default() -> Self {
+        Self::new(String::new(), &Material::default(), |_| None)
+    }
+}
+
+impl MaterialDescription {
+    // `texture_name` gives the name to save a texture as, if it can be saved
+    fn new(name: String, m: &Material, texture_name: impl Fn(u32) -> Option<String>) -> Self {
+        let texture_name = |texture| match texture {
+            NO_TEXTURE => None,
+            texture => texture_name(texture),
+        };
+        Self {
+            name,
+            albedo: m.albedo,
+            roughness: m.roughness,
+            metalness: m.metalness,
+            specular: m.specular,
+            transmission: m.transmission,
+            ior: m.ior,
+            absorption: m.absorption,
+            clearcoat: m.clearcoat,
+            clearcoat_roughness: m.clearcoat_roughness,
+            sheen_colour: m.sheen_colour,
+            emission_strength: m.emission_strength,
+            emitted_colour: m.emitted_colour,
+            alpha: None,
+            refraction_index: None,
+            emissivity: None,
+            smoothness: None,
+            albedo_texture: texture_name(m.albedo_texture),
+            roughness_texture: texture_name(m.roughness_texture),
+            metalness_texture: texture_name(m.metalness_texture),
+            emission_texture: texture_name(m.emission_texture),
+            pattern: m.pattern(),
+        }
+    }
+
+    fn to_material(&self, scene: &Scene, location: impl Fn() -> String) -> Result<Material> {
+        let texture = |name: &Option<String>, field: &str| match name {
+            Some(name) => scene
+                .find_texture(name)
+                .ok_or_else(|| anyhow!("{}.{field}: no texture called '{name}'", location())),
+            None => Ok(NO_TEXTURE),
+        };
+        // Both are divided by, so anything else ends up as infinities and NaNs in the render
+        let valid = |index: f32| index > 0. && index.is_finite();
+        if !valid(self.ior) {
+            bail!(
+                "{}.ior: must be positive and finite, got {}",
+                location(),
+                self.ior
+            );
+        }
+        if let Some(refraction_index) = self.refraction_index.filter(|&r| !valid(r)) {
+            bail!(
+                "{}.refraction_index: must be positive and finite, got {refraction_index}",
+                location()
+            );
+        }
+        let alpha = self.alpha.unwrap_or(1. - self.transmission);
+        let refraction_index = self.refraction_index.unwrap_or(1. / self.ior);
+        let material = Material::new(
+            self.albedo,
+            0.,
+            alpha,
+            refraction_index,
+            self.emissivity.unwrap_or(1.),
+            self.emission_strength,
+            self.emitted_colour,
+        )
+        .with_roughness(self.roughness)
+        .with_metalness(self.metalness)
+        .with_specular(self.specular)
+        .with_absorption(self.absorption)
+        .with_clearcoat(self.clearcoat, self.clearcoat_roughness)
+        .with_sheen(self.sheen_colour);
+        let material = match self.smoothness {
+            Some(smoothness) => material.with_smoothness(smoothness),
+            None => material,
+        };
+        Ok(material
+            .with_albedo_texture(texture(&self.albedo_texture, "albedo_texture")?)
+            .with_roughness_texture(texture(&self.roughness_texture, "roughness_texture")?)
+            .with_metalness_texture(texture(&self.metalness_texture, "metalness_texture")?)
+            .with_emission_texture(texture(&self.emission_texture, "emission_texture")?)
+            .with_pattern(self.pattern))
+    }
+}
+
+// Optional fields are written without Some(..) in RON when they used to be plain numbers
+fn deserialize_some<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<f32>, D::Error> {
+    f32::deserialize(deserializer).map(Some)
+}
+
+/// A PNG or JPEG image for materials to use
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextureDescription {
+    pub name: String,
+    pub path: String, // Relative to the scene file
+}
+
+fn default_material_name() -> String {
+    DEFAULT_MATERIAL_NAME.to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SphereDescription {
+    pub center: Vec3,
+    pub radius: f32,
+    #[serde(default = "default_material_name")]
+    pub material: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuadDescription {
+    pub q: Vec3, // Corner
+    pub u: Vec3, // First edge from q
+    pub v: Vec3, // Second edge from q
+    #[serde(default = "default_material_name")]
+    pub material: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TriangleDescription {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub c: Vec3,
+    #[serde(default = "default_material_name")]
+    pub material: String,
+    // Texture coordinates of a, b and c, the barycentric coordinates of the hit if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uvs: Option<[[f32; 2]; 3]>,
+}
+
+/// An OBJ or glTF file that is loaded once and drawn wherever an instance places it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshDescription {
+    pub name: String,
+    pub path: String, // Relative to the scene file
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceDescription {
+    pub mesh: String,
+    #[serde(default)]
+    pub transform: Transform,
+    // Draws the whole instance with this material instead of the mesh's own ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub material: Option<String>,
+}
+
+// Only used to check the version before trying to make sense of the rest of the file
+#[derive(Deserialize)]
+#[serde(rename = "SceneFile")]
+struct VersionProbe {
+    #[serde(default)]
+    version: u32, // 0 when missing, versions start at 1
+}
+
+impl SceneFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let format = SceneFormat::from_path(path)?;
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scene file {}", path.display()))?;
+        Self::parse(&contents, format)
+            .with_context(|| format!("Failed to load scene file {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = self.serialize(SceneFormat::from_path(path)?)?;
+        fs::write(path, contents)
+            .with_context(|| format!("Failed to write scene file {}", path.display()))
+    }
+
+    pub fn parse(contents: &str, format: SceneFormat) -> Result<Self> {
+        let probe: VersionProbe = deserialize(contents, format)?;
+        match probe.version {
+            SCENE_FILE_VERSION => deserialize(contents, format),
+            0 => bail!("version: missing field, expected `version: {SCENE_FILE_VERSION}`"),
+            v => bail!(
+                "version: scene file is version {v} but only version {SCENE_FILE_VERSION} is supported"
+            ),
+        }
+    }
+
+    pub fn serialize(&self, format: SceneFormat) -> Result<String> {
+        Ok(match format {
+            SceneFormat::Ron => ron::ser::to_string_pretty(
+                self,
+                ron::ser::PrettyConfig::default().struct_names(true),
+            )?,
+            SceneFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    pub fn from_scene(scene: &Scene, camera: Option<CameraSettings>) -> Self {
+        let textures = scene.get_textures();
+        for texture in textures.iter().filter(|t| t.source.is_none()) {
+            log::warn!(
+                "Texture {} wasn't loaded from a file so it is left out of the scene file",
+                texture.name
+            );
+        }
+        let texture_name = |index: u32| {
+            let texture = &textures[index as usize];
+            texture.source.is_some().then(|| texture.name.clone())
+        };
+        let material_name = |index: u32| scene.get_material_name(index).to_string();
+        // Mesh triangles are in the triangle array too but they are saved as the file they came
+        // from
+        let triangle_indices: Vec<usize> = (0..scene.len())
+            .filter(|&i| scene.get_type_of(i) == ObjectType::Triangle)
+            .map(|i| scene.get_index_of(i))
+            .collect();
+        let triangles = triangle_indices
+            .iter()
+            .map(|&i| &scene.get_triangle_arr()[i]);
+        let meshes = scene.get_meshes();
+
+        // The materials of meshes are loaded again along with them, so only the ones something
+        // else uses are saved
+        let mut in_mesh = vec![true; scene.get_triangle_arr().len()];
+        for &i in &triangle_indices {
+            in_mesh[i] = false;
+        }
+        let mut used = vec![false; scene.material_count()];
+        let mut used_by_meshes = vec![false; scene.material_count()];
+        for (t, in_mesh) in scene.get_triangle_arr().iter().zip(in_mesh) {
+            used_by_meshes[t.material as usize] |= in_mesh;
+        }
+        let object_materials = scene
+            .get_sphere_arr()
+            .iter()
+            .map(|s| s.material)
+            .chain(scene.get_quad_arr().iter().map(|q| q.material))
+            .chain(triangles.clone().map(|t| t.material))
+            .chain(scene.get_instance_arr().iter().filter_map(|i| i.material()));
+        for material in object_materials {
+            used[material as usize] = true;
+        }
+        let materials = scene.get_material_arr()[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (i + 1, m))
+            .filter(|&(i, _)| used[i] || !used_by_meshes[i])
+            .map(|(i, m)| {
+                let name = scene.get_material_name(i as u32).to_string();
+                MaterialDescription::new(name, m, texture_name)
+            })
+            .collect();
+        for mesh in meshes.iter().filter(|m| m.source.is_none()) {
+            log::warn!(
+                "Mesh {} wasn't loaded from a file so it is left out of the scene file",
+                mesh.name
+            );
+        }
+        Self {
+            version: SCENE_FILE_VERSION,
+            camera,
+            sky: scene.sky,
+            textures: textures
+                .iter()
+                .filter_map(|t| {
+                    Some(TextureDescription {
+                        name: t.name.clone(),
+                        path: t.source.clone()?,
+                    })
+                })
+                .collect(),
+            materials,
+            spheres: scene
+                .get_sphere_arr()
+                .iter()
+                .map(|s| SphereDescription {
+                    center: s.center,
+                    radius: s.radius,
+                    material: material_name(s.material),
+                })
+                .collect(),
+            quads: scene
+                .get_quad_arr()
+                .iter()
+                .map(|q| QuadDescription {
+                    q: q.q,
+                    u: q.u,
+                    v: q.v,
+                    material: material_name(q.material),
+                })
+                .collect(),
+            triangles: triangles
+                .map(|t| TriangleDescription {
+                    a: t.a,
+                    b: t.b,
+                    c: t.c,
+                    material: material_name(t.material),
+                    uvs: (t.uvs() != DEFAULT_TRIANGLE_UVS).then_some(t.uvs()),
+                })
+                .collect(),
+            meshes: meshes
+                .iter()
+                .filter_map(|m| {
+                    Some(MeshDescription {
+                        name: m.name.clone(),
+                        path: m.source.clone()?,
+                    })
+                })
+                .collect(),
+            instances: scene
+                .get_instance_arr()
+                .iter()
+                .enumerate()
+                .filter(|(_, instance)| meshes[instance.mesh() as usize].source.is_some())
+                .map(|(i, instance)| InstanceDescription {
+                    mesh: meshes[instance.mesh() as usize].name.clone(),
+                    transform: *scene.get_instance_transform(i),
+                    material: instance.material().map(material_name),
+                })
+                .collect(),
+        }
+    }
+
+    /// Build the scene, loading meshes and textures relative to the working directory
+    pub fn to_scene(&self) -> Result<Scene> {
+        self.to_scene_in(Path::new(""))
+    }
+
+    /// Build the scene, loading meshes and textures relative to `dir`
+    pub fn to_scene_in(&self, dir: &Path) -> Result<Scene> {
+        let mut scene = Scene::new();
+        scene.sky = self.sky;
+
+        for (i, t) in self.textures.iter().enumerate() {
+            if scene.find_texture(&t.name).is_some() {
+                bail!("textures[{i}]: the name '{}' is already in use", t.name);
+            }
+            load_texture(&mut scene, t, dir).with_context(|| format!("textures[{i}]"))?;
+        }
+
+        for (i, m) in self.materials.iter().enumerate() {
+            if m.name.is_empty() {
+                bail!("materials[{i}]: material needs a name");
+            }
+            if scene.find_material(&m.name).is_some() {
+                bail!("materials[{i}]: the name '{}' is already in use", m.name);
+            }
+            let material = m.to_material(&scene, || format!("materials[{i}]"))?;
+            scene.add_named_material(&m.name, material);
+        }
+
+        // NaNs and infinities would end up in the BVH's bounds, breaking it
+        for (i, s) in self.spheres.iter().enumerate() {
+            check_finite(&[("center", s.center)], || format!("spheres[{i}]"))?;
+            if !(s.radius > 0. && s.radius.is_finite()) {
+                bail!(
+                    "spheres[{i}].radius: must be positive and finite, got {}",
+                    s.radius
+                );
+            }
+            let material = find_material(&scene, &s.material, || format!("spheres[{i}]"))?;
+            scene.add_sphere(Sphere::new(s.center, s.radius, material));
+        }
+
+        for (i, q) in self.quads.iter().enumerate() {
+            check_finite(&[("q", q.q), ("u", q.u), ("v", q.v)], || {
+                format!("quads[{i}]")
+            })?;
+            if q.u.cross(&q.v).length_squared() == 0. {
+                bail!("quads[{i}]: u and v must not be parallel, the quad has no area");
+            }
+            let material = find_material(&scene, &q.material, || format!("quads[{i}]"))?;
+            scene.add_quad(Quad::new(q.q, q.u, q.v, material));
+        }
+
+        for (i, t) in self.triangles.iter().enumerate() {
+            check_finite(&[("a", t.a), ("b", t.b), ("c", t.c)], || {
+                format!("triangles[{i}]")
+            })?;
+            let material = find_material(&scene, &t.material, || format!("triangles[{i}]"))?;
+            let triangle = Triangle::new(t.a, t.b, t.c, material);
+            scene.add_triangle(triangle.with_uvs(t.uvs.unwrap_or(DEFAULT_TRIANGLE_UVS)));
+        }
+
+        for (i, m) in self.meshes.iter().enumerate() {
+            if scene.find_mesh(&m.name).is_some() {
+                bail!("meshes[{i}]: the name '{}' is already in use", m.name);
+            }
+            load_mesh(&mut scene, m, dir).with_context(|| format!("meshes[{i}]"))?;
+        }
+
+        for (i, instance) in self.instances.iter().enumerate() {
+            let mesh = scene.find_mesh(&instance.mesh).ok_or_else(|| {
+                anyhow!("instances[{i}].mesh: no mesh called '{}'", instance.mesh)
+            })?;
+            let material = match &instance.material {
+                Some(name) => Some(find_material(&scene, name, || format!("instances[{i}]"))?),
+                None => None,
+            };
+            scene.add_instance(mesh, instance.transform, material);
+        }
+
+        if scene.is_empty() {
+            bail!("scene needs at least one sphere, quad, triangle or instance");
+        }
+        Ok(scene)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_mesh(scene: &mut Scene, mesh: &MeshDescription, dir: &Path) -> Result<()> {
+    scene.load_instanced_mesh(&mesh.name, &dir.join(&mesh.path))?;
+    Ok(())
+}
+
+// Meshes have to be fetched on the web, which the synchronous loading here can't wait for
+#[cfg(target_arch = "wasm32")]
+fn load_mesh(_scene: &mut Scene, mesh: &MeshDescription, _dir: &Path) -> Result<()> {
+    bail!(
+        "can't load {} on the web, scene files there can't use meshes",
+        mesh.path
+    )
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_texture(scene: &mut Scene, texture: &TextureDescription, dir: &Path) -> Result<()> {
+    scene.load_texture(&texture.name, &dir.join(&texture.path))?;
+    Ok(())
+}
+
+// Images have to be fetched on the web too
+#[cfg(target_arch = "wasm32")]
+fn load_texture(_scene: &mut Scene, texture: &TextureDescription, _dir: &Path) -> Result<()> {
+    bail!(
+        "can't load {} on the web, scene files there can't use textures",
+        texture.path
+    )
+}
+
+fn check_finite(vectors: &[(&str, Vec3)], location: impl Fn() -> String) -> Result<()> {
+    for (field, v) in vectors {
+        let components: [f32; 3] = (*v).into();
+        if !components.iter().all(|c| c.is_finite()) {
+            bail!("{}.{field}: must be finite, got {components:?}", location());
+        }
+    }
+    Ok(())
+}
+
+fn find_material(scene: &Scene, name: &str, location: impl Fn() -> String) -> Result<u32> {
+    scene
+        .find_material(name)
+        .ok_or_else(|| anyhow!("{}.material: no material called '{name}'", location()))
+}
+
+// Errors from both formats include the path to the offending field as well as its position in
+// the file, e.g. "spheres[1].radius: invalid type: string \"a\", expected f32 at line 12 column 20"
+fn deserialize<T: for<'de> Deserialize<'de>>(contents: &str, format: SceneFormat) -> Result<T> {
+    match format {
+        SceneFormat::Ron => {
+            let mut deserializer = ron::Deserializer::from_str(contents)?;
+            let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+                let path = e.path().to_string();
+                let spanned = deserializer.span_error(e.into_inner());
+                anyhow!(
+                    "{}:{}: {path}: {}",
+                    spanned.position.line,
+                    spanned.position.col,
+                    spanned.code
+                )
+            })?;
+            deserializer
+                .end()
+                .map_err(|e| anyhow!("{}", deserializer.span_error(e)))?;
+            Ok(value)
+        }
+        SceneFormat::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(contents);
+            let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+                let path = e.path().to_string();
+                anyhow!("{path}: {}", e.into_inner())
+            })?;
+            deserializer.end()?;
+            Ok(value)
+        }
+    }
+}
+
+/// Load a scene file, returning the scene and the camera it describes (if any)
+pub fn load_scene(path: &Path) -> Result<(Scene, Option<CameraSettings>)> {
+    let file = SceneFile::load(path)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let scene = file
+        .to_scene_in(dir)
+        .with_context(|| format!("Invalid scene in {}", path.display()))?;
+    Ok((scene, file.camera))
+}
+
+/// Save a scene (and optionally the camera looking at it) in the format implied by the extension
+pub fn save_scene(path: &Path, scene: &Scene, camera: Option<CameraSettings>) -> Result<()> {
+    let mut file = SceneFile::from_scene(scene, camera);
+
+    // Meshes and textures are loaded relative to the working directory but the file's paths are
+    // relative to the file, so paths that don't lead into its directory are made absolute
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let relative_to_file = |loaded_from: &mut String| {
+        let source = Path::new(loaded_from.as_str());
+        let relative = match source.strip_prefix(dir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf()),
+        };
+        *loaded_from = relative.to_string_lossy().into_owned();
+    };
+    file.meshes
+        .iter_mut()
+        .for_each(|m| relative_to_file(&mut m.path));
+    file.textures
+        .iter_mut()
+        .for_each(|t| relative_to_file(&mut t.path));
+    file.save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{SceneFile, SceneFormat, SCENE_FILE_VERSION};
+    use crate::{config::CameraSettings, helpers::create_default_scene, primitives::Scene};
+
+    const SPHERE: &str = "spheres: [(center: (0., 0., 0.), radius: 1.)]";
+
+    fn parse_ron(fields: &str) -> anyhow::Result<SceneFile> {
+        SceneFile::parse(&format!("SceneFile(\n{fields}\n)"), SceneFormat::Ron)
+    }
+
+    fn error<T>(result: anyhow::Result<T>) -> String {
+        format!("{:#}", result.err().expect("should have failed"))
+    }
+
+    // Saving a scene and loading it again should give back the same scene, and the same file
+    // when it is saved again
+    #[test]
+    fn scenes_round_trip() {
+        let scene = create_default_scene();
+        for format in [SceneFormat::Ron, SceneFormat::Json] {
+            let file = SceneFile::from_scene(&scene, Some(CameraSettings::default()));
+            let contents = file.serialize(format).unwrap();
+            let parsed = SceneFile::parse(&contents, format).unwrap();
+            assert_eq!(parsed.serialize(format).unwrap(), contents);
+
+            let loaded = parsed.to_scene().unwrap();
+            let bytes = |scene: &Scene| {
+                [
+                    bytemuck::cast_slice::<_, u8>(scene.get_material_arr()).to_vec(),
+                    bytemuck::cast_slice(scene.get_sphere_arr()).to_vec(),
+                    bytemuck::cast_slice(scene.get_quad_arr()).to_vec(),
+                    bytemuck::cast_slice(scene.get_triangle_arr()).to_vec(),
+                ]
+            };
+            assert!(
+                bytes(&loaded) == bytes(&scene),
+                "{format:?} changed the scene"
+            );
+            for i in 0..scene.material_count() as u32 {
+                assert_eq!(loaded.get_material_name(i), scene.get_material_name(i));
+            }
+            assert!(parsed.camera.is_some());
+        }
+    }
+
+    // A mesh's materials are its own even when the scene has some with the same names, and
+    // aren't saved with the scene since they are loaded along with the mesh
+    #[test]
+    fn meshes_keep_their_materials() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let contents = format!(
+            "version: {SCENE_FILE_VERSION}, materials: [(name: \"gold\")], {SPHERE}, \
+            meshes: [(name: \"pyramid\", path: \"pyramid.obj\")], \
+            instances: [(mesh: \"pyramid\")]"
+        );
+        let scene = parse_ron(&contents).unwrap().to_scene_in(&dir).unwrap();
+        let gold = scene.find_material("gold").unwrap();
+        assert!(scene.get_triangle_arr().iter().all(|t| t.material != gold));
+
+        let file = SceneFile::from_scene(&scene, None);
+        let names: Vec<_> = file.materials.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["gold"]);
+        let saved = file.serialize(SceneFormat::Ron).unwrap();
+        let reloaded = SceneFile::parse(&saved, SceneFormat::Ron).unwrap();
+        let scene = reloaded.to_scene_in(&dir).unwrap();
+        let resaved = SceneFile::from_scene(&scene, None).serialize(SceneFormat::Ron);
+        assert_eq!(resaved.unwrap(), saved);
+    }
+
+    #[test]
+    fn version_is_checked() {
+        assert!(error(parse_ron(SPHERE)).starts_with("version: missing field"));
+        let newer = format!("version: {}, {SPHERE}", SCENE_FILE_VERSION + 1);
+        assert_eq!(
+            error(parse_ron(&newer)),
+            format!(
+                "version: scene file is version {} but only version {SCENE_FILE_VERSION} is \
+                supported",
+                SCENE_FILE_VERSION + 1
+            )
+        );
+        assert!(parse_ron(&format!("version: {SCENE_FILE_VERSION}, {SPHERE}")).is_ok());
+    }
+
+    // Misspelt fields would otherwise be silently replaced by their defaults
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let contents = format!("version: {SCENE_FILE_VERSION}, {SPHERE}, material: []");
+        let message = error(parse_ron(&contents));
+        assert!(
+            message.contains("material: Unexpected field named `material`"),
+            "{message}"
+        );
+        let contents = format!(
+            "{{\"version\": {SCENE_FILE_VERSION}, \"spheres\": [{{\"center\": [0, 0, 0], \
+            \"radius\": 1, \"colour\": 1}}]}}"
+        );
+        let message = error(SceneFile::parse(&contents, SceneFormat::Json));
+        assert!(
+            message.starts_with("spheres[0].colour: unknown field `colour`"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn refraction_index_must_be_positive() {
+        let scene = |material: &str| {
+            parse_ron(&format!(
+                "version: {SCENE_FILE_VERSION}, materials: [(name: \"glass\", {material})], \
+                {SPHERE}"
+            ))
+            .unwrap()
+            .to_scene()
+        };
+        assert_eq!(
+            error(scene("refraction_index: 0.")),
+            "materials[0].refraction_index: must be positive and finite, got 0"
+        );
+        assert_eq!(
+            error(scene("ior: -1.5")),
+            "materials[0].ior: must be positive and finite, got -1.5"
+        );
+        assert_eq!(
+            error(scene("refraction_index: NaN")),
+            "materials[0].refraction_index: must be positive and finite, got NaN"
+        );
+        assert_eq!(
+            error(scene("ior: NaN")),
+            "materials[0].ior: must be positive and finite, got NaN"
+        );
+        assert_eq!(
+            error(scene("ior: inf")),
+            "materials[0].ior: must be positive and finite, got inf"
+        );
+        assert!(scene("refraction_index: 0.67").is_ok());
+    }
+
+    #[test]
+    fn shapes_must_be_finite() {
+        let scene = |objects: &str| {
+            parse_ron(&format!("version: {SCENE_FILE_VERSION}, {objects}"))
+                .unwrap()
+                .to_scene()
+        };
+        assert_eq!(
+            error(scene("spheres: [(center: (NaN, 0., 0.), radius: 1.)]")),
+            "spheres[0].center: must be finite, got [NaN, 0.0, 0.0]"
+        );
+        for radius in ["NaN", "inf", "0."] {
+            let sphere = format!("spheres: [(center: (0., 0., 0.), radius: {radius})]");
+            assert!(error(scene(&sphere)).starts_with("spheres[0].radius: must be positive"));
+        }
+        let quad = |u: &str, v: &str| format!("quads: [(q: (0., 0., 0.), u: {u}, v: {v})]");
+        assert_eq!(
+            error(scene(&quad("(1., inf, 0.)", "(0., 0., 1.)"))),
+            "quads[0].u: must be finite, got [1.0, inf, 0.0]"
+        );
+        assert_eq!(
+            error(scene(&quad("(1., 0., 0.)", "(2., 0., 0.)"))),
+            "quads[0]: u and v must not be parallel, the quad has no area"
+        );
+        assert_eq!(
+            error(scene(
+                "triangles: [(a: (0., 0., 0.), b: (1., 0., 0.), c: (0., -inf, 0.))]"
+            )),
+            "triangles[0].c: must be finite, got [0.0, -inf, 0.0]"
+        );
+        assert!(scene(&quad("(1., 0., 0.)", "(0., 0., 1.)")).is_ok());
+    }
+
+    // Errors should point at where the problem is, both by the path to the field and its line
+    #[test]
+    fn errors_give_field_and_line() {
+        let contents = format!(
+            "version: {SCENE_FILE_VERSION},\nspheres: [\n    (center: (0., 0., 0.), \
+            radius: 1.),\n    (center: (0., 0., 0.), radius: \"a\"),\n]"
+        );
+        let message = error(parse_ron(&contents));
+        assert!(message.starts_with("5:"), "{message}");
+        assert!(message.contains(": spheres[1].radius: "), "{message}");
+
+        let contents = format!(
+            "{{\n\"version\": {SCENE_FILE_VERSION},\n\"spheres\": [{{\"center\": [0, 0, 0],\n\
+            \"radius\": \"a\"}}]\n}}"
+        );
+        let message = error(SceneFile::parse(&contents, SceneFormat::Json));
+        assert!(message.starts_with("spheres[0].radius: "), "{message}");
+        assert!(message.contains("line 4"), "{message}");
+    }
+}
@@ -1,7 +1,7 @@
 use cgmath::{dot, Matrix3, Vector3};
 use winit::dpi::PhysicalPosition;
 
-use crate::{Sphere, Uniforms, MAX_SPHERE_COUNT};
+use crate::{Sphere, Uniforms};
 
 struct Ray {
     origin: Vector3<f32>,
@@ -45,24 +45,24 @@ fn intersect_sphere(ray: &Ray, sphere: Sphere) -> f32 {
     let t2 = (mb + sqrt_d) * recip_a;
 
     if t1 > 0. {
-        return t1;
+        t1
     } else if t2 > 0. {
-        return t2;
+        t2
     } else {
         // Check if the solution is for time = 0
-        return f32::MAX;
+        f32::MAX
     }
 }
 
-fn intersect_scene(ray: &Ray, scene: &[Sphere; MAX_SPHERE_COUNT]) -> (usize, f32) {
+fn intersect_scene(ray: &Ray, scene: &[Sphere]) -> (usize, f32) {
     let mut closest_hit: f32 = f32::MAX;
     let mut hit_object_num: usize = 0;
-    for i in 0..scene.len() {
-        if scene[i].radius <= 0. {
+    for (i, sphere) in scene.iter().enumerate() {
+        if sphere.radius <= 0. {
             continue;
         }
         // Loop through each object
-        let hit = intersect_sphere(ray, scene[i]);
+        let hit = intersect_sphere(ray, *sphere);
         if hit > 0. && hit < closest_hit {
             closest_hit = hit;
             hit_object_num = i;
@@ -78,7 +78,7 @@ fn intersect_scene(ray: &Ray, scene: &[Sphere; MAX_SPHERE_COUNT]) -> (usize, f32
 pub fn get_selected_object(
     pos: &PhysicalPosition<f64>,
     uniforms: &Uniforms,
-    scene: &[Sphere; MAX_SPHERE_COUNT],
+    scene: &[Sphere],
 ) -> (usize, f32) {
     let mut u = (pos.x / (uniforms.width - 1) as f64) as f32;
     let mut v = (pos.y / (uniforms.height - 1) as f64) as f32;
@@ -89,7 +89,7 @@ pub fn get_selected_object(
     u = (2. * u - 1.)
         * ((uniforms.width as f32) / (uniforms.height as f32))
         * viewport_scale_factor;
-    v = (2. * v - 1.) * -1. * viewport_scale_factor;
+    v = -(2. * v - 1.) * viewport_scale_factor;
 
     let camera_rotation = Matrix3::<f32> {
         x: uniforms.camera.u.into(),
@@ -103,16 +103,16 @@ pub fn get_selected_object(
     intersect_scene(&ray, scene)
 }
 
-pub fn clear_all_selections(scene: &mut [Sphere; MAX_SPHERE_COUNT]) {
+pub fn clear_all_selections(scene: &mut [Sphere]) {
     for object in scene {
         object.is_selected = 0;
     }
 }
 
-pub fn add_selection(object_num: usize, scene: &mut [Sphere; MAX_SPHERE_COUNT]) {
+pub fn add_selection(object_num: usize, scene: &mut [Sphere]) {
     scene[object_num].is_selected = 1;
 }
 
-pub fn remove_selection(object_num: usize, scene: &mut [Sphere; MAX_SPHERE_COUNT]) {
+pub fn remove_selection(object_num: usize, scene: &mut [Sphere]) {
     scene[object_num].is_selected = 0;
 }
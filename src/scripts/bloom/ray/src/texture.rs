@@ -0,0 +1,135 @@
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+use image::{imageops, RgbaImage};
+
+use crate::primitives::Scene;
+
+/// Stands in for the index of a texture in materials that don't use one for a map
+pub(crate) const NO_TEXTURE: u32 = u32::MAX;
+
+// Textures are scaled down to fit in this many pixels along each side, which every GPU allows
+const MAX_TEXTURE_SIZE: u32 = 2048;
+
+static NEXT_TEXTURE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// An image materials can take their albedo, roughness, metalness or emitted colour from.
+///
+/// Colours are sRGB encoded, like the images they come from, and are decoded when they are
+/// sampled. Roughness and metalness are read from the green and blue channels as they are, like
+/// glTF's metallic-roughness textures
+#[derive(Debug, Clone)]
+pub struct Texture {
+    pub name: String,
+    pub source: Option<String>, // File (or URL) the image was loaded from, used when saving scenes
+    image: RgbaImage,
+    id: u64, // Tells the uploaded textures apart so they are only uploaded again once they change
+}
+
+impl Texture {
+    pub fn new(name: &str, image: RgbaImage, source: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            source,
+            image,
+            id: NEXT_TEXTURE_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Decode a PNG or JPEG image, `source` is where it came from if it is a file of its own
+    pub fn from_bytes(name: &str, bytes: &[u8], source: Option<String>) -> Result<Self> {
+        let image = image::load_from_memory(bytes)
+            .with_context(|| format!("Failed to decode image {name}"))?;
+        Ok(Self::new(name, image.to_rgba8(), source))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(name: &str, path: &std::path::Path) -> Result<Self> {
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load image {}", path.display()))?;
+        Ok(Self::new(
+            name,
+            image.to_rgba8(),
+            Some(path.to_string_lossy().into_owned()),
+        ))
+    }
+
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Scene {
+    /// Load a PNG or JPEG image as a texture for materials to use, returning its index
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_texture(&mut self, name: &str, path: &std::path::Path) -> Result<u32> {
+        Ok(self.add_texture(Texture::load(name, path)?))
+    }
+}
+
+/// The textures of a scene scaled to the same size, which they need to be to go in the texture
+/// array the shaders sample. The CPU tracer samples the same layers so both see the same texels
+pub(crate) struct TextureLayers<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub layers: Vec<Cow<'a, RgbaImage>>,
+}
+
+impl<'a> TextureLayers<'a> {
+    pub fn new(textures: &'a [Texture]) -> Self {
+        // Big enough for the largest texture along each side, as long as it fits
+        let largest = |side: fn(&RgbaImage) -> u32| {
+            let size = textures.iter().map(|t| side(&t.image)).max().unwrap_or(1);
+            size.min(MAX_TEXTURE_SIZE)
+        };
+        let (width, height) = (largest(RgbaImage::width), largest(RgbaImage::height));
+        let layers = textures
+            .iter()
+            .map(|t| {
+                if t.image.dimensions() == (width, height) {
+                    Cow::Borrowed(&t.image)
+                } else {
+                    Cow::Owned(imageops::resize(
+                        &t.image,
+                        width,
+                        height,
+                        imageops::FilterType::Triangle,
+                    ))
+                }
+            })
+            .collect();
+        Self {
+            width,
+            height,
+            layers,
+        }
+    }
+
+    /// Bilinearly filtered colour at `uv` in a layer, with the texture repeating outside of 0 to
+    /// 1 like the sampler the shaders use
+    pub fn sample(&self, layer: u32, [u, v]: [f32; 2]) -> [f32; 4] {
+        let image = &self.layers[layer as usize];
+        // Texel centres are half a texel in from their corners
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |dx: i64, dy: i64| {
+            let tx = (x0 as i64 + dx).rem_euclid(self.width as i64) as u32;
+            let ty = (y0 as i64 + dy).rem_euclid(self.height as i64) as u32;
+            image.get_pixel(tx, ty).0.map(|c| c as f32 / 255.)
+        };
+        let lerp =
+            |a: [f32; 4], b: [f32; 4], t: f32| std::array::from_fn(|i| a[i] + (b[i] - a[i]) * t);
+        let top = lerp(texel(0, 0), texel(1, 0), fx);
+        let bottom = lerp(texel(0, 1), texel(1, 1), fx);
+        lerp(top, bottom, fy)
+    }
+}
@@ -0,0 +1,101 @@
+use std::{fmt, iter, mem, time::Duration};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+// The standard library has no clock on the web
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+/// How long the GPU spent on each stage of tracing, summed over every pass. The megakernel has a
+/// single "trace" stage and the wavefront tracer one for each of its kernels
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StageTimings {
+    pub stages: Vec<(&'static str, Duration)>, // In the order they first ran
+}
+
+impl StageTimings {
+    pub fn get(&self, stage: &str) -> Option<Duration> {
+        self.stages
+            .iter()
+            .find(|(name, _)| *name == stage)
+            .map(|(_, time)| *time)
+    }
+
+    pub fn total(&self) -> Duration {
+        self.stages.iter().map(|(_, time)| *time).sum()
+    }
+
+    fn add(&mut self, stage: &'static str, time: Duration) {
+        match self.stages.iter_mut().find(|(name, _)| *name == stage) {
+            Some((_, total)) => *total += time,
+            None => self.stages.push((stage, time)),
+        }
+    }
+}
+
+impl fmt::Display for StageTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total();
+        for (name, time) in &self.stages {
+            let share = 100. * time.as_secs_f64() / total.as_secs_f64().max(f64::MIN_POSITIVE);
+            write!(f, "{name} {time:.2?} ({share:.0}%), ")?;
+        }
+        write!(f, "{total:.2?} in total")
+    }
+}
+
+/// Records the commands for each stage of tracing, timing them if asked to.
+///
+/// Timed stages are submitted on their own and waited for, so their time is measured on the CPU.
+/// That works on every GPU but adds a little overhead to each stage and stalls the pipeline, so
+/// only time renders that are being compared. The web doesn't allow waiting for the GPU, so the
+/// timings there only cover recording and submitting the commands
+pub(crate) struct StageTimer<'a> {
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    timings: Option<&'a mut StageTimings>,
+}
+
+impl<'a> StageTimer<'a> {
+    pub fn new(
+        device: &'a wgpu::Device,
+        queue: &'a wgpu::Queue,
+        timings: Option<&'a mut StageTimings>,
+    ) -> Self {
+        Self {
+            device,
+            queue,
+            timings,
+        }
+    }
+
+    /// Record a stage into `encoder`. When timing the stage is submitted straight away, and so
+    /// is anything recorded into the encoder before it
+    pub fn stage(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        stage: &'static str,
+        record: impl FnOnce(&mut wgpu::CommandEncoder),
+    ) {
+        let Some(timings) = self.timings.as_deref_mut() else {
+            record(encoder);
+            return;
+        };
+        // Finish off the earlier work so it isn't counted
+        submit_and_wait(self.device, self.queue, encoder);
+        let start = Instant::now();
+        record(encoder);
+        submit_and_wait(self.device, self.queue, encoder);
+        timings.add(stage, start.elapsed());
+    }
+}
+
+// Submit what has been recorded so far, leaving a fresh encoder in its place
+fn submit_and_wait(device: &wgpu::Device, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder) {
+    let fresh = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Stage Encoder"),
+    });
+    let recorded = mem::replace(encoder, fresh);
+    queue.submit(iter::once(recorded.finish()));
+    device.poll(wgpu::Maintain::Wait);
+}
@@ -0,0 +1,1055 @@
+// Scene, intersection and scattering code shared by the megakernel (megakernel.wgsl) and the
+// wavefront kernels (wavefront.wgsl), which are appended to it when their pipelines are created
+
+// ----------------------- RNG Tools ----------------------- 
+struct Rng {
+  state: u32,
+};
+var<private> rng: Rng;
+
+fn init_rng(pixel: vec2u, width: u32, frame_num: u32, user_seed: u32) {
+    // Seed the PRNG using the scalar index of the pixel, the current frame count and the user seed.
+    let seed = (pixel.x + pixel.y * width) ^ jenkins_hash(frame_num ^ jenkins_hash(user_seed));
+    rng.state = jenkins_hash(seed);
+}
+
+// A slightly modified version of the "One-at-a-Time Hash" function by Bob Jenkins.
+// See https://www.burtleburtle.net/bob/hash/doobs.html
+fn jenkins_hash(i: u32) -> u32 {
+    var x = i;
+    x += x << 10u;
+    x ^= x >> 6u;
+    x += x << 3u;
+    x ^= x >> 11u;
+    x += x << 15u;
+    return x;
+}
+
+// The 32-bit "xor" function from Marsaglia G., "Xorshift RNGs", Section 3.
+fn xorshift32() -> u32 {
+    var x = rng.state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    rng.state = x;
+    return x;
+}
+
+// Returns a random float in the range [0...1]. This sets the floating point exponent to zero and
+// sets the most significant 23 bits of a random 32-bit unsigned integer as the mantissa. That
+// generates a number in the range [1, 1.9999999], which is then mapped to [0, 0.9999999] by
+// subtraction. See Ray Tracing Gems II, Section 14.3.4.
+fn rand_f32() -> f32 {
+    return bitcast<f32>(0x3f800000u | (xorshift32() >> 9u)) - 1.;
+}
+
+// ----------------------- Path tracing ----------------------- 
+// Order of members in structs is very important for aligning purposes
+struct CameraUniforms {
+    origin: vec3f,
+    focal_distance: f32,
+    u: vec3f,
+    vfov: f32,
+    v: vec3f,
+    dof_scale: f32,
+    w: vec3f,
+}
+
+struct Sky {
+    zenith: vec3f,
+    horizon: vec3f,
+}
+
+struct Uniforms {
+    camera: CameraUniforms,
+    frame_num: u32,
+    width: u32,
+    height: u32,
+    seed: u32,
+    max_path_length: u32,
+    mesh_node_offset: u32,
+    mesh_object_offset: u32,
+    sky: Sky,
+};
+
+// See material.rs for what each parameter does
+struct Material {
+    albedo: vec3f,
+    transmission: f32,
+    ior: f32,
+    roughness: f32,
+    metalness: f32,
+    specular: f32,
+    emission_colour: vec3f,
+    emission_strength: f32,
+    sheen_colour: vec3f,
+    clearcoat: f32,
+    pattern_colour: vec3f,
+    pattern_scale: f32,
+    absorption: vec3f,
+    clearcoat_roughness: f32,
+    // Layers of `textures` to take the albedo, roughness, metalness and emitted colour from, or
+    // NO_TEXTURE
+    albedo_texture: u32,
+    roughness_texture: u32,
+    metalness_texture: u32,
+    emission_texture: u32,
+    // One of the PATTERN_ constants, see pattern.rs
+    pattern: u32,
+}
+
+struct Sphere {
+    center: vec3f,
+    radius: f32,
+    material: u32,
+    is_selected: u32,
+}
+
+struct Quad {
+    q: vec3f,
+    is_selected: u32,
+    u: vec3f,
+    v: vec3f,
+    normal: vec3f,
+    d: f32,
+    w: vec3f,
+    material: u32,
+}
+
+struct Triangle {
+    a: vec3f,
+    is_selected: u32,
+    b: vec3f,
+    material: u32,
+    c: vec3f,
+    uv_a: vec2f, // Texture coordinates of the corners
+    uv_b: vec2f,
+    uv_c: vec2f,
+}
+
+struct AABB {
+    min: vec3f,
+    left_child_index: u32,
+    max: vec3f,
+    right_child_index: u32,
+    first_object: u32,
+    object_count: u32,
+    is_populated: u32,
+    miss_index: u32,
+}
+
+struct ObjectRef {
+    object_type: u32,
+    object_index: u32,
+}
+
+struct Instance {
+    object_to_world: mat4x4f,
+    world_to_object: mat4x4f,
+    mesh: u32,
+    root_node: u32,
+    material: u32,
+    is_selected: u32,
+}
+
+// Incremented whenever BVH traversal has to give up on part of the tree
+struct TraversalCounters {
+    stack_overflows: atomic<u32>,
+    iteration_limit_hits: atomic<u32>,
+}
+
+@group(0) @binding(0) var radiance_samples_old: texture_2d<f32>;
+@group(0) @binding(1) var radiance_samples_new: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(2) var<uniform> uniforms: Uniforms;
+@group(0) @binding(3) var<storage, read> materials: array<Material>;
+@group(0) @binding(4) var<storage, read> bvh: array<AABB>;
+@group(0) @binding(5) var<storage, read> spheres: array<Sphere>;
+@group(0) @binding(6) var<storage, read> quads: array<Quad>;
+@group(0) @binding(7) var<storage, read> triangles: array<Triangle>;
+@group(0) @binding(8) var<storage, read> objects: array<ObjectRef>;
+@group(0) @binding(9) var<storage, read_write> traversal_counters: TraversalCounters;
+@group(0) @binding(10) var<storage, read> instances: array<Instance>;
+@group(0) @binding(11) var textures: texture_2d_array<f32>;
+@group(0) @binding(12) var texture_sampler: sampler;
+
+
+struct Ray {
+    origin: vec3f,
+    direction: vec3f,
+}
+
+struct Intersection {
+    normal: vec3f,
+    t: f32,
+    material: u32, // Index into materials, see get_material
+    uv: vec2f, // Texture coordinates, 0 to 1 across the surface with v = 0 along the top of images
+}
+
+struct Scatter {
+    attenuation: vec3f,
+    ray: Ray,
+    medium: vec3f, // Absorption of what the ray goes through next, see `transmittance`
+}
+
+const TAU: f32 = 6.283185307;
+const PI: f32 = 3.141592654;
+const F32_MAX: f32 = 3.40282346638528859812e+38;
+const U32_MAX: u32 = 4294967295;
+const EPSILON: f32 = 1e-2;
+const TRIANGLE_EPSILON: f32 = 1e-12;
+
+const QUAD_SELECT_WIDTH: f32 = 0.01;
+
+const OBJECT_TYPE_SPHERE: u32 = 0;
+const OBJECT_TYPE_QUAD: u32 = 1;
+const OBJECT_TYPE_TRIANGLE: u32 = 2;
+const OBJECT_TYPE_INSTANCE: u32 = 3;
+
+const NO_INSTANCE: u32 = U32_MAX;
+const NO_TEXTURE: u32 = U32_MAX;
+
+const PATTERN_SOLID: u32 = 0;
+const PATTERN_CHECKER_WORLD: u32 = 1;
+const PATTERN_CHECKER_UV: u32 = 2;
+const PATTERN_GRADIENT: u32 = 3;
+const PATTERN_NOISE: u32 = 4;
+const PATTERN_MARBLE: u32 = 5;
+
+// Octaves of noise summed up for turbulence, as in Ray Tracing: The Next Week
+const TURBULENCE_DEPTH: u32 = 7;
+
+// Stands in for the material of the edges of selected objects, which are highlighted
+const SELECTED_MATERIAL: u32 = U32_MAX - 1u;
+
+// Hits only record which material they have so they stay small (and so finding them doesn't
+// need the materials bound in the wavefront kernels)
+fn get_material(index: u32) -> Material {
+    if index == SELECTED_MATERIAL {
+        let red = vec3(1., 0., 0.);
+        return Material(
+            red, 0., 1.5, 1., 0., 0., red, 1., vec3(0.), 0., red, 1., vec3(0.), 0.,
+            NO_TEXTURE, NO_TEXTURE, NO_TEXTURE, NO_TEXTURE, PATTERN_SOLID
+        );
+    }
+    return materials[index];
+}
+
+fn sample_texture(layer: u32, uv: vec2f) -> vec4f {
+    // There are no mipmaps and compute shaders can't pick a level themselves anyway
+    return textureSampleLevel(textures, texture_sampler, uv, layer, 0.);
+}
+
+fn srgb_to_linear(c: vec3f) -> vec3f {
+    return select(pow((c + 0.055) / 1.055, vec3(2.4)), c / 12.92, c <= vec3(0.04045));
+}
+
+// Hash a lattice point with the same hash the random numbers are seeded with
+fn hash_lattice(c: vec3i) -> u32 {
+    let u = bitcast<vec3u>(c);
+    return jenkins_hash(u.x ^ jenkins_hash(u.y ^ jenkins_hash(u.z)));
+}
+
+// Dot product of the offset from a lattice point with one of the twelve gradients along the
+// edges of a cube, picked by the hash. From Ken Perlin's "Improving Noise"
+fn gradient_dot(hash: u32, p: vec3f) -> f32 {
+    let h = hash & 15u;
+    let u = select(p.y, p.x, h < 8u);
+    let v = select(select(p.z, p.x, h == 12u || h == 14u), p.y, h < 4u);
+    return select(-u, u, (h & 1u) == 0u) + select(-v, v, (h & 2u) == 0u);
+}
+
+// Perlin noise, between about -1 and 1 and zero on the integer lattice
+fn perlin_noise(p: vec3f) -> f32 {
+    let cell = floor(p);
+    let f = p - cell;
+    let i = vec3i(cell);
+    let s = f * f * f * (f * (f * 6. - 15.) + 10.);
+    var corners: array<f32, 8>;
+    for (var c = 0u; c < 8u; c++) {
+        let offset = vec3u(c & 1u, (c >> 1u) & 1u, c >> 2u);
+        corners[c] = gradient_dot(hash_lattice(i + vec3i(offset)), f - vec3f(offset));
+    }
+    let edges = vec4(
+        mix(corners[0], corners[1], s.x),
+        mix(corners[2], corners[3], s.x),
+        mix(corners[4], corners[5], s.x),
+        mix(corners[6], corners[7], s.x),
+    );
+    let faces = mix(edges.xz, edges.yw, s.y);
+    return mix(faces.x, faces.y, s.z);
+}
+
+// Noise summed over several octaves, each twice the frequency and half the weight of the last
+fn turbulence(p: vec3f) -> f32 {
+    var sum = 0.;
+    var q = p;
+    var weight = 1.;
+    for (var i = 0u; i < TURBULENCE_DEPTH; i++) {
+        sum += weight * abs(perlin_noise(q));
+        weight *= 0.5;
+        q *= 2.;
+    }
+    return sum;
+}
+
+// The albedo of a material at a point in the world with the texture coordinates `uv`, before
+// any albedo texture is applied
+fn pattern_albedo(material: Material, p: vec3f, uv: vec2f) -> vec3f {
+    let scale = material.pattern_scale;
+    var t = 0.;
+    switch material.pattern {
+        case PATTERN_CHECKER_WORLD: {
+            // Squares are centred on the lattice so a floor at y = 0 isn't split between two
+            let cell = vec3i(floor(p * scale + 0.5));
+            t = f32((cell.x + cell.y + cell.z) & 1);
+        }
+        case PATTERN_CHECKER_UV: {
+            let cell = vec2i(floor(uv * scale));
+            t = f32((cell.x + cell.y) & 1);
+        }
+        case PATTERN_GRADIENT: {
+            t = clamp(uv.x, 0., 1.);
+        }
+        case PATTERN_NOISE: {
+            t = min(turbulence(p * scale), 1.);
+        }
+        case PATTERN_MARBLE: {
+            t = 0.5 * (1. + sin(scale * p.z + 10. * turbulence(p)));
+        }
+        default: {}
+    }
+    return mix(material.albedo, material.pattern_colour, t);
+}
+
+// The material of the hit with its pattern and textures applied at the point that was hit
+fn surface_material(ray: Ray, hit: Intersection) -> Material {
+    var material = get_material(hit.material);
+    material.albedo = pattern_albedo(material, point_on_ray(ray, hit.t), hit.uv);
+    if material.albedo_texture != NO_TEXTURE {
+        material.albedo *= srgb_to_linear(sample_texture(material.albedo_texture, hit.uv).rgb);
+    }
+    if material.roughness_texture != NO_TEXTURE {
+        material.roughness *= sample_texture(material.roughness_texture, hit.uv).g;
+    }
+    if material.metalness_texture != NO_TEXTURE {
+        material.metalness *= sample_texture(material.metalness_texture, hit.uv).b;
+    }
+    if material.emission_texture != NO_TEXTURE {
+        let emission = sample_texture(material.emission_texture, hit.uv).rgb;
+        material.emission_colour *= srgb_to_linear(emission);
+    }
+    return material;
+}
+
+fn sky_colour(ray: Ray) ->vec3f {
+    // Get a value that goes from 1 to 0 as you go down
+    let t = 0.5 * (normalize(ray.direction).y + 1.);
+    // Make a vertical linear gradient from the horizon colour to the zenith colour
+    return (1. - t) * uniforms.sky.horizon + t * uniforms.sky.zenith;
+}
+
+// Get the position of point on a ray at a given time
+fn point_on_ray(ray: Ray, t: f32) -> vec3f{
+    return ray.origin + t * ray.direction;
+}
+
+fn generate_random_unit_vector() -> vec3f{
+    return normalize(vec3f(rand_f32()*2.-1., rand_f32()*2.-1., rand_f32()*2.-1.));
+}
+
+fn is_reflective_schlick(cosine: f32, ior: f32) -> bool {
+    var r0 = (1. - ior) / (1. + ior);
+    r0 = r0*r0;
+    return (r0 + (1.-r0)*pow((1. - cosine), 5.)) > rand_f32();
+}
+
+// ----------------------- Microfacets -----------------------
+// Metals, glass and the specular layers on top of other surfaces are made of tiny mirrors facing
+// along a GGX (Trowbridge-Reitz) distribution. Directions are in a frame with the normal along z,
+// see `tangent_frame`
+
+// Squared so roughness looks like it changes evenly
+fn ggx_alpha(roughness: f32) -> f32 {
+    return roughness * roughness;
+}
+
+// An orthonormal frame around a unit normal, from "Building an Orthonormal Basis, Revisited"
+// (Duff et al. 2017)
+fn tangent_frame(n: vec3f) -> mat3x3f {
+    let s = select(-1., 1., n.z >= 0.);
+    let a = -1. / (s + n.z);
+    let b = n.x * n.y * a;
+    return mat3x3f(
+        vec3(1. + s * n.x * n.x * a, s * b, -s * n.x),
+        vec3(b, s + n.y * n.y * a, -n.y),
+        n,
+    );
+}
+
+// How many of the microfacets face along `m`
+fn ggx_distribution(m: vec3f, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = m.z * m.z * (a2 - 1.) + 1.;
+    return a2 / (PI * d * d);
+}
+
+// Smith's Λ, the shadowed area of the microfacets seen from a direction
+fn smith_lambda(cos_theta: f32, alpha: f32) -> f32 {
+    let cos2 = cos_theta * cos_theta;
+    let tan2 = max(1. - cos2, 0.) / max(cos2, 1e-12);
+    return 0.5 * (sqrt(1. + alpha * alpha * tan2) - 1.);
+}
+
+// The masking of microfacets seen from a direction
+fn smith_g1(cos_theta: f32, alpha: f32) -> f32 {
+    return 1. / (1. + smith_lambda(cos_theta, alpha));
+}
+
+// The height correlated masking-shadowing of both directions
+fn smith_g2(wo: vec3f, wi: vec3f, alpha: f32) -> f32 {
+    return 1. / (1. + smith_lambda(wo.z, alpha) + smith_lambda(wi.z, alpha));
+}
+
+// The weight of a direction sampled from the visible normals, which is the height correlated
+// masking-shadowing term over the masking of the outgoing direction alone
+fn ggx_weight(wo: vec3f, wi: vec3f, alpha: f32) -> f32 {
+    let lambda_o = smith_lambda(wo.z, alpha);
+    return (1. + lambda_o) / (1. + lambda_o + smith_lambda(wi.z, alpha));
+}
+
+// A microfacet normal seen from `wo`, picked in proportion to how much of it is visible. From
+// "Sampling the GGX Distribution of Visible Normals" (Heitz 2018)
+fn sample_ggx_normal(wo: vec3f, alpha: f32) -> vec3f {
+    // A perfect mirror only has the one normal
+    if alpha == 0. {
+        return vec3(0., 0., 1.);
+    }
+    // Stretch the view so the microfacets become a hemisphere
+    let v = normalize(vec3(alpha * wo.x, alpha * wo.y, max(wo.z, 0.)));
+    let length2 = v.x * v.x + v.y * v.y;
+    let t1 = select(vec3(1., 0., 0.), vec3(-v.y, v.x, 0.) * inverseSqrt(length2), length2 > 0.);
+    let t2 = cross(v, t1);
+    // Pick a point on the disk facing the view, squashed to the part of it that is visible
+    let r = sqrt(rand_f32());
+    let phi = TAU * rand_f32();
+    let p1 = r * cos(phi);
+    let blend = 0.5 * (1. + v.z);
+    let p2 = (1. - blend) * sqrt(1. - p1 * p1) + blend * r * sin(phi);
+    let n = p1 * t1 + p2 * t2 + sqrt(max(0., 1. - p1 * p1 - p2 * p2)) * v;
+    // And unstretch it
+    return normalize(vec3(alpha * n.x, alpha * n.y, max(n.z, 0.)));
+}
+
+fn schlick_fresnel(f0: vec3f, cos_theta: f32) -> vec3f {
+    return f0 + (1. - f0) * pow(1. - clamp(cos_theta, 0., 1.), 5.);
+}
+
+fn schlick_weight(cos_theta: f32) -> f32 {
+    return pow(1. - clamp(cos_theta, 0., 1.), 5.);
+}
+
+fn luminance(c: vec3f) -> f32 {
+    return dot(c, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// ----------------------- Principled BSDF -----------------------
+// Opaque surfaces are a diffuse base with sheen, under a specular layer that is tinted by the
+// base colour as they get more metallic, under a clear coat. Light reflects off one layer or
+// another, picked in proportion to roughly how much each reflects from where the ray comes from,
+// and is weighted by the whole BSDF over the chance of picking the direction any of the ways
+
+// The smallest GGX alpha the layers of opaque surfaces are sampled with, which keeps their
+// distributions finite so they can be mixed
+const MIN_LAYER_ALPHA: f32 = 1e-3;
+// The clear coat is like a varnish with an IOR of 1.5
+const CLEARCOAT_F0: f32 = 0.04;
+
+// The parts of an opaque surface, as seen from `wo`
+struct Lobes {
+    metalness: f32,         // Of the opaque part of the material
+    dielectric_f0: f32,     // Reflectance head on the IOR gives the specular layer
+    diffuse: f32,           // How much light the specular layer lets through to the base
+    base: f32,              // How much light the clear coat lets through
+    alpha: f32,             // Of the specular layer
+    clearcoat_alpha: f32,
+    probabilities: vec3f,   // Of sampling the diffuse base, specular layer and clear coat
+}
+
+fn opaque_lobes(wo: vec3f, material: Material) -> Lobes {
+    // Metals that let light through are opaque, so the part of the material that ends up here
+    // is more metallic than the whole of it
+    let opaque = 1. - (1. - material.metalness) * material.transmission;
+    let metalness = select(0., material.metalness / opaque, opaque > 0.);
+    let r0 = (material.ior - 1.) / (material.ior + 1.);
+    let dielectric_f0 = r0 * r0;
+    let dielectric = material.specular * schlick_fresnel(vec3(dielectric_f0), wo.z).x;
+    let diffuse = (1. - metalness) * (1. - dielectric);
+    let coat = material.clearcoat * schlick_fresnel(vec3(CLEARCOAT_F0), wo.z).x;
+    let base = 1. - coat;
+    let specular = mix(vec3(dielectric), schlick_fresnel(material.albedo, wo.z), metalness);
+    let probabilities = vec3(
+        base * diffuse * (luminance(material.albedo) + luminance(material.sheen_colour)),
+        base * luminance(specular),
+        coat,
+    );
+    return Lobes(
+        metalness,
+        dielectric_f0,
+        diffuse,
+        base,
+        max(ggx_alpha(material.roughness), MIN_LAYER_ALPHA),
+        max(ggx_alpha(material.clearcoat_roughness), MIN_LAYER_ALPHA),
+        probabilities / max(probabilities.x + probabilities.y + probabilities.z, 1e-12),
+    );
+}
+
+// The BSDF times the cosine of `wi` and the chance of sampling `wi`, in the local frame
+fn evaluate_opaque(wo: vec3f, wi: vec3f, material: Material, lobes: Lobes) -> vec4f {
+    let h = normalize(wo + wi);
+    let cos_d = dot(wi, h);
+    let wo_z = max(wo.z, 1e-6);
+
+    let diffuse = lobes.diffuse * (material.albedo / PI + material.sheen_colour * schlick_weight(cos_d)) * wi.z;
+    let d = ggx_distribution(h, lobes.alpha);
+    let fresnel = mix(
+        vec3(material.specular * schlick_fresnel(vec3(lobes.dielectric_f0), cos_d).x),
+        schlick_fresnel(material.albedo, cos_d),
+        lobes.metalness,
+    );
+    let specular = fresnel * d * smith_g2(wo, wi, lobes.alpha) / (4. * wo_z);
+    let d_coat = ggx_distribution(h, lobes.clearcoat_alpha);
+    let coat = material.clearcoat * schlick_fresnel(vec3(CLEARCOAT_F0), cos_d).x * d_coat * smith_g2(wo, wi, lobes.clearcoat_alpha) / (4. * wo_z);
+
+    let pdf = lobes.probabilities.x * wi.z / PI
+        + lobes.probabilities.y * smith_g1(wo.z, lobes.alpha) * d / (4. * wo_z)
+        + lobes.probabilities.z * smith_g1(wo.z, lobes.clearcoat_alpha) * d_coat / (4. * wo_z);
+    return vec4(lobes.base * (diffuse + specular) + coat, pdf);
+}
+
+fn opaque_scatter(input_ray: Ray, hit: Intersection, material: Material, medium: vec3f) -> Scatter {
+    // Opaque surfaces are two sided, so reflect off whichever side was hit (mesh winding varies)
+    let normal = faceForward(hit.normal, input_ray.direction, hit.normal);
+    let frame = tangent_frame(normal);
+    let wo = -normalize(input_ray.direction) * frame;
+    let lobes = opaque_lobes(wo, material);
+
+    var wi: vec3f;
+    let pick = rand_f32();
+    if pick < lobes.probabilities.x {
+        let reflected = normal + generate_random_unit_vector();
+        wi = select(vec3(0., 0., 1.), normalize(reflected) * frame, dot(reflected, reflected) > 1e-12);
+    } else if pick < lobes.probabilities.x + lobes.probabilities.y {
+        wi = reflect(-wo, sample_ggx_normal(wo, lobes.alpha));
+    } else {
+        wi = reflect(-wo, sample_ggx_normal(wo, lobes.clearcoat_alpha));
+    }
+
+    // Reflections off the microfacets that end up below the surface are lost
+    var attenuation = vec3(0.);
+    if wi.z > 0. {
+        let value = evaluate_opaque(wo, wi, material, lobes);
+        if value.w > 0. {
+            attenuation = value.xyz / value.w;
+        }
+    }
+    // Bump the start of the reflected ray a little bit off the surface to
+    // try to minimize self intersections due to floating point errors
+    let output_ray = Ray(point_on_ray(input_ray, hit.t) + normal * EPSILON, frame * wi);
+    return Scatter(attenuation, output_ray, medium);
+}
+
+fn dielectric_scatter(input_ray: Ray, hit: Intersection, material: Material, medium: vec3f) -> Scatter {
+    // Figure out which side of the surface we are hitting
+    let normal = faceForward(hit.normal, input_ray.direction, hit.normal);
+    // Ratio of the indices of refraction on either side
+    let entering = dot(input_ray.direction, hit.normal) < 0.;
+    let eta = select(material.ior, 1. / material.ior, entering);
+
+    let frame = tangent_frame(normal);
+    let wo = -normalize(input_ray.direction) * frame;
+    let alpha = ggx_alpha(material.roughness);
+    // Light reflects off or refracts through one of the microfacets
+    let m = sample_ggx_normal(wo, alpha);
+    var wi = refract(-wo, m, eta);
+    let cos_theta = min(dot(wo, m), 1.0);
+
+    var output_ray: Ray;
+    var valid: bool;
+    var next_medium = medium;
+    // If angle is less than the critical angle, reflection occurs instead and refract returns vec3(0.)
+    if (wi.x == 0. && wi.y == 0. && wi.z == 0.) || is_reflective_schlick(cos_theta, material.ior) {
+        wi = reflect(-wo, m);
+        valid = wi.z > 0.;
+        output_ray = Ray(point_on_ray(input_ray, hit.t) + normal * EPSILON, frame * wi);
+    } else {
+        valid = wi.z < 0.;
+        output_ray = Ray(point_on_ray(input_ray, hit.t), frame * wi);
+        // Objects can't overlap, so a ray coming out of one goes back into the air
+        next_medium = select(vec3(0.), material.absorption, entering);
+    }
+
+    // Fresnel already picked between reflection and refraction, so only the masking is left. As
+    // with metals, light a microfacet sends out the wrong side of the surface is lost. Any tint
+    // comes from the absorption along the way through
+    var attenuation = vec3(0.);
+    if valid {
+        attenuation = vec3(ggx_weight(wo, wi, alpha));
+    }
+    return Scatter(attenuation, output_ray, next_medium);
+}
+
+// `medium` is the absorption of what the ray went through to get to the hit
+fn scatter(input_ray: Ray, hit: Intersection, material: Material, ray_medium: vec3f) -> Scatter {
+    let medium = medium_at_hit(ray_medium, input_ray, hit);
+    // Probability of going into the glass, which metals never do
+    if (1. - material.metalness) * material.transmission > rand_f32() {
+        return dielectric_scatter(input_ray, hit, material, medium);
+    }
+    return opaque_scatter(input_ray, hit, material, medium);
+}
+
+// The absorption of what a ray went through to get to the hit. Only rays that leave through the
+// back of a surface were inside it, so a ray that reaches the front of one came through the air.
+// That way a ray that went into an open surface (or one wound inside out) isn't left absorbing
+// light through the air after it, though only closed, outward facing objects absorb correctly
+fn medium_at_hit(medium: vec3f, ray: Ray, hit: Intersection) -> vec3f {
+    return select(vec3(0.), medium, dot(ray.direction, hit.normal) > 0.);
+}
+
+// How much light is left after going from the ray's origin to the hit through a medium that
+// absorbs `medium` of it per unit of distance (Beer-Lambert law)
+fn transmittance(medium: vec3f, ray: Ray, hit: Intersection) -> vec3f {
+    return exp(-medium_at_hit(medium, ray, hit) * hit.t * length(ray.direction));
+}
+
+// Create an empty intersection
+fn no_intersection() -> Intersection {
+    return Intersection(vec3(0.), -1., 0u, vec2(0.));
+}
+
+// Calculate if an intersection has occured
+fn is_intersection(hit: Intersection) -> bool {
+    return hit.t > 0.;
+}
+
+fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
+    let v = ray.origin - sphere.center;
+    let a = dot(ray.direction, ray.direction);
+    let b = dot(v, ray.direction);
+    let c = dot(v, v) - sphere.radius * sphere.radius;
+
+    // Find roots for the quadratic
+    let d = b * b - a * c;
+
+    // If no roots are found, the ray does not intersect with the sphere
+    if d < 0. {
+        return no_intersection();
+    }
+
+    // If there is a real solution, find the time at which it takes place
+    let sqrt_d = sqrt(d);
+    let recip_a = 1. / a;
+    let mb = -b;
+    let t1 = (mb - sqrt_d) * recip_a;
+    let t2 = (mb + sqrt_d) * recip_a;
+    let t = select(t2, t1, t1 > EPSILON);
+    if t <= EPSILON {
+        // Check if the solution is for time = 0
+        return no_intersection();
+    }
+
+    let p = point_on_ray(ray, t);
+    let N = (p - sphere.center) / sphere.radius;
+    let uv = sphere_uv(N);
+
+    // Highlight edges of selected object
+    if (sphere.is_selected > 0) && (d <= (.05 * sphere.radius)) {
+        return Intersection(N, t, SELECTED_MATERIAL, uv);
+    }
+
+    return Intersection(N, t, sphere.material, uv);
+}
+
+// Map a point on a sphere to longitude (around y, starting behind it at -x) and latitude (from
+// the top down), so an equirectangular image wraps around it
+fn sphere_uv(normal: vec3f) -> vec2f {
+    let longitude = atan2(-normal.z, normal.x) / TAU + 0.5;
+    let latitude = acos(clamp(normal.y, -1., 1.)) / (TAU / 2.);
+    return vec2(longitude, latitude);
+}
+
+fn intersect_quad(ray: Ray, quad: Quad) -> Intersection {
+    // Check if ray interects the plane
+    let denom = dot(quad.normal, ray.direction);
+
+    // Ray is parallel to the plane
+    if abs(denom) < 1e-8 {
+        return no_intersection();
+    }
+
+    let t = (quad.d - dot(quad.normal, ray.origin)) / denom;
+    if t < EPSILON {
+        return no_intersection();
+    }
+
+    let p = point_on_ray(ray, t);
+
+    // Check if the intersection is within the quad
+    let planar_to_hit = p - quad.q;
+    let alpha = dot(quad.w, cross(planar_to_hit, quad.v));
+    let beta = dot(quad.w, cross(quad.u, planar_to_hit));
+
+    if !(0 <= alpha && alpha <= 1 && 0 <= beta && beta <= 1) {
+        return no_intersection();
+    }
+
+    // Images are upright when u points right and v up, so q is at the bottom left of them
+    let uv = vec2(alpha, 1. - beta);
+    if quad.is_selected > 0 && (alpha > (1.-QUAD_SELECT_WIDTH) ||  alpha < QUAD_SELECT_WIDTH || beta > (1.-QUAD_SELECT_WIDTH) ||  beta < QUAD_SELECT_WIDTH) {
+        return Intersection(quad.normal, t, SELECTED_MATERIAL, uv);
+    }
+
+    return Intersection(quad.normal, t, quad.material, uv);
+}
+
+fn intersect_tri(ray: Ray, triangle: Triangle) -> Intersection {
+    // Möller–Trumbore
+    let e1 = triangle.b - triangle.a;
+    let e2 = triangle.c - triangle.a;
+
+    let ray_cross_e2 = cross(ray.direction, e2);
+    let det = dot(e1, ray_cross_e2);
+
+    // The determinant scales with the area of the triangle so this has to be tiny for small
+    // triangles in dense meshes to still be hit
+    if abs(det) < TRIANGLE_EPSILON {
+        return no_intersection(); // Ray is parallel to the triangle
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.origin - triangle.a;
+    let u = inv_det * dot(s, ray_cross_e2);
+    if u < 0.0 || 1.0 < u {
+        return no_intersection();
+    }
+
+    let s_cross_e1 = cross(s, e1);
+    let v = inv_det * dot(ray.direction, s_cross_e1);
+    if v < 0.0 || u + v > 1.0 {
+        return no_intersection();
+    }
+    
+	// At this stage we can compute t to find out where the intersection point is on the line.
+	let t = inv_det * dot(e2, s_cross_e1);
+
+	if t > EPSILON { // ray intersection
+        // u and v are the barycentric coordinates of the hit on b and c
+        let uv = (1. - u - v) * triangle.uv_a + u * triangle.uv_b + v * triangle.uv_c;
+        return Intersection(normalize(cross(e1, e2)), t, triangle.material, uv);
+	}
+
+    return no_intersection();
+}
+
+// Returns the distance along the ray to where it enters the box, or F32_MAX if it misses. Rays
+// starting inside the box enter it at 0
+fn intersect_aabb(ray: Ray, node: AABB) -> f32 {
+    let t_aabb_min = vec3(
+        (node.min.x - ray.origin.x)/ray.direction.x,
+        (node.min.y - ray.origin.y)/ray.direction.y,
+        (node.min.z - ray.origin.z)/ray.direction.z
+    );
+
+    let t_aabb_max = vec3(
+        (node.max.x - ray.origin.x)/ray.direction.x,
+        (node.max.y - ray.origin.y)/ray.direction.y,
+        (node.max.z - ray.origin.z)/ray.direction.z
+    );
+
+    // If the ray is coming in the opposite direction, the min and max values
+    // stored by the aabb will be reversed from our perspective
+    let t_min = min(t_aabb_min, t_aabb_max);
+    let t_max = max(t_aabb_min, t_aabb_max);
+
+    // Compare the biggest t_min and the smallest t_max to see 
+    // if there exists a time when the ray is within all dimensions
+    let max_t_min = max(max(t_min.x, t_min.y), t_min.z);
+    let min_t_max = min(min(t_max.x, t_max.y), t_max.z);
+
+    if min_t_max < 0. || max_t_min >= min_t_max {
+        return F32_MAX;
+    }
+    return max(max_t_min, 0.);
+}
+
+// A node waiting to be visited, along with where the ray enters it so it can be skipped once
+// something closer has been hit. Nodes of a mesh BVH also record which instance of the mesh they
+// are being visited for
+struct StackEntry {
+    node_index: u32,
+    t: f32,
+    instance_index: u32,
+}
+
+// Move a ray into the space of a mesh instance. The direction isn't normalised so distances
+// along the ray are the same in both spaces
+fn transform_ray(ray: Ray, instance: Instance) -> Ray {
+    return Ray(
+        (instance.world_to_object * vec4(ray.origin, 1.)).xyz,
+        (instance.world_to_object * vec4(ray.direction, 0.)).xyz,
+    );
+}
+
+fn intersect_primitive(ray: Ray, object: ObjectRef) -> Intersection {
+    if object.object_type == OBJECT_TYPE_SPHERE {
+        return intersect_sphere(ray, spheres[object.object_index]);
+    } else if object.object_type == OBJECT_TYPE_QUAD {
+        return intersect_quad(ray, quads[object.object_index]);
+    } else if object.object_type == OBJECT_TYPE_TRIANGLE {
+        return intersect_tri(ray, triangles[object.object_index]);
+    }
+    return no_intersection();
+}
+
+// Test a sphere, quad or triangle, keeping the hit if it is the closest one so far. Hits on a
+// mesh instance are moved back into world space
+fn intersect_leaf_object(
+    ray: Ray,
+    object: ObjectRef,
+    instance_index: u32,
+    closest_hit: ptr<function, Intersection>,
+) {
+    var hit = intersect_primitive(ray, object);
+    if hit.t <= 0. || hit.t >= (*closest_hit).t {
+        return;
+    }
+    if instance_index != NO_INSTANCE {
+        let instance = instances[instance_index];
+        // Normals are transformed by the inverse transpose of the object to world matrix
+        hit.normal = normalize((vec4(hit.normal, 0.) * instance.world_to_object).xyz);
+        if instance.material != U32_MAX {
+            hit.material = instance.material;
+        }
+    }
+    *closest_hit = hit;
+}
+
+fn intersect_scene(ray: Ray) -> Intersection {
+    // BVH_STACKLESS is prepended to the shader along with BVH_STACK_SIZE when the pipeline is
+    // created, so only one of these is compiled into the pipeline
+    if BVH_STACKLESS {
+        return intersect_scene_stackless(ray);
+    }
+    return intersect_scene_with_stack(ray);
+}
+
+fn intersect_scene_with_stack(world_ray: Ray) -> Intersection {
+    // BVH_STACK_SIZE is prepended to the shader when the pipeline is created, it is at least the
+    // depth of the BVH so the stack only overflows if the tree is deeper than the GPU can follow
+    var node_stack: array<StackEntry, BVH_STACK_SIZE>;
+    var stack_index = 0u;
+
+    var closest_hit = no_intersection();
+    closest_hit.t = F32_MAX;
+
+    if bvh[0].is_populated == 0 {
+        // There is nothing in the scene
+        return no_intersection();
+    }
+    let root_t = intersect_aabb(world_ray, bvh[0]);
+    if root_t < F32_MAX {
+        node_stack[0] = StackEntry(0u, root_t, NO_INSTANCE);
+        stack_index = 1u;
+    }
+
+    // The ray in the space of the instance whose mesh is being walked
+    var ray = world_ray;
+    var instance_index = NO_INSTANCE;
+
+    // Every node is visited at most once per instance, so this only stops a broken BVH from
+    // looping forever
+    let max_iterations = arrayLength(&bvh) * max(arrayLength(&instances), 1u);
+    for (var i = 0u; i < max_iterations && stack_index > 0u; i++) {
+        stack_index -= 1u;
+        let entry = node_stack[stack_index];
+        if entry.t >= closest_hit.t {
+            // Everything in this node is further away than what we have already hit
+            continue;
+        }
+        if entry.instance_index != instance_index {
+            instance_index = entry.instance_index;
+            if instance_index == NO_INSTANCE {
+                ray = world_ray;
+            } else {
+                ray = transform_ray(world_ray, instances[instance_index]);
+            }
+        }
+        // Indices in a mesh BVH are relative to where the mesh BVHs start
+        let node_offset = select(uniforms.mesh_node_offset, 0u, instance_index == NO_INSTANCE);
+        let object_offset = select(uniforms.mesh_object_offset, 0u, instance_index == NO_INSTANCE);
+
+        let node = bvh[entry.node_index];
+        if node.object_count > 0 {
+            let first = node.first_object + object_offset;
+            for (var j = first; j < first + node.object_count; j++) {
+                let object = objects[j];
+                if object.object_type != OBJECT_TYPE_INSTANCE {
+                    intersect_leaf_object(ray, object, instance_index, &closest_hit);
+                    continue;
+                }
+                // Carry on down the instance's mesh BVH
+                let root_index = uniforms.mesh_node_offset + instances[object.object_index].root_node;
+                let mesh_ray = transform_ray(world_ray, instances[object.object_index]);
+                let t = intersect_aabb(mesh_ray, bvh[root_index]);
+                if t < closest_hit.t {
+                    if stack_index < BVH_STACK_SIZE {
+                        node_stack[stack_index] = StackEntry(root_index, t, object.object_index);
+                        stack_index += 1u;
+                    } else {
+                        atomicAdd(&traversal_counters.stack_overflows, 1u);
+                    }
+                }
+            }
+            continue;
+        }
+
+        // Push the further child first so the nearer one is visited next, hits in it let the
+        // further one be culled
+        let left_index = node.left_child_index + node_offset;
+        let right_index = node.right_child_index + node_offset;
+        var near = StackEntry(left_index, intersect_aabb(ray, bvh[left_index]), instance_index);
+        var far = StackEntry(right_index, intersect_aabb(ray, bvh[right_index]), instance_index);
+        if far.t < near.t {
+            let swap = near;
+            near = far;
+            far = swap;
+        }
+        if far.t < closest_hit.t {
+            if stack_index < BVH_STACK_SIZE {
+                node_stack[stack_index] = far;
+                stack_index += 1u;
+            } else {
+                atomicAdd(&traversal_counters.stack_overflows, 1u);
+            }
+        }
+        if near.t < closest_hit.t {
+            if stack_index < BVH_STACK_SIZE {
+                node_stack[stack_index] = near;
+                stack_index += 1u;
+            } else {
+                atomicAdd(&traversal_counters.stack_overflows, 1u);
+            }
+        }
+    }
+    if stack_index > 0u {
+        atomicAdd(&traversal_counters.iteration_limit_hits, 1u);
+    }
+
+    if closest_hit.t < F32_MAX {
+        return closest_hit;
+    }
+    return no_intersection();
+}
+
+fn intersect_scene_stackless(ray: Ray) -> Intersection {
+    var closest_hit = no_intersection();
+    closest_hit.t = F32_MAX;
+
+    if bvh[0].is_populated == 0 {
+        // There is nothing in the scene
+        return no_intersection();
+    }
+
+    // Walk the nodes depth first, going down to the left child of every node the ray hits and
+    // skipping over the subtree of every node it misses (or only enters beyond the closest hit)
+    var node_index = 0u;
+    for (var i = 0u; i < arrayLength(&bvh) && node_index != U32_MAX; i++) {
+        let node = bvh[node_index];
+        if intersect_aabb(ray, node) >= closest_hit.t {
+            node_index = node.miss_index;
+        } else if node.object_count > 0 {
+            for (var j = node.first_object; j < node.first_object + node.object_count; j++) {
+                let object = objects[j];
+                if object.object_type == OBJECT_TYPE_INSTANCE {
+                    intersect_instance_stackless(ray, object.object_index, &closest_hit);
+                } else {
+                    intersect_leaf_object(ray, object, NO_INSTANCE, &closest_hit);
+                }
+            }
+            node_index = node.miss_index;
+        } else {
+            node_index = node.left_child_index;
+        }
+    }
+    if node_index != U32_MAX {
+        atomicAdd(&traversal_counters.iteration_limit_hits, 1u);
+    }
+
+    if closest_hit.t < F32_MAX {
+        return closest_hit;
+    }
+    return no_intersection();
+}
+
+// Walk the BVH of an instance's mesh the same way as the top level one
+fn intersect_instance_stackless(
+    world_ray: Ray,
+    instance_index: u32,
+    closest_hit: ptr<function, Intersection>,
+) {
+    let instance = instances[instance_index];
+    let ray = transform_ray(world_ray, instance);
+    let offset = uniforms.mesh_node_offset;
+
+    // Miss links in a mesh BVH are relative to where the mesh BVHs start, except for the end
+    var node_index = offset + instance.root_node;
+    for (var i = 0u; i < arrayLength(&bvh) && node_index != U32_MAX; i++) {
+        let node = bvh[node_index];
+        var next = node.miss_index;
+        if intersect_aabb(ray, node) < (*closest_hit).t {
+            if node.object_count > 0 {
+                let first = node.first_object + uniforms.mesh_object_offset;
+                for (var j = first; j < first + node.object_count; j++) {
+                    intersect_leaf_object(ray, objects[j], instance_index, closest_hit);
+                }
+            } else {
+                next = node.left_child_index;
+            }
+        }
+        node_index = select(next + offset, U32_MAX, next == U32_MAX);
+    }
+    if node_index != U32_MAX {
+        atomicAdd(&traversal_counters.iteration_limit_hits, 1u);
+    }
+}
+
+fn get_random_in_unit_disk() -> vec3f {
+    let r = rand_f32() * 2. - 1.;
+    let theta = rand_f32() * TAU;
+    return vec3(r*cos(theta), r*sin(theta), 0.);
+}
+
+// Make a ray through a point on the image plane in pixels, jittered around it to antialias the
+// image and spread over the lens to give depth of field
+fn camera_ray(pos: vec2f) -> Ray {
+    let aspect_ratio = f32(uniforms.width) / f32(uniforms.height);
+
+    let offset = get_random_in_unit_disk().xy;
+    // Add some jitter and normalize the viewport coordinates (0,0 top left; 1,1 bottom right)
+    var uv = (pos + offset) / vec2f(f32(uniforms.width-1u), f32(uniforms.height-1u));
+
+    // Map 'uv' from y-down (normalized) viewport coordinates to camera coordinates
+    // (y-up, x-right, right hand, screen height is 2 units)
+    uv = (2. * uv - vec2(1.)) * vec2(aspect_ratio,  -1.);
+
+    let viewport_scale_factor = 2. * uniforms.camera.focal_distance * tan(uniforms.camera.vfov/2.);
+
+    uv *= vec2(viewport_scale_factor);
+
+    // Compute the spheres-space ray direction by rotating the camera-space vector into a new basis
+    let camera_rotation = mat3x3(uniforms.camera.u, uniforms.camera.v, uniforms.camera.w);
+    let dof_offset = camera_rotation * get_random_in_unit_disk() * uniforms.camera.dof_scale;
+    let direction = camera_rotation * vec3(uv, uniforms.camera.focal_distance) - dof_offset;
+    let origin = uniforms.camera.origin + dof_offset;
+    return Ray(origin, normalize(direction));
+}
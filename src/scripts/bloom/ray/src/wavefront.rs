@@ -0,0 +1,446 @@
+use std::num::NonZeroU64;
+
+use crate::{
+    bvh::BvhTraversal,
+    gpu_tracer::{create_trace_shader, TraceBindings},
+    timings::StageTimer,
+    Uniforms,
+};
+
+// Have to stay in sync with wavefront.wgsl
+const WORKGROUP_SIZE: u32 = 64;
+const PATH_SIZE: u64 = 48;
+const QUEUED_RAY_SIZE: u64 = 64;
+const RAY_QUEUE_HEADER_SIZE: u64 = 16; // The length, padded to the alignment of the rays
+
+// Most paths traced at once, bigger images are traced in chunks of this many pixels. This keeps
+// every dispatch within the 65535 workgroups allowed along x
+const MAX_CHUNK_SIZE: u32 = 1 << 20;
+// The parameters for each chunk are at a multiple of this, the alignment of uniform buffer offsets
+const PARAMS_STRIDE: u64 = 256;
+
+// Bindings in trace.wgsl and wavefront.wgsl each group of kernels uses. Only 8 storage buffers can
+// be bound for a kernel, so extend can't have the materials or the paths alongside the scene.
+// generate, prepare_dispatch and shade share the path bindings
+const PATH_BINDINGS: [u32; 9] = [2, 3, 11, 12, 13, 14, 15, 16, 17];
+const EXTEND_BINDINGS: [u32; 9] = [2, 4, 5, 6, 7, 8, 9, 10, 14];
+const CONNECT_BINDINGS: [u32; 5] = [0, 1, 2, 13, 17];
+
+/// Same as `WavefrontParams` in wavefront.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct WavefrontParams {
+    first_pixel: u32,
+    path_count: u32,
+    _padding: [u32; 2],
+}
+
+/// Traces paths a bounce at a time with the kernels in wavefront.wgsl, see `TraceMode::Wavefront`
+pub(crate) struct Wavefront {
+    pixel_count: u32,
+    chunk_size: u32,
+    buffers: Buffers,
+    layouts: Layouts,
+    kernels: Kernels,
+    bind_groups: BindGroups,
+}
+
+struct Buffers {
+    params: wgpu::Buffer,
+    paths: wgpu::Buffer,
+    // The rays being traced on this bounce and the ones queued for the next, which swap places
+    // every bounce
+    ray_queues: [wgpu::Buffer; 2],
+    dispatch_args: wgpu::Buffer,
+}
+
+struct Layouts {
+    path: wgpu::BindGroupLayout,
+    extend: wgpu::BindGroupLayout,
+    connect: wgpu::BindGroupLayout,
+}
+
+struct Kernels {
+    generate: wgpu::ComputePipeline,
+    prepare_dispatch: wgpu::ComputePipeline,
+    extend: wgpu::ComputePipeline,
+    shade: wgpu::ComputePipeline,
+    connect: wgpu::ComputePipeline,
+}
+
+struct BindGroups {
+    // Indexed by which ray queue is being traced
+    path: [wgpu::BindGroup; 2],
+    extend: [wgpu::BindGroup; 2],
+    // Indexed by which accumulation texture is read from, like the megakernel's
+    connect: [wgpu::BindGroup; 2],
+}
+
+impl Wavefront {
+    pub fn new(
+        device: &wgpu::Device,
+        pixel_count: u32,
+        bindings: &TraceBindings,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) -> Self {
+        let chunk_size = pixel_count.clamp(1, MAX_CHUNK_SIZE);
+        let buffers = Buffers::new(device, pixel_count, chunk_size);
+        let layouts = Layouts::new(device);
+        let kernels = Kernels::new(device, &layouts, bvh_traversal, bvh_stack_size);
+        let bind_groups = layouts.create_bind_groups(device, &buffers, bindings);
+        Self {
+            pixel_count,
+            chunk_size,
+            buffers,
+            layouts,
+            kernels,
+            bind_groups,
+        }
+    }
+
+    pub fn recreate_bind_groups(&mut self, device: &wgpu::Device, bindings: &TraceBindings) {
+        self.bind_groups = self
+            .layouts
+            .create_bind_groups(device, &self.buffers, bindings);
+    }
+
+    /// Size the paths and queues for a new number of pixels, `bindings` has the new textures
+    pub fn resize(&mut self, device: &wgpu::Device, pixel_count: u32, bindings: &TraceBindings) {
+        self.pixel_count = pixel_count;
+        self.chunk_size = pixel_count.clamp(1, MAX_CHUNK_SIZE);
+        self.buffers = Buffers::new(device, pixel_count, self.chunk_size);
+        self.recreate_bind_groups(device, bindings);
+    }
+
+    pub fn recreate_pipelines(
+        &mut self,
+        device: &wgpu::Device,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) {
+        self.kernels = Kernels::new(device, &self.layouts, bvh_traversal, bvh_stack_size);
+    }
+
+    /// Trace a path through every pixel, a chunk of the image at a time
+    pub fn trace(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        timer: &mut StageTimer,
+        uniforms: &Uniforms,
+    ) {
+        let Buffers {
+            ray_queues,
+            dispatch_args,
+            ..
+        } = &self.buffers;
+        let kernels = &self.kernels;
+        let bind_groups = &self.bind_groups;
+
+        for (chunk, first_pixel) in (0..self.pixel_count)
+            .step_by(self.chunk_size as usize)
+            .enumerate()
+        {
+            let params_offset = [(chunk as u64 * PARAMS_STRIDE) as u32];
+            let path_count = self.chunk_size.min(self.pixel_count - first_pixel);
+            let workgroups = path_count.div_ceil(WORKGROUP_SIZE);
+
+            timer.stage(encoder, "generate", |encoder| {
+                encoder.clear_buffer(&ray_queues[0], 0, Some(RAY_QUEUE_HEADER_SIZE));
+                let mut pass = begin_pass(encoder, "Generate Pass");
+                pass.set_pipeline(&kernels.generate);
+                pass.set_bind_group(0, &bind_groups.path[0], &params_offset);
+                pass.dispatch_workgroups(workgroups, 1, 1);
+            });
+
+            // The rays left are only counted on the GPU, so extend and shade are dispatched
+            // indirectly with as many workgroups as they need
+            for bounce in 0..uniforms.max_path_length {
+                let current = (bounce % 2) as usize;
+                timer.stage(encoder, "extend", |encoder| {
+                    let mut pass = begin_pass(encoder, "Extend Pass");
+                    pass.set_pipeline(&kernels.prepare_dispatch);
+                    pass.set_bind_group(0, &bind_groups.path[current], &params_offset);
+                    pass.dispatch_workgroups(1, 1, 1);
+                    pass.set_pipeline(&kernels.extend);
+                    pass.set_bind_group(0, &bind_groups.extend[current], &[]);
+                    pass.dispatch_workgroups_indirect(dispatch_args, 0);
+                });
+                timer.stage(encoder, "shade", |encoder| {
+                    let next = &ray_queues[1 - current];
+                    encoder.clear_buffer(next, 0, Some(RAY_QUEUE_HEADER_SIZE));
+                    let mut pass = begin_pass(encoder, "Shade Pass");
+                    pass.set_pipeline(&kernels.shade);
+                    pass.set_bind_group(0, &bind_groups.path[current], &params_offset);
+                    pass.dispatch_workgroups_indirect(dispatch_args, 0);
+                });
+            }
+
+            timer.stage(encoder, "connect", |encoder| {
+                let mut pass = begin_pass(encoder, "Connect Pass");
+                pass.set_pipeline(&kernels.connect);
+                let textures = (uniforms.frame_num % 2) as usize;
+                pass.set_bind_group(0, &bind_groups.connect[textures], &params_offset);
+                pass.dispatch_workgroups(workgroups, 1, 1);
+            });
+        }
+    }
+}
+
+impl Buffers {
+    fn new(device: &wgpu::Device, pixel_count: u32, chunk_size: u32) -> Self {
+        // The chunks never change, so their parameters are only written once
+        let chunk_count = pixel_count.div_ceil(chunk_size).max(1);
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Wavefront Params"),
+            size: chunk_count as u64 * PARAMS_STRIDE,
+            usage: wgpu::BufferUsages::UNIFORM,
+            mapped_at_creation: true,
+        });
+        {
+            let mut mapped = params.slice(..).get_mapped_range_mut();
+            for chunk in 0..chunk_count {
+                let first_pixel = chunk * chunk_size;
+                let chunk_params = WavefrontParams {
+                    first_pixel,
+                    path_count: chunk_size.min(pixel_count.saturating_sub(first_pixel)),
+                    _padding: [0; 2],
+                };
+                let offset = (chunk as u64 * PARAMS_STRIDE) as usize;
+                let bytes = bytemuck::bytes_of(&chunk_params);
+                mapped[offset..offset + bytes.len()].copy_from_slice(bytes);
+            }
+        }
+        params.unmap();
+
+        let ray_queue = || {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Wavefront Ray Queue"),
+                size: RAY_QUEUE_HEADER_SIZE + chunk_size as u64 * QUEUED_RAY_SIZE,
+                // Only the length is ever cleared by a copy
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        Self {
+            params,
+            paths: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Wavefront Paths"),
+                size: chunk_size as u64 * PATH_SIZE,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }),
+            ray_queues: [ray_queue(), ray_queue()],
+            dispatch_args: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Wavefront Dispatch Args"),
+                size: 3 * std::mem::size_of::<u32>() as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+                mapped_at_creation: false,
+            }),
+        }
+    }
+
+    // The resource for a binding in wavefront.wgsl, with `current_queue` the ray queue being
+    // traced. Bindings in trace.wgsl come from `bindings`
+    fn entry<'a>(
+        &'a self,
+        bindings: &'a TraceBindings,
+        binding: u32,
+        current_queue: usize,
+        swap_textures: bool,
+    ) -> wgpu::BindGroupEntry<'a> {
+        let resource = match binding {
+            13 => self.paths.as_entire_binding(),
+            14 => self.ray_queues[current_queue].as_entire_binding(),
+            15 => self.ray_queues[1 - current_queue].as_entire_binding(),
+            16 => self.dispatch_args.as_entire_binding(),
+            17 => wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &self.params,
+                offset: 0,
+                size: NonZeroU64::new(std::mem::size_of::<WavefrontParams>() as u64),
+            }),
+            _ => return bindings.entry(binding, swap_textures),
+        };
+        wgpu::BindGroupEntry { binding, resource }
+    }
+}
+
+impl Layouts {
+    fn new(device: &wgpu::Device) -> Self {
+        let layout = |label: &str, bindings: &[u32]| {
+            let entries: Vec<_> = bindings.iter().map(|&b| layout_entry(b)).collect();
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some(label),
+                entries: &entries,
+            })
+        };
+        Self {
+            path: layout("Wavefront Path Bind Group Layout", &PATH_BINDINGS),
+            extend: layout("Wavefront Extend Bind Group Layout", &EXTEND_BINDINGS),
+            connect: layout("Wavefront Connect Bind Group Layout", &CONNECT_BINDINGS),
+        }
+    }
+
+    fn create_bind_groups(
+        &self,
+        device: &wgpu::Device,
+        buffers: &Buffers,
+        bindings: &TraceBindings,
+    ) -> BindGroups {
+        let bind_group = |layout: &wgpu::BindGroupLayout,
+                          binding_numbers: &[u32],
+                          current_queue: usize,
+                          swap_textures: bool| {
+            let entries: Vec<_> = binding_numbers
+                .iter()
+                .map(|&b| buffers.entry(bindings, b, current_queue, swap_textures))
+                .collect();
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Wavefront Bind Group"),
+                layout,
+                entries: &entries,
+            })
+        };
+        BindGroups {
+            path: [0, 1].map(|queue| bind_group(&self.path, &PATH_BINDINGS, queue, false)),
+            extend: [0, 1].map(|queue| bind_group(&self.extend, &EXTEND_BINDINGS, queue, false)),
+            connect: [false, true].map(|swap_textures| {
+                bind_group(&self.connect, &CONNECT_BINDINGS, 0, swap_textures)
+            }),
+        }
+    }
+}
+
+impl Kernels {
+    fn new(
+        device: &wgpu::Device,
+        layouts: &Layouts,
+        bvh_traversal: BvhTraversal,
+        bvh_stack_size: u32,
+    ) -> Self {
+        let shader = create_trace_shader(
+            device,
+            "Wavefront Shader",
+            include_str!("wavefront.wgsl"),
+            bvh_traversal,
+            bvh_stack_size,
+        );
+        let pipeline = |entry_point: &str, layout: &wgpu::BindGroupLayout| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(entry_point),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                cache: None,
+            })
+        };
+        Self {
+            generate: pipeline("generate", &layouts.path),
+            prepare_dispatch: pipeline("prepare_dispatch", &layouts.path),
+            extend: pipeline("extend", &layouts.extend),
+            shade: pipeline("shade", &layouts.path),
+            connect: pipeline("connect", &layouts.connect),
+        }
+    }
+}
+
+// Layout of a binding in wavefront.wgsl, or in trace.wgsl for the ones shared with the megakernel
+fn layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    let ty = match binding {
+        13..=16 => wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        17 => wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: true,
+            min_binding_size: NonZeroU64::new(std::mem::size_of::<WavefrontParams>() as u64),
+        },
+        _ => return TraceBindings::layout_entry(binding),
+    };
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty,
+        count: None,
+    }
+}
+
+fn begin_pass<'a>(encoder: &'a mut wgpu::CommandEncoder, label: &str) -> wgpu::ComputePass<'a> {
+    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: Some(label),
+        timestamp_writes: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{bvh::BvhTraversal, headless, RenderConfig, TraceMode};
+
+    // The kernels draw the same random numbers for each path as the megakernel does, so both
+    // tracers make exactly the same image
+    fn check_matches_megakernel(config: RenderConfig) {
+        let Ok((device, queue)) = pollster::block_on(headless::create_device()) else {
+            eprintln!("No graphics adapter, skipping");
+            return;
+        };
+        let render = |trace_mode| {
+            let config = RenderConfig {
+                trace_mode,
+                ..config.clone()
+            };
+            headless::render(&device, &queue, &config).unwrap()
+        };
+        let megakernel = render(TraceMode::Megakernel);
+        let wavefront = render(TraceMode::Wavefront);
+        let differing = megakernel
+            .chunks(4)
+            .zip(wavefront.chunks(4))
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(differing, 0, "Pixels differ between the tracers");
+    }
+
+    fn config() -> RenderConfig {
+        RenderConfig {
+            width: 61,
+            height: 47,
+            samples: 4,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_scene_matches_megakernel() {
+        check_matches_megakernel(config());
+    }
+
+    #[test]
+    fn instances_match_megakernel() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut config = RenderConfig {
+            scene: Some(manifest_dir.join("scenes/instances.ron")),
+            ..config()
+        };
+        config.bvh.traversal = BvhTraversal::Stackless;
+        check_matches_megakernel(config);
+    }
+
+    #[test]
+    fn textures_match_megakernel() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        check_matches_megakernel(RenderConfig {
+            scene: Some(manifest_dir.join("scenes/textures.ron")),
+            ..config()
+        });
+    }
+}
@@ -0,0 +1,151 @@
+// ----------------------- Wavefront -----------------------
+// Trace paths one bounce at a time with a kernel for each step, instead of following every path to
+// its end in one invocation. Rays waiting for the next step are kept in a queue that only holds the
+// paths still going, so every invocation of a kernel does the same kind of work on a live path:
+//
+// generate: start a path for each pixel and queue its camera ray
+// extend: find where each queued ray hits the scene
+// shade: add the light from what each ray hit (or the sky), then queue the scattered ray if the
+//        path carries on. The new queue is compacted, paths that ended leave no gaps in it
+// connect: add the finished paths to their pixels in the accumulation texture
+//
+// The paths are kept for a chunk of the image at a time so the queues don't grow with the image,
+// and every dispatch fits along x
+
+// What a path has gathered so far, kept between kernels. There is one for each pixel of the chunk
+struct Path {
+    throughput: vec3f,
+    rng_state: u32,
+    radiance: vec3f,
+    length: u32,
+    medium: vec3f, // Absorption of what the path's next ray goes through, see `transmittance`
+}
+
+// A ray waiting to be extended, extend fills in what it hits for shade
+struct QueuedRay {
+    origin: vec3f,
+    path_index: u32,
+    direction: vec3f,
+    t: f32, // Negative if the ray missed everything
+    normal: vec3f,
+    material: u32,
+    uv: vec2f,
+}
+
+struct RayQueue {
+    length: atomic<u32>,
+    rays: array<QueuedRay>,
+}
+
+// Same as `WavefrontParams` in wavefront.rs
+struct WavefrontParams {
+    first_pixel: u32, // Pixels are numbered row by row from the top left
+    path_count: u32,
+}
+
+@group(0) @binding(13) var<storage, read_write> paths: array<Path>;
+@group(0) @binding(14) var<storage, read_write> ray_queue: RayQueue;
+@group(0) @binding(15) var<storage, read_write> next_ray_queue: RayQueue;
+@group(0) @binding(16) var<storage, read_write> dispatch_args: array<u32, 3>;
+@group(0) @binding(17) var<uniform> params: WavefrontParams;
+
+// Has to match WORKGROUP_SIZE in wavefront.rs
+const WORKGROUP_SIZE: u32 = 64u;
+
+fn pixel_of_path(path_index: u32) -> vec2u {
+    let pixel_index = params.first_pixel + path_index;
+    return vec2u(pixel_index % uniforms.width, pixel_index / uniforms.width);
+}
+
+fn queued_ray(ray: Ray, path_index: u32) -> QueuedRay {
+    return QueuedRay(ray.origin, path_index, ray.direction, -1., vec3f(0.), 0u, vec2f(0.));
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn generate(@builtin(global_invocation_id) id: vec3u) {
+    let path_index = id.x;
+    if path_index >= params.path_count {
+        return;
+    }
+    // The random numbers are drawn in the same order as in the megakernel, so both trace the
+    // same paths
+    let pixel = pixel_of_path(path_index);
+    init_rng(pixel, uniforms.width, uniforms.frame_num, uniforms.seed);
+    let ray = camera_ray(vec2f(pixel) + 0.5);
+    paths[path_index] = Path(vec3f(1.), rng.state, vec3f(0.), 0u, vec3f(0.));
+
+    if uniforms.max_path_length > 0u {
+        let slot = atomicAdd(&ray_queue.length, 1u);
+        ray_queue.rays[slot] = queued_ray(ray, path_index);
+    }
+}
+
+// Size the dispatches of extend and shade to the number of rays in the queue
+@compute @workgroup_size(1)
+fn prepare_dispatch() {
+    dispatch_args[0] = (atomicLoad(&ray_queue.length) + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+    dispatch_args[1] = 1u;
+    dispatch_args[2] = 1u;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn extend(@builtin(global_invocation_id) id: vec3u) {
+    let index = id.x;
+    if index >= atomicLoad(&ray_queue.length) {
+        return;
+    }
+    let queued = ray_queue.rays[index];
+    let hit = intersect_scene(Ray(queued.origin, queued.direction));
+    ray_queue.rays[index].t = hit.t;
+    ray_queue.rays[index].normal = hit.normal;
+    ray_queue.rays[index].material = hit.material;
+    ray_queue.rays[index].uv = hit.uv;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn shade(@builtin(global_invocation_id) id: vec3u) {
+    let index = id.x;
+    if index >= atomicLoad(&ray_queue.length) {
+        return;
+    }
+    let queued = ray_queue.rays[index];
+    let ray = Ray(queued.origin, queued.direction);
+    let hit = Intersection(queued.normal, queued.t, queued.material, queued.uv);
+    var path = paths[queued.path_index];
+    if !is_intersection(hit) {
+        // The path ends in the sky
+        path.radiance += path.throughput * sky_colour(ray);
+        paths[queued.path_index] = path;
+        return;
+    }
+
+    rng.state = path.rng_state;
+    path.throughput *= transmittance(path.medium, ray, hit);
+    let material = surface_material(ray, hit);
+    path.radiance += path.throughput * material.emission_colour * material.emission_strength;
+    let scattered = scatter(ray, hit, material, path.medium);
+    path.throughput *= scattered.attenuation;
+    path.medium = scattered.medium;
+    path.length += 1u;
+    path.rng_state = rng.state;
+    paths[queued.path_index] = path;
+
+    if path.length < uniforms.max_path_length {
+        let slot = atomicAdd(&next_ray_queue.length, 1u);
+        next_ray_queue.rays[slot] = queued_ray(scattered.ray, queued.path_index);
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn connect(@builtin(global_invocation_id) id: vec3u) {
+    let path_index = id.x;
+    if path_index >= params.path_count {
+        return;
+    }
+    let pixel = pixel_of_path(path_index);
+    var old_sum = vec4f(0.);
+    if uniforms.frame_num > 1 {
+        old_sum = textureLoad(radiance_samples_old, pixel, 0);
+    }
+    textureStore(radiance_samples_new, pixel, old_sum + vec4(paths[path_index].radiance, 1.));
+}
@@ -0,0 +1,96 @@
+//! Checks on the BVHs every builder makes for the canonical scenes, using `BVH::validate` and
+//! `BVH::stats`.
+
+use std::path::Path;
+
+use ray_rs::{
+    create_bvh, create_scene, BvhBuilder, BvhSettings, RenderConfig, Scene, Transform, Vec3,
+};
+
+const BUILDERS: [BvhBuilder; 3] = [BvhBuilder::Median, BvhBuilder::Sah, BvhBuilder::Lbvh];
+
+fn load_scene(scene: Option<&str>, meshes: &[&str]) -> Scene {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let config = RenderConfig {
+        scene: scene.map(|path| manifest_dir.join(path)),
+        meshes: meshes.iter().map(|path| manifest_dir.join(path)).collect(),
+        ..Default::default()
+    };
+    create_scene(&config).unwrap().0
+}
+
+fn scenes() -> Vec<(&'static str, Scene)> {
+    vec![
+        ("default scene", load_scene(None, &[])),
+        ("cornell box", load_scene(Some("scenes/cornell.ron"), &[])),
+        ("instances", load_scene(Some("scenes/instances.ron"), &[])),
+        ("mesh", load_scene(None, &["scenes/pyramid.obj"])),
+    ]
+}
+
+#[test]
+fn builders_make_valid_trees() {
+    for (name, scene) in scenes() {
+        for builder in BUILDERS {
+            for max_leaf_size in [1, 4] {
+                let settings = BvhSettings {
+                    builder,
+                    max_leaf_size,
+                    ..Default::default()
+                };
+                let bvh = create_bvh(&scene, &settings);
+                if let Err(e) = bvh.validate(&scene) {
+                    panic!("{builder:?} BVH for the {name} is invalid: {e:#}");
+                }
+
+                let stats = bvh.stats();
+                assert_eq!(stats.object_count, scene.len(), "{builder:?}, {name}");
+                let objects_in_leaves: usize = stats
+                    .leaf_sizes
+                    .iter()
+                    .enumerate()
+                    .map(|(size, count)| size * count)
+                    .sum();
+                assert_eq!(objects_in_leaves, scene.len(), "{builder:?}, {name}");
+                // Every internal node has two children
+                assert_eq!(stats.node_count, 2 * stats.leaf_count() - 1);
+                assert!(stats.depth >= 1 && stats.sah_cost > 0. && stats.overlap >= 0.);
+                if builder != BvhBuilder::Lbvh {
+                    assert!(stats.leaf_sizes.len() <= max_leaf_size as usize + 1);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn empty_scene() {
+    let scene = Scene::new();
+    for builder in BUILDERS {
+        let settings = BvhSettings {
+            builder,
+            ..Default::default()
+        };
+        let bvh = create_bvh(&scene, &settings);
+        bvh.validate(&scene).unwrap();
+        assert!(bvh.is_empty());
+
+        let stats = bvh.stats();
+        assert_eq!((stats.node_count, stats.object_count), (1, 0));
+        assert_eq!(stats.depth, 0);
+        assert_eq!(stats.leaf_count(), 0);
+    }
+}
+
+#[test]
+fn refit_keeps_the_tree_valid() {
+    let mut scene = load_scene(Some("scenes/instances.ron"), &[]);
+    let mut bvh = create_bvh(&scene, &BvhSettings::default());
+    scene.set_instance_transform(0, Transform::from_translation(Vec3::new(3., 2., 1.)));
+    assert!(
+        bvh.validate(&scene).is_err(),
+        "The moved instance should be outside its leaf"
+    );
+    bvh.refit(&scene);
+    bvh.validate(&scene).unwrap();
+}
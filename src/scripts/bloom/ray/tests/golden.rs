@@ -0,0 +1,242 @@
+//! Golden image tests: render a few canonical scenes on the CPU and compare them with the
+//! reference images in tests/golden.
+//!
+//! Run with `UPDATE_GOLDEN=1 cargo test --test golden` to replace the references after an
+//! intentional change to the output. When a comparison fails the render and a diff image are
+//! written next to the test binaries (see the failure message for where).
+
+use std::path::{Path, PathBuf};
+
+use ray_rs::{
+    render_cpu, BvhBuilder, BvhSettings, BvhTraversal, CameraOverrides, RenderConfig, Vec3,
+};
+
+const WIDTH: u32 = 96;
+const HEIGHT: u32 = 72;
+const SAMPLES: u32 = 32;
+const SEED: u32 = 1;
+
+// Root mean square error allowed between the render and the reference, in 8 bit sRGB steps.
+// Renders with the same seed are identical on the same machine, but different maths libraries
+// round differently and send the odd path another way, so a little noise has to be tolerated
+const MAX_RMSE: f64 = 2.0;
+
+// Both images are averaged over blocks of this many pixels square before they are compared, which
+// cancels out most of that noise while keeping the structure of the image
+const BLOCK_SIZE: u32 = 2;
+
+// Differences are multiplied by this in the diff image so small ones are still visible
+const DIFF_GAIN: u8 = 8;
+
+fn manifest_path(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+fn config() -> RenderConfig {
+    RenderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        samples: SAMPLES,
+        seed: SEED,
+        ..Default::default()
+    }
+}
+
+fn scene_config(scene: &str) -> RenderConfig {
+    RenderConfig {
+        scene: Some(manifest_path(scene)),
+        ..config()
+    }
+}
+
+/// Render `config` and compare it with tests/golden/{name}.png
+fn check_golden(name: &str, config: RenderConfig) {
+    let render = render_cpu(&config)
+        .unwrap_or_else(|e| panic!("Failed to render {name}: {e:#}"))
+        .to_rgba8();
+    let render = image::RgbaImage::from_raw(config.width, config.height, render)
+        .expect("The render has one pixel per width x height");
+
+    let reference_path = manifest_path(&format!("tests/golden/{name}.png"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        render
+            .save(&reference_path)
+            .unwrap_or_else(|e| panic!("Failed to write {}: {e}", reference_path.display()));
+        return;
+    }
+
+    let reference = image::open(&reference_path)
+        .unwrap_or_else(|e| {
+            panic!(
+                "Failed to open {}: {e}\nRun with UPDATE_GOLDEN=1 to create it",
+                reference_path.display()
+            )
+        })
+        .to_rgba8();
+    assert_eq!(
+        reference.dimensions(),
+        render.dimensions(),
+        "{name} was rendered at a different size than its reference"
+    );
+
+    let rmse = block_rmse(&render, &reference);
+    if rmse > MAX_RMSE {
+        let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let render_path = output_dir.join(format!("{name}.png"));
+        let diff_path = output_dir.join(format!("{name}.diff.png"));
+        render.save(&render_path).unwrap();
+        diff_image(&render, &reference).save(&diff_path).unwrap();
+        panic!(
+            "{name} differs from its reference (RMSE {rmse:.2}, at most {MAX_RMSE} allowed)\n\
+            Render: {}\nDiff: {}",
+            render_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+fn block_rmse(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+    let block_average = |image: &image::RgbaImage, bx: u32, by: u32, channel: usize| {
+        let mut sum = 0.;
+        let mut count = 0.;
+        for y in by * BLOCK_SIZE..((by + 1) * BLOCK_SIZE).min(image.height()) {
+            for x in bx * BLOCK_SIZE..((bx + 1) * BLOCK_SIZE).min(image.width()) {
+                sum += image.get_pixel(x, y)[channel] as f64;
+                count += 1.;
+            }
+        }
+        sum / count
+    };
+
+    let mut squared_error = 0.;
+    let mut count = 0.;
+    for by in 0..a.height().div_ceil(BLOCK_SIZE) {
+        for bx in 0..a.width().div_ceil(BLOCK_SIZE) {
+            for channel in 0..3 {
+                let error = block_average(a, bx, by, channel) - block_average(b, bx, by, channel);
+                squared_error += error * error;
+                count += 1.;
+            }
+        }
+    }
+    (squared_error / count).sqrt()
+}
+
+fn diff_image(a: &image::RgbaImage, b: &image::RgbaImage) -> image::RgbaImage {
+    image::RgbaImage::from_fn(a.width(), a.height(), |x, y| {
+        let (pa, pb) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let diff = |c: usize| pa[c].abs_diff(pb[c]).saturating_mul(DIFF_GAIN);
+        image::Rgba([diff(0), diff(1), diff(2), u8::MAX])
+    })
+}
+
+#[test]
+fn default_scene() {
+    check_golden("default_scene", config());
+}
+
+#[test]
+fn cornell_box() {
+    // Only a small part of the paths find the light, so this needs more samples to converge
+    check_golden(
+        "cornell_box",
+        RenderConfig {
+            samples: 4 * SAMPLES,
+            ..scene_config("scenes/cornell.ron")
+        },
+    );
+}
+
+#[test]
+fn glass_spheres() {
+    check_golden("glass_spheres", scene_config("scenes/glass.ron"));
+}
+
+#[test]
+fn mesh_instances() {
+    check_golden("mesh_instances", scene_config("scenes/instances.ron"));
+}
+
+#[test]
+fn textured_surfaces() {
+    check_golden("textured_surfaces", scene_config("scenes/textures.ron"));
+}
+
+#[test]
+fn procedural_patterns() {
+    check_golden("procedural_patterns", scene_config("scenes/patterns.ron"));
+}
+
+#[test]
+fn rough_metal_and_glass() {
+    check_golden(
+        "rough_metal_and_glass",
+        scene_config("scenes/microfacets.ron"),
+    );
+}
+
+#[test]
+fn principled_materials() {
+    check_golden(
+        "principled_materials",
+        scene_config("scenes/principled.ron"),
+    );
+}
+
+#[test]
+fn tinted_glass_volumes() {
+    check_golden(
+        "tinted_glass_volumes",
+        scene_config("scenes/absorption.ron"),
+    );
+}
+
+fn triangle_mesh_config() -> RenderConfig {
+    RenderConfig {
+        meshes: vec![manifest_path("scenes/pyramid.obj")],
+        camera: CameraOverrides {
+            position: Some(Vec3::new(2., 1.5, 3.)),
+            look_at: Some(Vec3::new(0., 0.6, 0.)),
+            ..Default::default()
+        },
+        ..config()
+    }
+}
+
+#[test]
+fn triangle_mesh() {
+    check_golden("triangle_mesh", triangle_mesh_config());
+}
+
+#[test]
+fn triangle_mesh_stackless() {
+    // Walking the tree by its miss links finds the same hits, so this matches the same reference
+    let config = triangle_mesh_config();
+    check_golden(
+        "triangle_mesh",
+        RenderConfig {
+            bvh: BvhSettings {
+                traversal: BvhTraversal::Stackless,
+                ..config.bvh
+            },
+            ..config
+        },
+    );
+}
+
+#[test]
+fn triangle_mesh_lbvh() {
+    // A different tree over the same triangles finds the same hits
+    let config = triangle_mesh_config();
+    check_golden(
+        "triangle_mesh",
+        RenderConfig {
+            bvh: BvhSettings {
+                builder: BvhBuilder::Lbvh,
+                ..config.bvh
+            },
+            ..config
+        },
+    );
+}
@@ -0,0 +1,194 @@
+//! Drives `Renderer` through its public API the way an app embedding the tracer would, checking
+//! that changes to the scene, camera and size give the same image as starting from scratch, and
+//! that the shaders trace the same image as the CPU tracer.
+//!
+//! Every test is skipped if there is no graphics adapter, not even a software one.
+
+use std::path::Path;
+
+use ray_rs::{
+    create_device, create_scene, render_cpu, CameraSettings, RenderConfig, RenderTarget, Renderer,
+    Scene, Vec3,
+};
+
+const SAMPLES: u32 = 4;
+
+fn config() -> RenderConfig {
+    RenderConfig {
+        width: 48,
+        height: 36,
+        samples: SAMPLES,
+        ..Default::default()
+    }
+}
+
+// Root mean square error allowed between the GPU and CPU renders of a scene, in 8 bit sRGB steps.
+// Both follow the same paths, but now and then rounding sends one of them another way
+const MAX_CPU_RMSE: f64 = 2.0;
+// Those paths only change a few pixels, while the shaders and the CPU tracer working something out
+// differently changes every pixel that depends on it. This is the fraction of pixels allowed to be
+// more than CPU_PIXEL_TOLERANCE steps apart
+const MAX_CPU_PIXELS_OFF: f64 = 0.01;
+const CPU_PIXEL_TOLERANCE: i32 = 2;
+
+fn scene_config(path: Option<&str>) -> RenderConfig {
+    RenderConfig {
+        scene: path.map(|path| Path::new(env!("CARGO_MANIFEST_DIR")).join(path)),
+        ..config()
+    }
+}
+
+fn load_scene(path: Option<&str>) -> (Scene, CameraSettings) {
+    create_scene(&scene_config(path)).unwrap()
+}
+
+fn device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    let device = pollster::block_on(create_device()).ok();
+    if device.is_none() {
+        eprintln!("No graphics adapter, skipping");
+    }
+    device
+}
+
+fn new_renderer<'a>(
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    config: &RenderConfig,
+    scene: Scene,
+    camera: &CameraSettings,
+) -> Renderer<'a> {
+    Renderer::new(device, queue, RenderTarget::None, config, scene, camera).unwrap()
+}
+
+// A finished image of a scene from a renderer that has only ever seen it
+fn fresh_render(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    config: &RenderConfig,
+    (scene, camera): (Scene, CameraSettings),
+) -> Vec<u8> {
+    let mut renderer = new_renderer(device, queue, config, scene, &camera);
+    renderer.render_samples(SAMPLES).unwrap();
+    renderer.read_back().unwrap()
+}
+
+#[test]
+fn samples_can_be_rendered_in_batches() {
+    let Some((device, queue)) = device() else {
+        return;
+    };
+    let (scene, camera) = load_scene(None);
+    let mut renderer = new_renderer(&device, &queue, &config(), scene, &camera);
+    for _ in 0..SAMPLES / 2 {
+        renderer.render_samples(2).unwrap();
+    }
+    assert_eq!(renderer.samples_traced(), SAMPLES);
+    assert_eq!(renderer.samples_left(), 0);
+    assert!(
+        renderer.read_back().unwrap() == fresh_render(&device, &queue, &config(), load_scene(None)),
+        "Rendering the samples in batches changed the image"
+    );
+}
+
+#[test]
+fn changing_scene_and_camera_starts_again() {
+    let Some((device, queue)) = device() else {
+        return;
+    };
+    let (scene, camera) = load_scene(None);
+    let mut renderer = new_renderer(&device, &queue, &config(), scene, &camera);
+    renderer.render_samples(SAMPLES).unwrap();
+    let first = renderer.read_back().unwrap();
+
+    // Look somewhere else for a bit, then back
+    renderer.set_camera(&CameraSettings {
+        position: Vec3::new(-3., 1., 2.),
+        ..camera
+    });
+    renderer.render_samples(SAMPLES / 2).unwrap();
+    assert_eq!(renderer.samples_traced(), SAMPLES / 2);
+    renderer.set_camera(&camera);
+    renderer.render_samples(SAMPLES).unwrap();
+    assert!(
+        renderer.read_back().unwrap() == first,
+        "Moving the camera away and back changed the image"
+    );
+
+    let (cornell, cornell_camera) = load_scene(Some("scenes/cornell.ron"));
+    renderer.set_scene(cornell);
+    renderer.set_camera(&cornell_camera);
+    renderer.render_samples(SAMPLES).unwrap();
+    let from_scratch = fresh_render(
+        &device,
+        &queue,
+        &config(),
+        load_scene(Some("scenes/cornell.ron")),
+    );
+    assert!(
+        renderer.read_back().unwrap() == from_scratch,
+        "Switching scenes gave a different image than rendering the new scene from scratch"
+    );
+}
+
+#[test]
+fn resizing_replaces_the_target() {
+    let Some((device, queue)) = device() else {
+        return;
+    };
+    let (scene, camera) = load_scene(None);
+    let mut renderer = new_renderer(&device, &queue, &config(), scene, &camera);
+    renderer.render_samples(SAMPLES).unwrap();
+
+    let resized = RenderConfig {
+        width: 30,
+        height: 41,
+        ..config()
+    };
+    renderer.resize(resized.width, resized.height);
+    assert_eq!(renderer.size(), (resized.width, resized.height));
+    assert_eq!(renderer.samples_traced(), 0);
+    renderer.render_samples(SAMPLES).unwrap();
+    assert!(
+        renderer.read_back().unwrap() == fresh_render(&device, &queue, &resized, load_scene(None)),
+        "Resizing gave a different image than rendering at the new size from scratch"
+    );
+}
+
+#[test]
+fn gpu_matches_cpu() {
+    let Some((device, queue)) = device() else {
+        return;
+    };
+    // A scene for each kind of material and surface the shaders have to agree on
+    for path in [
+        None,
+        Some("scenes/cornell.ron"),
+        Some("scenes/textures.ron"),
+        Some("scenes/patterns.ron"),
+        Some("scenes/microfacets.ron"),
+        Some("scenes/principled.ron"),
+        Some("scenes/absorption.ron"),
+    ] {
+        let config = scene_config(path);
+        let gpu = fresh_render(&device, &queue, &config, load_scene(path));
+        let cpu = render_cpu(&config).unwrap().to_rgba8();
+        let pixels = || gpu.chunks_exact(4).zip(cpu.chunks_exact(4));
+        let difference = |a: &[u8], b: &[u8], c: usize| a[c] as i32 - b[c] as i32;
+        let squared_error: f64 = pixels()
+            .flat_map(|(a, b)| (0..3).map(move |c| (difference(a, b, c) as f64).powi(2)))
+            .sum();
+        let pixel_count = (config.width * config.height) as f64;
+        let rmse = (squared_error / (3. * pixel_count)).sqrt();
+        let pixels_off = pixels()
+            .filter(|(a, b)| (0..3).any(|c| difference(a, b, c).abs() > CPU_PIXEL_TOLERANCE))
+            .count() as f64
+            / pixel_count;
+        let name = path.unwrap_or("the default scene");
+        assert!(
+            rmse <= MAX_CPU_RMSE && pixels_off <= MAX_CPU_PIXELS_OFF,
+            "The GPU and CPU renders of {name} differ by an RMSE of {rmse:.2}, with {:.1}% of \
+            the pixels more than {CPU_PIXEL_TOLERANCE} steps apart",
+            100. * pixels_off
+        );
+    }
+}